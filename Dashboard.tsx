@@ -52,7 +52,7 @@ const Dashboard = () => {
         <ul>
           {Object.entries(deviceHealth).map(([deviceId, health]) => (
             <li key={deviceId}>
-              {deviceId}: {health.is_online ? 'Online' : 'Offline'} (Last update: {health.last_update})
+              {deviceId}: {health.status === 'online' ? 'Online' : health.status === 'offline' ? 'Offline' : 'Unknown'} (Last update: {health.last_update})
             </li>
           ))}
         </ul>
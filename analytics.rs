@@ -1,39 +1,258 @@
 // analytics.rs
 
-use crate::device::{Device, DeviceManager};
-use std::collections::HashMap;
+use crate::config::AnalyticsConfig;
+use crate::device::{DeviceManager, Reading};
+use crate::notification::{Notification, NotificationDispatcher};
+use crate::tenant::TenantManager;
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::sync::{Arc, Mutex};
 
 /// Analytics service that processes and analyzes data from IoT devices.
 pub struct Analytics {
     device_manager: Arc<DeviceManager>,
+    config: AnalyticsConfig,
     // Here we might store historical data, statistical models, or other analytical data structures
     // For simplicity, we'll just keep a count of the number of updates per device
     update_counts: Arc<Mutex<HashMap<String, u64>>>,
+    // Event-time windows per device, keyed by window start
+    windows: Arc<Mutex<HashMap<String, DeviceWindows>>>,
+    // Side output for readings that arrived after their window was finalized
+    late_readings: Arc<Mutex<VecDeque<Reading>>>,
+    // Set once late readings were dropped, until the side output is drained again
+    late_readings_overflowed: Arc<Mutex<bool>>,
+    // Number of late readings dropped from the full side output so far
+    dropped_late_readings: Arc<Mutex<u64>>,
+    tenants: Option<Arc<TenantManager>>,
+    notifications: Option<Arc<NotificationDispatcher>>,
+}
+
+/// Running aggregate of a single metric within a window.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MetricAggregate {
+    pub count: u64,
+    pub sum: f64,
+    pub min: f64,
+    pub max: f64,
+}
+
+impl MetricAggregate {
+    fn new(value: f64) -> Self {
+        MetricAggregate {
+            count: 1,
+            sum: value,
+            min: value,
+            max: value,
+        }
+    }
+
+    fn add(&mut self, value: f64) {
+        self.count += 1;
+        self.sum += value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    fn merge(&mut self, other: &MetricAggregate) {
+        self.count += other.count;
+        self.sum += other.sum;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+
+    /// Returns the average of the values in the window.
+    pub fn mean(&self) -> f64 {
+        self.sum / self.count as f64
+    }
+}
+
+/// Aggregates of all metrics of a device within one tumbling event-time window.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Window {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub metrics: HashMap<String, MetricAggregate>,
+    /// Set once the watermark passed the end of the window plus the allowed lateness.
+    pub finalized: bool,
+    /// Incremented every time late data changes the window after it was first passed by the watermark.
+    pub revision: u32,
+}
+
+/// Event-time bookkeeping for a single device.
+#[derive(Debug, Default)]
+struct DeviceWindows {
+    /// Highest event time seen so far; readings older than this are out of order.
+    watermark: Option<DateTime<Utc>>,
+    windows: BTreeMap<i64, Window>,
+}
+
+/// How an event-time reading was handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventOutcome {
+    /// The reading was in order.
+    OnTime,
+    /// The reading was out of order but within the allowed lateness; its window was recomputed.
+    Late { window_start: DateTime<Utc> },
+    /// The reading's window was already finalized, so it went to the late-data side output.
+    TooLate,
 }
 
 impl Analytics {
     /// Creates a new Analytics service.
     pub fn new(device_manager: Arc<DeviceManager>) -> Self {
+        Self::with_config(device_manager, AnalyticsConfig::default())
+    }
+
+    /// Creates a new Analytics service with the given window settings.
+    pub fn with_config(device_manager: Arc<DeviceManager>, config: AnalyticsConfig) -> Self {
         Analytics {
             device_manager,
+            config,
             update_counts: Arc::new(Mutex::new(HashMap::new())),
+            windows: Arc::new(Mutex::new(HashMap::new())),
+            late_readings: Arc::new(Mutex::new(VecDeque::new())),
+            late_readings_overflowed: Arc::new(Mutex::new(false)),
+            dropped_late_readings: Arc::new(Mutex::new(0)),
+            tenants: None,
+            notifications: None,
         }
     }
 
-    /// Processes incoming data for a device and updates analytics.
+    /// Applies the retention configured for each device's tenant to its windows.
+    pub fn with_tenants(mut self, tenants: Arc<TenantManager>) -> Self {
+        self.tenants = Some(tenants);
+        self
+    }
+
+    /// Raises an alert through the dispatcher when late readings overflow the side output.
+    pub fn with_notifications(mut self, notifications: Arc<NotificationDispatcher>) -> Self {
+        self.notifications = Some(notifications);
+        self
+    }
+
+    /// Processes incoming data for a device and updates analytics, using the current time
+    /// as the event time.
     pub fn process_device_data(&self, device_id: &str, data: &HashMap<String, f64>) {
+        self.process_event(device_id, data, Utc::now());
+    }
+
+    /// Processes a reading taken at `event_time`, which may be earlier than readings already
+    /// seen when a device uploads buffered data.
+    pub fn process_event(&self, device_id: &str, data: &HashMap<String, f64>, event_time: DateTime<Utc>) -> EventOutcome {
+        let outcome = self.update_windows(device_id, data, event_time);
+
+        if outcome == EventOutcome::TooLate {
+            let mut late_readings = self.late_readings.lock().unwrap();
+            late_readings.push_back(Reading {
+                device_id: device_id.to_string(),
+                data: data.clone(),
+                received_at: Utc::now(),
+                event_time,
+            });
+            let mut dropped = 0;
+            while late_readings.len() > self.config.late_readings_limit {
+                late_readings.pop_front();
+                dropped += 1;
+            }
+            if dropped > 0 {
+                *self.dropped_late_readings.lock().unwrap() += dropped;
+                self.late_readings_dropped(device_id);
+            }
+            return outcome;
+        }
+
         let mut update_counts = self.update_counts.lock().unwrap();
         let count = update_counts.entry(device_id.to_string()).or_insert(0);
         *count += 1;
 
         // Here you would implement more complex analytics, such as:
-        // - Aggregating data over time
         // - Running statistical analysis
         // - Detecting anomalies or patterns
         // - Generating insights or predictions based on the data
+        outcome
+    }
+
+    fn update_windows(&self, device_id: &str, data: &HashMap<String, f64>, event_time: DateTime<Utc>) -> EventOutcome {
+        let window_secs = self.config.window_secs.max(1) as i64;
+        let allowed_lateness = Duration::seconds(self.config.allowed_lateness_secs as i64);
+        let retention = self
+            .tenants
+            .as_ref()
+            .and_then(|tenants| tenants.settings_for_device(device_id).retention_days)
+            .map(|days| Duration::days(days as i64));
+
+        // Event times from the future still land in their window but advance the watermark
+        // no further than the current time plus the allowed skew
+        let horizon = Utc::now() + Duration::seconds(self.config.max_future_skew_secs as i64);
+        let watermark_time = event_time.min(horizon);
+
+        let mut windows = self.windows.lock().unwrap();
+        let device = windows.entry(device_id.to_string()).or_default();
+
+        let watermark = device.watermark.unwrap_or(watermark_time);
+        if event_time < watermark - allowed_lateness {
+            return EventOutcome::TooLate;
+        }
+
+        let start_secs = event_time.timestamp().div_euclid(window_secs) * window_secs;
+        let window = device.windows.entry(start_secs).or_insert_with(|| {
+            let start = Utc.timestamp_opt(start_secs, 0).unwrap();
+            Window {
+                start,
+                end: start + Duration::seconds(window_secs),
+                metrics: HashMap::new(),
+                finalized: false,
+                revision: 0,
+            }
+        });
+        if window.finalized {
+            return EventOutcome::TooLate;
+        }
+        for (metric, value) in data {
+            match window.metrics.get_mut(metric) {
+                Some(aggregate) => aggregate.add(*value),
+                None => {
+                    window.metrics.insert(metric.clone(), MetricAggregate::new(*value));
+                }
+            }
+        }
+
+        let outcome = if event_time < watermark && window.end <= watermark {
+            // The watermark already moved past this window, so its result changed after the fact
+            window.revision += 1;
+            EventOutcome::Late { window_start: window.start }
+        } else if event_time < watermark {
+            EventOutcome::Late { window_start: window.start }
+        } else {
+            EventOutcome::OnTime
+        };
+
+        // Advance the watermark, finalize windows that can no longer change and drop old ones.
+        // Windows still open to late readings are kept, so none of those readings is lost
+        let watermark = watermark.max(watermark_time);
+        device.watermark = Some(watermark);
+        for window in device.windows.values_mut() {
+            if window.end + allowed_lateness <= watermark {
+                window.finalized = true;
+            }
+        }
+        let excess = device.windows.len().saturating_sub(self.config.retained_windows);
+        let evicted: Vec<i64> = device
+            .windows
+            .iter()
+            .filter(|(_, window)| window.finalized)
+            .map(|(start, _)| *start)
+            .take(excess)
+            .collect();
+        for start in evicted {
+            device.windows.remove(&start);
+        }
+        if let Some(retention) = retention {
+            device.windows.retain(|_, window| !window.finalized || window.end + retention > watermark);
+        }
 
-        // For now, we're just updating a simple count of updates per device
+        outcome
     }
 
     /// Retrieves the analytics data for a specific device.
@@ -47,6 +266,89 @@ impl Analytics {
         let update_counts = self.update_counts.lock().unwrap();
         update_counts.clone()
     }
+
+    /// Retrieves the event-time windows of a device, oldest first.
+    pub fn get_device_windows(&self, device_id: &str) -> Vec<Window> {
+        let windows = self.windows.lock().unwrap();
+        windows
+            .get(device_id)
+            .map(|device| device.windows.values().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Combines all windows of a device, finalized or not, into one aggregate per metric.
+    pub fn get_device_metrics(&self, device_id: &str) -> HashMap<String, MetricAggregate> {
+        let mut metrics: HashMap<String, MetricAggregate> = HashMap::new();
+        for window in self.get_device_windows(device_id) {
+            for (metric, aggregate) in &window.metrics {
+                match metrics.get_mut(metric) {
+                    Some(total) => total.merge(aggregate),
+                    None => {
+                        metrics.insert(metric.clone(), aggregate.clone());
+                    }
+                }
+            }
+        }
+        metrics
+    }
+
+    /// Retrieves the current watermark of a device.
+    pub fn get_watermark(&self, device_id: &str) -> Option<DateTime<Utc>> {
+        let windows = self.windows.lock().unwrap();
+        windows.get(device_id).and_then(|device| device.watermark)
+    }
+
+    /// Combines the finalized windows of every device into one aggregate per metric.
+    pub fn rollup_finalized_windows(&self) -> HashMap<String, HashMap<String, MetricAggregate>> {
+        let windows = self.windows.lock().unwrap();
+        let mut rollups = HashMap::new();
+        for (device_id, device) in windows.iter() {
+            let mut metrics: HashMap<String, MetricAggregate> = HashMap::new();
+            for window in device.windows.values().filter(|window| window.finalized) {
+                for (metric, aggregate) in &window.metrics {
+                    match metrics.get_mut(metric) {
+                        Some(total) => total.merge(aggregate),
+                        None => {
+                            metrics.insert(metric.clone(), aggregate.clone());
+                        }
+                    }
+                }
+            }
+            if !metrics.is_empty() {
+                rollups.insert(device_id.clone(), metrics);
+            }
+        }
+        rollups
+    }
+
+    /// Removes and returns the readings that arrived too late to be included in any window.
+    pub fn take_late_readings(&self) -> Vec<Reading> {
+        let mut late_readings = self.late_readings.lock().unwrap();
+        *self.late_readings_overflowed.lock().unwrap() = false;
+        late_readings.drain(..).collect()
+    }
+
+    /// Returns how many late readings were dropped because the side output was full.
+    pub fn get_dropped_late_readings(&self) -> u64 {
+        *self.dropped_late_readings.lock().unwrap()
+    }
+
+    /// Raises an alert the first time late readings are dropped since the side output was drained.
+    fn late_readings_dropped(&self, device_id: &str) {
+        let mut overflowed = self.late_readings_overflowed.lock().unwrap();
+        if *overflowed {
+            return;
+        }
+        *overflowed = true;
+        if let Some(notifications) = &self.notifications {
+            notifications.raise(Notification::new(
+                "Late readings dropped".to_string(),
+                format!("over {} late readings are waiting; the oldest are dropped", self.config.late_readings_limit),
+                "warning".to_string(),
+                Some(device_id.to_string()),
+            ));
+        }
+    }
 }
 
 #[cfg(test)]
@@ -89,4 +391,109 @@ mod tests {
         assert_eq!(all_analytics.get(&device_id_1), Some(&1));
         assert_eq!(all_analytics.get(&device_id_2), Some(&1));
     }
+
+    #[test]
+    fn test_late_data_recomputes_windows_and_side_outputs() {
+        let device_manager = Arc::new(DeviceManager::new());
+        let config = AnalyticsConfig {
+            window_secs: 60,
+            allowed_lateness_secs: 300,
+            ..AnalyticsConfig::default()
+        };
+        let analytics = Analytics::with_config(device_manager, config);
+        let base = Utc.timestamp_opt(1_700_000_040, 0).unwrap(); // aligned to a window boundary
+        let reading = |value: f64| HashMap::from([("temperature".to_string(), value)]);
+
+        assert_eq!(analytics.process_event("d1", &reading(10.0), base), EventOutcome::OnTime);
+        assert_eq!(
+            analytics.process_event("d1", &reading(20.0), base + Duration::seconds(120)),
+            EventOutcome::OnTime
+        );
+
+        // Out of order, but within the allowed lateness: the first window is updated again
+        let outcome = analytics.process_event("d1", &reading(30.0), base + Duration::seconds(5));
+        assert!(matches!(outcome, EventOutcome::Late { .. }));
+        let first = &analytics.get_device_windows("d1")[0];
+        assert_eq!(first.metrics["temperature"].count, 2);
+        assert_eq!(first.metrics["temperature"].mean(), 20.0);
+        assert_eq!(first.revision, 1);
+
+        // Once the watermark is far enough ahead the window is final and late data is diverted
+        analytics.process_event("d1", &reading(40.0), base + Duration::seconds(1000));
+        assert!(analytics.get_device_windows("d1")[0].finalized);
+        assert_eq!(analytics.process_event("d1", &reading(50.0), base), EventOutcome::TooLate);
+
+        let late = analytics.take_late_readings();
+        assert_eq!(late.len(), 1);
+        assert_eq!(late[0].event_time, base);
+        assert_eq!(analytics.get_device_analytics("d1"), Some(4));
+
+        // The late reading stays out of the device's metrics too
+        let metrics = analytics.get_device_metrics("d1");
+        assert_eq!(metrics["temperature"].count, 4);
+        assert_eq!(metrics["temperature"].max, 40.0);
+    }
+
+    #[test]
+    fn test_window_limit_keeps_windows_open_to_late_data() {
+        let config = AnalyticsConfig {
+            window_secs: 60,
+            allowed_lateness_secs: 300,
+            retained_windows: 1,
+            late_readings_limit: 1,
+            ..AnalyticsConfig::default()
+        };
+        let analytics = Analytics::with_config(Arc::new(DeviceManager::new()), config);
+        let base = Utc.timestamp_opt(1_700_000_040, 0).unwrap();
+        let reading = HashMap::from([("temperature".to_string(), 20.0)]);
+
+        // Over the limit, but every window may still receive late readings
+        for offset in [0, 60, 120] {
+            analytics.process_event("d1", &reading, base + Duration::seconds(offset));
+        }
+        assert_eq!(analytics.get_device_windows("d1").len(), 3);
+        analytics.process_event("d1", &reading, base + Duration::seconds(5));
+        assert_eq!(analytics.get_device_windows("d1")[0].metrics["temperature"].count, 2);
+
+        // Once final they are dropped down to the limit, and late data goes to the side output
+        analytics.process_event("d1", &reading, base + Duration::seconds(1000));
+        assert_eq!(analytics.get_device_windows("d1").len(), 1);
+        for offset in [0, 60] {
+            let outcome = analytics.process_event("d1", &reading, base + Duration::seconds(offset));
+            assert_eq!(outcome, EventOutcome::TooLate);
+        }
+        assert_eq!(analytics.get_dropped_late_readings(), 1);
+        assert_eq!(analytics.take_late_readings().len(), 1);
+    }
+
+    #[test]
+    fn test_future_event_times_do_not_run_the_watermark_ahead() {
+        let analytics = Analytics::new(Arc::new(DeviceManager::new()));
+        let reading = HashMap::from([("temperature".to_string(), 21.0)]);
+        let now = Utc::now();
+
+        analytics.process_event("d1", &reading, now + Duration::days(365));
+        let watermark = analytics.get_watermark("d1").unwrap();
+        assert!(watermark <= Utc::now() + Duration::seconds(300));
+        // Readings on time by the real clock are not diverted as too late
+        let outcome = analytics.process_event("d1", &reading, now);
+        assert!(matches!(outcome, EventOutcome::Late { .. }));
+    }
+
+    #[test]
+    fn test_tenant_retention_drops_old_windows() {
+        let mut acme = crate::tenant::Tenant::new("acme".to_string(), "Acme".to_string());
+        acme.settings.retention_days = Some(1);
+        let tenants = Arc::new(TenantManager::new(vec![acme]));
+        let analytics = Analytics::new(Arc::new(DeviceManager::new())).with_tenants(tenants);
+        let base = Utc.timestamp_opt(1_700_000_040, 0).unwrap();
+        let reading = HashMap::from([("temperature".to_string(), 20.0)]);
+
+        for device_id in ["acme/d1", "d1"] {
+            analytics.process_event(device_id, &reading, base);
+            analytics.process_event(device_id, &reading, base + Duration::days(2));
+        }
+        assert_eq!(analytics.get_device_windows("acme/d1").len(), 1);
+        assert_eq!(analytics.get_device_windows("d1").len(), 2);
+    }
 }
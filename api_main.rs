@@ -1,49 +1,47 @@
-use actix_web::{web, App, HttpServer, Responder, HttpResponse};
-use std::sync::Arc;
-use crate::api_service::{ApiService, ApiServiceImpl};
-use crate::config::Config;
-use crate::storage_service::StorageService;
-use crate::processing_service::ProcessingService;
+// api_main.rs
 
-async fn health_check() -> impl Responder {
-    HttpResponse::Ok().json("Service is up and running")
-}
+use my_iot_platform::{initialize_services, Config, Result};
+use std::env;
+use std::time::Duration;
+use tokio::sync::watch;
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    let config = Arc::new(Config::new());
-    let storage_service = Arc::new(StorageService::new(config.clone()));
-    let processing_service = Arc::new(ProcessingService::new(config.clone()));
-    let api_service = Arc::new(ApiServiceImpl::new(storage_service, processing_service));
+/// How often the health saved by the ingestion pipeline is read back from storage.
+const HEALTH_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
 
-    HttpServer::new(move || {
-        App::new()
-            .data(api_service.clone())
-            .route("/health", web::get().to(health_check))
-            // Define other routes here
-            .service(
-                web::scope("/api")
-                    // Add API endpoints here
-                    // For example, to get data from a specific device:
-                    .route("/devices/{device_id}", web::get().to(ApiService::get_device_data))
-                    // To get analytics data:
-                    .route("/analytics", web::get().to(ApiService::get_analytics_data))
-                    // To monitor devices in real-time:
-                    .route("/monitor", web::get().to(ApiService::monitor_devices))
-            )
-    })
-    .bind(format!("{}:{}", config.server.host, config.server.port))?
-    .run()
-    .await
-}
+#[tokio::main]
+async fn main() -> Result<()> {
+    // Load configuration from a file or environment variables
+    let config_path = env::var("CONFIG_PATH").unwrap_or_else(|_| "config.toml".into());
+    let config = Config::from_file(config_path.into())?;
+
+    // Initialize services; the pipeline runs in ingestion_main, so the API serves the readings,
+    // alerts and device health it persists to storage
+    let (_, storage_service, processing_service, api_service) = initialize_services(config)?;
 
-// You would also define the necessary handlers for the routes here, or in a separate module.
-// For example:
-// async fn get_device_data(info: web::Path<(u32,)>, api: web::Data<Arc<ApiServiceImpl>>) -> impl Responder {
-//     let device_id = info.into_inner();
-//     let data = api.get_device_data(device_id).await;
-//     match data {
-//         Ok(data) => HttpResponse::Ok().json(data),
-//         Err(_) => HttpResponse::InternalServerError().finish(),
-//     }
-// }
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            println!("Shutdown requested");
+            let _ = shutdown_tx.send(true);
+        }
+    });
+
+    // Keep the health state up to date with the snapshots saved by the pipeline's scheduler
+    let monitoring = processing_service.monitoring();
+    let mut refresh_shutdown = shutdown_rx.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(HEALTH_REFRESH_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => match storage_service.load_device_health() {
+                    Ok(health) => monitoring.restore_monitoring_data(health),
+                    Err(e) => eprintln!("Failed to read device health from storage: {}", e),
+                },
+                _ = refresh_shutdown.changed() => break,
+            }
+        }
+    });
+
+    // Serve the REST API on APIConfig::api_endpoint until Ctrl-C
+    api_service.run(shutdown_rx).await
+}
@@ -1,61 +1,1925 @@
-use actix_web::{web, App, HttpResponse, HttpServer, Responder};
+// api_service.rs
+
+use crate::analytics::Analytics;
+use crate::audit::{AuditEntry, AuditLog, AuditQuery, ChainVerification};
+use crate::auth::{
+    ApiKey, AuthError, AuthService, CredentialKind, IssuedApiKey, Principal, Scope, MAX_ROTATION_GRACE_SECS,
+};
+use crate::config::{APIConfig, DeviceAuthConfig, RateLimitConfig};
+use crate::device::{Device, DeviceManager, DeviceQuery, DeviceUpdate, Reading};
+use crate::device_auth::{DeviceAuth, IssuedDeviceCredential, SourceFailures};
+use crate::events::{AlertQuery, AlertRecord, EventBus, EventKind, SubscriptionFilter};
+use crate::graphql::{self, ApiSchema};
+use crate::listing::{parse_fields, select_fields};
+use crate::monitoring::{DeviceHealth, HealthReport, Monitoring};
+use crate::openapi::{self, Operation};
+use crate::rate_limit::{QuotaUsage, RateLimits, Rejected};
+use crate::scheduler::{JobStatus, Scheduler};
+use crate::storage_service::StorageService;
+use crate::tenant::{
+    is_valid_id, local_device, scoped_device_id, split_device_id, tenant_view, Tenant, TenantManager, TenantSettings,
+    DEFAULT_TENANT,
+};
+use crate::tls::CertificateStore;
+use crate::users::{validate_password, Grant, Role, SessionToken, User, UserUpdate};
+use async_graphql::http::WebSocketProtocols;
+use async_graphql_warp::GraphQLWebSocket;
+use chrono::{DateTime, TimeZone, Utc};
+use futures::{SinkExt, StreamExt};
+use hyper::server::conn::Http;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use crate::analytics;
-use crate::monitoring;
-use crate::device::Device;
-
-// Define a struct for the API state that might include references to analytics and monitoring services
-pub struct ApiService {
-    analytics: analytics::AnalyticsService,
-    monitoring: monitoring::MonitoringService,
-}
-
-// Define request and response data structures
-#[derive(Serialize, Deserialize)]
-struct DeviceDataRequest {
-    device_id: String,
-}
-
-#[derive(Serialize, Deserialize)]
-struct DeviceDataResponse {
-    device_id: String,
-    data: Vec<Device>,
-}
-
-// Define API endpoints
-async fn get_device_data(info: web::Json<DeviceDataRequest>, api: web::Data<ApiService>) -> impl Responder {
-    let device_data = api.analytics.get_device_data(&info.device_id).await;
-    HttpResponse::Ok().json(DeviceDataResponse {
-        device_id: info.device_id.clone(),
-        data: device_data,
-    })
-}
-
-async fn get_monitoring_data(api: web::Data<ApiService>) -> impl Responder {
-    let monitoring_data = api.monitoring.get_monitoring_data().await;
-    HttpResponse::Ok().json(monitoring_data)
-}
-
-// Configure the API service
-pub fn config(cfg: &mut web::ServiceConfig) {
-    cfg.service(
-        web::resource("/device_data")
-            .route(web::post().to(get_device_data))
-    );
-    cfg.service(
-        web::resource("/monitoring_data")
-            .route(web::get().to(get_monitoring_data))
-    );
-}
-
-// Start the API server
-pub async fn start_api_server(api_service: ApiService) -> std::io::Result<()> {
-    HttpServer::new(move || {
-        App::new()
-            .data(api_service.clone())
-            .configure(config)
-    })
-    .bind("127.0.0.1:8080")?
-    .run()
-    .await
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tokio_rustls::TlsAcceptor;
+use warp::http::StatusCode;
+use warp::filters::BoxedFilter;
+use warp::path::FullPath;
+use warp::ws::{Message, WebSocket};
+use warp::{Filter, Rejection, Reply};
+
+/// Largest request body accepted, in bytes.
+const MAX_BODY_BYTES: u64 = 64 * 1024;
+
+/// REST API exposing devices, analytics, health and jobs to the dashboard, with a GraphQL
+/// endpoint over the same services. Every route except `/health`, `/openapi.json` and sign-in
+/// requires an API key, JWT or user session granting the route's scope.
+#[derive(Clone)]
+pub struct APIService {
+    config: APIConfig,
+    auth: Arc<AuthService>,
+    device_manager: Arc<DeviceManager>,
+    analytics: Arc<Analytics>,
+    monitoring: Arc<Monitoring>,
+    scheduler: Arc<Scheduler>,
+    events: Arc<EventBus>,
+    tenants: Arc<TenantManager>,
+    device_auth: Arc<DeviceAuth>,
+    rate_limits: Arc<RateLimits>,
+    audit: Arc<AuditLog>,
+    storage: Option<Arc<StorageService>>,
+    graphql: ApiSchema,
+}
+
+/// Body of `POST /device_data`.
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct DeviceDataRequest {
+    pub device_id: String,
+}
+
+/// The readings stored for a device, oldest first.
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct DeviceDataResponse {
+    pub device_id: String,
+    pub data: Vec<Reading>,
+}
+
+/// The credentials a streaming client connected with.
+#[derive(Clone)]
+struct StreamCredentials {
+    authorization: Option<String>,
+    api_key: Option<String>,
+}
+
+/// Stream filters as query parameters, each a comma separated list,
+/// e.g. `?devices=d1,d2&events=reading,health`.
+#[derive(Debug, Default, Deserialize, JsonSchema)]
+struct StreamQuery {
+    devices: Option<String>,
+    tags: Option<String>,
+    events: Option<String>,
+}
+
+impl StreamQuery {
+    fn into_filter(self) -> Result<SubscriptionFilter, Rejection> {
+        let split = |list: Option<String>| -> Vec<String> {
+            list.map(|list| list.split(',').map(|item| item.trim().to_string()).filter(|item| !item.is_empty()).collect())
+                .unwrap_or_default()
+        };
+        let events = split(self.events)
+            .into_iter()
+            .map(|kind| {
+                serde_json::from_value::<EventKind>(serde_json::Value::String(kind.clone()))
+                    .map_err(|_| warp::reject::custom(ApiError::bad_request(format!("unknown event type {}", kind))))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(SubscriptionFilter {
+            devices: split(self.devices),
+            tags: split(self.tags),
+            events,
+            ..SubscriptionFilter::default()
+        })
+    }
+}
+
+/// An error returned to API clients as a JSON body.
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    message: String,
+    /// Seconds to wait before retrying, sent as the `Retry-After` header.
+    retry_after_secs: Option<u64>,
+}
+
+impl ApiError {
+    /// Creates an error with the given status code.
+    pub fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        ApiError {
+            status,
+            message: message.into(),
+            retry_after_secs: None,
+        }
+    }
+
+    /// Creates a 404 error.
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, message)
+    }
+
+    /// Creates a 400 error.
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, message)
+    }
+}
+
+impl warp::reject::Reject for ApiError {}
+
+impl From<AuthError> for ApiError {
+    fn from(error: AuthError) -> Self {
+        match error {
+            AuthError::Unauthorized(message) => ApiError::new(StatusCode::UNAUTHORIZED, message),
+            AuthError::Forbidden(message) => ApiError::new(StatusCode::FORBIDDEN, message),
+        }
+    }
+}
+
+impl From<Rejected> for ApiError {
+    fn from(rejected: Rejected) -> Self {
+        ApiError {
+            retry_after_secs: rejected.retry_after_secs(),
+            ..ApiError::new(StatusCode::TOO_MANY_REQUESTS, rejected.to_string())
+        }
+    }
+}
+
+/// Body of a request creating an API key.
+#[derive(Debug, Deserialize, JsonSchema)]
+struct CreateKeyRequest {
+    name: String,
+    scopes: Vec<Scope>,
+    #[serde(default)]
+    expires_at: Option<DateTime<Utc>>,
+    /// Tenant of the key; the caller's tenant when missing.
+    #[serde(default)]
+    tenant_id: Option<String>,
+}
+
+/// How long the replaced secret of a rotated API key keeps working, in seconds, at most a week.
+#[derive(Debug, Deserialize, JsonSchema)]
+struct RotateKeyQuery {
+    #[serde(default = "default_grace_secs")]
+    grace_secs: u64,
+}
+
+fn default_grace_secs() -> u64 {
+    3600
+}
+
+/// Body of a sign-in request.
+#[derive(Debug, Deserialize, JsonSchema)]
+struct LoginRequest {
+    username: String,
+    password: String,
+}
+
+/// Body of a request creating a user.
+#[derive(Debug, Deserialize, JsonSchema)]
+struct CreateUserRequest {
+    username: String,
+    password: String,
+    #[serde(default)]
+    role: Option<Role>,
+    #[serde(default)]
+    grants: Vec<Grant>,
+    /// Tenant of the user; the caller's tenant when missing.
+    #[serde(default)]
+    tenant_id: Option<String>,
+}
+
+/// Body of a request revoking a JWT, given either as the token itself or, by platform
+/// admins only, by its `jti` claim.
+#[derive(Debug, Deserialize, JsonSchema)]
+struct RevokeTokenRequest {
+    #[serde(default)]
+    token: Option<String>,
+    #[serde(default)]
+    jti: Option<String>,
+}
+
+/// Body of every error response.
+#[derive(Debug, Serialize, JsonSchema)]
+struct ErrorBody {
+    code: u16,
+    error: String,
+}
+
+impl APIService {
+    /// Creates a new APIService backed by the shared services.
+    pub fn new(
+        config: APIConfig,
+        auth: Arc<AuthService>,
+        device_manager: Arc<DeviceManager>,
+        analytics: Arc<Analytics>,
+        monitoring: Arc<Monitoring>,
+        scheduler: Arc<Scheduler>,
+        events: Arc<EventBus>,
+    ) -> Self {
+        let graphql = graphql::schema(graphql::Services {
+            device_manager: device_manager.clone(),
+            analytics: analytics.clone(),
+            monitoring: monitoring.clone(),
+            events: events.clone(),
+        });
+        APIService {
+            config,
+            auth,
+            device_manager,
+            analytics,
+            monitoring,
+            scheduler,
+            events,
+            tenants: Arc::new(TenantManager::new(Vec::new())),
+            device_auth: Arc::new(
+                DeviceAuth::new(DeviceAuthConfig::default()).expect("device credentials without a file need no I/O"),
+            ),
+            rate_limits: Arc::new(RateLimits::new(RateLimitConfig::default())),
+            audit: Arc::new(AuditLog::new(None, None).expect("an audit log without a file needs no I/O")),
+            storage: None,
+            graphql,
+        }
+    }
+
+    /// Serves the tenants of the given manager instead of only the default tenant.
+    pub fn with_tenants(mut self, tenants: Arc<TenantManager>) -> Self {
+        self.tenants = tenants;
+        self
+    }
+
+    /// Issues device credentials through the given service, shared with ingestion which
+    /// checks datagrams against them.
+    pub fn with_device_auth(mut self, device_auth: Arc<DeviceAuth>) -> Self {
+        self.device_auth = device_auth;
+        self
+    }
+
+    /// Applies the rate limits of the given service, shared with ingestion, and reports the
+    /// tenants' usage it keeps track of.
+    pub fn with_rate_limits(mut self, rate_limits: Arc<RateLimits>) -> Self {
+        self.rate_limits = rate_limits;
+        self
+    }
+
+    /// Records administrative actions in the given log instead of one kept only in memory.
+    pub fn with_audit_log(mut self, audit: Arc<AuditLog>) -> Self {
+        self.audit = audit;
+        self
+    }
+
+    /// Removes the stored data of deleted tenants from the given storage.
+    pub fn with_storage(mut self, storage: Arc<StorageService>) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+
+    /// Serves the API on the configured endpoint until the shutdown signal fires.
+    pub async fn run(&self, mut shutdown: watch::Receiver<bool>) -> crate::Result<()> {
+        if let Some(tls) = &self.config.tls {
+            return self.run_tls(CertificateStore::new(tls.clone())?, shutdown).await;
+        }
+        let (addr, server) = warp::serve(self.routes()).try_bind_with_graceful_shutdown(self.config.api_endpoint, async move {
+            let _ = shutdown.changed().await;
+        })?;
+        println!("API service listening on {}", addr);
+        server.await;
+        Ok(())
+    }
+
+    /// Serves the API over HTTPS, reloading the certificate when its files change. Connections
+    /// already open when the shutdown signal fires are left to finish on their own.
+    async fn run_tls(&self, certificates: CertificateStore, mut shutdown: watch::Receiver<bool>) -> crate::Result<()> {
+        let listener = TcpListener::bind(self.config.api_endpoint).await?;
+        println!("API service listening on {} (TLS)", listener.local_addr()?);
+        let service = warp::service(self.routes());
+        let handshake_timeout = certificates.handshake_timeout();
+
+        let reload = certificates.watch(shutdown.clone());
+        let accept = async {
+            loop {
+                tokio::select! {
+                    accepted = listener.accept() => {
+                        let (stream, peer) = match accepted {
+                            Ok(accepted) => accepted,
+                            Err(e) => {
+                                eprintln!("Couldn't accept an API connection: {}", e);
+                                continue;
+                            }
+                        };
+                        let acceptor = TlsAcceptor::from(certificates.tls_config());
+                        let service = service.clone();
+                        tokio::spawn(async move {
+                            // Clients that never finish the handshake must not hold their connection
+                            let stream = match tokio::time::timeout(handshake_timeout, acceptor.accept(stream)).await {
+                                Ok(Ok(stream)) => stream,
+                                Ok(Err(e)) => {
+                                    eprintln!("TLS handshake with {} failed: {}", peer, e);
+                                    return;
+                                }
+                                Err(_) => {
+                                    eprintln!("TLS handshake with {} timed out", peer);
+                                    return;
+                                }
+                            };
+                            // Upgrades are needed for the WebSocket stream
+                            let connection = Http::new().serve_connection(stream, service).with_upgrades();
+                            if let Err(e) = connection.await {
+                                eprintln!("API connection with {} failed: {}", peer, e);
+                            }
+                        });
+                    }
+                    _ = shutdown.changed() => break,
+                }
+            }
+        };
+        tokio::join!(accept, reload);
+        Ok(())
+    }
+
+    /// Identifies the caller from the `Authorization: Bearer` or `X-API-Key` header.
+    fn authenticate(&self) -> impl Filter<Extract = (Principal,), Error = Rejection> + Clone {
+        let auth = self.auth.clone();
+        let rate_limits = self.rate_limits.clone();
+        warp::header::optional::<String>("authorization")
+            .and(warp::header::optional::<String>("x-api-key"))
+            .and(warp::addr::remote())
+            .and_then(move |authorization: Option<String>, api_key: Option<String>, remote: Option<SocketAddr>| {
+                let auth = auth.clone();
+                let rate_limits = rate_limits.clone();
+                async move {
+                    let mut principal = auth
+                        .authenticate(authorization.as_deref(), api_key.as_deref())
+                        .map_err(|e| warp::reject::custom(ApiError::from(e)))?;
+                    principal.source_ip = remote.map(|addr| addr.ip());
+                    rate_limits.check_api(&principal).map_err(|e| warp::reject::custom(ApiError::from(e)))?;
+                    Ok::<_, Rejection>(principal)
+                }
+            })
+    }
+
+    /// Keeps the credentials a streaming client connected with, so they can be checked again
+    /// while the stream lasts.
+    fn stream_credentials() -> impl Filter<Extract = (StreamCredentials,), Error = Rejection> + Clone {
+        warp::header::optional::<String>("authorization")
+            .and(warp::header::optional::<String>("x-api-key"))
+            .map(|authorization, api_key| StreamCredentials { authorization, api_key })
+    }
+
+    /// Identifies the caller and requires the scope. Routes apply this after matching the path
+    /// and method, so unknown routes still answer 404 and 405.
+    fn authorize(&self, scope: Scope) -> impl Filter<Extract = (Principal,), Error = Rejection> + Clone {
+        self.authenticate().and_then(move |principal: Principal| async move {
+            if principal.has_scope(scope) {
+                Ok(principal)
+            } else {
+                Err(warp::reject::custom(ApiError::from(AuthError::Forbidden(format!("missing scope {:?}", scope)))))
+            }
+        })
+    }
+
+    /// Like `authorize`, but also admits callers granted the scope on device groups only. The
+    /// routes using it must limit such callers to the devices of their groups.
+    fn authorize_devices(&self, scope: Scope) -> impl Filter<Extract = (Principal,), Error = Rejection> + Clone {
+        self.authenticate().and_then(move |principal: Principal| async move {
+            if principal.has_scope_anywhere(scope) {
+                Ok(principal)
+            } else {
+                Err(warp::reject::custom(ApiError::from(AuthError::Forbidden(format!("missing scope {:?}", scope)))))
+            }
+        })
+    }
+
+    /// Like `authorize`, but only for callers of the default tenant, which operates the platform.
+    fn authorize_platform(&self, scope: Scope) -> impl Filter<Extract = (Principal,), Error = Rejection> + Clone {
+        self.authorize(scope).and_then(|principal: Principal| async move {
+            if principal.tenant_id == DEFAULT_TENANT {
+                Ok(principal)
+            } else {
+                Err(warp::reject::custom(ApiError::new(StatusCode::FORBIDDEN, "reserved to the platform operator")))
+            }
+        })
+    }
+
+    /// Builds the API's routes, with every error turned into a JSON response. Callers only
+    /// see their own tenant's devices and data, addressed by the tenant's own device ids.
+    /// Each route is declared with the operation describing it in the OpenAPI document; routes
+    /// marked as reserved to the platform operator also require the caller to belong to the
+    /// default tenant.
+    pub fn routes(&self) -> impl Filter<Extract = impl Reply, Error = Infallible> + Clone {
+        use Scope::{Admin, ManageDevices, ReadTelemetry};
+        let mut table = RouteTable::default();
+
+        let health = table
+            .route(Operation::new("get", "/health", "Liveness check").returns::<serde_json::Value>(200))
+            .map(|| warp::reply::json(&serde_json::json!({ "status": "ok" })));
+
+        // Answered with the document built once every route is declared, see below
+        let openapi =
+            table.route(Operation::new("get", "/openapi.json", "This document").returns::<serde_json::Value>(200));
+
+        // Listings are returned as plain arrays for the dashboard, see `page_reply`
+        let api = self.clone();
+        let list_devices = table
+            .route(
+                Operation::new("get", "/api/devices", "List devices; see X-Total-Count and X-Next-Cursor for paging")
+                    .scope(ReadTelemetry)
+                    .query::<DeviceQuery>()
+                    .returns::<Vec<Device>>(200)
+                    .sparse_fields(),
+            )
+            .and(self.authorize_devices(Scope::ReadTelemetry))
+            .and(warp::query::<DeviceQuery>())
+            .and_then(move |principal: Principal, mut query: DeviceQuery| {
+                let api = api.clone();
+                async move {
+                    if !principal.has_scope(Scope::ReadTelemetry) {
+                        query.groups = Some(principal.groups_with(Scope::ReadTelemetry));
+                    }
+                    query.tenant_id = Some(principal.tenant_id);
+                    let page = api
+                        .device_manager
+                        .query_devices(&query, &api.monitoring.get_activity())
+                        .map_err(|e| warp::reject::custom(ApiError::bad_request(e)))?;
+                    let devices: Vec<Device> = page.devices.into_iter().map(local_device).collect();
+                    Ok::<_, Rejection>(page_reply(devices, page.total, page.next_cursor, query.fields.as_deref()))
+                }
+            });
+
+        let api = self.clone();
+        let create_device = table
+            .route(
+                Operation::new("post", "/api/devices", "Provision a device")
+                    .scope(ManageDevices)
+                    .body::<Device>()
+                    .returns::<Device>(201),
+            )
+            .and(self.authorize_devices(Scope::ManageDevices))
+            .and(warp::body::content_length_limit(MAX_BODY_BYTES))
+            .and(warp::body::json())
+            .and_then(move |principal: Principal, mut device: Device| {
+                let api = api.clone();
+                async move {
+                    if !is_valid_id(&device.id) {
+                        return Err(warp::reject::custom(ApiError::bad_request(
+                            "device id must not be empty or contain '/'",
+                        )));
+                    }
+                    if !principal.can_access_device(&device.tags, Scope::ManageDevices) {
+                        return Err(warp::reject::custom(ApiError::new(
+                            StatusCode::FORBIDDEN,
+                            "devices can only be created in your device groups",
+                        )));
+                    }
+                    if !api.tenants.can_add_device(&principal.tenant_id, &api.device_manager) {
+                        return Err(warp::reject::custom(ApiError::new(
+                            StatusCode::FORBIDDEN,
+                            format!("tenant {} reached its device quota", principal.tenant_id),
+                        )));
+                    }
+                    let reply = warp::reply::json(&device);
+                    let after = snapshot(&device);
+                    let device_id = device.id.clone();
+                    device.id = scoped_device_id(&principal.tenant_id, &device_id);
+                    let scoped_id = device.id.clone();
+                    if !api.device_manager.create_device(device) {
+                        return Err(warp::reject::custom(ApiError::new(
+                            StatusCode::CONFLICT,
+                            format!("device {} already exists", device_id),
+                        )));
+                    }
+                    // Only a create that happened is recorded; it is undone if it cannot be
+                    let audited = api.audit(&principal, &principal.tenant_id, "device.create", &device_id, None, after);
+                    if let Err(rejection) = audited {
+                        api.device_manager.remove_device(&scoped_id);
+                        return Err(rejection);
+                    }
+                    Ok(warp::reply::with_status(reply, StatusCode::CREATED))
+                }
+            });
+
+        let api = self.clone();
+        let update_device = table
+            .route_with_param(
+                Operation::new("patch", "/api/devices/{device_id}", "Update a device's metadata")
+                    .scope(ManageDevices)
+                    .body::<DeviceUpdate>()
+                    .returns::<Device>(200),
+            )
+            .and(self.authorize_devices(Scope::ManageDevices))
+            .and(warp::body::content_length_limit(MAX_BODY_BYTES))
+            .and(warp::body::json())
+            .and_then(move |device_id: String, principal: Principal, update: DeviceUpdate| {
+                let api = api.clone();
+                async move {
+                    let scoped_id = api.accessible_device(&principal, &device_id, Scope::ManageDevices)?;
+                    // Moving a device out of the caller's groups would take it out of their reach
+                    if update.tags.as_ref().is_some_and(|tags| !principal.can_access_device(tags, Scope::ManageDevices)) {
+                        return Err(warp::reject::custom(ApiError::new(
+                            StatusCode::FORBIDDEN,
+                            "devices can only be moved within your device groups",
+                        )));
+                    }
+                    let not_found = || {
+                        warp::reject::custom(ApiError::not_found(format!("device {} not found", device_id)))
+                    };
+                    let before = api.device_manager.get_device(&scoped_id).map(local_device).ok_or_else(not_found)?;
+                    let mut after = before.clone();
+                    after.apply_update(update.clone());
+                    let (before, after) = (snapshot(&before), snapshot(&after));
+                    api.audit(&principal, &principal.tenant_id, "device.update", &device_id, before, after)?;
+                    match api.device_manager.update_device(&scoped_id, update) {
+                        Some(device) => Ok(warp::reply::json(&local_device(device))),
+                        None => Err(not_found()),
+                    }
+                }
+            });
+
+        let api = self.clone();
+        let delete_device = table
+            .route_with_param(
+                Operation::new("delete", "/api/devices/{device_id}", "Delete a device and revoke its credentials")
+                    .scope(ManageDevices)
+                    .returns_nothing(204),
+            )
+            .and(self.authorize_devices(Scope::ManageDevices))
+            .and_then(move |device_id: String, principal: Principal| {
+                let api = api.clone();
+                async move {
+                    let scoped_id = api.accessible_device(&principal, &device_id, Scope::ManageDevices)?;
+                    let not_found = || {
+                        warp::reject::custom(ApiError::not_found(format!("device {} not found", device_id)))
+                    };
+                    let device = api.device_manager.get_device(&scoped_id).ok_or_else(not_found)?;
+                    let before = snapshot(&local_device(device));
+                    api.audit(&principal, &principal.tenant_id, "device.delete", &device_id, before, None)?;
+                    if api.device_manager.remove_device(&scoped_id).is_none() {
+                        return Err(not_found());
+                    }
+                    // A device created again under the same id must not inherit the old credentials
+                    api.device_auth.revoke_credentials(&scoped_id).map_err(internal_error)?;
+                    Ok(StatusCode::NO_CONTENT)
+                }
+            });
+
+        let api = self.clone();
+        let get_device = table
+            .route_with_param(
+                Operation::new("get", "/api/devices/{device_id}", "Get a device")
+                    .scope(ReadTelemetry)
+                    .returns::<Device>(200),
+            )
+            .and(self.authorize_devices(Scope::ReadTelemetry))
+            .and_then(move |device_id: String, principal: Principal| {
+                let api = api.clone();
+                async move {
+                    let scoped_id = api.accessible_device(&principal, &device_id, Scope::ReadTelemetry)?;
+                    match api.device_manager.get_device(&scoped_id) {
+                        Some(device) => Ok(warp::reply::json(&local_device(device))),
+                        None => Err(warp::reject::custom(ApiError::not_found(format!("device {} not found", device_id)))),
+                    }
+                }
+            });
+
+        // Device credentials; issuing new ones replaces the old, so this also rotates them. The
+        // secret and token are only returned once
+        let api = self.clone();
+        let issue_credentials = table
+            .route_with_param(
+                Operation::new("post", "/api/devices/{device_id}/credentials", "Issue or rotate a device's credentials")
+                    .scope(ManageDevices)
+                    .returns::<IssuedDeviceCredential>(201),
+            )
+            .and(self.authorize_devices(Scope::ManageDevices))
+            .and_then(move |device_id: String, principal: Principal| {
+                let api = api.clone();
+                async move {
+                    let scoped_id = api.accessible_device(&principal, &device_id, Scope::ManageDevices)?;
+                    // The secret and token are never recorded; the entry's time tells when they were issued
+                    let (tenant_id, action) = (&principal.tenant_id, "device_credentials.issue");
+                    api.audit(&principal, tenant_id, action, &device_id, None, None)?;
+                    let mut issued = api.device_auth.issue_credentials(&scoped_id).map_err(internal_error)?;
+                    issued.device_id = device_id;
+                    Ok::<_, Rejection>(warp::reply::with_status(warp::reply::json(&issued), StatusCode::CREATED))
+                }
+            });
+
+        let api = self.clone();
+        let revoke_credentials = table
+            .route_with_param(
+                Operation::new("delete", "/api/devices/{device_id}/credentials", "Revoke a device's credentials")
+                    .scope(ManageDevices)
+                    .returns_nothing(204),
+            )
+            .and(self.authorize_devices(Scope::ManageDevices))
+            .and_then(move |device_id: String, principal: Principal| {
+                let api = api.clone();
+                async move {
+                    let scoped_id = api.accessible_device(&principal, &device_id, Scope::ManageDevices)?;
+                    let no_credentials = || {
+                        warp::reject::custom(ApiError::not_found(format!("device {} has no credentials", device_id)))
+                    };
+                    let credentials = api.device_auth.get_credentials(&scoped_id).ok_or_else(no_credentials)?;
+                    let (tenant_id, action) = (&principal.tenant_id, "device_credentials.revoke");
+                    let before = serde_json::json!({ "created_at": credentials.created_at });
+                    api.audit(&principal, tenant_id, action, &device_id, Some(before), None)?;
+                    if !api.device_auth.revoke_credentials(&scoped_id).map_err(internal_error)? {
+                        return Err(no_credentials());
+                    }
+                    Ok(StatusCode::NO_CONTENT)
+                }
+            });
+
+        let api = self.clone();
+        let list_alerts = table
+            .route(
+                Operation::new(
+                    "get",
+                    "/api/alerts",
+                    "List recent alerts; see X-Total-Count and X-Next-Cursor for paging",
+                )
+                .scope(ReadTelemetry)
+                .query::<AlertQuery>()
+                .returns::<Vec<AlertRecord>>(200)
+                .sparse_fields(),
+            )
+            .and(self.authorize_devices(Scope::ReadTelemetry))
+            .and(warp::query::<AlertQuery>())
+            .and_then(move |principal: Principal, mut query: AlertQuery| {
+                let api = api.clone();
+                async move {
+                    if !principal.has_scope(Scope::ReadTelemetry) {
+                        query.groups = Some(principal.groups_with(Scope::ReadTelemetry));
+                    }
+                    query.tenant_id = Some(principal.tenant_id);
+                    let page = api
+                        .events
+                        .list_alerts(&query, &api.device_manager)
+                        .map_err(|e| warp::reject::custom(ApiError::bad_request(e)))?;
+                    let alerts: Vec<AlertRecord> = page
+                        .items
+                        .into_iter()
+                        .map(|mut alert| {
+                            alert.notification.device_id =
+                                alert.notification.device_id.map(|device_id| split_device_id(&device_id).1.to_string());
+                            alert
+                        })
+                        .collect();
+                    Ok::<_, Rejection>(page_reply(alerts, page.total, page.next_cursor, query.fields.as_deref()))
+                }
+            });
+
+        // Sources failing to authenticate their datagrams, for spotting abuse
+        let api = self.clone();
+        let auth_failures = table
+            .route(
+                Operation::new(
+                    "get",
+                    "/api/ingestion/auth_failures",
+                    "Sources failing to authenticate (platform operator)",
+                )
+                .scope(Admin)
+                .returns::<Vec<SourceFailures>>(200),
+            )
+            .and(self.authorize_platform(Scope::Admin))
+            .map(move |_: Principal| warp::reply::json(&api.device_auth.get_auth_failures()));
+
+        let api = self.clone();
+        let analytics = table
+            .route(
+                Operation::new("get", "/api/analytics", "Message counts by device")
+                    .scope(ReadTelemetry)
+                    .returns::<HashMap<String, u64>>(200),
+            )
+            .and(self.authorize_devices(Scope::ReadTelemetry))
+            .map(move |principal: Principal| warp::reply::json(&api.visible(&principal, api.analytics.get_all_analytics())));
+
+        // The routes the dashboard used before the `/api` prefix; readings come from storage
+        let api = self.clone();
+        let device_data = table
+            .route(
+                Operation::new("post", "/device_data", "Readings stored for a device, oldest first")
+                    .scope(ReadTelemetry)
+                    .body::<DeviceDataRequest>()
+                    .returns::<DeviceDataResponse>(200),
+            )
+            .and(self.authorize_devices(Scope::ReadTelemetry))
+            .and(warp::body::content_length_limit(MAX_BODY_BYTES))
+            .and(warp::body::json())
+            .and_then(move |principal: Principal, request: DeviceDataRequest| {
+                let api = api.clone();
+                async move {
+                    let scoped_id = api.accessible_device(&principal, &request.device_id, Scope::ReadTelemetry)?;
+                    let readings = match &api.storage {
+                        Some(storage) => storage.load_readings(&principal.tenant_id).map_err(internal_error)?,
+                        None => Vec::new(),
+                    };
+                    let data = readings
+                        .into_iter()
+                        .filter(|reading| reading.device_id == scoped_id)
+                        .map(|reading| Reading {
+                            device_id: request.device_id.clone(),
+                            ..reading
+                        })
+                        .collect();
+                    Ok::<_, Rejection>(warp::reply::json(&DeviceDataResponse {
+                        device_id: request.device_id,
+                        data,
+                    }))
+                }
+            });
+
+        let api = self.clone();
+        let monitoring_data = table
+            .route(
+                Operation::new("get", "/monitoring_data", "Health of every device, as on /api/monitor")
+                    .scope(ReadTelemetry)
+                    .returns::<HashMap<String, DeviceHealth>>(200),
+            )
+            .and(self.authorize_devices(Scope::ReadTelemetry))
+            .map(move |principal: Principal| warp::reply::json(&api.visible(&principal, api.monitoring.get_monitoring_data())));
+
+        let api = self.clone();
+        let monitor = table
+            .route(
+                Operation::new("get", "/api/monitor", "Health of every device")
+                    .scope(ReadTelemetry)
+                    .returns::<HashMap<String, DeviceHealth>>(200),
+            )
+            .and(self.authorize_devices(Scope::ReadTelemetry))
+            .map(move |principal: Principal| warp::reply::json(&api.visible(&principal, api.monitoring.get_monitoring_data())));
+
+        let api = self.clone();
+        let health_reports = table
+            .route(
+                Operation::new("get", "/api/device_health", "Health reports of every device")
+                    .scope(ReadTelemetry)
+                    .returns::<HashMap<String, HealthReport>>(200),
+            )
+            .and(self.authorize_devices(Scope::ReadTelemetry))
+            .map(move |principal: Principal| {
+                let reports = api.visible(&principal, api.monitoring.get_all_health_reports());
+                let reports: HashMap<String, HealthReport> = reports
+                    .into_iter()
+                    .map(|(device_id, mut report)| {
+                        report.device_id = device_id.clone();
+                        (device_id, report)
+                    })
+                    .collect();
+                warp::reply::json(&reports)
+            });
+
+        let api = self.clone();
+        let health_report = table
+            .route_with_param(
+                Operation::new("get", "/api/device_health/{device_id}", "Health report of a device")
+                    .scope(ReadTelemetry)
+                    .returns::<HealthReport>(200),
+            )
+            .and(self.authorize_devices(Scope::ReadTelemetry))
+            .and_then(move |device_id: String, principal: Principal| {
+                let api = api.clone();
+                async move {
+                    let scoped_id = api.accessible_device(&principal, &device_id, Scope::ReadTelemetry)?;
+                    match api.monitoring.get_health_report(&scoped_id) {
+                        Some(mut report) => {
+                            report.device_id = device_id;
+                            Ok(warp::reply::json(&report))
+                        }
+                        None => Err(warp::reject::custom(ApiError::not_found(format!("no health data for device {}", device_id)))),
+                    }
+                }
+            });
+
+        // Scheduled jobs work across all tenants, so only the platform operator sees them
+        let api = self.clone();
+        let jobs = table
+            .route(
+                Operation::new("get", "/api/jobs", "Scheduled jobs (platform operator)")
+                    .scope(ReadTelemetry)
+                    .returns::<Vec<JobStatus>>(200),
+            )
+            .and(self.authorize_platform(Scope::ReadTelemetry))
+            .map(move |_: Principal| warp::reply::json(&api.scheduler.get_job_statuses()));
+
+        let api = self.clone();
+        let job = table
+            .route_with_param(
+                Operation::new("get", "/api/jobs/{name}", "A scheduled job (platform operator)")
+                    .scope(ReadTelemetry)
+                    .returns::<JobStatus>(200),
+            )
+            .and(self.authorize_platform(Scope::ReadTelemetry))
+            .and_then(move |name: String, _: Principal| {
+                let api = api.clone();
+                async move {
+                    match api.scheduler.get_job_status(&name) {
+                        Some(status) => Ok(warp::reply::json(&status)),
+                        None => Err(warp::reject::custom(ApiError::not_found(format!("job {} not found", name)))),
+                    }
+                }
+            });
+
+        // Live events: WebSocket clients can replace their filter by sending it as a JSON message
+        let api = self.clone();
+        let stream_ws = table
+            .route(
+                Operation::new("get", "/api/stream/ws", "Live events over a WebSocket")
+                    .scope(ReadTelemetry)
+                    .query::<StreamQuery>()
+                    .returns_nothing(101),
+            )
+            .and(warp::ws())
+            .and(self.authorize_devices(Scope::ReadTelemetry))
+            .and(Self::stream_credentials())
+            .and(warp::query::<StreamQuery>())
+            .and_then(
+                move |ws: warp::ws::Ws, principal: Principal, credentials: StreamCredentials, query: StreamQuery| {
+                    let api = api.clone();
+                    async move {
+                        let mut filter = query.into_filter()?;
+                        restrict_filter(&mut filter, &principal);
+                        let lapsed = api.clone().credentials_lapse(principal, credentials);
+                        Ok::<_, Rejection>(ws.on_upgrade(move |socket| api.stream_websocket(socket, filter, lapsed)))
+                    }
+                },
+            );
+
+        let api = self.clone();
+        let stream_sse = table
+            .route(
+                Operation::new("get", "/api/stream/sse", "Live events as server-sent events")
+                    .scope(ReadTelemetry)
+                    .query::<StreamQuery>()
+                    .returns_nothing(200),
+            )
+            .and(self.authorize_devices(Scope::ReadTelemetry))
+            .and(Self::stream_credentials())
+            .and(warp::query::<StreamQuery>())
+            .and_then(move |principal: Principal, credentials: StreamCredentials, query: StreamQuery| {
+                let api = api.clone();
+                async move {
+                    let mut filter = query.into_filter()?;
+                    restrict_filter(&mut filter, &principal);
+                    let lapsed = api.clone().credentials_lapse(principal, credentials);
+                    let events = api.sse_events(filter).take_until(lapsed);
+                    Ok::<_, Rejection>(warp::sse::reply(warp::sse::keep_alive().stream(events)))
+                }
+            });
+
+        // GraphQL, letting the dashboard fetch devices with their health, metrics and alerts
+        // in one request. Errors in a query are reported in the response body
+        let api = self.clone();
+        let graphql = table
+            .route(
+                Operation::new("post", "/api/graphql", "GraphQL queries over devices, metrics, health and alerts")
+                    .scope(ReadTelemetry)
+                    .body::<serde_json::Value>()
+                    .returns::<serde_json::Value>(200),
+            )
+            .and(self.authorize_devices(Scope::ReadTelemetry))
+            .and(warp::body::content_length_limit(MAX_BODY_BYTES))
+            .and(warp::body::json())
+            .and_then(move |principal: Principal, request: async_graphql::Request| {
+                let api = api.clone();
+                async move {
+                    let response = api.graphql.execute(request.data(principal)).await;
+                    Ok::<_, Rejection>(warp::reply::json(&response))
+                }
+            });
+
+        // GraphQL subscriptions over the graphql-ws or graphql-transport-ws protocol
+        let api = self.clone();
+        let graphql_ws = table
+            .route(
+                Operation::new("get", "/api/graphql/ws", "GraphQL subscriptions over a WebSocket")
+                    .scope(ReadTelemetry)
+                    .returns_nothing(101),
+            )
+            .and(warp::ws())
+            .and(async_graphql_warp::graphql_protocol())
+            .and(self.authorize_devices(Scope::ReadTelemetry))
+            .and(Self::stream_credentials())
+            .map(
+                move |ws: warp::ws::Ws,
+                      protocol: WebSocketProtocols,
+                      principal: Principal,
+                      credentials: StreamCredentials| {
+                    let schema = api.graphql.clone();
+                    let lapsed = api.clone().credentials_lapse(principal.clone(), credentials);
+                    let reply = ws.on_upgrade(move |socket| async move {
+                        let mut data = async_graphql::Data::default();
+                        data.insert(principal);
+                        // Dropping the connection closes it once the credentials lapse
+                        tokio::select! {
+                            _ = GraphQLWebSocket::new(socket, schema, protocol).with_data(data).serve() => {}
+                            _ = lapsed => {}
+                        }
+                    });
+                    warp::reply::with_header(reply, "Sec-WebSocket-Protocol", protocol.sec_websocket_protocol())
+                },
+            );
+
+        // API key management; every key and token can look up its own identity. Tenant admins
+        // manage their tenant's keys, platform admins the keys of every tenant
+        let whoami = table
+            .route(
+                Operation::new("get", "/api/auth/whoami", "The caller's identity")
+                    .authenticated()
+                    .returns::<Principal>(200),
+            )
+            .and(self.authenticate())
+            .map(|principal: Principal| warp::reply::json(&principal));
+
+        let api = self.clone();
+        let list_keys = table
+            .route(
+                Operation::new("get", "/api/auth/keys", "List API keys")
+                    .scope(Admin)
+                    .returns::<Vec<ApiKey>>(200),
+            )
+            .and(self.authorize(Scope::Admin))
+            .map(move |principal: Principal| {
+                let tenant_id = (!principal.is_platform_admin()).then_some(principal.tenant_id.as_str());
+                warp::reply::json(&api.auth.list_keys(tenant_id))
+            });
+
+        let api = self.clone();
+        let create_key = table
+            .route(
+                Operation::new("post", "/api/auth/keys", "Create an API key")
+                    .scope(Admin)
+                    .body::<CreateKeyRequest>()
+                    .returns::<IssuedApiKey>(201),
+            )
+            .and(self.authorize(Scope::Admin))
+            .and(warp::body::content_length_limit(MAX_BODY_BYTES))
+            .and(warp::body::json())
+            .and_then(move |principal: Principal, request: CreateKeyRequest| {
+                let api = api.clone();
+                async move {
+                    let tenant_id = api.target_tenant(&principal, request.tenant_id)?;
+                    let issued = api
+                        .auth
+                        .create_key(&tenant_id, &request.name, request.scopes, request.expires_at)
+                        .map_err(internal_error)?;
+                    // The key's id is only known once it exists, so it is revoked again if the creation
+                    // cannot be recorded; its secret was never handed out
+                    let key_id = &issued.key.id;
+                    let after = snapshot(&issued.key);
+                    let audited = api.audit(&principal, &tenant_id, "api_key.create", key_id, None, after);
+                    if let Err(rejection) = audited {
+                        api.auth.revoke_key(key_id).map_err(internal_error)?;
+                        return Err(rejection);
+                    }
+                    Ok::<_, Rejection>(warp::reply::with_status(warp::reply::json(&issued), StatusCode::CREATED))
+                }
+            });
+
+        let api = self.clone();
+        let rotate_key = table
+            .route_with_param(
+                Operation::new("post", "/api/auth/keys/{key_id}/rotate", "Rotate an API key's secret")
+                    .scope(Admin)
+                    .query::<RotateKeyQuery>()
+                    .returns::<IssuedApiKey>(200),
+            )
+            .and(self.authorize(Scope::Admin))
+            .and(warp::query::<RotateKeyQuery>())
+            .and_then(move |key_id: String, principal: Principal, query: RotateKeyQuery| {
+                let api = api.clone();
+                async move {
+                    let key = api.visible_key(&principal, &key_id)?;
+                    let grace_secs = query.grace_secs.min(MAX_ROTATION_GRACE_SECS);
+                    let grace = chrono::Duration::seconds(grace_secs as i64);
+                    let not_found = || {
+                        warp::reject::custom(ApiError::not_found(format!("API key {} not found", key_id)))
+                    };
+                    if key.revoked_at.is_some() {
+                        return Err(not_found());
+                    }
+                    let mut after = key.clone();
+                    after.previous_expires_at = Some(Utc::now() + grace);
+                    let (before, after) = (snapshot(&key), snapshot(&after));
+                    api.audit(&principal, &key.tenant_id, "api_key.rotate", &key_id, before, after)?;
+                    match api.auth.rotate_key(&key_id, grace).map_err(internal_error)? {
+                        Some(issued) => Ok(warp::reply::json(&issued)),
+                        None => Err(not_found()),
+                    }
+                }
+            });
+
+        let api = self.clone();
+        let revoke_key = table
+            .route_with_param(
+                Operation::new("delete", "/api/auth/keys/{key_id}", "Revoke an API key")
+                    .scope(Admin)
+                    .returns_nothing(204),
+            )
+            .and(self.authorize(Scope::Admin))
+            .and_then(move |key_id: String, principal: Principal| {
+                let api = api.clone();
+                async move {
+                    let key = api.visible_key(&principal, &key_id)?;
+                    api.audit(&principal, &key.tenant_id, "api_key.revoke", &key_id, snapshot(&key), None)?;
+                    api.auth.revoke_key(&key_id).map_err(internal_error)?;
+                    Ok::<_, Rejection>(StatusCode::NO_CONTENT)
+                }
+            });
+
+        let api = self.clone();
+        let revoke_token = table
+            .route(
+                Operation::new("post", "/api/auth/tokens/revoke", "Revoke a JWT")
+                    .scope(Admin)
+                    .body::<RevokeTokenRequest>()
+                    .returns_nothing(204),
+            )
+            .and(self.authorize(Scope::Admin))
+            .and(warp::body::content_length_limit(MAX_BODY_BYTES))
+            .and(warp::body::json())
+            .and_then(move |principal: Principal, request: RevokeTokenRequest| {
+                let api = api.clone();
+                async move {
+                    // A bare jti could belong to any tenant's token, so only the token itself
+                    // proves whose it is
+                    let (tenant_id, jti, expires_at) = match (request.token, request.jti) {
+                        (Some(token), _) => {
+                            let claims = api
+                                .auth
+                                .decode_token(&token)
+                                .map_err(|e| warp::reject::custom(ApiError::bad_request(e.to_string())))?;
+                            let jti = claims
+                                .jti
+                                .ok_or_else(|| warp::reject::custom(ApiError::bad_request("the token has no jti")))?;
+                            let expires_at = Utc.timestamp_opt(claims.exp, 0).single();
+                            (claims.tenant.unwrap_or_else(|| DEFAULT_TENANT.to_string()), jti, expires_at)
+                        }
+                        (None, Some(jti)) if principal.is_platform_admin() => (principal.tenant_id.clone(), jti, None),
+                        (None, Some(_)) => {
+                            return Err(warp::reject::custom(ApiError::new(
+                                StatusCode::FORBIDDEN,
+                                "only platform admins can revoke tokens by jti",
+                            )))
+                        }
+                        (None, None) => {
+                            return Err(warp::reject::custom(ApiError::bad_request("either token or jti is required")))
+                        }
+                    };
+                    if tenant_id != principal.tenant_id && !principal.is_platform_admin() {
+                        return Err(warp::reject::custom(ApiError::new(
+                            StatusCode::FORBIDDEN,
+                            "the token belongs to another tenant",
+                        )));
+                    }
+                    api.audit(&principal, &tenant_id, "token.revoke", &jti, None, None)?;
+                    api.auth.revoke_token(&jti, expires_at).map_err(internal_error)?;
+                    Ok::<_, Rejection>(StatusCode::NO_CONTENT)
+                }
+            });
+
+        // Users sign in with a password for a session token, sent as `Authorization: Bearer`
+        let api = self.clone();
+        let login = table
+            .route(
+                Operation::new("post", "/api/auth/login", "Sign in with a password")
+                    .body::<LoginRequest>()
+                    .returns::<SessionToken>(200),
+            )
+            .and(warp::addr::remote())
+            .and(warp::body::content_length_limit(MAX_BODY_BYTES))
+            .and(warp::body::json())
+            .and_then(move |remote: Option<SocketAddr>, request: LoginRequest| {
+                let api = api.clone();
+                async move {
+                    // Limit password guessing, and the failed attempts it writes to the audit log, by
+                    // address, as there is no principal yet
+                    if let Some(remote) = remote {
+                        api.rate_limits
+                            .check_sign_in(remote.ip())
+                            .map_err(|e| warp::reject::custom(ApiError::from(e)))?;
+                    }
+                    // Hashing the password is deliberately slow, so keep it off the async workers
+                    let (worker, username) = (api.clone(), request.username.clone());
+                    let session = tokio::task::spawn_blocking(move || {
+                        worker.auth.users().login(&request.username, &request.password)
+                    })
+                    .await
+                    .map_err(|e| internal_error(e.into()))?;
+                    // Attempts on unknown usernames are the platform operator's to see
+                    let tenant_id = match &session {
+                        Some(session) => session.user.tenant_id.clone(),
+                        None => {
+                            let user = api.auth.users().find_user(&username);
+                            user.map_or_else(|| DEFAULT_TENANT.to_string(), |user| user.tenant_id)
+                        }
+                    };
+                    let actor = sign_in_principal(&username, &tenant_id, remote);
+                    match session {
+                        Some(session) => {
+                            // The session is ended again if the sign-in cannot be recorded
+                            if let Err(rejection) = api.audit(&actor, &tenant_id, "user.login", &username, None, None) {
+                                api.auth.users().logout(&session.token);
+                                return Err(rejection);
+                            }
+                            Ok(warp::reply::json(&session))
+                        }
+                        None => {
+                            api.audit(&actor, &tenant_id, "user.login_failed", &username, None, None)?;
+                            Err(warp::reject::custom(ApiError::from(AuthError::Unauthorized(
+                                "invalid username or password".to_string(),
+                            ))))
+                        }
+                    }
+                }
+            });
+
+        let api = self.clone();
+        let logout = table
+            .route(
+                Operation::new("post", "/api/auth/logout", "End the caller's session")
+                    .authenticated()
+                    .returns_nothing(204),
+            )
+            .and(self.authenticate())
+            .and(warp::header::optional::<String>("authorization"))
+            .and_then(move |principal: Principal, authorization: Option<String>| {
+                let api = api.clone();
+                async move {
+                    let token = authorization.as_deref().and_then(|header| header.strip_prefix("Bearer "));
+                    if let Some(token) = token.filter(|_| principal.credential == CredentialKind::Session) {
+                        let (tenant_id, username) = (&principal.tenant_id, &principal.subject);
+                        api.audit(&principal, tenant_id, "user.logout", username, None, None)?;
+                        api.auth.users().logout(token.trim());
+                    }
+                    Ok::<_, Rejection>(StatusCode::NO_CONTENT)
+                }
+            });
+
+        // User management; tenant admins manage their tenant's users, platform admins everyone
+        let api = self.clone();
+        let list_users = table
+            .route(
+                Operation::new("get", "/api/users", "List users")
+                    .scope(Admin)
+                    .returns::<Vec<User>>(200),
+            )
+            .and(self.authorize(Scope::Admin))
+            .map(move |principal: Principal| {
+                let tenant_id = (!principal.is_platform_admin()).then_some(principal.tenant_id.as_str());
+                warp::reply::json(&api.auth.users().list_users(tenant_id))
+            });
+
+        let api = self.clone();
+        let create_user = table
+            .route(
+                Operation::new("post", "/api/users", "Create a user")
+                    .scope(Admin)
+                    .body::<CreateUserRequest>()
+                    .returns::<User>(201),
+            )
+            .and(self.authorize(Scope::Admin))
+            .and(warp::body::content_length_limit(MAX_BODY_BYTES))
+            .and(warp::body::json())
+            .and_then(move |principal: Principal, request: CreateUserRequest| {
+                let api = api.clone();
+                async move {
+                    let tenant_id = api.target_tenant(&principal, request.tenant_id)?;
+                    validate_password(&request.password).map_err(|e| warp::reject::custom(ApiError::bad_request(e)))?;
+                    let (worker, target_tenant) = (api.clone(), tenant_id.clone());
+                    let created = tokio::task::spawn_blocking(move || {
+                        worker.auth.users().create_user(
+                            &target_tenant,
+                            &request.username,
+                            &request.password,
+                            request.role,
+                            request.grants,
+                        )
+                    })
+                    .await
+                    .map_err(|e| internal_error(e.into()))?
+                    .map_err(internal_error)?;
+                    match created {
+                        Some(user) => {
+                            // The user's id is only known once it exists, so it is removed again if
+                            // the creation cannot be recorded
+                            let after = snapshot(&user);
+                            let audited = api.audit(&principal, &tenant_id, "user.create", &user.id, None, after);
+                            if let Err(rejection) = audited {
+                                api.auth.users().remove_user(&user.id).map_err(internal_error)?;
+                                return Err(rejection);
+                            }
+                            Ok(warp::reply::with_status(warp::reply::json(&user), StatusCode::CREATED))
+                        }
+                        None => Err(warp::reject::custom(ApiError::new(StatusCode::CONFLICT, "username is taken"))),
+                    }
+                }
+            });
+
+        let api = self.clone();
+        let update_user = table
+            .route_with_param(
+                Operation::new("patch", "/api/users/{user_id}", "Update a user")
+                    .scope(Admin)
+                    .body::<UserUpdate>()
+                    .returns::<User>(200),
+            )
+            .and(self.authorize(Scope::Admin))
+            .and(warp::body::content_length_limit(MAX_BODY_BYTES))
+            .and(warp::body::json())
+            .and_then(move |user_id: String, principal: Principal, update: UserUpdate| {
+                let api = api.clone();
+                async move {
+                    let before = api.visible_user(&principal, &user_id)?;
+                    if let Some(password) = &update.password {
+                        validate_password(password).map_err(|e| warp::reject::custom(ApiError::bad_request(e)))?;
+                    }
+                    let mut after = before.clone();
+                    after.apply_update(&update);
+                    let (tenant_id, before, after) = (before.tenant_id.clone(), snapshot(&before), snapshot(&after));
+                    api.audit(&principal, &tenant_id, "user.update", &user_id, before, after)?;
+                    let (worker, target) = (api.clone(), user_id.clone());
+                    let updated = tokio::task::spawn_blocking(move || worker.auth.users().update_user(&target, update))
+                        .await
+                        .map_err(|e| internal_error(e.into()))?
+                        .map_err(internal_error)?;
+                    match updated {
+                        Some(user) => Ok(warp::reply::json(&user)),
+                        None => Err(warp::reject::custom(ApiError::not_found("user not found"))),
+                    }
+                }
+            });
+
+        let api = self.clone();
+        let delete_user = table
+            .route_with_param(
+                Operation::new("delete", "/api/users/{user_id}", "Delete a user")
+                    .scope(Admin)
+                    .returns_nothing(204),
+            )
+            .and(self.authorize(Scope::Admin))
+            .and_then(move |user_id: String, principal: Principal| {
+                let api = api.clone();
+                async move {
+                    let user = api.visible_user(&principal, &user_id)?;
+                    api.audit(&principal, &user.tenant_id, "user.delete", &user_id, snapshot(&user), None)?;
+                    api.auth.users().remove_user(&user_id).map_err(internal_error)?;
+                    Ok::<_, Rejection>(StatusCode::NO_CONTENT)
+                }
+            });
+
+        // The audit log; tenant admins only see their own tenant's entries
+        let api = self.clone();
+        let audit_log = table
+            .route(
+                Operation::new("get", "/api/audit", "Query the audit log")
+                    .scope(Admin)
+                    .query::<AuditQuery>()
+                    .returns::<Vec<AuditEntry>>(200),
+            )
+            .and(self.authorize(Scope::Admin))
+            .and(warp::query::<AuditQuery>())
+            .and_then(move |principal: Principal, query: AuditQuery| {
+                let api = api.clone();
+                async move {
+                    let entries = api.audit.query(&audit_query(&principal, query)).map_err(internal_error)?;
+                    Ok::<_, Rejection>(warp::reply::json(&entries))
+                }
+            });
+
+        let api = self.clone();
+        let export_audit_log = table
+            .route(
+                Operation::new("get", "/api/audit/export", "Export the audit log as JSON Lines")
+                    .scope(Admin)
+                    .query::<AuditQuery>()
+                    .returns::<AuditEntry>(200)
+                    .content_type("application/x-ndjson"),
+            )
+            .and(self.authorize(Scope::Admin))
+            .and(warp::query::<AuditQuery>())
+            .and_then(move |principal: Principal, query: AuditQuery| {
+                let api = api.clone();
+                async move {
+                    let body = api.audit.export(&audit_query(&principal, query)).map_err(internal_error)?;
+                    Ok::<_, Rejection>(warp::reply::with_header(body, "content-type", "application/x-ndjson"))
+                }
+            });
+
+        // Checking the chain covers every tenant's entries
+        let api = self.clone();
+        let verify_audit_log = table
+            .route(
+                Operation::new("get", "/api/audit/verify", "Check the audit log's hash chain (platform operator)")
+                    .scope(Admin)
+                    .returns::<ChainVerification>(200),
+            )
+            .and(self.authorize_platform(Scope::Admin))
+            .and_then(move |_: Principal| {
+                let api = api.clone();
+                async move {
+                    let verification = api.audit.verify().map_err(internal_error)?;
+                    Ok::<_, Rejection>(warp::reply::json(&verification))
+                }
+            });
+
+        // Message and storage usage against the quotas; platform admins see every tenant's
+        let api = self.clone();
+        let usage = table
+            .route(
+                Operation::new("get", "/api/usage", "Usage against the quotas")
+                    .scope(Admin)
+                    .returns::<Vec<QuotaUsage>>(200),
+            )
+            .and(self.authorize(Scope::Admin))
+            .map(move |principal: Principal| {
+                let tenant_id = (!principal.is_platform_admin()).then_some(principal.tenant_id.as_str());
+                warp::reply::json(&api.rate_limits.get_usage(tenant_id))
+            });
+
+        // Tenant management is reserved to the platform operator
+        let api = self.clone();
+        let list_tenants = table
+            .route(
+                Operation::new("get", "/api/tenants", "List tenants (platform operator)")
+                    .scope(Admin)
+                    .returns::<Vec<Tenant>>(200),
+            )
+            .and(self.authorize_platform(Scope::Admin))
+            .map(move |_: Principal| warp::reply::json(&api.tenants.list_tenants()));
+
+        let api = self.clone();
+        let create_tenant = table
+            .route(
+                Operation::new("post", "/api/tenants", "Create a tenant (platform operator)")
+                    .scope(Admin)
+                    .body::<Tenant>()
+                    .returns::<Tenant>(201),
+            )
+            .and(self.authorize_platform(Scope::Admin))
+            .and(warp::body::content_length_limit(MAX_BODY_BYTES))
+            .and(warp::body::json())
+            .and_then(move |principal: Principal, tenant: Tenant| {
+                let api = api.clone();
+                async move {
+                    if !is_valid_id(&tenant.id) {
+                        return Err(warp::reject::custom(ApiError::bad_request(
+                            "tenant id must not be empty or contain '/'",
+                        )));
+                    }
+                    let reply = warp::reply::json(&tenant);
+                    let after = snapshot(&tenant);
+                    let tenant_id = tenant.id.clone();
+                    if !api.tenants.create_tenant(tenant) {
+                        return Err(warp::reject::custom(ApiError::new(
+                            StatusCode::CONFLICT,
+                            format!("tenant {} already exists", tenant_id),
+                        )));
+                    }
+                    // Only a create that happened is recorded; it is undone if it cannot be
+                    let audited = api.audit(&principal, &tenant_id, "tenant.create", &tenant_id, None, after);
+                    if let Err(rejection) = audited {
+                        api.tenants.remove_tenant(&tenant_id);
+                        return Err(rejection);
+                    }
+                    Ok(warp::reply::with_status(reply, StatusCode::CREATED))
+                }
+            });
+
+        let api = self.clone();
+        let get_tenant = table
+            .route_with_param(
+                Operation::new("get", "/api/tenants/{tenant_id}", "Get a tenant (platform operator)")
+                    .scope(Admin)
+                    .returns::<Tenant>(200),
+            )
+            .and(self.authorize_platform(Scope::Admin))
+            .and_then(move |tenant_id: String, _: Principal| {
+                let api = api.clone();
+                async move {
+                    match api.tenants.get_tenant(&tenant_id) {
+                        Some(tenant) => Ok(warp::reply::json(&tenant)),
+                        None => Err(warp::reject::custom(ApiError::not_found(format!("tenant {} not found", tenant_id)))),
+                    }
+                }
+            });
+
+        let api = self.clone();
+        let update_tenant_settings = table
+            .route_with_param(
+                Operation::new(
+                    "put",
+                    "/api/tenants/{tenant_id}/settings",
+                    "Replace a tenant's settings (platform operator)",
+                )
+                .scope(Admin)
+                .body::<TenantSettings>()
+                .returns::<Tenant>(200),
+            )
+            .and(self.authorize_platform(Scope::Admin))
+            .and(warp::body::content_length_limit(MAX_BODY_BYTES))
+            .and(warp::body::json())
+            .and_then(move |tenant_id: String, principal: Principal, settings: TenantSettings| {
+                let api = api.clone();
+                async move {
+                    let not_found = || {
+                        warp::reject::custom(ApiError::not_found(format!("tenant {} not found", tenant_id)))
+                    };
+                    let before = api.tenants.get_tenant(&tenant_id).ok_or_else(not_found)?.settings;
+                    let (before, after) = (snapshot(&before), snapshot(&settings));
+                    api.audit(&principal, &tenant_id, "tenant.settings", &tenant_id, before, after)?;
+                    match api.tenants.update_settings(&tenant_id, settings) {
+                        Some(tenant) => Ok(warp::reply::json(&tenant)),
+                        None => Err(not_found()),
+                    }
+                }
+            });
+
+        // A tenant can only be removed once its devices are gone, so no data is left orphaned
+        let api = self.clone();
+        let delete_tenant = table
+            .route_with_param(
+                Operation::new(
+                    "delete",
+                    "/api/tenants/{tenant_id}",
+                    "Delete a tenant without devices (platform operator)",
+                )
+                .scope(Admin)
+                .returns_nothing(204),
+            )
+            .and(self.authorize_platform(Scope::Admin))
+            .and_then(move |tenant_id: String, principal: Principal| {
+                let api = api.clone();
+                async move {
+                    if tenant_id == DEFAULT_TENANT {
+                        return Err(warp::reject::custom(ApiError::bad_request("the default tenant cannot be removed")));
+                    }
+                    if api.device_manager.count_devices(&tenant_id) > 0 {
+                        return Err(warp::reject::custom(ApiError::new(
+                            StatusCode::CONFLICT,
+                            format!("tenant {} still has devices", tenant_id),
+                        )));
+                    }
+                    let not_found = || {
+                        warp::reject::custom(ApiError::not_found(format!("tenant {} not found", tenant_id)))
+                    };
+                    let tenant = api.tenants.get_tenant(&tenant_id).ok_or_else(not_found)?;
+                    // Nothing issued for the tenant may outlive it. Every step is tried even if an
+                    // earlier one fails, and the tenant is only removed once all of them succeeded,
+                    // so deleting it again finishes the job
+                    let mut failures = Vec::new();
+                    if let Err(e) = api.auth.revoke_tenant(&tenant_id) {
+                        failures.push(format!("revoking API keys and tokens: {}", e));
+                    }
+                    if let Err(e) = api.auth.users().remove_tenant_users(&tenant_id) {
+                        failures.push(format!("removing users: {}", e));
+                    }
+                    if let Err(e) = api.device_auth.revoke_tenant_credentials(&tenant_id) {
+                        failures.push(format!("revoking device credentials: {}", e));
+                    }
+                    if let Some(Err(e)) = api.storage.as_ref().map(|storage| storage.delete_tenant_data(&tenant_id)) {
+                        failures.push(format!("deleting stored data: {}", e));
+                    }
+                    if !failures.is_empty() {
+                        let after = serde_json::json!({ "failures": failures });
+                        api.audit(&principal, &tenant_id, "tenant.delete_failed", &tenant_id, None, Some(after))?;
+                        return Err(internal_error(failures.join("; ").into()));
+                    }
+                    if api.tenants.remove_tenant(&tenant_id).is_none() {
+                        return Err(not_found());
+                    }
+                    let before = snapshot(&tenant);
+                    let audited = api.audit(&principal, &tenant_id, "tenant.delete", &tenant_id, before, None);
+                    if let Err(rejection) = audited {
+                        api.tenants.create_tenant(tenant);
+                        return Err(rejection);
+                    }
+                    Ok(StatusCode::NO_CONTENT)
+                }
+            });
+
+        // The document is the same for every request, so it is only built once
+        let document = Arc::new(openapi::document::<ErrorBody>("IoT Platform API", table.operations()));
+        let openapi = openapi.map(move || warp::reply::json(document.as_ref()));
+
+        health
+            .or(openapi)
+            .or(whoami)
+            .or(list_keys)
+            .or(create_key)
+            .or(rotate_key)
+            .or(revoke_key)
+            .or(revoke_token)
+            .or(login)
+            .or(logout)
+            .or(list_users)
+            .or(create_user)
+            .or(update_user)
+            .or(delete_user)
+            .or(audit_log)
+            .or(export_audit_log)
+            .or(verify_audit_log)
+            .or(usage)
+            .or(list_tenants)
+            .or(create_tenant)
+            .or(get_tenant)
+            .or(update_tenant_settings)
+            .or(delete_tenant)
+            .or(stream_ws)
+            .or(stream_sse)
+            .or(graphql)
+            .or(graphql_ws)
+            .or(list_devices)
+            .or(create_device)
+            .or(get_device)
+            .or(update_device)
+            .or(delete_device)
+            .or(issue_credentials)
+            .or(revoke_credentials)
+            .or(list_alerts)
+            .or(auth_failures)
+            .or(analytics)
+            .or(monitor)
+            .or(device_data)
+            .or(monitoring_data)
+            .or(health_reports)
+            .or(health_report)
+            .or(jobs)
+            .or(job)
+            .recover(handle_rejection)
+    }
+
+    /// Returns the platform-wide id of one of the caller's devices if the caller has the scope
+    /// on it. Devices out of reach are reported as missing.
+    fn accessible_device(&self, principal: &Principal, device_id: &str, scope: Scope) -> Result<String, Rejection> {
+        let scoped_id = scoped_device_id(&principal.tenant_id, device_id);
+        let accessible = principal.has_scope(scope)
+            || self
+                .device_manager
+                .get_device(&scoped_id)
+                .is_some_and(|device| principal.can_access_device(&device.tags, scope));
+        if accessible {
+            Ok(scoped_id)
+        } else {
+            Err(warp::reject::custom(ApiError::not_found(format!("device {} not found", device_id))))
+        }
+    }
+
+    /// Keeps the entries keyed by platform-wide device id the caller may read, keyed by the
+    /// tenant's own device ids.
+    fn visible<T>(&self, principal: &Principal, entries: HashMap<String, T>) -> HashMap<String, T> {
+        let entries = tenant_view(entries, &principal.tenant_id);
+        if principal.has_scope(Scope::ReadTelemetry) {
+            return entries;
+        }
+        entries
+            .into_iter()
+            .filter(|(device_id, _)| {
+                self.device_manager
+                    .get_device(&scoped_device_id(&principal.tenant_id, device_id))
+                    .is_some_and(|device| principal.can_access_device(&device.tags, Scope::ReadTelemetry))
+            })
+            .collect()
+    }
+
+    /// Checks that an API key exists and belongs to the caller's tenant, unless the caller is
+    /// a platform admin. Keys of other tenants are reported as missing.
+    fn visible_key(&self, principal: &Principal, key_id: &str) -> Result<ApiKey, Rejection> {
+        match self.auth.get_key(key_id) {
+            Some(key) if principal.is_platform_admin() || key.tenant_id == principal.tenant_id => Ok(key),
+            _ => Err(warp::reject::custom(ApiError::not_found(format!("API key {} not found", key_id)))),
+        }
+    }
+
+    /// Checks that a user exists and belongs to the caller's tenant, unless the caller is a
+    /// platform admin. Users of other tenants are reported as missing.
+    fn visible_user(&self, principal: &Principal, user_id: &str) -> Result<User, Rejection> {
+        match self.auth.users().get_user(user_id) {
+            Some(user) if principal.is_platform_admin() || user.tenant_id == principal.tenant_id => Ok(user),
+            _ => Err(warp::reject::custom(ApiError::not_found("user not found"))),
+        }
+    }
+
+    /// Returns the tenant a new key or user is created in: the caller's own, or for platform
+    /// admins any existing tenant.
+    fn target_tenant(&self, principal: &Principal, requested: Option<String>) -> Result<String, Rejection> {
+        let tenant_id = requested.unwrap_or_else(|| principal.tenant_id.clone());
+        if tenant_id != principal.tenant_id && !principal.is_platform_admin() {
+            return Err(warp::reject::custom(ApiError::new(
+                StatusCode::FORBIDDEN,
+                "only platform admins can act on other tenants",
+            )));
+        }
+        if self.tenants.get_tenant(&tenant_id).is_none() {
+            return Err(warp::reject::custom(ApiError::bad_request(format!("unknown tenant {}", tenant_id))));
+        }
+        Ok(tenant_id)
+    }
+
+    /// Records an administrative action in the audit log. Routes record a change before making
+    /// it, so a change that cannot be recorded fails without having been made.
+    fn audit(
+        &self,
+        principal: &Principal,
+        tenant_id: &str,
+        action: &str,
+        target: &str,
+        before: Option<serde_json::Value>,
+        after: Option<serde_json::Value>,
+    ) -> Result<(), Rejection> {
+        self.audit.record(principal, tenant_id, action, target, before, after).map_err(internal_error)?;
+        Ok(())
+    }
+}
+
+impl APIService {
+    /// Resolves once the credentials a stream was opened with stop granting what the caller had
+    /// then, e.g. after a logout, the revocation of a key or token, or a change of the caller's
+    /// groups. They are checked every `reauth_interval_secs`.
+    async fn credentials_lapse(self, principal: Principal, credentials: StreamCredentials) {
+        let period = Duration::from_secs(self.events.config().reauth_interval_secs.max(1));
+        let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        loop {
+            interval.tick().await;
+            let current = self.auth.authenticate(credentials.authorization.as_deref(), credentials.api_key.as_deref());
+            match current {
+                Ok(current) if grants_same_stream(&principal, &current) => {}
+                _ => return,
+            }
+        }
+    }
+
+    /// Pushes events to a WebSocket client until it disconnects, falls behind too often or its
+    /// credentials lapse.
+    async fn stream_websocket(
+        self,
+        socket: WebSocket,
+        filter: SubscriptionFilter,
+        lapsed: impl std::future::Future<Output = ()>,
+    ) {
+        let send_timeout = Duration::from_millis(self.events.config().send_timeout_ms);
+        let restrictions = filter.clone();
+        let (mut sender, mut receiver) = socket.split();
+        let mut subscription = self.events.subscribe(filter);
+        tokio::pin!(lapsed);
+
+        loop {
+            tokio::select! {
+                _ = &mut lapsed => break,
+                event = subscription.next(&self.device_manager) => {
+                    let event = match event {
+                        Some(event) => event,
+                        None => break,
+                    };
+                    let text = match serde_json::to_string(&event.into_local()) {
+                        Ok(text) => text,
+                        Err(e) => {
+                            eprintln!("Failed to serialize stream event: {}", e);
+                            continue;
+                        }
+                    };
+                    // A client that stops reading would otherwise hold the connection forever
+                    match tokio::time::timeout(send_timeout, sender.send(Message::text(text))).await {
+                        Ok(Ok(())) => {}
+                        _ => break,
+                    }
+                }
+                message = receiver.next() => match message {
+                    Some(Ok(message)) if message.is_text() => {
+                        match serde_json::from_str::<SubscriptionFilter>(message.to_str().unwrap_or_default()) {
+                            // Clients can narrow their filter but never leave their tenant or device groups
+                            Ok(mut filter) => {
+                                filter.tenant_id = restrictions.tenant_id.clone();
+                                filter.groups = restrictions.groups.clone();
+                                subscription.set_filter(filter);
+                            }
+                            Err(e) => eprintln!("Ignoring invalid stream filter: {}", e),
+                        }
+                    }
+                    Some(Ok(message)) if message.is_close() => break,
+                    Some(Ok(_)) => {}
+                    _ => break,
+                },
+            }
+        }
+        let _ = sender.close().await;
+    }
+
+    /// Turns a subscription into a stream of server-sent events.
+    fn sse_events(self, filter: SubscriptionFilter) -> impl futures::Stream<Item = Result<warp::sse::Event, Infallible>> {
+        let subscription = self.events.subscribe(filter);
+        futures::stream::unfold((self, subscription), |(api, mut subscription)| async move {
+            let event = subscription.next(&api.device_manager).await?.into_local();
+            let sse_event = warp::sse::Event::default()
+                .event(match event.kind() {
+                    Some(EventKind::Reading) => "reading",
+                    Some(EventKind::Health) => "health",
+                    Some(EventKind::Alert) => "alert",
+                    None => "lagged",
+                })
+                .json_data(&event)
+                .unwrap_or_default();
+            Some((Ok(sse_event), (api, subscription)))
+        })
+    }
+}
+
+
+/// The routes of the API together with the operations describing them in the OpenAPI
+/// document. Routes match the method and path of their operation, so the two cannot drift.
+#[derive(Default)]
+struct RouteTable {
+    operations: Vec<Operation>,
+}
+
+impl RouteTable {
+    /// Declares a route without path parameters.
+    fn route(&mut self, operation: Operation) -> BoxedFilter<()> {
+        assert!(!operation.path.contains('{'), "{} takes a path parameter", operation.path);
+        self.matching(operation).map(|_: Vec<String>| ()).untuple_one().boxed()
+    }
+
+    /// Declares a route with one path parameter, which it extracts.
+    fn route_with_param(&mut self, operation: Operation) -> BoxedFilter<(String,)> {
+        assert_eq!(operation.path.matches('{').count(), 1, "{} must take one path parameter", operation.path);
+        self.matching(operation).map(|mut parameters: Vec<String>| parameters.remove(0)).boxed()
+    }
+
+    /// Returns the operations of the declared routes.
+    fn operations(&self) -> &[Operation] {
+        &self.operations
+    }
+
+    /// Records the operation and matches requests for its path and method, extracting the path
+    /// parameters. Requests for the path with another method are rejected as not allowed.
+    fn matching(&mut self, operation: Operation) -> BoxedFilter<(Vec<String>,)> {
+        let method = match operation.method {
+            "get" => warp::get().boxed(),
+            "post" => warp::post().boxed(),
+            "put" => warp::put().boxed(),
+            "patch" => warp::patch().boxed(),
+            "delete" => warp::delete().boxed(),
+            other => panic!("unsupported method {}", other),
+        };
+        let template = operation.path;
+        self.operations.push(operation);
+        warp::path::full()
+            .and_then(move |path: FullPath| async move {
+                path_parameters(template, path.as_str()).ok_or_else(warp::reject::not_found)
+            })
+            .and(method)
+            .boxed()
+    }
+}
+
+/// Returns the parameters of a request path if it matches a path template such as
+/// `/api/devices/{device_id}`.
+fn path_parameters(template: &str, path: &str) -> Option<Vec<String>> {
+    let expected: Vec<&str> = template.split('/').collect();
+    let segments: Vec<&str> = path.split('/').collect();
+    if segments.len() != expected.len() {
+        return None;
+    }
+    let mut parameters = Vec::new();
+    for (segment, expected) in segments.into_iter().zip(expected) {
+        if expected.starts_with('{') && !segment.is_empty() {
+            parameters.push(segment.to_string());
+        } else if segment != expected {
+            return None;
+        }
+    }
+    Some(parameters)
+}
+
+/// Returns true if a caller's current identity may still receive the events of a stream opened
+/// under the original one.
+fn grants_same_stream(original: &Principal, current: &Principal) -> bool {
+    let readable_groups = |principal: &Principal| {
+        let mut groups = principal.groups_with(Scope::ReadTelemetry);
+        groups.sort();
+        groups
+    };
+    current.tenant_id == original.tenant_id
+        && current.has_scope_anywhere(Scope::ReadTelemetry)
+        && current.has_scope(Scope::ReadTelemetry) == original.has_scope(Scope::ReadTelemetry)
+        && readable_groups(current) == readable_groups(original)
+}
+
+/// Limits a stream filter to the caller's tenant and, for callers granted access to device
+/// groups only, to the devices of those groups.
+fn restrict_filter(filter: &mut SubscriptionFilter, principal: &Principal) {
+    filter.tenant_id = Some(principal.tenant_id.clone());
+    if !principal.has_scope(Scope::ReadTelemetry) {
+        filter.groups = Some(principal.groups_with(Scope::ReadTelemetry));
+    }
+}
+
+/// Limits an audit log query to the caller's tenant, unless the caller is a platform admin.
+fn audit_query(principal: &Principal, mut query: AuditQuery) -> AuditQuery {
+    query.tenant_id = (!principal.is_platform_admin()).then(|| principal.tenant_id.clone());
+    query
+}
+
+/// Replies with a page of a listing as a plain array, with the number of items across all
+/// pages in `X-Total-Count` and the cursor of the next page, if any, in `X-Next-Cursor`.
+/// Only the requested fields of each item are returned when `fields` is set.
+fn page_reply<T: Serialize>(
+    items: Vec<T>,
+    total: usize,
+    next_cursor: Option<String>,
+    fields: Option<&str>,
+) -> warp::reply::Response {
+    let mut items: Vec<serde_json::Value> =
+        items.iter().map(|item| serde_json::to_value(item).expect("listed items serialize to JSON")).collect();
+    if let Some(fields) = parse_fields(fields) {
+        items = items.into_iter().map(|item| select_fields(item, &fields)).collect();
+    }
+    let mut response =
+        warp::reply::with_header(warp::reply::json(&items), "X-Total-Count", total.to_string()).into_response();
+    if let Some(cursor) = next_cursor.and_then(|cursor| cursor.parse().ok()) {
+        response.headers_mut().insert("X-Next-Cursor", cursor);
+    }
+    response
+}
+
+/// Returns the principal a sign-in attempt is audited as, there being no session yet.
+fn sign_in_principal(username: &str, tenant_id: &str, remote: Option<SocketAddr>) -> Principal {
+    Principal {
+        subject: username.to_string(),
+        scopes: Vec::new(),
+        credential: CredentialKind::Session,
+        tenant_id: tenant_id.to_string(),
+        group_scopes: HashMap::new(),
+        source_ip: remote.map(|addr| addr.ip()),
+    }
+}
+
+/// Captures a value as it was before or after a change, for the audit log.
+fn snapshot<T: Serialize>(value: &T) -> Option<serde_json::Value> {
+    serde_json::to_value(value).ok()
+}
+
+/// Logs an unexpected error and turns it into a 500 response without leaking its details.
+fn internal_error(error: Box<dyn std::error::Error + Send + Sync>) -> Rejection {
+    eprintln!("API request failed: {}", error);
+    warp::reject::custom(ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "internal server error"))
+}
+
+/// Turns a rejection into a JSON error body with a matching status code.
+async fn handle_rejection(rejection: Rejection) -> Result<impl Reply, Infallible> {
+    let mut retry_after_secs = None;
+    let (status, message) = if let Some(error) = rejection.find::<ApiError>() {
+        retry_after_secs = error.retry_after_secs;
+        (error.status, error.message.clone())
+    } else if rejection.is_not_found() {
+        (StatusCode::NOT_FOUND, "not found".to_string())
+    } else if rejection.find::<warp::reject::MethodNotAllowed>().is_some() {
+        (StatusCode::METHOD_NOT_ALLOWED, "method not allowed".to_string())
+    } else if let Some(error) = rejection.find::<warp::filters::body::BodyDeserializeError>() {
+        (StatusCode::BAD_REQUEST, error.to_string())
+    } else if let Some(error) = rejection.find::<warp::reject::InvalidQuery>() {
+        (StatusCode::BAD_REQUEST, error.to_string())
+    } else if let Some(error) = rejection.find::<warp::reject::InvalidHeader>() {
+        (StatusCode::BAD_REQUEST, error.to_string())
+    } else {
+        eprintln!("Unhandled API rejection: {:?}", rejection);
+        (StatusCode::INTERNAL_SERVER_ERROR, "internal server error".to_string())
+    };
+
+    let body = ErrorBody {
+        code: status.as_u16(),
+        error: message,
+    };
+    let mut response = warp::reply::with_status(warp::reply::json(&body), status).into_response();
+    if status == StatusCode::UNAUTHORIZED {
+        response
+            .headers_mut()
+            .insert(warp::http::header::WWW_AUTHENTICATE, warp::http::HeaderValue::from_static("Bearer"));
+    }
+    if let Some(secs) = retry_after_secs {
+        response.headers_mut().insert(warp::http::header::RETRY_AFTER, warp::http::HeaderValue::from(secs));
+    }
+    Ok(response)
 }
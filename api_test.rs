@@ -2,67 +2,845 @@
 #[cfg(test)]
 mod tests {
     use super::*;
-    use actix_web::{test, web, App, http};
-    use crate::config::APIConfig;
-    use crate::device::DeviceManager;
     use crate::analytics::Analytics;
-    use crate::monitoring::Monitoring;
+    use crate::auth::{hash_secret, AuthService, Scope};
+    use crate::config::{
+        APIConfig, ApiKeyConfig, AuditConfig, AuthConfig, MonitoringConfig, RateLimit, RateLimitConfig, StreamConfig,
+    };
+    use crate::rate_limit::RateLimits;
+    use crate::device::{Device, DeviceManager, Reading};
+    use crate::events::{Event, EventBus};
+    use crate::monitoring::{DeviceHealth, DeviceStatus, Monitoring};
+    use crate::notification::{Notification, NotificationDispatcher};
+    use crate::scheduler::Scheduler;
+    use crate::storage_service::StorageService;
+    use crate::tenant::{Tenant, TenantManager, DEFAULT_TENANT};
+    use std::collections::HashMap;
     use std::sync::Arc;
 
-    // Setup a test server with the ApiService
-    fn setup_api_service() -> ApiService {
+    const ADMIN_KEY: &str = "iotk_admin_s3cret";
+    const READ_KEY: &str = "iotk_reader_s3cret";
+    const ACME_KEY: &str = "iotk_acme_s3cret";
+
+    // Setup an ApiService backed by fresh shared services, with an admin and a read-only key
+    // for the default tenant and an admin key for the acme tenant
+    fn setup_api_service() -> (APIService, Arc<DeviceManager>) {
         let device_manager = Arc::new(DeviceManager::new());
-        let analytics = Analytics::new(device_manager.clone());
-        let monitoring = Monitoring::new(device_manager);
+        let analytics = Arc::new(Analytics::new(device_manager.clone()));
+        let monitoring = Arc::new(Monitoring::new(device_manager.clone()));
+        let scheduler = Arc::new(Scheduler::new(None).unwrap());
+        let config = APIConfig {
+            api_endpoint: "127.0.0.1:0".parse().unwrap(),
+            stream: StreamConfig::default(),
+            auth: AuthConfig {
+                api_keys: vec![
+                    ApiKeyConfig {
+                        id: "admin".to_string(),
+                        secret_sha256: hash_secret(ADMIN_KEY),
+                        scopes: vec![Scope::Admin],
+                        tenant_id: DEFAULT_TENANT.to_string(),
+                        expires_at: None,
+                    },
+                    ApiKeyConfig {
+                        id: "reader".to_string(),
+                        secret_sha256: hash_secret(READ_KEY),
+                        scopes: vec![Scope::ReadTelemetry],
+                        tenant_id: DEFAULT_TENANT.to_string(),
+                        expires_at: None,
+                    },
+                    ApiKeyConfig {
+                        id: "acme".to_string(),
+                        secret_sha256: hash_secret(ACME_KEY),
+                        scopes: vec![Scope::Admin],
+                        tenant_id: "acme".to_string(),
+                        expires_at: None,
+                    },
+                ],
+                ..AuthConfig::default()
+            },
+            tls: None,
+            audit: AuditConfig::default(),
+        };
+        let auth = Arc::new(AuthService::new(config.auth.clone()).unwrap());
+        // Streams check their credentials every second, so tests see revocations end them quickly
+        let events = Arc::new(EventBus::new(StreamConfig {
+            reauth_interval_secs: 1,
+            ..StreamConfig::default()
+        }));
 
-        ApiService {
-            analytics,
-            monitoring,
-        }
+        let tenants = Arc::new(TenantManager::new(vec![Tenant::new("acme".to_string(), "Acme".to_string())]));
+        let api_service = APIService::new(config, auth, device_manager.clone(), analytics, monitoring, scheduler, events)
+            .with_tenants(tenants);
+        (api_service, device_manager)
     }
 
-    #[actix_rt::test]
-    async fn test_get_device_data() {
-        let api_service = setup_api_service();
-        let mut app = test::init_service(
-            App::new()
-                .data(api_service)
-                .configure(api_service::config)
-        ).await;
+    #[tokio::test]
+    async fn test_get_devices() {
+        let (api_service, device_manager) = setup_api_service();
+        device_manager.add_device(Device::new("device123".to_string(), "Temperature Sensor".to_string()));
+        let routes = api_service.routes();
+
+        let resp = warp::test::request().header("x-api-key", ADMIN_KEY).path("/api/devices").reply(&routes).await;
+        assert_eq!(resp.status(), 200);
+        let devices: Vec<Device> = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(devices.len(), 1);
+
+        let resp = warp::test::request()
+            .header("x-api-key", ADMIN_KEY)
+            .path("/api/devices/device123")
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), 200);
+        let device: Device = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(device.name, "Temperature Sensor");
+    }
+
+    #[tokio::test]
+    async fn test_device_provisioning() {
+        let (api_service, device_manager) = setup_api_service();
+        let routes = api_service.routes();
+
+        let resp = warp::test::request()
+            .header("x-api-key", ADMIN_KEY)
+            .method("POST")
+            .path("/api/devices")
+            .json(&serde_json::json!({
+                "id": "meter1",
+                "name": "Power Meter",
+                "device_type": "meter",
+                "tags": ["basement"],
+                "attributes": {"phase": 3}
+            }))
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), 201);
+        let resp = warp::test::request()
+            .header("x-api-key", ADMIN_KEY)
+            .method("POST")
+            .path("/api/devices")
+            .json(&serde_json::json!({"id": "meter1", "name": "Again", "device_type": null}))
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), 409);
+        // Only the create that happened is in the audit log
+        let resp = warp::test::request()
+            .header("x-api-key", ADMIN_KEY)
+            .path("/api/audit?action=device.create")
+            .reply(&routes)
+            .await;
+        let entries: Vec<AuditEntry> = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(entries.len(), 1);
 
-        let req = test::TestRequest::post()
-            .uri("/device_data")
-            .set_json(&DeviceDataRequest { device_id: "device123".to_string() })
-            .to_request();
+        let resp = warp::test::request()
+            .header("x-api-key", ADMIN_KEY)
+            .method("PATCH")
+            .path("/api/devices/meter1")
+            .json(&serde_json::json!({"firmware_version": "1.4.2", "owner": "facilities"}))
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(device_manager.get_device("meter1").unwrap().firmware_version.as_deref(), Some("1.4.2"));
 
-        let resp = test::call_service(&mut app, req).await;
-        assert_eq!(resp.status(), http::StatusCode::OK);
+        let resp = warp::test::request()
+            .header("x-api-key", ADMIN_KEY)
+            .path("/api/devices?tag=basement&limit=10")
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.headers()["X-Total-Count"], "1");
 
-        let result: DeviceDataResponse = test::read_body_json(resp).await;
-        assert_eq!(result.device_id, "device123");
+        let resp = warp::test::request()
+            .header("x-api-key", ADMIN_KEY)
+            .method("POST")
+            .path("/api/devices/meter1/credentials")
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), 201);
+        let issued: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(issued["device_id"], "meter1");
+        assert!(issued["token"].as_str().unwrap().starts_with("iotd_"));
+        assert!(api_service.device_auth.get_credentials("meter1").is_some());
+
+        // Deleting the device also revokes its credentials
+        let resp = warp::test::request()
+            .header("x-api-key", ADMIN_KEY)
+            .method("DELETE")
+            .path("/api/devices/meter1")
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), 204);
+        assert!(device_manager.get_device("meter1").is_none());
+        assert!(api_service.device_auth.get_credentials("meter1").is_none());
     }
 
-    #[actix_rt::test]
+    #[tokio::test]
     async fn test_get_monitoring_data() {
-        let api_service = setup_api_service();
-        let mut app = test::init_service(
-            App::new()
-                .data(api_service)
-                .configure(api_service::config)
-        ).await;
-
-        let req = test::TestRequest::get()
-            .uri("/monitoring_data")
-            .to_request();
-
-        let resp = test::call_service(&mut app, req).await;
-        assert_eq!(resp.status(), http::StatusCode::OK);
-
-        // Assuming the monitoring data is a HashMap<String, DeviceHealth>
-        let result: HashMap<String, DeviceHealth> = test::read_body_json(resp).await;
-        // Here you would add assertions to check if the monitoring data is as expected
-        // For example:
-        // assert!(result.contains_key("device123"));
+        let (api_service, _) = setup_api_service();
+        let routes = api_service.routes();
+
+        let resp = warp::test::request().header("x-api-key", ADMIN_KEY).path("/api/monitor").reply(&routes).await;
+        assert_eq!(resp.status(), 200);
+
+        let result: HashMap<String, DeviceHealth> = serde_json::from_slice(resp.body()).unwrap();
+        assert!(result.is_empty());
+        // Also served on the route the dashboard used before the `/api` prefix
+        let resp = warp::test::request().header("x-api-key", READ_KEY).path("/monitoring_data").reply(&routes).await;
+        assert_eq!(resp.status(), 200);
+
+        let resp = warp::test::request()
+            .header("x-api-key", READ_KEY)
+            .method("POST")
+            .path("/device_data")
+            .json(&DeviceDataRequest { device_id: "d1".to_string() })
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), 200);
+        let response: DeviceDataResponse = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(response.device_id, "d1");
+        assert!(response.data.is_empty());
+
+        let resp = warp::test::request().path("/health").reply(&routes).await;
+        assert_eq!(resp.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn test_device_data_is_read_from_storage() {
+        let (api_service, device_manager) = setup_api_service();
+        let mut config = crate::config::Config::from_env().unwrap();
+        let data_dir = std::env::temp_dir().join(format!("iot_api_device_data_{}", crate::auth::random_hex(8)));
+        config.storage_config.data_dir = Some(data_dir.clone());
+        let storage = Arc::new(StorageService::new(config.storage_config, device_manager));
+        for device_id in ["acme/d1", "d1", "acme/d2"] {
+            let data = HashMap::from([("temperature".to_string(), 20.0)]);
+            storage.store_reading(&Reading::new(device_id.to_string(), data)).unwrap();
+        }
+        let routes = api_service.with_storage(storage).routes();
+
+        let resp = warp::test::request()
+            .header("x-api-key", ACME_KEY)
+            .method("POST")
+            .path("/device_data")
+            .json(&DeviceDataRequest { device_id: "d1".to_string() })
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), 200);
+        let response: DeviceDataResponse = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(response.data.len(), 1);
+        assert_eq!(response.data[0].device_id, "d1");
+        std::fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_websocket_stream_applies_filters() {
+        let (api_service, _) = setup_api_service();
+        let events = api_service.events.clone();
+        let routes = api_service.routes();
+
+        let mut client = warp::test::ws()
+            .header("x-api-key", READ_KEY)
+            .path("/api/stream/ws?devices=d1&events=reading")
+            .handshake(routes)
+            .await
+            .unwrap();
+        // The subscription is created once the upgraded connection starts
+        while events.subscriber_count() == 0 {
+            tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        }
+
+        for device_id in ["d2", "d1"] {
+            let data = HashMap::from([("temperature".to_string(), 20.0)]);
+            events.publish(Event::Reading { reading: Reading::new(device_id.to_string(), data) });
+        }
+        let message = client.recv().await.unwrap();
+        let event: serde_json::Value = serde_json::from_str(message.to_str().unwrap()).unwrap();
+        assert_eq!(event["type"], "reading");
+        assert_eq!(event["reading"]["device_id"], "d1");
+
+        let resp = warp::test::request()
+            .header("x-api-key", ADMIN_KEY)
+            .path("/api/stream/sse?events=bogus")
+            .reply(&api_service.routes())
+            .await;
+        assert_eq!(resp.status(), 400);
+    }
+
+    #[tokio::test]
+    async fn test_streams_end_when_their_key_is_revoked() {
+        let (api_service, _) = setup_api_service();
+        let events = api_service.events.clone();
+        let routes = api_service.routes();
+        let resp = warp::test::request()
+            .header("x-api-key", ADMIN_KEY)
+            .method("POST")
+            .path("/api/auth/keys")
+            .json(&serde_json::json!({"name": "dashboard", "scopes": ["read_telemetry"]}))
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), 201);
+        let issued: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+
+        let mut client = warp::test::ws()
+            .header("x-api-key", issued["secret"].as_str().unwrap())
+            .path("/api/stream/ws")
+            .handshake(routes.clone())
+            .await
+            .unwrap();
+        while events.subscriber_count() == 0 {
+            tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        }
+
+        let resp = warp::test::request()
+            .header("x-api-key", ADMIN_KEY)
+            .method("DELETE")
+            .path(&format!("/api/auth/keys/{}", issued["key"]["id"].as_str().unwrap()))
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), 204);
+        let closed = tokio::time::timeout(std::time::Duration::from_secs(5), client.recv_closed()).await;
+        assert!(closed.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_monitoring_alerts_reach_the_stream() {
+        let (api_service, device_manager) = setup_api_service();
+        let events = api_service.events.clone();
+        let routes = api_service.routes();
+        // Wired like initialize_services: monitoring raises alerts through a dispatcher on the API's bus
+        let notifications =
+            Arc::new(NotificationDispatcher::new(1, std::time::Duration::ZERO).with_event_bus(events.clone()));
+        let config = MonitoringConfig {
+            default_interval_secs: 1,
+            grace_multiplier: 1.0,
+            ..MonitoringConfig::default()
+        };
+        let monitoring = Monitoring::with_config(device_manager.clone(), config)
+            .with_event_bus(events.clone())
+            .with_notifications(notifications);
+
+        device_manager.add_device(Device::new("d1".to_string(), "Sensor".to_string()));
+        let mut health = DeviceHealth::unknown();
+        health.status = DeviceStatus::Online;
+        health.last_update = Some(chrono::Utc::now() - chrono::Duration::seconds(10));
+        monitoring.restore_monitoring_data(HashMap::from([("d1".to_string(), health)]));
+
+        let mut client = warp::test::ws()
+            .header("x-api-key", READ_KEY)
+            .path("/api/stream/ws?events=alert")
+            .handshake(routes)
+            .await
+            .unwrap();
+        while events.subscriber_count() == 0 {
+            tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        }
+
+        // The device has been silent for longer than its threshold, so this check takes it offline
+        monitoring.monitor_devices();
+        let message = client.recv().await.unwrap();
+        let event: serde_json::Value = serde_json::from_str(message.to_str().unwrap()).unwrap();
+        assert_eq!(event["type"], "alert");
+        assert_eq!(event["notification"]["title"], "Device offline");
+        assert_eq!(event["notification"]["device_id"], "d1");
+
+        // The alert is also kept in the history served by the listing
+        let resp = warp::test::request()
+            .header("x-api-key", READ_KEY)
+            .path("/api/alerts")
+            .reply(&api_service.routes())
+            .await;
+        assert_eq!(resp.status(), 200);
+        let alerts: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(alerts[0]["title"], "Device offline");
+    }
+
+    #[tokio::test]
+    async fn test_routes_require_credentials_and_scopes() {
+        let (api_service, _) = setup_api_service();
+        let routes = api_service.routes();
+
+        let resp = warp::test::request().path("/api/devices").reply(&routes).await;
+        assert_eq!(resp.status(), 401);
+        assert_eq!(resp.headers()["WWW-Authenticate"], "Bearer");
+        let resp = warp::test::request().path("/health").reply(&routes).await;
+        assert_eq!(resp.status(), 200);
+
+        let resp = warp::test::request()
+            .header("x-api-key", READ_KEY)
+            .method("POST")
+            .path("/api/devices")
+            .json(&serde_json::json!({"id": "d1", "name": "Sensor", "device_type": null}))
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), 403);
+
+        // Keys issued over the API work until they are revoked
+        let resp = warp::test::request()
+            .header("x-api-key", ADMIN_KEY)
+            .method("POST")
+            .path("/api/auth/keys")
+            .json(&serde_json::json!({"name": "provisioning", "scopes": ["manage_devices"]}))
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), 201);
+        let issued: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        let secret = issued["secret"].as_str().unwrap();
+        let key_id = issued["key"]["id"].as_str().unwrap();
+
+        let resp = warp::test::request().header("x-api-key", secret).path("/api/auth/whoami").reply(&routes).await;
+        assert_eq!(resp.status(), 200);
+        let resp = warp::test::request().header("x-api-key", secret).path("/api/auth/keys").reply(&routes).await;
+        assert_eq!(resp.status(), 403);
+
+        let resp = warp::test::request()
+            .header("x-api-key", ADMIN_KEY)
+            .method("DELETE")
+            .path(&format!("/api/auth/keys/{}", key_id))
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), 204);
+        let resp = warp::test::request().header("x-api-key", secret).path("/api/auth/whoami").reply(&routes).await;
+        assert_eq!(resp.status(), 401);
+    }
+
+    #[tokio::test]
+    async fn test_tenants_only_see_their_own_devices() {
+        let (api_service, device_manager) = setup_api_service();
+        device_manager.add_device(Device::new("d1".to_string(), "Operator Sensor".to_string()));
+        let routes = api_service.routes();
+
+        let resp = warp::test::request()
+            .header("x-api-key", ACME_KEY)
+            .method("POST")
+            .path("/api/devices")
+            .json(&serde_json::json!({"id": "d1", "name": "Acme Sensor", "device_type": null}))
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), 201);
+        assert_eq!(device_manager.get_device("acme/d1").unwrap().name, "Acme Sensor");
+
+        let resp = warp::test::request().header("x-api-key", ACME_KEY).path("/api/devices").reply(&routes).await;
+        let devices: Vec<Device> = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].id, "d1");
+        assert_eq!(devices[0].name, "Acme Sensor");
+
+        let resp = warp::test::request().header("x-api-key", ADMIN_KEY).path("/api/devices/d1").reply(&routes).await;
+        let device: Device = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(device.name, "Operator Sensor");
+
+        // Platform resources are out of reach for other tenants, even for their admins
+        let resp = warp::test::request().header("x-api-key", ACME_KEY).path("/api/jobs").reply(&routes).await;
+        assert_eq!(resp.status(), 403);
+        let resp = warp::test::request().header("x-api-key", ACME_KEY).path("/api/tenants").reply(&routes).await;
+        assert_eq!(resp.status(), 403);
+        let resp = warp::test::request()
+            .header("x-api-key", ACME_KEY)
+            .method("DELETE")
+            .path("/api/auth/keys/admin")
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn test_removed_tenants_lose_their_credentials() {
+        let (api_service, _) = setup_api_service();
+        let routes = api_service.routes();
+
+        let resp = warp::test::request()
+            .header("x-api-key", ACME_KEY)
+            .method("POST")
+            .path("/api/users")
+            .json(&serde_json::json!({"username": "bob", "password": "correct horse battery", "role": "viewer"}))
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), 201);
+        let resp = warp::test::request()
+            .method("POST")
+            .path("/api/auth/login")
+            .json(&serde_json::json!({"username": "bob", "password": "correct horse battery"}))
+            .reply(&routes)
+            .await;
+        let session: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        let bearer = format!("Bearer {}", session["token"].as_str().unwrap());
+
+        let resp = warp::test::request()
+            .header("x-api-key", ADMIN_KEY)
+            .method("DELETE")
+            .path("/api/tenants/acme")
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), 204);
+
+        // Neither the tenant's keys nor its users' sessions survive it
+        let resp = warp::test::request().header("x-api-key", ACME_KEY).path("/api/auth/whoami").reply(&routes).await;
+        assert_eq!(resp.status(), 401);
+        let resp = warp::test::request().header("authorization", &bearer).path("/api/auth/whoami").reply(&routes).await;
+        assert_eq!(resp.status(), 401);
+        let resp = warp::test::request()
+            .method("POST")
+            .path("/api/auth/login")
+            .json(&serde_json::json!({"username": "bob", "password": "correct horse battery"}))
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), 401);
+    }
+
+    #[tokio::test]
+    async fn test_only_platform_admins_revoke_tokens_by_jti() {
+        let (api_service, _) = setup_api_service();
+        let routes = api_service.routes();
+
+        for (key, body, status) in [
+            (ACME_KEY, serde_json::json!({"jti": "t1"}), 403),
+            (ACME_KEY, serde_json::json!({"token": "not.a.jwt"}), 400),
+            (ADMIN_KEY, serde_json::json!({}), 400),
+            (ADMIN_KEY, serde_json::json!({"jti": "t1"}), 204),
+        ] {
+            let resp = warp::test::request()
+                .header("x-api-key", key)
+                .method("POST")
+                .path("/api/auth/tokens/revoke")
+                .json(&body)
+                .reply(&routes)
+                .await;
+            assert_eq!(resp.status(), status, "{} {}", key, body);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_listings_are_paged_with_cursors() {
+        let (api_service, device_manager) = setup_api_service();
+        for (id, name) in [("d1", "Boiler"), ("d2", "Attic"), ("d3", "Cellar")] {
+            device_manager.add_device(Device::new(id.to_string(), name.to_string()));
+        }
+        let routes = api_service.routes();
+
+        let resp = warp::test::request()
+            .header("x-api-key", READ_KEY)
+            .path("/api/devices?sort=-name&limit=2&fields=name")
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.headers()["X-Total-Count"], "3");
+        let devices: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(devices, serde_json::json!([{"id": "d3", "name": "Cellar"}, {"id": "d1", "name": "Boiler"}]));
+
+        let cursor = resp.headers()["X-Next-Cursor"].to_str().unwrap();
+        let resp = warp::test::request()
+            .header("x-api-key", READ_KEY)
+            .path(&format!("/api/devices?sort=-name&limit=2&cursor={}", cursor))
+            .reply(&routes)
+            .await;
+        let devices: Vec<Device> = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].id, "d2");
+        assert!(resp.headers().get("X-Next-Cursor").is_none());
+
+        let resp = warp::test::request()
+            .header("x-api-key", READ_KEY)
+            .path(&format!("/api/devices?sort=name&cursor={}", cursor))
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), 400);
+
+        // Alerts of other tenants stay out of the listing
+        for device_id in ["d1", "acme/d1"] {
+            let (title, severity) = ("Offline".to_string(), "critical".to_string());
+            let notification = Notification::new(title, "".to_string(), severity, Some(device_id.to_string()));
+            api_service.events.publish(Event::Alert { notification });
+        }
+        let resp = warp::test::request()
+            .header("x-api-key", ACME_KEY)
+            .path("/api/alerts?severity=critical")
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.headers()["X-Total-Count"], "1");
+        let alerts: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(alerts[0]["device_id"], "d1");
+        assert_eq!(alerts[0]["id"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_graphql_queries_devices_of_the_callers_tenant() {
+        let (api_service, device_manager) = setup_api_service();
+        device_manager.add_device(Device::new("d1".to_string(), "Operator Sensor".to_string()));
+        device_manager.add_device(Device::new("acme/d1".to_string(), "Acme Sensor".to_string()));
+        let routes = api_service.routes();
+        let query = serde_json::json!({"query": "{ devices { totalCount nodes { id name health { status } } } }"});
+
+        let resp = warp::test::request().method("POST").path("/api/graphql").json(&query).reply(&routes).await;
+        assert_eq!(resp.status(), 401);
+
+        let resp = warp::test::request()
+            .header("x-api-key", ACME_KEY)
+            .method("POST")
+            .path("/api/graphql")
+            .json(&query)
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), 200);
+        let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(body["data"]["devices"]["totalCount"], 1);
+        assert_eq!(body["data"]["devices"]["nodes"][0]["id"], "d1");
+        assert_eq!(body["data"]["devices"]["nodes"][0]["name"], "Acme Sensor");
+
+        let resp = warp::test::request()
+            .header("x-api-key", ACME_KEY)
+            .method("POST")
+            .path("/api/graphql")
+            .json(&serde_json::json!({"query": "{ devices(sort: \"owner\") { totalCount } }"}))
+            .reply(&routes)
+            .await;
+        let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert!(body["errors"][0]["message"].as_str().unwrap().contains("cannot sort by owner"));
+    }
+
+    #[tokio::test]
+    async fn test_users_sign_in_and_are_limited_to_their_groups() {
+        let (api_service, device_manager) = setup_api_service();
+        let mut line1 = Device::new("d1".to_string(), "Line 1 Sensor".to_string());
+        line1.tags = vec!["line1".to_string()];
+        device_manager.add_device(line1);
+        device_manager.add_device(Device::new("d2".to_string(), "Line 2 Sensor".to_string()));
+        let routes = api_service.routes();
+
+        let resp = warp::test::request()
+            .header("x-api-key", ADMIN_KEY)
+            .method("POST")
+            .path("/api/users")
+            .json(&serde_json::json!({
+                "username": "alice",
+                "password": "correct horse battery",
+                "grants": [{"group": "line1", "role": "operator"}]
+            }))
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), 201);
+        let user: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert!(user.get("password_hash").is_none());
+
+        let resp = warp::test::request()
+            .method("POST")
+            .path("/api/auth/login")
+            .json(&serde_json::json!({"username": "alice", "password": "wrong password"}))
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), 401);
+        let resp = warp::test::request()
+            .method("POST")
+            .path("/api/auth/login")
+            .json(&serde_json::json!({"username": "alice", "password": "correct horse battery"}))
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), 200);
+        let session: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        let bearer = format!("Bearer {}", session["token"].as_str().unwrap());
+
+        let resp = warp::test::request().header("authorization", &bearer).path("/api/devices").reply(&routes).await;
+        let devices: Vec<Device> = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].id, "d1");
+        let resp = warp::test::request().header("authorization", &bearer).path("/api/devices/d2").reply(&routes).await;
+        assert_eq!(resp.status(), 404);
+        let resp = warp::test::request().header("authorization", &bearer).path("/api/users").reply(&routes).await;
+        assert_eq!(resp.status(), 403);
+
+        let resp = warp::test::request()
+            .header("x-api-key", ADMIN_KEY)
+            .path("/api/audit?action=user.")
+            .reply(&routes)
+            .await;
+        let changes: Vec<AuditEntry> = serde_json::from_slice(resp.body()).unwrap();
+        let actions: Vec<(&str, &str)> =
+            changes.iter().map(|entry| (entry.action.as_str(), entry.actor.as_str())).collect();
+        assert_eq!(actions, [("user.create", "admin"), ("user.login_failed", "alice"), ("user.login", "alice")]);
+
+        let resp = warp::test::request()
+            .header("authorization", &bearer)
+            .method("POST")
+            .path("/api/auth/logout")
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), 204);
+        let resp = warp::test::request().header("authorization", &bearer).path("/api/devices").reply(&routes).await;
+        assert_eq!(resp.status(), 401);
+        let resp = warp::test::request()
+            .header("x-api-key", ADMIN_KEY)
+            .path("/api/audit?action=user.logout")
+            .reply(&routes)
+            .await;
+        let changes: Vec<AuditEntry> = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].target, "alice");
+    }
+
+    #[tokio::test]
+    async fn test_changes_that_cannot_be_audited_are_not_made() {
+        let (api_service, device_manager) = setup_api_service();
+        // The log's directory disappears after it is opened, so no entry can be written
+        let dir = std::env::temp_dir().join(format!("iot_api_audit_{}", crate::auth::random_hex(8)));
+        std::fs::create_dir_all(&dir).unwrap();
+        let audit = AuditLog::new(Some(dir.join("audit.jsonl")), None).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let routes = api_service.with_audit_log(Arc::new(audit)).routes();
+
+        let resp = warp::test::request()
+            .header("x-api-key", ADMIN_KEY)
+            .method("POST")
+            .path("/api/devices")
+            .json(&serde_json::json!({"id": "meter1", "name": "Power Meter"}))
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), 500);
+        assert!(device_manager.list_devices().is_empty());
+
+        // A key only gets its id once created, so it is revoked again
+        let resp = warp::test::request()
+            .header("x-api-key", ADMIN_KEY)
+            .method("POST")
+            .path("/api/auth/keys")
+            .json(&serde_json::json!({"name": "ci", "scopes": ["read_telemetry"]}))
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), 500);
+        let resp = warp::test::request().header("x-api-key", ADMIN_KEY).path("/api/auth/keys").reply(&routes).await;
+        let keys: Vec<ApiKey> = serde_json::from_slice(resp.body()).unwrap();
+        assert!(keys.iter().filter(|key| key.name == "ci").all(|key| key.revoked_at.is_some()));
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited_clients_are_told_when_to_retry() {
+        let (api_service, _) = setup_api_service();
+        let config = RateLimitConfig {
+            per_api_client: Some(RateLimit { per_second: 1.0, burst: 1 }),
+            ..RateLimitConfig::default()
+        };
+        let routes = api_service.with_rate_limits(Arc::new(RateLimits::new(config))).routes();
+
+        let resp = warp::test::request().header("x-api-key", READ_KEY).path("/api/devices").reply(&routes).await;
+        assert_eq!(resp.status(), 200);
+        let resp = warp::test::request().header("x-api-key", READ_KEY).path("/api/devices").reply(&routes).await;
+        assert_eq!(resp.status(), 429);
+        assert_eq!(resp.headers()["Retry-After"], "1");
+
+        // Every client has its own bucket
+        let resp = warp::test::request().header("x-api-key", ADMIN_KEY).path("/api/devices").reply(&routes).await;
+        assert_eq!(resp.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn test_changes_are_recorded_in_the_audit_log() {
+        let (api_service, device_manager) = setup_api_service();
+        device_manager.add_device(Device::new("meter1".to_string(), "Power Meter".to_string()));
+        let routes = api_service.routes();
+
+        let resp = warp::test::request()
+            .header("x-api-key", ADMIN_KEY)
+            .remote_addr("10.0.0.7:40000".parse().unwrap())
+            .method("PATCH")
+            .path("/api/devices/meter1")
+            .json(&serde_json::json!({"name": "Basement Meter"}))
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), 200);
+        let resp = warp::test::request()
+            .header("x-api-key", ADMIN_KEY)
+            .method("DELETE")
+            .path("/api/devices/meter1")
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), 204);
+
+        let resp = warp::test::request()
+            .header("x-api-key", ADMIN_KEY)
+            .path("/api/audit?action=device.update")
+            .reply(&routes)
+            .await;
+        let entries: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(entries.as_array().unwrap().len(), 1);
+        assert_eq!(entries[0]["actor"], "admin");
+        assert_eq!(entries[0]["target"], "meter1");
+        assert_eq!(entries[0]["source_ip"], "10.0.0.7");
+        assert_eq!(entries[0]["before"]["name"], "Power Meter");
+        assert_eq!(entries[0]["after"]["name"], "Basement Meter");
+
+        let resp = warp::test::request()
+            .header("x-api-key", ADMIN_KEY)
+            .path("/api/audit/export")
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.headers()["content-type"], "application/x-ndjson");
+        assert_eq!(String::from_utf8_lossy(resp.body()).lines().count(), 2);
+        let resp = warp::test::request()
+            .header("x-api-key", ADMIN_KEY)
+            .path("/api/audit/verify")
+            .reply(&routes)
+            .await;
+        let verification: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(verification["valid"], true);
+
+        // Other tenants neither see the entries nor verify the chain
+        let resp = warp::test::request().header("x-api-key", ACME_KEY).path("/api/audit").reply(&routes).await;
+        assert_eq!(resp.body().as_ref(), b"[]");
+        let resp = warp::test::request().header("x-api-key", ACME_KEY).path("/api/audit/verify").reply(&routes).await;
+        assert_eq!(resp.status(), 403);
+    }
+
+    // Fails when a route is added, removed or moved without updating `operations`
+    #[tokio::test]
+    async fn test_openapi_document_matches_the_routes() {
+        let (api_service, _) = setup_api_service();
+        let resp = warp::test::request().path("/openapi.json").reply(&api_service.routes()).await;
+        assert_eq!(resp.status(), 200);
+        let document: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(document["openapi"], "3.0.3");
+
+        // Routes are declared from their operations, so every documented operation must be
+        // served: without credentials it fails on them, never on the path or method
+        let routes = api_service.routes();
+        let mut checked = 0;
+        for (path, methods) in document["paths"].as_object().unwrap() {
+            let segments: Vec<&str> =
+                path.split('/').map(|segment| if segment.starts_with('{') { "x" } else { segment }).collect();
+            for method in methods.as_object().unwrap().keys() {
+                let resp = warp::test::request()
+                    .method(&method.to_uppercase())
+                    .path(&segments.join("/"))
+                    .reply(&routes)
+                    .await;
+                assert_ne!(resp.status(), 404, "{} {}", method, path);
+                assert_ne!(resp.status(), 405, "{} {}", method, path);
+                checked += 1;
+            }
+        }
+        assert!(checked > 40);
+
+        // The listings that take `fields` document partial items too
+        let devices = &document["paths"]["/api/devices"]["get"]["responses"]["200"]["content"]["application/json"];
+        assert_eq!(devices["schema"]["oneOf"][1]["items"]["type"], "object");
+    }
+
+    #[tokio::test]
+    async fn test_errors_are_json() {
+        let (api_service, _) = setup_api_service();
+        let routes = api_service.routes();
+
+        let resp = warp::test::request()
+            .header("x-api-key", ADMIN_KEY)
+            .path("/api/devices/missing")
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), 404);
+        let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(body["code"], 404);
+        assert_eq!(body["error"], "device missing not found");
+
+        let resp = warp::test::request()
+            .header("x-api-key", ADMIN_KEY)
+            .method("DELETE")
+            .path("/api/analytics")
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), 405);
     }
 }
 ```
@@ -0,0 +1,390 @@
+// audit.rs
+
+use crate::auth::Principal;
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Hash the first entry links to.
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Number of entries a log without a file keeps; older ones are dropped.
+const MEMORY_LIMIT: usize = 10_000;
+
+/// A single administrative action. Each entry includes the hash of the entry before it, so
+/// changing or removing an entry breaks the chain from there on.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct AuditEntry {
+    pub sequence: u64,
+    pub at: DateTime<Utc>,
+    /// The API key id, token subject or user id that acted.
+    pub actor: String,
+    /// The tenant the action applies to.
+    pub tenant_id: String,
+    pub action: String,
+    pub target: String,
+    #[serde(default)]
+    pub before: Option<serde_json::Value>,
+    #[serde(default)]
+    pub after: Option<serde_json::Value>,
+    #[serde(default)]
+    pub source_ip: Option<IpAddr>,
+    pub previous_hash: String,
+    /// Hex encoded SHA-256 of the entry with an empty `hash`.
+    pub hash: String,
+}
+
+impl AuditEntry {
+    /// Computes the hash of the entry's contents, including the previous entry's hash.
+    fn compute_hash(&self) -> String {
+        let unhashed = AuditEntry {
+            hash: String::new(),
+            ..self.clone()
+        };
+        let contents = serde_json::to_vec(&unhashed).expect("audit entries serialize to JSON");
+        hex::encode(Sha256::digest(&contents))
+    }
+}
+
+/// Narrows down the audit entries returned.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct AuditQuery {
+    #[serde(default)]
+    pub actor: Option<String>,
+    /// Matches the action exactly, or all actions starting with it when it ends in `.`.
+    #[serde(default)]
+    pub action: Option<String>,
+    #[serde(default)]
+    pub target: Option<String>,
+    #[serde(default)]
+    pub since: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub until: Option<DateTime<Utc>>,
+    /// Returns only the most recent entries.
+    #[serde(default)]
+    pub limit: Option<usize>,
+    #[serde(skip)]
+    pub tenant_id: Option<String>,
+}
+
+impl AuditQuery {
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        let action_differs = |action: &String| {
+            if action.ends_with('.') {
+                !entry.action.starts_with(action.as_str())
+            } else {
+                &entry.action != action
+            }
+        };
+        if self.action.as_ref().is_some_and(action_differs)
+            || self.actor.as_ref().is_some_and(|actor| actor != &entry.actor)
+            || self.target.as_ref().is_some_and(|target| target != &entry.target)
+            || self.tenant_id.as_ref().is_some_and(|tenant_id| tenant_id != &entry.tenant_id)
+        {
+            return false;
+        }
+        !self.since.is_some_and(|since| entry.at < since) && !self.until.is_some_and(|until| entry.at >= until)
+    }
+}
+
+/// Result of checking the hash chain.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ChainVerification {
+    pub entries: usize,
+    pub valid: bool,
+    /// Sequence number of the first entry that does not match its hash, predecessor or anchor.
+    pub first_invalid: Option<u64>,
+    /// Hash of the latest entry; keeping it outside the platform anchors the log.
+    pub head: Option<String>,
+    /// Sequence number of the latest anchored entry the chain was checked against.
+    pub anchored: Option<u64>,
+}
+
+/// The hash of an entry as written to the anchor file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Anchor {
+    sequence: u64,
+    hash: String,
+}
+
+/// Append-only log of administrative actions, persisted as one JSON entry per line.
+pub struct AuditLog {
+    // The most recent entries. With a file only the last one is kept, to chain the next entry
+    // to, and the rest is read from the file
+    entries: Arc<Mutex<VecDeque<AuditEntry>>>,
+    path: Option<PathBuf>,
+    anchor_path: Option<PathBuf>,
+}
+
+impl AuditLog {
+    /// Opens the log, picking up after the entries already written to its file. The hash of
+    /// each new entry is also appended to the anchor file, if any, which should live where
+    /// whoever can rewrite the log cannot, e.g. on append-only storage or another host. A
+    /// chain that was tampered with is reported but kept, so new entries are still recorded.
+    pub fn new(path: Option<PathBuf>, anchor_path: Option<PathBuf>) -> crate::Result<Self> {
+        let log = AuditLog {
+            entries: Arc::new(Mutex::new(VecDeque::new())),
+            path,
+            anchor_path,
+        };
+        let mut last = None;
+        log.for_each_entry(|entry| last = Some(entry))?;
+        log.entries.lock().unwrap().extend(last);
+        if let Some(sequence) = log.verify()?.first_invalid {
+            eprintln!("Audit log was modified at entry {}", sequence);
+        }
+        Ok(log)
+    }
+
+    /// Appends an entry for an action taken by the principal.
+    pub fn record(
+        &self,
+        principal: &Principal,
+        tenant_id: &str,
+        action: &str,
+        target: &str,
+        before: Option<serde_json::Value>,
+        after: Option<serde_json::Value>,
+    ) -> crate::Result<AuditEntry> {
+        let mut entries = self.entries.lock().unwrap();
+        let (sequence, previous_hash) = match entries.back() {
+            Some(last) => (last.sequence + 1, last.hash.clone()),
+            None => (0, GENESIS_HASH.to_string()),
+        };
+        let mut entry = AuditEntry {
+            sequence,
+            at: Utc::now(),
+            actor: principal.subject.clone(),
+            tenant_id: tenant_id.to_string(),
+            action: action.to_string(),
+            target: target.to_string(),
+            before,
+            after,
+            source_ip: principal.source_ip,
+            previous_hash,
+            hash: String::new(),
+        };
+        entry.hash = entry.compute_hash();
+
+        // The entry only counts as recorded once it is on disk
+        if let Some(path) = &self.path {
+            append_line(path, &serde_json::to_string(&entry)?)?;
+        }
+        if let Some(anchor_path) = &self.anchor_path {
+            let anchor = Anchor {
+                sequence,
+                hash: entry.hash.clone(),
+            };
+            // The entry is recorded already; a lagging anchor only covers fewer entries
+            if let Err(e) = append_line(anchor_path, &serde_json::to_string(&anchor)?) {
+                eprintln!("Failed to anchor audit entry {}: {}", sequence, e);
+            }
+        }
+        entries.push_back(entry.clone());
+        let limit = if self.path.is_some() { 1 } else { MEMORY_LIMIT };
+        while entries.len() > limit {
+            entries.pop_front();
+        }
+        Ok(entry)
+    }
+
+    /// Returns the entries matching the query, oldest first.
+    pub fn query(&self, query: &AuditQuery) -> crate::Result<Vec<AuditEntry>> {
+        let mut matching = VecDeque::new();
+        self.for_each_entry(|entry| {
+            if query.matches(&entry) {
+                matching.push_back(entry);
+                if query.limit.is_some_and(|limit| matching.len() > limit) {
+                    matching.pop_front();
+                }
+            }
+        })?;
+        Ok(matching.into())
+    }
+
+    /// Returns the entries matching the query as JSON Lines, one entry per line, for export.
+    pub fn export(&self, query: &AuditQuery) -> crate::Result<String> {
+        Ok(self
+            .query(query)?
+            .iter()
+            .map(|entry| serde_json::to_string(entry).expect("audit entries serialize to JSON") + "\n")
+            .collect())
+    }
+
+    /// Checks that every entry matches its hash and links to the entry before it, and that
+    /// the latest anchored entry is still there with the anchored hash.
+    pub fn verify(&self) -> crate::Result<ChainVerification> {
+        let anchor = self.latest_anchor()?;
+        let mut previous: Option<(u64, String)> = None;
+        let (mut entries, mut first_invalid) = (0, None);
+        self.for_each_entry(|entry| {
+            entries += 1;
+            let links = match &previous {
+                Some((sequence, hash)) => entry.sequence == sequence + 1 && &entry.previous_hash == hash,
+                // Logs without a file drop their oldest entries, so theirs may start anywhere
+                None => self.path.is_none() || (entry.sequence == 0 && entry.previous_hash == GENESIS_HASH),
+            };
+            let contradicts_anchor =
+                anchor.as_ref().is_some_and(|anchor| anchor.sequence == entry.sequence && anchor.hash != entry.hash);
+            if first_invalid.is_none() && (!links || contradicts_anchor || entry.hash != entry.compute_hash()) {
+                first_invalid = Some(entry.sequence);
+            }
+            previous = Some((entry.sequence, entry.hash));
+        })?;
+        // Removing entries from the end takes the anchored entry with them
+        let last_sequence = previous.as_ref().map(|(sequence, _)| *sequence);
+        if let Some(anchor) = &anchor {
+            if first_invalid.is_none() && last_sequence.map_or(true, |last| anchor.sequence > last) {
+                first_invalid = Some(anchor.sequence);
+            }
+        }
+        Ok(ChainVerification {
+            entries,
+            valid: first_invalid.is_none(),
+            first_invalid,
+            head: previous.map(|(_, hash)| hash),
+            anchored: anchor.map(|anchor| anchor.sequence),
+        })
+    }
+
+    /// Calls `visit` with each entry, oldest first, read from the file if there is one.
+    fn for_each_entry(&self, mut visit: impl FnMut(AuditEntry)) -> crate::Result<()> {
+        // Holding the lock keeps a concurrent write from showing up half done
+        let entries = self.entries.lock().unwrap();
+        let path = match &self.path {
+            Some(path) => path,
+            None => {
+                entries.iter().cloned().for_each(visit);
+                return Ok(());
+            }
+        };
+        if !path.exists() {
+            return Ok(());
+        }
+        for line in BufReader::new(File::open(path)?).lines() {
+            let line = line?;
+            if !line.trim().is_empty() {
+                visit(serde_json::from_str(&line)?);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the last anchor written, if any.
+    fn latest_anchor(&self) -> crate::Result<Option<Anchor>> {
+        let path = match &self.anchor_path {
+            Some(path) if path.exists() => path,
+            _ => return Ok(None),
+        };
+        let mut latest = None;
+        for line in BufReader::new(File::open(path)?).lines() {
+            let line = line?;
+            if !line.trim().is_empty() {
+                latest = Some(serde_json::from_str(&line)?);
+            }
+        }
+        Ok(latest)
+    }
+}
+
+/// Appends a line to a file and waits until it is on disk.
+fn append_line(path: &Path, line: &str) -> crate::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", line)?;
+    file.sync_data()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{CredentialKind, Scope};
+    use crate::tenant::DEFAULT_TENANT;
+
+    fn admin() -> Principal {
+        Principal {
+            subject: "admin".to_string(),
+            scopes: vec![Scope::Admin],
+            credential: CredentialKind::ApiKey,
+            tenant_id: DEFAULT_TENANT.to_string(),
+            group_scopes: Default::default(),
+            source_ip: Some("10.0.0.1".parse().unwrap()),
+        }
+    }
+
+    #[test]
+    fn test_entries_are_chained_and_persisted() {
+        let path = std::env::temp_dir().join(format!("iot_audit_{}.jsonl", crate::auth::random_hex(8)));
+        let log = AuditLog::new(Some(path.clone()), None).unwrap();
+        let before = serde_json::json!({"name": "Sensor"});
+        let after = serde_json::json!({"name": "Boiler Sensor"});
+        log.record(&admin(), DEFAULT_TENANT, "device.update", "d1", Some(before), Some(after)).unwrap();
+        log.record(&admin(), "acme", "device.delete", "d2", None, None).unwrap();
+
+        let reopened = AuditLog::new(Some(path.clone()), None).unwrap();
+        let entries = reopened.query(&AuditQuery::default()).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].previous_hash, entries[0].hash);
+        assert_eq!(entries[0].source_ip, Some("10.0.0.1".parse().unwrap()));
+        assert!(reopened.verify().unwrap().valid);
+        // Only the last entry stays in memory, to chain the next one to
+        assert_eq!(reopened.entries.lock().unwrap().len(), 1);
+        let entry = reopened.record(&admin(), DEFAULT_TENANT, "device.delete", "d1", None, None).unwrap();
+        assert_eq!(entry.previous_hash, entries[1].hash);
+
+        let query = AuditQuery {
+            action: Some("device.".to_string()),
+            tenant_id: Some("acme".to_string()),
+            ..AuditQuery::default()
+        };
+        assert_eq!(reopened.query(&query).unwrap().len(), 1);
+        assert_eq!(reopened.export(&query).unwrap().lines().count(), 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_modified_entries_break_the_chain() {
+        let log = AuditLog::new(None, None).unwrap();
+        for target in ["d1", "d2", "d3"] {
+            log.record(&admin(), DEFAULT_TENANT, "device.delete", target, None, None).unwrap();
+        }
+        log.entries.lock().unwrap()[1].actor = "someone else".to_string();
+        let verification = log.verify().unwrap();
+        assert!(!verification.valid);
+        assert_eq!(verification.first_invalid, Some(1));
+
+        // Removing an entry is caught by the next entry's link
+        log.entries.lock().unwrap().remove(1);
+        assert_eq!(log.verify().unwrap().first_invalid, Some(2));
+    }
+
+    #[test]
+    fn test_anchor_catches_entries_removed_from_the_end() {
+        let dir = std::env::temp_dir().join(format!("iot_audit_{}", crate::auth::random_hex(8)));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (path, anchor_path) = (dir.join("audit.jsonl"), dir.join("anchor.jsonl"));
+        let log = AuditLog::new(Some(path.clone()), Some(anchor_path.clone())).unwrap();
+        for target in ["d1", "d2", "d3"] {
+            log.record(&admin(), DEFAULT_TENANT, "device.delete", target, None, None).unwrap();
+        }
+        let verification = log.verify().unwrap();
+        assert!(verification.valid);
+        assert_eq!(verification.anchored, Some(2));
+        assert_eq!(verification.head, log.entries.lock().unwrap().back().map(|entry| entry.hash.clone()));
+
+        // What is left is a valid chain on its own, but no longer reaches the anchor
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let truncated: String = contents.lines().take(2).map(|line| format!("{}\n", line)).collect();
+        std::fs::write(&path, truncated).unwrap();
+        let reopened = AuditLog::new(Some(path), Some(anchor_path)).unwrap();
+        assert_eq!(reopened.verify().unwrap().first_invalid, Some(2));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,662 @@
+// auth.rs
+
+use crate::config::{AuthConfig, JwtAlgorithm};
+use crate::tenant::DEFAULT_TENANT;
+use crate::users::{UserManager, SESSION_PREFIX};
+use chrono::{DateTime, Duration, Utc};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use rand::RngCore;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+/// Prefix of the API keys issued by the platform.
+const API_KEY_PREFIX: &str = "iotk";
+
+/// Longest time the old secret of a rotated key keeps working.
+pub const MAX_ROTATION_GRACE_SECS: u64 = 7 * 24 * 3600;
+
+/// How long a token revoked by its `jti` alone stays revoked, as its expiry is unknown.
+const REVOKED_JTI_TTL_DAYS: i64 = 30;
+
+/// A permission granted to an API key or token.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    /// Read devices, telemetry, analytics and health.
+    ReadTelemetry,
+    /// Create, update and delete devices.
+    ManageDevices,
+    /// Everything, including managing API keys.
+    Admin,
+}
+
+impl Scope {
+    /// Parses a scope name as used in JWT `scope` claims.
+    pub fn parse(name: &str) -> Option<Scope> {
+        serde_json::from_value(serde_json::Value::String(name.to_string())).ok()
+    }
+}
+
+/// How a client proved its identity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum CredentialKind {
+    ApiKey,
+    Jwt,
+    /// A user's session token.
+    Session,
+    /// Authentication is disabled.
+    Anonymous,
+}
+
+/// The authenticated caller of a request.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Principal {
+    /// The API key id or the token subject.
+    pub subject: String,
+    pub scopes: Vec<Scope>,
+    pub credential: CredentialKind,
+    /// The tenant whose data the principal may access.
+    pub tenant_id: String,
+    /// Scopes granted only on device groups, i.e. on devices carrying the group's tag.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub group_scopes: HashMap<String, Vec<Scope>>,
+    /// Address the request came from, recorded with the actions the principal takes.
+    #[serde(skip)]
+    pub source_ip: Option<IpAddr>,
+}
+
+impl Principal {
+    /// Returns true if the principal was granted the scope, directly or through admin.
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        self.scopes.contains(&Scope::Admin) || self.scopes.contains(&scope)
+    }
+
+    /// Returns true if the principal was granted the scope on the tenant or on any device group.
+    pub fn has_scope_anywhere(&self, scope: Scope) -> bool {
+        self.has_scope(scope)
+            || self
+                .group_scopes
+                .values()
+                .any(|scopes| scopes.contains(&Scope::Admin) || scopes.contains(&scope))
+    }
+
+    /// Returns true if the principal has the scope on a device with the given tags.
+    pub fn can_access_device(&self, tags: &[String], scope: Scope) -> bool {
+        self.has_scope(scope) || self.groups_with(scope).iter().any(|group| tags.contains(group))
+    }
+
+    /// Returns the device groups the principal has the scope on.
+    pub fn groups_with(&self, scope: Scope) -> Vec<String> {
+        self.group_scopes
+            .iter()
+            .filter(|(_, scopes)| scopes.contains(&Scope::Admin) || scopes.contains(&scope))
+            .map(|(group, _)| group.clone())
+            .collect()
+    }
+
+    /// Returns true for admins of the default tenant, who manage tenants and the platform.
+    pub fn is_platform_admin(&self) -> bool {
+        self.tenant_id == DEFAULT_TENANT && self.scopes.contains(&Scope::Admin)
+    }
+}
+
+/// Why a request was not allowed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthError {
+    /// No valid credentials were presented.
+    Unauthorized(String),
+    /// The credentials are valid but lack a required scope.
+    Forbidden(String),
+}
+
+impl std::fmt::Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuthError::Unauthorized(message) | AuthError::Forbidden(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+/// An API key as stored by the platform. Only a hash of the secret is kept.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    #[serde(default = "default_tenant")]
+    pub tenant_id: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "String::is_empty", default)]
+    #[schemars(skip)]
+    secret_hash: String,
+    /// The secret replaced by the last rotation, accepted until `previous_expires_at`.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    #[schemars(skip)]
+    previous_secret_hash: Option<String>,
+    #[serde(default)]
+    pub previous_expires_at: Option<DateTime<Utc>>,
+}
+
+impl ApiKey {
+    /// Returns a copy without the secret hashes, for listing keys over the API.
+    pub fn redacted(&self) -> ApiKey {
+        ApiKey {
+            secret_hash: String::new(),
+            previous_secret_hash: None,
+            ..self.clone()
+        }
+    }
+
+    fn accepts(&self, secret_hash: &str, now: DateTime<Utc>) -> bool {
+        if self.revoked_at.is_some() || self.expires_at.is_some_and(|expires_at| expires_at <= now) {
+            return false;
+        }
+        if self.secret_hash == secret_hash {
+            return true;
+        }
+        self.previous_secret_hash.as_deref() == Some(secret_hash)
+            && self.previous_expires_at.is_some_and(|expires_at| expires_at > now)
+    }
+}
+
+/// An API key together with its secret, returned only when the key is created or rotated.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct IssuedApiKey {
+    pub key: ApiKey,
+    /// The value clients send in the `X-API-Key` header.
+    pub secret: String,
+}
+
+/// Claims read from JWT bearer tokens.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Claims {
+    pub sub: String,
+    pub exp: i64,
+    /// Space separated scopes, as in OAuth 2.
+    #[serde(default)]
+    pub scope: String,
+    #[serde(default)]
+    pub jti: Option<String>,
+    /// Tenant the token grants access to; the default tenant when missing.
+    #[serde(default)]
+    pub tenant: Option<String>,
+    /// When the token was issued, as a Unix timestamp.
+    #[serde(default)]
+    pub iat: Option<i64>,
+}
+
+/// What is persisted to the key store file.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct KeyStore {
+    keys: HashMap<String, ApiKey>,
+    /// Revoked tokens by `jti`, with the time after which they expire anyway and can be forgotten.
+    revoked_tokens: HashMap<String, DateTime<Utc>>,
+    /// Removed tenants by the time they were removed; tokens issued for them before then
+    /// are rejected, so a tenant created again under the same id starts clean.
+    #[serde(default)]
+    revoked_tenants: HashMap<String, DateTime<Utc>>,
+}
+
+/// Authenticates API requests with API keys or JWT bearer tokens and manages API keys.
+pub struct AuthService {
+    config: AuthConfig,
+    /// JWT verification keys by key id.
+    jwt_keys: HashMap<String, (Algorithm, DecodingKey)>,
+    store: Arc<Mutex<KeyStore>>,
+    store_path: Option<PathBuf>,
+    users: UserManager,
+}
+
+impl AuthService {
+    /// Creates the service, loading the JWT verification keys and the persisted API keys.
+    pub fn new(config: AuthConfig) -> crate::Result<Self> {
+        let mut jwt_keys = HashMap::new();
+        for key in &config.jwt_keys {
+            let decoding_key = match key.algorithm {
+                JwtAlgorithm::Hs256 => {
+                    let secret = key.secret.as_ref().ok_or_else(|| format!("JWT key {} needs a secret", key.kid))?;
+                    (Algorithm::HS256, DecodingKey::from_secret(secret.as_bytes()))
+                }
+                JwtAlgorithm::Rs256 => {
+                    let path = key
+                        .public_key_path
+                        .as_ref()
+                        .ok_or_else(|| format!("JWT key {} needs a public_key_path", key.kid))?;
+                    (Algorithm::RS256, DecodingKey::from_rsa_pem(&std::fs::read(path)?)?)
+                }
+            };
+            jwt_keys.insert(key.kid.clone(), decoding_key);
+        }
+
+        let mut store: KeyStore = match &config.key_store_path {
+            Some(path) if path.exists() => serde_json::from_slice(&std::fs::read(path)?)?,
+            _ => KeyStore::default(),
+        };
+        // Keys from the configuration file are added to the ones created over the API; a key
+        // that was rotated or revoked over the API keeps its persisted state
+        for key in &config.api_keys {
+            store.keys.entry(key.id.clone()).or_insert_with(|| ApiKey {
+                id: key.id.clone(),
+                name: key.id.clone(),
+                scopes: key.scopes.clone(),
+                tenant_id: key.tenant_id.clone(),
+                created_at: Utc::now(),
+                expires_at: key.expires_at,
+                revoked_at: None,
+                secret_hash: key.secret_sha256.to_lowercase(),
+                previous_secret_hash: None,
+                previous_expires_at: None,
+            });
+        }
+
+        let users = UserManager::new(
+            config.user_store_path.clone(),
+            Duration::seconds(config.session_ttl_secs as i64),
+        )?;
+
+        Ok(AuthService {
+            store_path: config.key_store_path.clone(),
+            users,
+            config,
+            jwt_keys,
+            store: Arc::new(Mutex::new(store)),
+        })
+    }
+
+    /// Identifies the caller from the `Authorization` and `X-API-Key` headers.
+    pub fn authenticate(&self, authorization: Option<&str>, api_key: Option<&str>) -> Result<Principal, AuthError> {
+        if !self.config.enabled {
+            return Ok(Principal {
+                subject: "anonymous".to_string(),
+                scopes: vec![Scope::Admin],
+                credential: CredentialKind::Anonymous,
+                tenant_id: DEFAULT_TENANT.to_string(),
+                group_scopes: HashMap::new(),
+                source_ip: None,
+            });
+        }
+        if let Some(api_key) = api_key {
+            return self.authenticate_api_key(api_key);
+        }
+        match authorization.and_then(|header| header.strip_prefix("Bearer ")).map(str::trim) {
+            Some(token) if token.starts_with(SESSION_PREFIX) => self
+                .users
+                .authenticate_session(token)
+                .ok_or_else(|| AuthError::Unauthorized("invalid or expired session".to_string())),
+            Some(token) => self.authenticate_jwt(token),
+            None => Err(AuthError::Unauthorized("missing credentials".to_string())),
+        }
+    }
+
+    /// Returns the users who can sign in with a password.
+    pub fn users(&self) -> &UserManager {
+        &self.users
+    }
+
+    /// Authenticates the caller and checks that it was granted the scope.
+    pub fn authorize(
+        &self,
+        authorization: Option<&str>,
+        api_key: Option<&str>,
+        scope: Scope,
+    ) -> Result<Principal, AuthError> {
+        let principal = self.authenticate(authorization, api_key)?;
+        if !principal.has_scope(scope) {
+            return Err(AuthError::Forbidden(format!("missing scope {:?}", scope)));
+        }
+        Ok(principal)
+    }
+
+    fn authenticate_api_key(&self, api_key: &str) -> Result<Principal, AuthError> {
+        let invalid = || AuthError::Unauthorized("invalid API key".to_string());
+        let (id, _) = parse_api_key(api_key).ok_or_else(invalid)?;
+
+        let store = self.store.lock().unwrap();
+        let key = store.keys.get(id).ok_or_else(invalid)?;
+        if !key.accepts(&hash_secret(api_key), Utc::now()) {
+            return Err(invalid());
+        }
+        Ok(Principal {
+            subject: key.id.clone(),
+            scopes: key.scopes.clone(),
+            credential: CredentialKind::ApiKey,
+            tenant_id: key.tenant_id.clone(),
+            group_scopes: HashMap::new(),
+            source_ip: None,
+        })
+    }
+
+    /// Checks a JWT's signature, expiry, issuer and audience and returns its claims. Whether
+    /// it was revoked is not checked.
+    pub fn decode_token(&self, token: &str) -> Result<Claims, AuthError> {
+        let invalid = |reason: String| AuthError::Unauthorized(format!("invalid token: {}", reason));
+        let header = jsonwebtoken::decode_header(token).map_err(|e| invalid(e.to_string()))?;
+
+        // Several keys can be configured at once so they can be rotated; the `kid` picks one
+        let (algorithm, key) = match &header.kid {
+            Some(kid) => self.jwt_keys.get(kid),
+            None if self.jwt_keys.len() == 1 => self.jwt_keys.values().next(),
+            None => None,
+        }
+        .ok_or_else(|| invalid("unknown signing key".to_string()))?;
+
+        let mut validation = Validation::new(*algorithm);
+        if let Some(issuer) = &self.config.jwt_issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        match &self.config.jwt_audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+        Ok(jsonwebtoken::decode::<Claims>(token, key, &validation)
+            .map_err(|e| invalid(e.to_string()))?
+            .claims)
+    }
+
+    fn authenticate_jwt(&self, token: &str) -> Result<Principal, AuthError> {
+        let invalid = |reason: &str| AuthError::Unauthorized(format!("invalid token: {}", reason));
+        let claims = self.decode_token(token)?;
+        let tenant_id = claims.tenant.unwrap_or_else(default_tenant);
+        {
+            let store = self.store.lock().unwrap();
+            if claims.jti.as_ref().map_or(false, |jti| store.revoked_tokens.contains_key(jti)) {
+                return Err(invalid("token was revoked"));
+            }
+            // Tokens without an issue time cannot be told apart from ones issued before removal
+            if let Some(removed_at) = store.revoked_tenants.get(&tenant_id) {
+                if claims.iat.map_or(true, |iat| iat <= removed_at.timestamp()) {
+                    return Err(invalid("tenant was removed"));
+                }
+            }
+        }
+        Ok(Principal {
+            subject: claims.sub,
+            scopes: claims.scope.split_whitespace().filter_map(Scope::parse).collect(),
+            credential: CredentialKind::Jwt,
+            tenant_id,
+            group_scopes: HashMap::new(),
+            source_ip: None,
+        })
+    }
+
+    /// Issues a new API key for a tenant. The secret is only returned here.
+    pub fn create_key(
+        &self,
+        tenant_id: &str,
+        name: &str,
+        scopes: Vec<Scope>,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> crate::Result<IssuedApiKey> {
+        let id = random_hex(8);
+        let secret = format!("{}_{}_{}", API_KEY_PREFIX, id, random_hex(32));
+        let key = ApiKey {
+            id: id.clone(),
+            name: name.to_string(),
+            scopes,
+            tenant_id: tenant_id.to_string(),
+            created_at: Utc::now(),
+            expires_at,
+            revoked_at: None,
+            secret_hash: hash_secret(&secret),
+            previous_secret_hash: None,
+            previous_expires_at: None,
+        };
+
+        self.store.lock().unwrap().keys.insert(id, key.clone());
+        self.save()?;
+        Ok(IssuedApiKey {
+            key: key.redacted(),
+            secret,
+        })
+    }
+
+    /// Replaces the secret of a key. The old secret keeps working for the grace period so
+    /// clients can switch over without downtime.
+    /// The grace period is capped at [`MAX_ROTATION_GRACE_SECS`].
+    pub fn rotate_key(&self, id: &str, grace: Duration) -> crate::Result<Option<IssuedApiKey>> {
+        let grace = grace.clamp(Duration::zero(), Duration::seconds(MAX_ROTATION_GRACE_SECS as i64));
+        let previous_expires_at =
+            Utc::now().checked_add_signed(grace).ok_or("the grace period is out of range")?;
+        let secret = format!("{}_{}_{}", API_KEY_PREFIX, id, random_hex(32));
+        let rotated = {
+            let mut store = self.store.lock().unwrap();
+            match store.keys.get_mut(id) {
+                Some(key) if key.revoked_at.is_none() => {
+                    key.previous_secret_hash = Some(std::mem::replace(&mut key.secret_hash, hash_secret(&secret)));
+                    key.previous_expires_at = Some(previous_expires_at);
+                    Some(key.redacted())
+                }
+                _ => None,
+            }
+        };
+        self.save()?;
+        Ok(rotated.map(|key| IssuedApiKey { key, secret }))
+    }
+
+    /// Revokes an API key. Returns false if there is no such key.
+    pub fn revoke_key(&self, id: &str) -> crate::Result<bool> {
+        let revoked = {
+            let mut store = self.store.lock().unwrap();
+            match store.keys.get_mut(id) {
+                Some(key) => {
+                    key.revoked_at.get_or_insert_with(Utc::now);
+                    true
+                }
+                None => false,
+            }
+        };
+        self.save()?;
+        Ok(revoked)
+    }
+
+    /// Revokes a JWT by its `jti` claim until it expires. Without its expiry it stays revoked
+    /// for a fixed period instead.
+    pub fn revoke_token(&self, jti: &str, expires_at: Option<DateTime<Utc>>) -> crate::Result<()> {
+        let now = Utc::now();
+        let until = expires_at.unwrap_or_else(|| now + Duration::days(REVOKED_JTI_TTL_DAYS));
+        {
+            let mut store = self.store.lock().unwrap();
+            // Expired tokens are rejected anyway, so their revocations need not be kept
+            store.revoked_tokens.retain(|_, expires_at| *expires_at > now);
+            store.revoked_tokens.insert(jti.to_string(), until);
+        }
+        self.save()
+    }
+
+    /// Revokes every API key of a removed tenant and the JWTs issued for it so far.
+    /// Returns the number of keys revoked.
+    pub fn revoke_tenant(&self, tenant_id: &str) -> crate::Result<usize> {
+        let revoked = {
+            let mut store = self.store.lock().unwrap();
+            let now = Utc::now();
+            store.revoked_tenants.insert(tenant_id.to_string(), now);
+            let mut revoked = 0;
+            for key in store.keys.values_mut().filter(|key| key.tenant_id == tenant_id) {
+                if key.revoked_at.is_none() {
+                    key.revoked_at = Some(now);
+                    revoked += 1;
+                }
+            }
+            revoked
+        };
+        self.save()?;
+        Ok(revoked)
+    }
+
+    /// Retrieves an API key without its secret.
+    pub fn get_key(&self, id: &str) -> Option<ApiKey> {
+        self.store.lock().unwrap().keys.get(id).map(ApiKey::redacted)
+    }
+
+    /// Lists the API keys of a tenant, or of all tenants, without their secrets.
+    pub fn list_keys(&self, tenant_id: Option<&str>) -> Vec<ApiKey> {
+        let store = self.store.lock().unwrap();
+        let mut keys: Vec<ApiKey> = store
+            .keys
+            .values()
+            .filter(|key| match tenant_id {
+                Some(tenant_id) => key.tenant_id == tenant_id,
+                None => true,
+            })
+            .map(ApiKey::redacted)
+            .collect();
+        keys.sort_by(|a, b| a.created_at.cmp(&b.created_at));
+        keys
+    }
+
+    /// Writes the API keys and revoked tokens to the key store file.
+    fn save(&self) -> crate::Result<()> {
+        let path = match &self.store_path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let contents = serde_json::to_vec(&*self.store.lock().unwrap())?;
+
+        // Write to a temporary file first so a crash never leaves a truncated key store behind
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, contents)?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+}
+
+fn default_tenant() -> String {
+    DEFAULT_TENANT.to_string()
+}
+
+/// Splits an API key of the form `iotk_<id>_<secret>` into its id and secret.
+fn parse_api_key(api_key: &str) -> Option<(&str, &str)> {
+    let rest = api_key.strip_prefix(API_KEY_PREFIX)?.strip_prefix('_')?;
+    rest.split_once('_')
+}
+
+/// Returns the hex encoded SHA-256 of an API key, as stored and configured.
+pub fn hash_secret(secret: &str) -> String {
+    hex::encode(Sha256::digest(secret.as_bytes()))
+}
+
+/// Returns `bytes` random bytes, hex encoded.
+pub(crate) fn random_hex(bytes: usize) -> String {
+    let mut buf = vec![0u8; bytes];
+    rand::thread_rng().fill_bytes(&mut buf);
+    hex::encode(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ApiKeyConfig, JwtKeyConfig};
+    use jsonwebtoken::{EncodingKey, Header};
+
+    fn auth_service() -> AuthService {
+        AuthService::new(AuthConfig {
+            api_keys: vec![ApiKeyConfig {
+                id: "ops".to_string(),
+                secret_sha256: hash_secret("iotk_ops_s3cret"),
+                scopes: vec![Scope::ReadTelemetry],
+                tenant_id: DEFAULT_TENANT.to_string(),
+                expires_at: None,
+            }],
+            jwt_keys: vec![JwtKeyConfig {
+                kid: "2024-01".to_string(),
+                algorithm: JwtAlgorithm::Hs256,
+                secret: Some("signing-secret".to_string()),
+                public_key_path: None,
+            }],
+            ..AuthConfig::default()
+        })
+        .unwrap()
+    }
+
+    fn token(kid: &str, scope: &str, jti: &str) -> String {
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = Some(kid.to_string());
+        let claims = Claims {
+            sub: "dashboard".to_string(),
+            exp: (Utc::now() + Duration::hours(1)).timestamp(),
+            scope: scope.to_string(),
+            jti: Some(jti.to_string()),
+            tenant: Some("acme".to_string()),
+            iat: Some(Utc::now().timestamp()),
+        };
+        jsonwebtoken::encode(&header, &claims, &EncodingKey::from_secret(b"signing-secret")).unwrap()
+    }
+
+    #[test]
+    fn test_api_keys_scopes_rotation_and_revocation() {
+        let auth = auth_service();
+
+        let principal = auth.authorize(None, Some("iotk_ops_s3cret"), Scope::ReadTelemetry).unwrap();
+        assert_eq!(principal.subject, "ops");
+        assert!(matches!(
+            auth.authorize(None, Some("iotk_ops_s3cret"), Scope::ManageDevices),
+            Err(AuthError::Forbidden(_))
+        ));
+        assert!(matches!(auth.authenticate(None, Some("iotk_ops_wrong")), Err(AuthError::Unauthorized(_))));
+        assert!(matches!(auth.authenticate(None, None), Err(AuthError::Unauthorized(_))));
+
+        let issued = auth.create_key(DEFAULT_TENANT, "provisioning", vec![Scope::ManageDevices], None).unwrap();
+        assert!(auth.authorize(None, Some(&issued.secret), Scope::ManageDevices).is_ok());
+
+        // The old secret keeps working during the grace period only
+        let rotated = auth.rotate_key(&issued.key.id, Duration::minutes(5)).unwrap().unwrap();
+        assert!(auth.authenticate(None, Some(&issued.secret)).is_ok());
+        assert!(auth.authenticate(None, Some(&rotated.secret)).is_ok());
+        let expired = auth.rotate_key(&issued.key.id, Duration::zero()).unwrap().unwrap();
+        assert!(auth.authenticate(None, Some(&rotated.secret)).is_err());
+
+        assert!(auth.revoke_key(&issued.key.id).unwrap());
+        assert!(auth.authenticate(None, Some(&expired.secret)).is_err());
+    }
+
+    #[test]
+    fn test_jwt_bearer_tokens() {
+        let auth = auth_service();
+
+        let bearer = format!("Bearer {}", token("2024-01", "read_telemetry manage_devices", "t1"));
+        let principal = auth.authorize(Some(&bearer), None, Scope::ManageDevices).unwrap();
+        assert_eq!(principal.subject, "dashboard");
+        assert_eq!(principal.credential, CredentialKind::Jwt);
+        assert_eq!(principal.tenant_id, "acme");
+        assert!(auth.authorize(Some(&bearer), None, Scope::Admin).is_err());
+
+        let unknown_key = format!("Bearer {}", token("2023-12", "admin", "t2"));
+        assert!(auth.authenticate(Some(&unknown_key), None).is_err());
+
+        auth.revoke_token("expired", Some(Utc::now() - Duration::minutes(1))).unwrap();
+        auth.revoke_token("t1", None).unwrap();
+        assert!(auth.authenticate(Some(&bearer), None).is_err());
+        let store = auth.store.lock().unwrap();
+        assert_eq!(store.revoked_tokens.keys().collect::<Vec<_>>(), vec!["t1"]);
+    }
+
+    #[test]
+    fn test_rotation_grace_is_capped() {
+        let auth = auth_service();
+        let issued = auth.create_key(DEFAULT_TENANT, "gateway", vec![Scope::ReadTelemetry], None).unwrap();
+        let rotated = auth.rotate_key(&issued.key.id, Duration::max_value()).unwrap().unwrap();
+        let cap = Utc::now() + Duration::seconds(MAX_ROTATION_GRACE_SECS as i64);
+        assert!(rotated.key.previous_expires_at.unwrap() <= cap);
+    }
+
+    #[test]
+    fn test_removed_tenants_lose_their_keys_and_tokens() {
+        let auth = auth_service();
+        let issued = auth.create_key("acme", "gateway", vec![Scope::ReadTelemetry], None).unwrap();
+        let bearer = format!("Bearer {}", token("2024-01", "read_telemetry", "t3"));
+        assert!(auth.authenticate(Some(&bearer), None).is_ok());
+
+        assert_eq!(auth.revoke_tenant("acme").unwrap(), 1);
+        assert!(auth.authenticate(None, Some(&issued.secret)).is_err());
+        assert!(auth.authenticate(Some(&bearer), None).is_err());
+        assert!(auth.authenticate(None, Some("iotk_ops_s3cret")).is_ok());
+    }
+}
@@ -0,0 +1,212 @@
+// dedup.rs
+
+use crate::config::DedupConfig;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+
+/// What identifies a message as a retransmission of one already received.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum MessageKey {
+    /// A message id supplied by the device.
+    Id(String),
+    /// The device timestamp and a hash of the readings, for devices without message ids.
+    Fingerprint { timestamp: u64, payload_hash: u64 },
+}
+
+impl MessageKey {
+    /// Returns the device's message id, or the device timestamp and payload when there is
+    /// no id. Messages with neither cannot be recognised as duplicates.
+    pub fn of(message_id: Option<&str>, data: &HashMap<String, f64>) -> Option<Self> {
+        match (message_id, data.get("timestamp")) {
+            (Some(id), _) => Some(MessageKey::Id(id.to_string())),
+            (None, Some(timestamp)) => Some(MessageKey::Fingerprint {
+                timestamp: timestamp.to_bits(),
+                payload_hash: payload_hash(data),
+            }),
+            (None, None) => None,
+        }
+    }
+}
+
+/// Counters describing how many messages were recognised as duplicates.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DedupMetrics {
+    pub checked: u64,
+    pub duplicates: u64,
+    pub duplicates_per_device: HashMap<String, u64>,
+    /// Number of messages currently remembered.
+    pub tracked: usize,
+}
+
+struct DedupState {
+    seen: HashSet<(String, MessageKey)>,
+    /// Remembered messages in arrival order, used to forget them once the window passes.
+    order: VecDeque<(DateTime<Utc>, String, MessageKey)>,
+    metrics: DedupMetrics,
+}
+
+/// Recognises messages that devices sent more than once, e.g. retransmitted UDP
+/// datagrams or QoS-1 MQTT redeliveries, within a configurable window.
+pub struct Deduplicator {
+    config: DedupConfig,
+    state: Arc<Mutex<DedupState>>,
+}
+
+impl Deduplicator {
+    /// Creates a new Deduplicator.
+    pub fn new(config: DedupConfig) -> Self {
+        Deduplicator {
+            config,
+            state: Arc::new(Mutex::new(DedupState {
+                seen: HashSet::new(),
+                order: VecDeque::new(),
+                metrics: DedupMetrics::default(),
+            })),
+        }
+    }
+
+    /// Returns true if the message was already received within the window.
+    /// Messages are matched by the device's message id, or by the device timestamp and
+    /// payload when there is no id. Messages with neither are never treated as duplicates.
+    pub fn is_duplicate(&self, device_id: &str, message_id: Option<&str>, data: &HashMap<String, f64>) -> bool {
+        self.check_at(device_id, message_id, data, Utc::now())
+    }
+
+    fn check_at(&self, device_id: &str, message_id: Option<&str>, data: &HashMap<String, f64>, now: DateTime<Utc>) -> bool {
+        if !self.config.enabled {
+            return false;
+        }
+        let key = match MessageKey::of(message_id, data) {
+            Some(key) => key,
+            None => return false,
+        };
+
+        let mut state = self.state.lock().unwrap();
+        self.expire(&mut state, now);
+        state.metrics.checked += 1;
+
+        let entry = (device_id.to_string(), key);
+        if state.seen.contains(&entry) {
+            state.metrics.duplicates += 1;
+            *state.metrics.duplicates_per_device.entry(device_id.to_string()).or_insert(0) += 1;
+            return true;
+        }
+
+        state.order.push_back((now, entry.0.clone(), entry.1.clone()));
+        state.seen.insert(entry);
+        while state.order.len() > self.config.max_entries {
+            if let Some((_, device_id, key)) = state.order.pop_front() {
+                state.seen.remove(&(device_id, key));
+            }
+        }
+        false
+    }
+
+    /// Forgets a message that was recorded but could not be handled, so a retransmission
+    /// of it is accepted instead of being dropped as a duplicate.
+    pub fn forget(&self, device_id: &str, key: &MessageKey) {
+        let mut state = self.state.lock().unwrap();
+        let entry = (device_id.to_string(), key.clone());
+        if state.seen.remove(&entry) {
+            state.order.retain(|(_, seen_device, seen_key)| seen_device != device_id || seen_key != key);
+        }
+    }
+
+    /// Forgets messages that were received before the window.
+    fn expire(&self, state: &mut DedupState, now: DateTime<Utc>) {
+        let cutoff = now - Duration::seconds(self.config.window_secs as i64);
+        while let Some((received_at, _, _)) = state.order.front() {
+            if *received_at > cutoff {
+                break;
+            }
+            let (_, device_id, key) = state.order.pop_front().unwrap();
+            state.seen.remove(&(device_id, key));
+        }
+    }
+
+    /// Returns a snapshot of the duplicate counters.
+    pub fn get_metrics(&self) -> DedupMetrics {
+        let state = self.state.lock().unwrap();
+        DedupMetrics {
+            tracked: state.seen.len(),
+            ..state.metrics.clone()
+        }
+    }
+}
+
+/// Hashes the readings independently of their order in the message.
+fn payload_hash(data: &HashMap<String, f64>) -> u64 {
+    let mut entries: Vec<_> = data.iter().collect();
+    entries.sort_by(|a, b| a.0.cmp(b.0));
+
+    let mut hasher = DefaultHasher::new();
+    for (metric, value) in entries {
+        metric.hash(&mut hasher);
+        value.to_bits().hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reading(timestamp: f64, temperature: f64) -> HashMap<String, f64> {
+        HashMap::from([("timestamp".to_string(), timestamp), ("temperature".to_string(), temperature)])
+    }
+
+    #[test]
+    fn test_duplicates_by_message_id_and_fingerprint() {
+        let dedup = Deduplicator::new(DedupConfig::default());
+        let data = reading(1_700_000_000.0, 21.5);
+
+        assert!(!dedup.is_duplicate("d1", Some("42"), &data));
+        assert!(dedup.is_duplicate("d1", Some("42"), &data));
+        // Message ids are scoped to the device
+        assert!(!dedup.is_duplicate("d2", Some("42"), &data));
+
+        assert!(!dedup.is_duplicate("d1", None, &data));
+        assert!(dedup.is_duplicate("d1", None, &data));
+        assert!(!dedup.is_duplicate("d1", None, &reading(1_700_000_000.0, 22.0)));
+
+        // Without an id or timestamp identical readings are legitimate repeats
+        let untimed = HashMap::from([("temperature".to_string(), 21.5)]);
+        assert!(!dedup.is_duplicate("d1", None, &untimed));
+        assert!(!dedup.is_duplicate("d1", None, &untimed));
+
+        let metrics = dedup.get_metrics();
+        assert_eq!(metrics.duplicates, 2);
+        assert_eq!(metrics.duplicates_per_device["d1"], 2);
+    }
+
+    #[test]
+    fn test_messages_are_forgotten_after_the_window() {
+        let dedup = Deduplicator::new(DedupConfig {
+            enabled: true,
+            window_secs: 60,
+            max_entries: 100,
+        });
+        let data = reading(1_700_000_000.0, 21.5);
+        let now = Utc::now();
+
+        assert!(!dedup.check_at("d1", Some("1"), &data, now));
+        assert!(dedup.check_at("d1", Some("1"), &data, now + Duration::seconds(30)));
+        assert!(!dedup.check_at("d1", Some("1"), &data, now + Duration::seconds(61)));
+    }
+
+    #[test]
+    fn test_forgotten_messages_are_accepted_again() {
+        let dedup = Deduplicator::new(DedupConfig::default());
+        let data = reading(1_700_000_000.0, 21.5);
+
+        assert!(!dedup.is_duplicate("d1", None, &data));
+        dedup.forget("d1", &MessageKey::of(None, &data).unwrap());
+        assert!(!dedup.is_duplicate("d1", None, &data));
+        assert!(dedup.is_duplicate("d1", None, &data));
+        assert_eq!(dedup.get_metrics().tracked, 1);
+    }
+}
@@ -1,32 +1,252 @@
 // device.rs
 
+use crate::listing::{paginate, time_key, Sort};
+use crate::monitoring::{DeviceActivity, DeviceStatus};
+use crate::tenant::split_device_id;
+use chrono::{DateTime, TimeZone, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 
 /// Represents a single IoT device with its associated data.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
 pub struct Device {
     pub id: String,
     pub name: String,
+    pub device_type: Option<String>,
+    #[serde(default)]
     pub data: HashMap<String, f64>, // Assuming devices send data as key-value pairs
+    #[serde(default)]
+    pub firmware_version: Option<String>,
+    #[serde(default)]
+    pub location: Option<Location>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub owner: Option<String>,
+    /// Free-form attributes supplied when the device was provisioned.
+    #[serde(default)]
+    pub attributes: HashMap<String, serde_json::Value>,
 }
 
+/// Where a device is installed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Location {
+    pub latitude: f64,
+    pub longitude: f64,
+    /// Human readable place, e.g. a building or room.
+    #[serde(default)]
+    pub description: Option<String>,
+}
+
+/// Changes to a device's metadata; fields left out are not changed.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct DeviceUpdate {
+    pub name: Option<String>,
+    pub device_type: Option<String>,
+    pub firmware_version: Option<String>,
+    pub location: Option<Location>,
+    pub tags: Option<Vec<String>>,
+    pub owner: Option<String>,
+    /// Attributes to set; a null value removes the attribute.
+    pub attributes: Option<HashMap<String, serde_json::Value>>,
+}
+
+/// Filters, order and paging for listing devices.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct DeviceQuery {
+    pub device_type: Option<String>,
+    pub owner: Option<String>,
+    pub tag: Option<String>,
+    pub firmware_version: Option<String>,
+    /// Case-insensitive substring of the device id or name.
+    pub search: Option<String>,
+    /// Connectivity state; devices that never reported are `unknown`.
+    pub status: Option<DeviceStatus>,
+    /// Only devices last seen at or after this time.
+    pub last_seen_after: Option<DateTime<Utc>>,
+    /// Only devices last seen before this time, or never.
+    pub last_seen_before: Option<DateTime<Utc>>,
+    /// One of `id`, `name`, `device_type` or `last_seen`, prefixed with `-` for descending.
+    pub sort: Option<String>,
+    /// The `next_cursor` of the previous page. Takes precedence over `offset`.
+    pub cursor: Option<String>,
+    #[serde(default)]
+    pub offset: usize,
+    pub limit: Option<usize>,
+    /// Comma separated fields to return, e.g. `id,name,tags`; all fields when missing.
+    pub fields: Option<String>,
+    /// Only devices of this tenant; set by the API from the caller's credentials.
+    #[serde(skip)]
+    pub tenant_id: Option<String>,
+    /// Only devices carrying one of these tags, for callers granted access to device groups only.
+    #[serde(skip)]
+    pub groups: Option<Vec<String>>,
+}
+
+/// One page of devices matching a query.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DevicePage {
+    pub devices: Vec<Device>,
+    /// Number of devices matching the filters across all pages.
+    pub total: usize,
+    /// Cursor of the next page; missing on the last page.
+    pub next_cursor: Option<String>,
+}
+
+/// Fields devices can be sorted by.
+const SORT_FIELDS: [&str; 4] = ["id", "name", "device_type", "last_seen"];
+
 impl Device {
     /// Creates a new IoT device with a unique identifier and name.
     pub fn new(id: String, name: String) -> Self {
         Device {
             id,
             name,
+            device_type: None,
             data: HashMap::new(),
+            firmware_version: None,
+            location: None,
+            tags: Vec::new(),
+            owner: None,
+            attributes: HashMap::new(),
         }
     }
 
+    /// Sets the device type used to look up type-wide settings such as reporting intervals.
+    pub fn with_type(mut self, device_type: String) -> Self {
+        self.device_type = Some(device_type);
+        self
+    }
+
     /// Updates the device's data with new key-value pairs.
     pub fn update_data(&mut self, new_data: HashMap<String, f64>) {
         for (key, value) in new_data.into_iter() {
             self.data.insert(key, value);
         }
     }
+
+    /// Applies a metadata update to the device.
+    pub fn apply_update(&mut self, update: DeviceUpdate) {
+        if let Some(name) = update.name {
+            self.name = name;
+        }
+        if let Some(device_type) = update.device_type {
+            self.device_type = Some(device_type);
+        }
+        if let Some(firmware_version) = update.firmware_version {
+            self.firmware_version = Some(firmware_version);
+        }
+        if let Some(location) = update.location {
+            self.location = Some(location);
+        }
+        if let Some(tags) = update.tags {
+            self.tags = tags;
+        }
+        if let Some(owner) = update.owner {
+            self.owner = Some(owner);
+        }
+        for (key, value) in update.attributes.unwrap_or_default() {
+            if value.is_null() {
+                self.attributes.remove(&key);
+            } else {
+                self.attributes.insert(key, value);
+            }
+        }
+    }
+
+    /// Returns true if the device passes the query's filters.
+    pub fn matches(&self, query: &DeviceQuery) -> bool {
+        let (tenant_id, device_id) = split_device_id(&self.id);
+        if query.tenant_id.as_ref().is_some_and(|expected| expected != tenant_id) {
+            return false;
+        }
+        if query.groups.as_ref().is_some_and(|groups| !self.tags.iter().any(|tag| groups.contains(tag))) {
+            return false;
+        }
+        if let Some(search) = &query.search {
+            let search = search.to_lowercase();
+            if !device_id.to_lowercase().contains(&search) && !self.name.to_lowercase().contains(&search) {
+                return false;
+            }
+        }
+        if let Some(tag) = &query.tag {
+            if !self.tags.contains(tag) {
+                return false;
+            }
+        }
+        filter_matches(&query.device_type, &self.device_type)
+            && filter_matches(&query.owner, &self.owner)
+            && filter_matches(&query.firmware_version, &self.firmware_version)
+    }
+}
+
+/// A single set of readings received from a device, as passed between services.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Reading {
+    pub device_id: String,
+    pub data: HashMap<String, f64>,
+    pub received_at: DateTime<Utc>,
+    /// When the device took the reading; equal to `received_at` unless the device supplied it.
+    pub event_time: DateTime<Utc>,
+}
+
+impl Reading {
+    /// Creates a reading stamped with the current time.
+    pub fn new(device_id: String, data: HashMap<String, f64>) -> Self {
+        let now = Utc::now();
+        Reading {
+            device_id,
+            data,
+            received_at: now,
+            event_time: now,
+        }
+    }
+
+    /// Creates a reading from device telemetry. A numeric `timestamp` entry holding Unix
+    /// seconds is taken as the event time and removed from the readings.
+    pub fn from_device(device_id: String, mut data: HashMap<String, f64>) -> Self {
+        let event_time = data.remove("timestamp").and_then(|timestamp| {
+            let secs = timestamp.floor();
+            let nanos = ((timestamp - secs) * 1e9) as u32;
+            Utc.timestamp_opt(secs as i64, nanos).single()
+        });
+        let reading = Reading::new(device_id, data);
+        match event_time {
+            Some(event_time) => reading.with_event_time(event_time),
+            None => reading,
+        }
+    }
+
+    /// Sets the time at which the device took the reading.
+    pub fn with_event_time(mut self, event_time: DateTime<Utc>) -> Self {
+        self.event_time = event_time;
+        self
+    }
+}
+
+impl DeviceQuery {
+    /// Returns true if the device's activity passes the status and last seen filters.
+    fn matches_activity(&self, activity: &DeviceActivity) -> bool {
+        if self.status.is_some_and(|status| status != activity.status) {
+            return false;
+        }
+        if let Some(after) = self.last_seen_after {
+            if !activity.last_seen.is_some_and(|last_seen| last_seen >= after) {
+                return false;
+            }
+        }
+        !self.last_seen_before.is_some_and(|before| activity.last_seen.is_some_and(|last_seen| last_seen >= before))
+    }
+}
+
+/// Returns true if there is no filter or the value equals it.
+fn filter_matches(filter: &Option<String>, value: &Option<String>) -> bool {
+    match filter {
+        Some(filter) => value.as_ref() == Some(filter),
+        None => true,
+    }
 }
 
 /// Manages a collection of IoT devices.
@@ -43,15 +263,34 @@ impl DeviceManager {
     }
 
     /// Adds a new device to the manager.
-    pub fn add_device(&mut self, device: Device) {
+    pub fn add_device(&self, device: Device) {
+        let mut devices = self.devices.lock().unwrap();
+        devices.insert(device.id.clone(), device);
+    }
+
+    /// Adds a device unless one with the same id is already registered.
+    /// Returns false if the id is taken.
+    pub fn create_device(&self, device: Device) -> bool {
         let mut devices = self.devices.lock().unwrap();
+        if devices.contains_key(&device.id) {
+            return false;
+        }
         devices.insert(device.id.clone(), device);
+        true
+    }
+
+    /// Removes a device from the manager by its unique identifier, returning it if it existed.
+    pub fn remove_device(&self, device_id: &str) -> Option<Device> {
+        let mut devices = self.devices.lock().unwrap();
+        devices.remove(device_id)
     }
 
-    /// Removes a device from the manager by its unique identifier.
-    pub fn remove_device(&mut self, device_id: &str) {
+    /// Updates the metadata of a device, returning the updated device if it exists.
+    pub fn update_device(&self, device_id: &str, update: DeviceUpdate) -> Option<Device> {
         let mut devices = self.devices.lock().unwrap();
-        devices.remove(device_id);
+        let device = devices.get_mut(device_id)?;
+        device.apply_update(update);
+        Some(device.clone())
     }
 
     /// Retrieves a device by its unique identifier.
@@ -61,18 +300,72 @@ impl DeviceManager {
     }
 
     /// Updates the data for a specific device.
-    pub fn update_device_data(&mut self, device_id: &str, new_data: HashMap<String, f64>) {
+    pub fn update_device_data(&self, device_id: &str, new_data: HashMap<String, f64>) {
         let mut devices = self.devices.lock().unwrap();
         if let Some(device) = devices.get_mut(device_id) {
             device.update_data(new_data);
         }
     }
 
+    /// Adds a device with the given id unless it is already registered.
+    pub fn ensure_device(&self, device_id: &str) {
+        let mut devices = self.devices.lock().unwrap();
+        devices
+            .entry(device_id.to_string())
+            .or_insert_with(|| Device::new(device_id.to_string(), "Unnamed Device".to_string()));
+    }
+
+    /// Returns the number of devices registered by a tenant.
+    pub fn count_devices(&self, tenant_id: &str) -> usize {
+        let devices = self.devices.lock().unwrap();
+        devices.keys().filter(|id| split_device_id(id).0 == tenant_id).count()
+    }
+
     /// Returns a list of all devices.
     pub fn list_devices(&self) -> Vec<Device> {
         let devices = self.devices.lock().unwrap();
         devices.values().cloned().collect()
     }
+
+    /// Returns a page of the devices matching the query, in the query's order or by id.
+    /// `activity` is what monitoring knows of the devices, keyed by platform-wide id.
+    /// Fails on an unknown sort field or an invalid cursor.
+    pub fn query_devices(
+        &self,
+        query: &DeviceQuery,
+        activity: &HashMap<String, DeviceActivity>,
+    ) -> Result<DevicePage, String> {
+        let sort = Sort::parse(query.sort.as_deref(), &SORT_FIELDS, "id")?;
+        let never_seen = DeviceActivity {
+            status: DeviceStatus::Unknown,
+            last_seen: None,
+        };
+        let activity_of = |device: &Device| activity.get(&device.id).copied().unwrap_or(never_seen);
+        let matching: Vec<Device> = {
+            let devices = self.devices.lock().unwrap();
+            devices
+                .values()
+                .filter(|device| device.matches(query) && query.matches_activity(&activity_of(device)))
+                .cloned()
+                .collect()
+        };
+
+        let key = |device: &Device| {
+            let value = match sort.field.as_str() {
+                "name" => Some(device.name.clone()),
+                "device_type" => device.device_type.clone(),
+                "last_seen" => time_key(activity_of(device).last_seen),
+                _ => None,
+            };
+            (value, device.id.clone())
+        };
+        let page = paginate(matching, &sort, key, query.cursor.as_deref(), query.offset, query.limit)?;
+        Ok(DevicePage {
+            devices: page.items,
+            total: page.total,
+            next_cursor: page.next_cursor,
+        })
+    }
 }
 
 #[cfg(test)]
@@ -81,7 +374,7 @@ mod tests {
 
     #[test]
     fn test_add_and_get_device() {
-        let mut manager = DeviceManager::new();
+        let manager = DeviceManager::new();
         let device = Device::new("device1".to_string(), "Temperature Sensor".to_string());
         manager.add_device(device.clone());
 
@@ -91,7 +384,7 @@ mod tests {
 
     #[test]
     fn test_remove_device() {
-        let mut manager = DeviceManager::new();
+        let manager = DeviceManager::new();
         let device = Device::new("device1".to_string(), "Temperature Sensor".to_string());
         manager.add_device(device);
 
@@ -102,7 +395,7 @@ mod tests {
 
     #[test]
     fn test_update_device_data() {
-        let mut manager = DeviceManager::new();
+        let manager = DeviceManager::new();
         let mut device = Device::new("device1".to_string(), "Temperature Sensor".to_string());
         device.data.insert("temperature".to_string(), 25.0);
         manager.add_device(device);
@@ -116,7 +409,7 @@ mod tests {
 
     #[test]
     fn test_list_devices() {
-        let mut manager = DeviceManager::new();
+        let manager = DeviceManager::new();
         let device1 = Device::new("device1".to_string(), "Temperature Sensor 1".to_string());
         let device2 = Device::new("device2".to_string(), "Temperature Sensor 2".to_string());
         manager.add_device(device1);
@@ -125,4 +418,116 @@ mod tests {
         let devices = manager.list_devices();
         assert_eq!(devices.len(), 2);
     }
+
+    #[test]
+    fn test_reading_event_time_from_device_timestamp() {
+        let data = HashMap::from([("temperature".to_string(), 21.0), ("timestamp".to_string(), 1_700_000_000.5)]);
+        let reading = Reading::from_device("device1".to_string(), data);
+
+        assert_eq!(reading.event_time.timestamp(), 1_700_000_000);
+        assert_eq!(reading.event_time.timestamp_subsec_millis(), 500);
+        assert!(!reading.data.contains_key("timestamp"));
+        assert!(reading.received_at > reading.event_time);
+    }
+
+    #[test]
+    fn test_update_and_query_devices() {
+        let manager = DeviceManager::new();
+        for i in 0..5 {
+            let mut device = Device::new(format!("sensor{}", i), format!("Sensor {}", i)).with_type("thermometer".to_string());
+            device.tags = vec![if i % 2 == 0 { "indoor" } else { "outdoor" }.to_string()];
+            assert!(manager.create_device(device));
+        }
+        assert!(!manager.create_device(Device::new("sensor0".to_string(), "Duplicate".to_string())));
+
+        let update = DeviceUpdate {
+            firmware_version: Some("2.1.0".to_string()),
+            owner: Some("acme".to_string()),
+            attributes: Some(HashMap::from([("floor".to_string(), serde_json::json!(3))])),
+            ..DeviceUpdate::default()
+        };
+        let updated = manager.update_device("sensor1", update).unwrap();
+        assert_eq!(updated.firmware_version.as_deref(), Some("2.1.0"));
+        assert_eq!(updated.attributes["floor"], 3);
+        assert!(manager.update_device("missing", DeviceUpdate::default()).is_none());
+
+        let indoor = manager
+            .query_devices(
+                &DeviceQuery {
+                    tag: Some("indoor".to_string()),
+                    offset: 1,
+                    limit: Some(1),
+                    ..DeviceQuery::default()
+                },
+                &HashMap::new(),
+            )
+            .unwrap();
+        assert_eq!(indoor.total, 3);
+        assert_eq!(indoor.devices.len(), 1);
+        assert_eq!(indoor.devices[0].id, "sensor2");
+
+        let owned = manager
+            .query_devices(
+                &DeviceQuery {
+                    owner: Some("acme".to_string()),
+                    ..DeviceQuery::default()
+                },
+                &HashMap::new(),
+            )
+            .unwrap();
+        assert_eq!(owned.devices[0].id, "sensor1");
+    }
+
+    #[test]
+    fn test_query_devices_by_activity() {
+        let manager = DeviceManager::new();
+        for id in ["d1", "d2", "d3"] {
+            manager.add_device(Device::new(id.to_string(), id.to_uppercase()));
+        }
+        let at = |secs| Some(Utc.timestamp_opt(secs, 0).unwrap());
+        let activity = HashMap::from([
+            ("d1".to_string(), DeviceActivity { status: DeviceStatus::Online, last_seen: at(2_000) }),
+            ("d2".to_string(), DeviceActivity { status: DeviceStatus::Offline, last_seen: at(1_000) }),
+        ]);
+
+        let online = DeviceQuery {
+            status: Some(DeviceStatus::Online),
+            ..DeviceQuery::default()
+        };
+        let page = manager.query_devices(&online, &activity).unwrap();
+        assert_eq!(page.devices.len(), 1);
+        assert_eq!(page.devices[0].id, "d1");
+
+        // Devices never seen count as seen before any time
+        let stale = DeviceQuery {
+            last_seen_before: at(1_500),
+            sort: Some("-last_seen".to_string()),
+            ..DeviceQuery::default()
+        };
+        let page = manager.query_devices(&stale, &activity).unwrap();
+        let ids: Vec<&str> = page.devices.iter().map(|device| device.id.as_str()).collect();
+        assert_eq!(ids, ["d2", "d3"]);
+
+        let first = DeviceQuery {
+            sort: Some("last_seen".to_string()),
+            limit: Some(2),
+            ..DeviceQuery::default()
+        };
+        // Never seen sorts before any time
+        let page = manager.query_devices(&first, &activity).unwrap();
+        assert_eq!(page.devices[0].id, "d3");
+        let next = DeviceQuery {
+            cursor: page.next_cursor,
+            ..first
+        };
+        let page = manager.query_devices(&next, &activity).unwrap();
+        assert_eq!(page.devices[0].id, "d1");
+        assert!(page.next_cursor.is_none());
+
+        let bad_sort = DeviceQuery {
+            sort: Some("owner".to_string()),
+            ..DeviceQuery::default()
+        };
+        assert!(manager.query_devices(&bad_sort, &activity).is_err());
+    }
 }
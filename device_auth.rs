@@ -0,0 +1,470 @@
+// device_auth.rs
+
+use crate::auth::{hash_secret, random_hex};
+use crate::config::DeviceAuthConfig;
+use crate::notification::sign_payload;
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use hmac::{Hmac, Mac};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+/// Prefix of device tokens, which tell them apart from signing secrets in device configs.
+const DEVICE_TOKEN_PREFIX: &str = "iotd";
+
+/// Longest nonce accepted in signed datagrams.
+const MAX_NONCE_LENGTH: usize = 64;
+
+/// Most source addresses failed attempts are kept for; the oldest are forgotten first.
+const MAX_TRACKED_SOURCES: usize = 10_000;
+
+/// A datagram wrapping a device's payload with the credentials it is authenticated by. Signed
+/// datagrams carry `timestamp`, `nonce` and `signature`, the HMAC-SHA256 computed by
+/// `sign_datagram`. Devices connected over DTLS may send their `token` instead.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceEnvelope {
+    /// The device's id within its tenant.
+    pub device_id: String,
+    /// The telemetry or heartbeat message, as the device would send it unauthenticated.
+    pub payload: String,
+    /// Unix time the datagram was signed at, in seconds.
+    #[serde(default)]
+    pub timestamp: Option<i64>,
+    /// Value the device never repeats within the allowed clock skew, of at most 64 letters,
+    /// digits, `-` and `_`.
+    #[serde(default)]
+    pub nonce: Option<String>,
+    #[serde(default)]
+    pub signature: Option<String>,
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+impl DeviceEnvelope {
+    /// Parses a datagram as an envelope, returning None for plain device messages.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        serde_json::from_slice(data).ok()
+    }
+}
+
+/// Returns the hex encoded signature of a datagram, computed over the device id, timestamp,
+/// nonce and payload joined by dots. The nonce holds no dots, so the joined fields cannot be
+/// split any other way.
+pub fn sign_datagram(secret: &str, device_id: &str, timestamp: i64, nonce: &str, payload: &str) -> String {
+    sign_payload(secret, signing_input(device_id, timestamp, nonce, payload).as_bytes())
+}
+
+fn signing_input(device_id: &str, timestamp: i64, nonce: &str, payload: &str) -> String {
+    format!("{}.{}.{}.{}", device_id, timestamp, nonce, payload)
+}
+
+/// Why a datagram was not accepted.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthFailure {
+    /// The datagram was neither signed nor carried a token.
+    Unauthenticated,
+    /// No credentials were issued for the device.
+    UnknownDevice,
+    /// A signed datagram lacked its timestamp or nonce, or the nonce was not allowed.
+    Malformed,
+    BadSignature,
+    BadToken,
+    /// The timestamp is further from the service's clock than the allowed skew.
+    StaleTimestamp,
+    /// The nonce was already used, i.e. the datagram was recorded and sent again.
+    ReplayedNonce,
+    /// A DTLS handshake failed, e.g. because the client certificate was not accepted.
+    HandshakeFailed,
+    /// A token was sent in the clear, where anyone on the path could read and reuse it.
+    UnencryptedToken,
+}
+
+impl fmt::Display for AuthFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match self {
+            AuthFailure::Unauthenticated => "datagram is not authenticated",
+            AuthFailure::UnknownDevice => "device has no credentials",
+            AuthFailure::Malformed => "signed datagram lacks its timestamp or has no valid nonce",
+            AuthFailure::BadSignature => "signature does not match",
+            AuthFailure::BadToken => "device token does not match",
+            AuthFailure::StaleTimestamp => "timestamp is outside the allowed clock skew",
+            AuthFailure::ReplayedNonce => "nonce was already used",
+            AuthFailure::HandshakeFailed => "DTLS handshake failed",
+            AuthFailure::UnencryptedToken => "device tokens are only accepted over DTLS",
+        };
+        write!(f, "{}", reason)
+    }
+}
+
+/// The credentials of a device. The secret and token hash are only kept in the store.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceCredential {
+    /// The platform-wide device id.
+    pub device_id: String,
+    pub created_at: DateTime<Utc>,
+    /// Key datagrams are signed with. HMAC needs it in the clear, so it is stored as issued.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    secret: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    token_hash: String,
+}
+
+impl DeviceCredential {
+    /// Returns the credential without its secret and token hash.
+    pub fn redacted(&self) -> Self {
+        DeviceCredential {
+            secret: String::new(),
+            token_hash: String::new(),
+            ..self.clone()
+        }
+    }
+}
+
+/// Newly issued device credentials. The secret and token are only shown once.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct IssuedDeviceCredential {
+    pub device_id: String,
+    pub created_at: DateTime<Utc>,
+    pub secret: String,
+    pub token: String,
+}
+
+/// Failed authentication attempts from a single source address.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct SourceFailures {
+    pub source: IpAddr,
+    pub count: u64,
+    pub first_at: DateTime<Utc>,
+    pub last_at: DateTime<Utc>,
+    pub last_reason: AuthFailure,
+    /// True once the count reached the configured failure threshold.
+    pub suspicious: bool,
+}
+
+/// Failed attempts by source, with the sources in the order they first failed.
+#[derive(Default)]
+struct FailureLog {
+    by_source: HashMap<IpAddr, SourceFailures>,
+    order: VecDeque<IpAddr>,
+}
+
+/// Device credentials persisted across restarts.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CredentialStore {
+    credentials: HashMap<String, DeviceCredential>,
+}
+
+/// Issues device credentials and checks the datagrams devices send against them.
+pub struct DeviceAuth {
+    config: DeviceAuthConfig,
+    store: Arc<Mutex<CredentialStore>>,
+    /// Nonces of accepted datagrams by device, with the time after which their timestamp
+    /// would be rejected anyway and they can be forgotten.
+    nonces: Arc<Mutex<HashMap<(String, String), DateTime<Utc>>>>,
+    failures: Arc<Mutex<FailureLog>>,
+}
+
+impl DeviceAuth {
+    /// Creates the service, loading the persisted device credentials.
+    pub fn new(config: DeviceAuthConfig) -> crate::Result<Self> {
+        let store: CredentialStore = match &config.credentials_path {
+            Some(path) if path.exists() => serde_json::from_slice(&std::fs::read(path)?)?,
+            _ => CredentialStore::default(),
+        };
+        Ok(DeviceAuth {
+            config,
+            store: Arc::new(Mutex::new(store)),
+            nonces: Arc::new(Mutex::new(HashMap::new())),
+            failures: Arc::new(Mutex::new(FailureLog::default())),
+        })
+    }
+
+    /// Returns true if unauthenticated datagrams must be rejected.
+    pub fn is_required(&self) -> bool {
+        self.config.required
+    }
+
+    /// Issues new credentials for a device, replacing any it had before.
+    pub fn issue_credentials(&self, device_id: &str) -> crate::Result<IssuedDeviceCredential> {
+        let secret = random_hex(32);
+        let token = format!("{}_{}", DEVICE_TOKEN_PREFIX, random_hex(24));
+        let credential = DeviceCredential {
+            device_id: device_id.to_string(),
+            created_at: Utc::now(),
+            secret: secret.clone(),
+            token_hash: hash_secret(&token),
+        };
+        let issued = IssuedDeviceCredential {
+            device_id: device_id.to_string(),
+            created_at: credential.created_at,
+            secret,
+            token,
+        };
+        self.store.lock().unwrap().credentials.insert(device_id.to_string(), credential);
+        self.save()?;
+        Ok(issued)
+    }
+
+    /// Revokes a device's credentials. Returns false if it had none.
+    pub fn revoke_credentials(&self, device_id: &str) -> crate::Result<bool> {
+        let removed = self.store.lock().unwrap().credentials.remove(device_id).is_some();
+        if removed {
+            self.save()?;
+        }
+        Ok(removed)
+    }
+
+    /// Revokes the credentials of every device of a removed tenant, returning how many
+    /// devices had some.
+    pub fn revoke_tenant_credentials(&self, tenant_id: &str) -> crate::Result<usize> {
+        let prefix = format!("{}/", tenant_id);
+        let revoked = {
+            let mut store = self.store.lock().unwrap();
+            let before = store.credentials.len();
+            store.credentials.retain(|device_id, _| !device_id.starts_with(&prefix));
+            before - store.credentials.len()
+        };
+        if revoked > 0 {
+            self.save()?;
+        }
+        Ok(revoked)
+    }
+
+    /// Retrieves a device's credentials, without the secret and token hash.
+    pub fn get_credentials(&self, device_id: &str) -> Option<DeviceCredential> {
+        self.store.lock().unwrap().credentials.get(device_id).map(DeviceCredential::redacted)
+    }
+
+    /// Checks a datagram's signature or token against the credentials of the device, given
+    /// by its platform-wide id. Tokens are only accepted on encrypted channels.
+    pub fn verify(&self, device_id: &str, envelope: &DeviceEnvelope, encrypted: bool) -> Result<(), AuthFailure> {
+        self.verify_at(device_id, envelope, encrypted, Utc::now())
+    }
+
+    fn verify_at(
+        &self,
+        device_id: &str,
+        envelope: &DeviceEnvelope,
+        encrypted: bool,
+        now: DateTime<Utc>,
+    ) -> Result<(), AuthFailure> {
+        let credential = self
+            .store
+            .lock()
+            .unwrap()
+            .credentials
+            .get(device_id)
+            .cloned()
+            .ok_or(AuthFailure::UnknownDevice)?;
+
+        if let Some(signature) = &envelope.signature {
+            let (timestamp, nonce) = match (envelope.timestamp, &envelope.nonce) {
+                (Some(timestamp), Some(nonce)) if is_valid_nonce(nonce) => (timestamp, nonce),
+                _ => return Err(AuthFailure::Malformed),
+            };
+            let skew = Duration::seconds(self.config.max_clock_skew_secs as i64);
+            let signed_at = Utc.timestamp_opt(timestamp, 0).single().ok_or(AuthFailure::Malformed)?;
+            if signed_at < now - skew || signed_at > now + skew {
+                return Err(AuthFailure::StaleTimestamp);
+            }
+
+            // Datagrams signed by the device carry the id the device knows itself by
+            let signature = hex::decode(signature).map_err(|_| AuthFailure::BadSignature)?;
+            let mut mac =
+                Hmac::<Sha256>::new_from_slice(credential.secret.as_bytes()).expect("HMAC accepts keys of any size");
+            mac.update(signing_input(&envelope.device_id, timestamp, nonce, &envelope.payload).as_bytes());
+            mac.verify_slice(&signature).map_err(|_| AuthFailure::BadSignature)?;
+
+            // Only remember nonces of genuine datagrams, so forged ones cannot fill the cache
+            let mut nonces = self.nonces.lock().unwrap();
+            let key = (device_id.to_string(), nonce.clone());
+            if nonces.contains_key(&key) {
+                return Err(AuthFailure::ReplayedNonce);
+            }
+            nonces.insert(key, signed_at + skew);
+            Ok(())
+        } else if let Some(token) = &envelope.token {
+            if !encrypted {
+                return Err(AuthFailure::UnencryptedToken);
+            }
+            if hash_secret(token) == credential.token_hash {
+                Ok(())
+            } else {
+                Err(AuthFailure::BadToken)
+            }
+        } else {
+            Err(AuthFailure::Unauthenticated)
+        }
+    }
+
+    /// Forgets the nonces of datagrams whose timestamps would be rejected by now anyway.
+    pub fn sweep_nonces(&self, now: DateTime<Utc>) {
+        self.nonces.lock().unwrap().retain(|_, expires_at| *expires_at > now);
+    }
+
+    /// Counts a failed attempt from a source address, reporting sources that reach the
+    /// failure threshold.
+    pub fn record_failure(&self, source: IpAddr, reason: AuthFailure) {
+        let now = Utc::now();
+        let mut failures = self.failures.lock().unwrap();
+        let FailureLog { by_source, order } = &mut *failures;
+        if !by_source.contains_key(&source) {
+            if by_source.len() >= MAX_TRACKED_SOURCES {
+                if let Some(oldest) = order.pop_front() {
+                    by_source.remove(&oldest);
+                }
+            }
+            order.push_back(source);
+        }
+        let entry = by_source.entry(source).or_insert_with(|| SourceFailures {
+            source,
+            count: 0,
+            first_at: now,
+            last_at: now,
+            last_reason: reason.clone(),
+            suspicious: false,
+        });
+        entry.count += 1;
+        entry.last_at = now;
+        entry.last_reason = reason;
+        if entry.count == self.config.failure_threshold {
+            entry.suspicious = true;
+            eprintln!("Source {} failed to authenticate {} times", source, entry.count);
+        }
+    }
+
+    /// Returns the failed attempts by source, most failures first.
+    pub fn get_auth_failures(&self) -> Vec<SourceFailures> {
+        let mut failures: Vec<SourceFailures> = self.failures.lock().unwrap().by_source.values().cloned().collect();
+        failures.sort_by(|a, b| b.count.cmp(&a.count).then(a.source.cmp(&b.source)));
+        failures
+    }
+
+    /// Writes the device credentials to the credentials file.
+    fn save(&self) -> crate::Result<()> {
+        let path = match &self.config.credentials_path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let contents = serde_json::to_vec(&*self.store.lock().unwrap())?;
+
+        // Write to a temporary file first so a crash never leaves truncated credentials behind
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, contents)?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+}
+
+/// Returns true for nonces of 1 to 64 letters, digits, `-` and `_`.
+fn is_valid_nonce(nonce: &str) -> bool {
+    !nonce.is_empty()
+        && nonce.len() <= MAX_NONCE_LENGTH
+        && nonce.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signed(secret: &str, timestamp: i64, nonce: &str) -> DeviceEnvelope {
+        let payload = r#"{"temperature": 21.5}"#.to_string();
+        DeviceEnvelope {
+            device_id: "sensor1".to_string(),
+            signature: Some(sign_datagram(secret, "sensor1", timestamp, nonce, &payload)),
+            payload,
+            timestamp: Some(timestamp),
+            nonce: Some(nonce.to_string()),
+            token: None,
+        }
+    }
+
+    #[test]
+    fn test_signed_datagrams_are_checked_and_replays_rejected() {
+        let auth = DeviceAuth::new(DeviceAuthConfig::default()).unwrap();
+        let issued = auth.issue_credentials("acme/sensor1").unwrap();
+        let now = Utc::now();
+        let timestamp = now.timestamp();
+
+        let envelope = signed(&issued.secret, timestamp, "n1");
+        assert_eq!(auth.verify_at("acme/sensor1", &envelope, false, now), Ok(()));
+        assert_eq!(auth.verify_at("acme/sensor1", &envelope, false, now), Err(AuthFailure::ReplayedNonce));
+        assert_eq!(auth.verify_at("acme/sensor2", &envelope, false, now), Err(AuthFailure::UnknownDevice));
+
+        let mut tampered = signed(&issued.secret, timestamp, "n2");
+        tampered.payload = r#"{"temperature": 99.0}"#.to_string();
+        assert_eq!(auth.verify_at("acme/sensor1", &tampered, false, now), Err(AuthFailure::BadSignature));
+        let forged = signed("guess", timestamp, "n3");
+        assert_eq!(auth.verify_at("acme/sensor1", &forged, false, now), Err(AuthFailure::BadSignature));
+
+        let stale = signed(&issued.secret, timestamp - 3600, "n4");
+        assert_eq!(auth.verify_at("acme/sensor1", &stale, false, now), Err(AuthFailure::StaleTimestamp));
+
+        // Nonces with dots could shift the boundaries between the signed fields
+        let dotted = signed(&issued.secret, timestamp, "n5.x");
+        assert_eq!(auth.verify_at("acme/sensor1", &dotted, false, now), Err(AuthFailure::Malformed));
+
+        // Once its timestamp has expired a nonce is forgotten, and the datagram is stale anyway
+        auth.sweep_nonces(now + Duration::hours(1));
+        assert!(auth.nonces.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_tokens_and_revocation() {
+        let auth = DeviceAuth::new(DeviceAuthConfig::default()).unwrap();
+        let issued = auth.issue_credentials("sensor1").unwrap();
+        let mut envelope = DeviceEnvelope {
+            device_id: "sensor1".to_string(),
+            payload: r#"{"type": "heartbeat"}"#.to_string(),
+            timestamp: None,
+            nonce: None,
+            signature: None,
+            token: Some(issued.token.clone()),
+        };
+        assert_eq!(auth.verify("sensor1", &envelope, false), Err(AuthFailure::UnencryptedToken));
+        assert_eq!(auth.verify("sensor1", &envelope, true), Ok(()));
+        assert!(auth.get_credentials("sensor1").unwrap().secret.is_empty());
+
+        envelope.token = Some("iotd_guess".to_string());
+        assert_eq!(auth.verify("sensor1", &envelope, true), Err(AuthFailure::BadToken));
+        envelope.token = None;
+        assert_eq!(auth.verify("sensor1", &envelope, true), Err(AuthFailure::Unauthenticated));
+
+        assert!(auth.revoke_credentials("sensor1").unwrap());
+        envelope.token = Some(issued.token);
+        assert_eq!(auth.verify("sensor1", &envelope, true), Err(AuthFailure::UnknownDevice));
+    }
+
+    #[test]
+    fn test_failures_are_counted_per_source() {
+        let config = DeviceAuthConfig {
+            failure_threshold: 2,
+            ..DeviceAuthConfig::default()
+        };
+        let auth = DeviceAuth::new(config).unwrap();
+        let attacker: IpAddr = "10.0.0.9".parse().unwrap();
+        auth.record_failure("10.0.0.1".parse().unwrap(), AuthFailure::BadToken);
+        auth.record_failure(attacker, AuthFailure::BadSignature);
+        auth.record_failure(attacker, AuthFailure::ReplayedNonce);
+
+        let failures = auth.get_auth_failures();
+        assert_eq!(failures[0].source, attacker);
+        assert_eq!(failures[0].count, 2);
+        assert_eq!(failures[0].last_reason, AuthFailure::ReplayedNonce);
+        assert!(failures[0].suspicious);
+        assert!(!failures[1].suspicious);
+
+        // The number of sources kept is bounded, forgetting the earliest first
+        for i in 0..MAX_TRACKED_SOURCES as u32 {
+            auth.record_failure(IpAddr::from((0x0b00_0000 + i).to_be_bytes()), AuthFailure::BadToken);
+        }
+        let failures = auth.get_auth_failures();
+        assert_eq!(failures.len(), MAX_TRACKED_SOURCES);
+        assert!(failures.iter().all(|failure| failure.source != "10.0.0.1".parse::<IpAddr>().unwrap()));
+    }
+}
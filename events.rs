@@ -0,0 +1,519 @@
+// events.rs
+
+use crate::config::StreamConfig;
+use crate::device::{DeviceManager, Reading};
+use crate::listing::{paginate, time_key, Page, Sort};
+use crate::monitoring::StatusTransition;
+use crate::notification::Notification;
+use crate::storage_service::StorageService;
+use crate::tenant::{split_device_id, DEFAULT_TENANT};
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+
+/// Something that happened on the platform and is pushed to streaming API clients.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// A reading that went through the processing pipeline.
+    Reading { reading: Reading },
+    /// A device went online, offline or back to unknown.
+    HealthTransition { device_id: String, transition: StatusTransition },
+    /// A notification raised by an alert.
+    Alert { notification: Notification },
+    /// Sent only to a client that fell behind, with the number of events it missed.
+    Lagged { skipped: u64 },
+}
+
+/// The kinds of events a client can subscribe to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    Reading,
+    Health,
+    Alert,
+}
+
+impl Event {
+    /// Returns the kind of the event, or None for events every subscriber receives.
+    pub fn kind(&self) -> Option<EventKind> {
+        match self {
+            Event::Reading { .. } => Some(EventKind::Reading),
+            Event::HealthTransition { .. } => Some(EventKind::Health),
+            Event::Alert { .. } => Some(EventKind::Alert),
+            Event::Lagged { .. } => None,
+        }
+    }
+
+    /// Returns the device the event is about, if any.
+    pub fn device_id(&self) -> Option<&str> {
+        match self {
+            Event::Reading { reading } => Some(&reading.device_id),
+            Event::HealthTransition { device_id, .. } => Some(device_id),
+            Event::Alert { notification } => notification.device_id.as_deref(),
+            Event::Lagged { .. } => None,
+        }
+    }
+
+    /// Returns the event with its device id as seen by the device's tenant.
+    pub fn into_local(self) -> Event {
+        let local = |device_id: String| split_device_id(&device_id).1.to_string();
+        match self {
+            Event::Reading { mut reading } => {
+                reading.device_id = local(reading.device_id);
+                Event::Reading { reading }
+            }
+            Event::HealthTransition { device_id, transition } => Event::HealthTransition {
+                device_id: local(device_id),
+                transition,
+            },
+            Event::Alert { mut notification } => {
+                notification.device_id = notification.device_id.map(local);
+                Event::Alert { notification }
+            }
+            Event::Lagged { skipped } => Event::Lagged { skipped },
+        }
+    }
+}
+
+/// Which events a client wants to receive. Empty lists match everything.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SubscriptionFilter {
+    #[serde(default)]
+    pub devices: Vec<String>,
+    /// Devices carrying any of these tags.
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub events: Vec<EventKind>,
+    /// Only events of this tenant's devices; set by the API from the caller's credentials.
+    #[serde(skip)]
+    pub tenant_id: Option<String>,
+    /// Only events of devices carrying one of these tags; set by the API for callers granted
+    /// access to device groups only.
+    #[serde(skip)]
+    pub groups: Option<Vec<String>>,
+}
+
+impl SubscriptionFilter {
+    /// Returns true if the event passes the filter. Alerts that are not about a specific
+    /// device pass the device and tag filters; they belong to the default tenant.
+    pub fn matches(&self, event: &Event, device_manager: &DeviceManager) -> bool {
+        let kind = match event.kind() {
+            Some(kind) => kind,
+            None => return true,
+        };
+        if !self.events.is_empty() && !self.events.contains(&kind) {
+            return false;
+        }
+        let (tenant_id, local_id) = match event.device_id() {
+            Some(device_id) => split_device_id(device_id),
+            None => (DEFAULT_TENANT, ""),
+        };
+        if self.tenant_id.as_ref().is_some_and(|expected| expected != tenant_id) {
+            return false;
+        }
+        if let Some(groups) = &self.groups {
+            let in_group = event.device_id().and_then(|device_id| device_manager.get_device(device_id)).is_some_and(
+                |device| device.tags.iter().any(|tag| groups.contains(tag)),
+            );
+            if !in_group {
+                return false;
+            }
+        }
+        if self.devices.is_empty() && self.tags.is_empty() {
+            return true;
+        }
+        let device_id = match event.device_id() {
+            Some(device_id) => device_id,
+            None => return kind == EventKind::Alert,
+        };
+        if self.devices.iter().any(|device| device == local_id) {
+            return true;
+        }
+        device_manager
+            .get_device(device_id)
+            .is_some_and(|device| device.tags.iter().any(|tag| self.tags.contains(tag)))
+    }
+}
+
+/// An alert kept after it was published, for listing past alerts.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct AlertRecord {
+    /// Increases with every alert.
+    pub id: u64,
+    #[serde(flatten)]
+    pub notification: Notification,
+}
+
+/// Filters, order and paging for listing past alerts.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct AlertQuery {
+    pub device_id: Option<String>,
+    /// Alerts about devices carrying this tag.
+    pub tag: Option<String>,
+    pub severity: Option<String>,
+    /// Only alerts raised at or after this time.
+    pub since: Option<DateTime<Utc>>,
+    /// Only alerts raised before this time.
+    pub until: Option<DateTime<Utc>>,
+    /// One of `timestamp`, `severity` or `device_id`, prefixed with `-` for descending.
+    /// Newest first when missing.
+    pub sort: Option<String>,
+    /// The `next_cursor` of the previous page. Takes precedence over `offset`.
+    pub cursor: Option<String>,
+    #[serde(default)]
+    pub offset: usize,
+    pub limit: Option<usize>,
+    /// Comma separated fields to return, e.g. `id,title,severity`; all fields when missing.
+    pub fields: Option<String>,
+    /// Only alerts of this tenant; set by the API from the caller's credentials.
+    #[serde(skip)]
+    pub tenant_id: Option<String>,
+    /// Only alerts about devices carrying one of these tags, for callers granted access to
+    /// device groups only.
+    #[serde(skip)]
+    pub groups: Option<Vec<String>>,
+}
+
+/// Fields alerts can be sorted by.
+const ALERT_SORT_FIELDS: [&str; 3] = ["timestamp", "severity", "device_id"];
+
+impl AlertQuery {
+    /// Returns the tags of every device when the query filters by tag or group, so that each
+    /// alert does not look its device up again.
+    fn device_tags(&self, device_manager: &DeviceManager) -> Option<HashMap<String, Vec<String>>> {
+        if self.tag.is_none() && self.groups.is_none() {
+            return None;
+        }
+        Some(device_manager.list_devices().into_iter().map(|device| (device.id, device.tags)).collect())
+    }
+
+    /// Returns true if the alert passes the filters, given the device tags collected by
+    /// `device_tags`. Alerts that are not about a specific device belong to the default tenant.
+    fn matches(&self, notification: &Notification, device_tags: Option<&HashMap<String, Vec<String>>>) -> bool {
+        let (tenant_id, local_id) = match notification.device_id.as_deref() {
+            Some(device_id) => split_device_id(device_id),
+            None => (DEFAULT_TENANT, ""),
+        };
+        if self.tenant_id.as_ref().is_some_and(|expected| expected != tenant_id)
+            || self.device_id.as_ref().is_some_and(|device_id| device_id != local_id)
+            || self.severity.as_ref().is_some_and(|severity| severity != &notification.severity)
+            || self.since.is_some_and(|since| notification.timestamp < since)
+            || self.until.is_some_and(|until| notification.timestamp >= until)
+        {
+            return false;
+        }
+        let device_tags = match device_tags {
+            Some(device_tags) => device_tags,
+            None => return true,
+        };
+        let tags = notification.device_id.as_ref().and_then(|device_id| device_tags.get(device_id));
+        let tags = tags.map(Vec::as_slice).unwrap_or_default();
+        !self.tag.as_ref().is_some_and(|tag| !tags.contains(tag))
+            && !self.groups.as_ref().is_some_and(|groups| !tags.iter().any(|tag| groups.contains(tag)))
+    }
+}
+
+/// The most recent alerts, oldest first.
+#[derive(Default)]
+struct AlertHistory {
+    next_id: u64,
+    records: VecDeque<AlertRecord>,
+}
+
+/// Broadcasts events to every subscribed streaming client, keeping alerts for listing. Alerts
+/// are persisted when storage is configured and the most recent ones are kept in memory
+/// otherwise.
+pub struct EventBus {
+    sender: broadcast::Sender<Event>,
+    config: StreamConfig,
+    alerts: Mutex<AlertHistory>,
+    storage: Option<Arc<StorageService>>,
+}
+
+impl EventBus {
+    /// Creates a bus that buffers up to `channel_capacity` events for slow subscribers.
+    pub fn new(config: StreamConfig) -> Self {
+        let (sender, _) = broadcast::channel(config.channel_capacity.max(1));
+        EventBus {
+            sender,
+            config,
+            alerts: Mutex::new(AlertHistory::default()),
+            storage: None,
+        }
+    }
+
+    /// Persists alerts to the given storage, numbering new alerts after the stored ones. Alerts
+    /// stay in memory when the storage has no data directory.
+    pub fn with_storage(mut self, storage: Arc<StorageService>) -> crate::Result<Self> {
+        if !storage.has_partitions() {
+            return Ok(self);
+        }
+        let last_id = storage.load_alerts(None)?.iter().map(|alert| alert.id).max();
+        self.alerts.get_mut().unwrap().next_id = last_id.map_or(0, |id| id + 1);
+        self.storage = Some(storage);
+        Ok(self)
+    }
+
+    /// Publishes an event to all current subscribers.
+    pub fn publish(&self, event: Event) {
+        if let Event::Alert { notification } = &event {
+            let mut alerts = self.alerts.lock().unwrap();
+            let record = AlertRecord {
+                id: alerts.next_id,
+                notification: notification.clone(),
+            };
+            alerts.next_id += 1;
+            match &self.storage {
+                Some(storage) => {
+                    if let Err(e) = storage.store_alert(&record) {
+                        eprintln!("Failed to store alert {}: {}", record.id, e);
+                    }
+                }
+                None => {
+                    alerts.records.push_back(record);
+                    while alerts.records.len() > self.config.alert_history {
+                        alerts.records.pop_front();
+                    }
+                }
+            }
+        }
+        // Sending only fails when nobody is subscribed, in which case the event is not needed
+        let _ = self.sender.send(event);
+    }
+
+    /// Returns a page of the past alerts matching the query. Fails on an unknown sort field or
+    /// an invalid cursor.
+    pub fn list_alerts(&self, query: &AlertQuery, device_manager: &DeviceManager) -> Result<Page<AlertRecord>, String> {
+        let sort = Sort::parse(query.sort.as_deref(), &ALERT_SORT_FIELDS, "-timestamp")?;
+        let device_tags = query.device_tags(device_manager);
+        let records: Vec<AlertRecord> = match &self.storage {
+            Some(storage) => storage
+                .load_alerts(query.tenant_id.as_deref())
+                .map_err(|e| format!("could not read stored alerts: {}", e))?,
+            None => self.alerts.lock().unwrap().records.iter().cloned().collect(),
+        };
+        let matching: Vec<AlertRecord> = records
+            .into_iter()
+            .filter(|record| query.matches(&record.notification, device_tags.as_ref()))
+            .collect();
+        // Zero padded so that ids order like numbers
+        let key = |record: &AlertRecord| {
+            let value = match sort.field.as_str() {
+                "severity" => Some(record.notification.severity.clone()),
+                "device_id" => record.notification.device_id.clone(),
+                _ => time_key(Some(record.notification.timestamp)),
+            };
+            (value, format!("{:020}", record.id))
+        };
+        paginate(matching, &sort, key, query.cursor.as_deref(), query.offset, query.limit)
+    }
+
+    /// Subscribes to the events passing the filter.
+    pub fn subscribe(&self, filter: SubscriptionFilter) -> Subscription {
+        Subscription {
+            receiver: self.sender.subscribe(),
+            filter,
+            lag_incidents: 0,
+            max_lag_incidents: self.config.max_lag_incidents,
+        }
+    }
+
+    /// Returns the number of connected subscribers.
+    pub fn subscriber_count(&self) -> usize {
+        self.sender.receiver_count()
+    }
+
+    /// Returns the streaming settings.
+    pub fn config(&self) -> &StreamConfig {
+        &self.config
+    }
+}
+
+/// A single client's view of the event bus.
+pub struct Subscription {
+    receiver: broadcast::Receiver<Event>,
+    filter: SubscriptionFilter,
+    lag_incidents: u32,
+    max_lag_incidents: u32,
+}
+
+impl Subscription {
+    /// Waits for the next event for the client. A client that falls behind gets a `Lagged`
+    /// event; one that keeps falling behind is disconnected by returning None.
+    pub async fn next(&mut self, device_manager: &DeviceManager) -> Option<Event> {
+        loop {
+            match self.receiver.recv().await {
+                Ok(event) if self.filter.matches(&event, device_manager) => return Some(event),
+                Ok(_) => {}
+                Err(RecvError::Lagged(skipped)) => {
+                    self.lag_incidents += 1;
+                    if self.lag_incidents > self.max_lag_incidents {
+                        eprintln!("Disconnecting slow stream subscriber after {} lag incidents", self.lag_incidents);
+                        return None;
+                    }
+                    return Some(Event::Lagged { skipped });
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    /// Replaces the subscription's filter.
+    pub fn set_filter(&mut self, filter: SubscriptionFilter) {
+        self.filter = filter;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::Device;
+
+    fn reading_event(device_id: &str) -> Event {
+        Event::Reading {
+            reading: Reading::new(device_id.to_string(), HashMap::from([("temperature".to_string(), 20.0)])),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_subscription_filters_by_device_tag_and_kind() {
+        let device_manager = DeviceManager::new();
+        let mut tagged = Device::new("d2".to_string(), "Tagged".to_string());
+        tagged.tags = vec!["roof".to_string()];
+        device_manager.add_device(tagged);
+
+        let bus = EventBus::new(StreamConfig::default());
+        let mut subscription = bus.subscribe(SubscriptionFilter {
+            devices: vec!["d1".to_string()],
+            tags: vec!["roof".to_string()],
+            events: vec![EventKind::Reading, EventKind::Alert],
+            ..SubscriptionFilter::default()
+        });
+
+        bus.publish(reading_event("d3"));
+        bus.publish(reading_event("d2"));
+        bus.publish(Event::Alert {
+            notification: Notification::new("Disk".to_string(), "full".to_string(), "warning".to_string(), None),
+        });
+
+        let first = subscription.next(&device_manager).await.unwrap();
+        assert_eq!(first.device_id(), Some("d2"));
+        assert_eq!(subscription.next(&device_manager).await.unwrap().kind(), Some(EventKind::Alert));
+    }
+
+    #[test]
+    fn test_recent_alerts_are_listed_newest_first() {
+        let device_manager = DeviceManager::new();
+        let bus = EventBus::new(StreamConfig {
+            alert_history: 3,
+            ..StreamConfig::default()
+        });
+        for device_id in ["d1", "acme/d1", "d2", "d3"] {
+            let (title, severity) = ("Offline".to_string(), "warning".to_string());
+            let notification = Notification::new(title, "".to_string(), severity, Some(device_id.to_string()));
+            bus.publish(Event::Alert { notification });
+        }
+
+        // The oldest alert was dropped, and other tenants' alerts are not listed
+        let query = AlertQuery {
+            tenant_id: Some(DEFAULT_TENANT.to_string()),
+            limit: Some(1),
+            ..AlertQuery::default()
+        };
+        let page = bus.list_alerts(&query, &device_manager).unwrap();
+        assert_eq!(page.total, 2);
+        assert_eq!(page.items[0].notification.device_id.as_deref(), Some("d3"));
+        let next = AlertQuery {
+            cursor: page.next_cursor,
+            ..query
+        };
+        let page = bus.list_alerts(&next, &device_manager).unwrap();
+        assert_eq!(page.items[0].id, 2);
+        assert!(page.next_cursor.is_none());
+    }
+
+    #[test]
+    fn test_stored_alerts_outlive_the_bus() {
+        let mut config = crate::config::Config::from_env().unwrap();
+        let data_dir = std::env::temp_dir().join(format!("iot_events_alerts_{}", crate::auth::random_hex(8)));
+        config.storage_config.data_dir = Some(data_dir.clone());
+        let device_manager = Arc::new(DeviceManager::new());
+        let mut tagged = Device::new("acme/d1".to_string(), "Tagged".to_string());
+        tagged.tags = vec!["roof".to_string()];
+        device_manager.add_device(tagged);
+        let storage = Arc::new(StorageService::new(config.storage_config, device_manager.clone()));
+        let alert = |device_id: &str| Event::Alert {
+            notification: Notification::new(
+                "Offline".to_string(),
+                "".to_string(),
+                "warning".to_string(),
+                Some(device_id.to_string()),
+            ),
+        };
+
+        let bus = EventBus::new(StreamConfig::default()).with_storage(storage.clone()).unwrap();
+        bus.publish(alert("acme/d1"));
+        bus.publish(alert("acme/d2"));
+        let bus = EventBus::new(StreamConfig::default()).with_storage(storage).unwrap();
+        bus.publish(alert("acme/d1"));
+
+        let query = AlertQuery {
+            tag: Some("roof".to_string()),
+            tenant_id: Some("acme".to_string()),
+            ..AlertQuery::default()
+        };
+        let page = bus.list_alerts(&query, &device_manager).unwrap();
+        let ids: Vec<u64> = page.items.iter().map(|record| record.id).collect();
+        assert_eq!(ids, vec![2, 0]);
+        std::fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_slow_subscriber_is_told_then_disconnected() {
+        let device_manager = DeviceManager::new();
+        let bus = EventBus::new(StreamConfig {
+            channel_capacity: 2,
+            max_lag_incidents: 1,
+            ..StreamConfig::default()
+        });
+        let mut subscription = bus.subscribe(SubscriptionFilter::default());
+
+        for _ in 0..5 {
+            bus.publish(reading_event("d1"));
+        }
+        assert!(matches!(subscription.next(&device_manager).await, Some(Event::Lagged { skipped: 3 })));
+        // The two most recent events are still delivered
+        assert!(matches!(subscription.next(&device_manager).await, Some(Event::Reading { .. })));
+        assert!(matches!(subscription.next(&device_manager).await, Some(Event::Reading { .. })));
+
+        for _ in 0..5 {
+            bus.publish(reading_event("d1"));
+        }
+        assert!(subscription.next(&device_manager).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_subscription_only_sees_its_tenant() {
+        let device_manager = DeviceManager::new();
+        let bus = EventBus::new(StreamConfig::default());
+        let mut subscription = bus.subscribe(SubscriptionFilter {
+            devices: vec!["s1".to_string()],
+            tenant_id: Some("acme".to_string()),
+            ..SubscriptionFilter::default()
+        });
+
+        bus.publish(reading_event("s1"));
+        bus.publish(reading_event("other/s1"));
+        bus.publish(reading_event("acme/s1"));
+
+        let event = subscription.next(&device_manager).await.unwrap().into_local();
+        assert_eq!(event.device_id(), Some("s1"));
+        assert!(matches!(event, Event::Reading { ref reading } if reading.device_id == "s1"));
+    }
+}
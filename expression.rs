@@ -0,0 +1,429 @@
+// expression.rs
+
+use std::collections::HashMap;
+
+/// A parsed expression that computes a virtual metric from a device's readings.
+///
+/// Supported syntax:
+/// - numbers, `pi` and `e`
+/// - metric names of the same device, and `prev(metric)` for the metric's previous value
+/// - arithmetic `+ - * / % ^`, comparisons `< <= > >= == !=` and logic `&& || !`
+/// - conditionals `cond ? a : b` or `if(cond, a, b)`
+/// - functions `abs sqrt exp ln log10 sin cos tan floor ceil round min max pow`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(f64),
+    Metric(String),
+    Previous(String),
+    Unary(char, Box<Expr>),
+    Binary(String, Box<Expr>, Box<Expr>),
+    Conditional(Box<Expr>, Box<Expr>, Box<Expr>),
+    Call(String, Vec<Expr>),
+}
+
+/// Values an expression can refer to while being evaluated.
+pub struct EvalContext<'a> {
+    pub current: &'a HashMap<String, f64>,
+    pub previous: Option<&'a HashMap<String, f64>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Ident(String),
+    Op(String),
+    LParen,
+    RParen,
+    Comma,
+    Question,
+    Colon,
+}
+
+fn tokenize(source: &str) -> crate::Result<Vec<Token>> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_ascii_digit() || c == '.' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            // Scientific notation such as 1.5e-3
+            if i < chars.len() && (chars[i] == 'e' || chars[i] == 'E') {
+                let mut j = i + 1;
+                if j < chars.len() && (chars[j] == '+' || chars[j] == '-') {
+                    j += 1;
+                }
+                if j < chars.len() && chars[j].is_ascii_digit() {
+                    i = j;
+                    while i < chars.len() && chars[i].is_ascii_digit() {
+                        i += 1;
+                    }
+                }
+            }
+            let literal: String = chars[start..i].iter().collect();
+            let number = literal
+                .parse()
+                .map_err(|_| format!("invalid number '{}' at position {}", literal, start))?;
+            tokens.push(Token::Number(number));
+        } else if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            tokens.push(Token::Ident(chars[start..i].iter().collect()));
+        } else {
+            let two: String = chars[i..(i + 2).min(chars.len())].iter().collect();
+            if ["<=", ">=", "==", "!=", "&&", "||"].contains(&two.as_str()) {
+                tokens.push(Token::Op(two));
+                i += 2;
+                continue;
+            }
+            tokens.push(match c {
+                '+' | '-' | '*' | '/' | '%' | '^' | '<' | '>' | '!' => Token::Op(c.to_string()),
+                '(' => Token::LParen,
+                ')' => Token::RParen,
+                ',' => Token::Comma,
+                '?' => Token::Question,
+                ':' => Token::Colon,
+                _ => return Err(format!("unexpected character '{}' at position {}", c, i).into()),
+            });
+            i += 1;
+        }
+    }
+    Ok(tokens)
+}
+
+/// Returns the binding power of a binary operator; higher binds tighter.
+fn binding_power(op: &str) -> Option<(u8, u8)> {
+    // (left, right) binding powers; `^` is right associative
+    match op {
+        "||" => Some((1, 2)),
+        "&&" => Some((3, 4)),
+        "==" | "!=" => Some((5, 6)),
+        "<" | "<=" | ">" | ">=" => Some((7, 8)),
+        "+" | "-" => Some((9, 10)),
+        "*" | "/" | "%" => Some((11, 12)),
+        "^" => Some((14, 13)),
+        _ => None,
+    }
+}
+
+/// Binding power of prefix operators, between multiplication and exponentiation so `-x^2` is `-(x^2)`.
+const PREFIX_POWER: u8 = 13;
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn expect(&mut self, expected: Token) -> crate::Result<()> {
+        match self.next() {
+            Some(token) if token == expected => Ok(()),
+            other => Err(format!("expected {:?}, found {:?}", expected, other).into()),
+        }
+    }
+
+    fn parse_expression(&mut self) -> crate::Result<Expr> {
+        let condition = self.parse_binary(0)?;
+        if self.peek() == Some(&Token::Question) {
+            self.next();
+            let then = self.parse_expression()?;
+            self.expect(Token::Colon)?;
+            let otherwise = self.parse_expression()?;
+            return Ok(Expr::Conditional(Box::new(condition), Box::new(then), Box::new(otherwise)));
+        }
+        Ok(condition)
+    }
+
+    fn parse_binary(&mut self, min_power: u8) -> crate::Result<Expr> {
+        let mut left = self.parse_prefix()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Op(op)) => op.clone(),
+                _ => break,
+            };
+            let (left_power, right_power) = match binding_power(&op) {
+                Some(powers) => powers,
+                None => break,
+            };
+            if left_power < min_power {
+                break;
+            }
+            self.next();
+            let right = self.parse_binary(right_power)?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn parse_prefix(&mut self) -> crate::Result<Expr> {
+        match self.next() {
+            Some(Token::Number(value)) => Ok(Expr::Number(value)),
+            Some(Token::Op(op)) if op == "-" || op == "!" || op == "+" => {
+                let operand = self.parse_binary(PREFIX_POWER)?;
+                Ok(match op.as_str() {
+                    "+" => operand,
+                    _ => Expr::Unary(op.chars().next().unwrap(), Box::new(operand)),
+                })
+            }
+            Some(Token::LParen) => {
+                let inner = self.parse_expression()?;
+                self.expect(Token::RParen)?;
+                Ok(inner)
+            }
+            Some(Token::Ident(name)) => {
+                if self.peek() != Some(&Token::LParen) {
+                    return Ok(match name.as_str() {
+                        "pi" => Expr::Number(std::f64::consts::PI),
+                        "e" => Expr::Number(std::f64::consts::E),
+                        _ => Expr::Metric(name),
+                    });
+                }
+                self.next();
+                let mut args = Vec::new();
+                if self.peek() != Some(&Token::RParen) {
+                    loop {
+                        args.push(self.parse_expression()?);
+                        if self.peek() == Some(&Token::Comma) {
+                            self.next();
+                        } else {
+                            break;
+                        }
+                    }
+                }
+                self.expect(Token::RParen)?;
+                build_call(name, args)
+            }
+            other => Err(format!("unexpected token {:?}", other).into()),
+        }
+    }
+}
+
+/// Validates a function call and turns the special forms into their own nodes.
+fn build_call(name: String, mut args: Vec<Expr>) -> crate::Result<Expr> {
+    let arity = match name.as_str() {
+        "abs" | "sqrt" | "exp" | "ln" | "log10" | "sin" | "cos" | "tan" | "floor" | "ceil" | "round" | "prev" => 1,
+        "min" | "max" | "pow" => 2,
+        "if" => 3,
+        _ => return Err(format!("unknown function '{}'", name).into()),
+    };
+    if args.len() != arity {
+        return Err(format!("{} expects {} argument(s), got {}", name, arity, args.len()).into());
+    }
+
+    match name.as_str() {
+        "prev" => match args.pop() {
+            Some(Expr::Metric(metric)) => Ok(Expr::Previous(metric)),
+            _ => Err("prev expects a metric name".into()),
+        },
+        "if" => {
+            let otherwise = args.pop().unwrap();
+            let then = args.pop().unwrap();
+            let condition = args.pop().unwrap();
+            Ok(Expr::Conditional(Box::new(condition), Box::new(then), Box::new(otherwise)))
+        }
+        _ => Ok(Expr::Call(name, args)),
+    }
+}
+
+fn truth(value: f64) -> bool {
+    value != 0.0 && !value.is_nan()
+}
+
+fn from_bool(value: bool) -> f64 {
+    if value {
+        1.0
+    } else {
+        0.0
+    }
+}
+
+impl Expr {
+    /// Parses an expression from its source text.
+    pub fn parse(source: &str) -> crate::Result<Self> {
+        let mut parser = Parser {
+            tokens: tokenize(source)?,
+            position: 0,
+        };
+        let expr = parser.parse_expression()?;
+        if let Some(token) = parser.peek() {
+            return Err(format!("unexpected trailing token {:?}", token).into());
+        }
+        Ok(expr)
+    }
+
+    /// Evaluates the expression. Returns None when a referenced metric or previous value
+    /// is not available, in which case no virtual metric should be produced.
+    pub fn evaluate(&self, context: &EvalContext) -> Option<f64> {
+        Some(match self {
+            Expr::Number(value) => *value,
+            Expr::Metric(name) => *context.current.get(name)?,
+            Expr::Previous(name) => *context.previous?.get(name)?,
+            Expr::Unary('-', operand) => -operand.evaluate(context)?,
+            Expr::Unary(_, operand) => from_bool(!truth(operand.evaluate(context)?)),
+            Expr::Binary(op, left, right) => {
+                let left = left.evaluate(context)?;
+                // Short-circuit logic so the other side may reference missing metrics
+                match op.as_str() {
+                    "&&" if !truth(left) => return Some(0.0),
+                    "||" if truth(left) => return Some(1.0),
+                    _ => {}
+                }
+                let right = right.evaluate(context)?;
+                match op.as_str() {
+                    "+" => left + right,
+                    "-" => left - right,
+                    "*" => left * right,
+                    "/" => left / right,
+                    "%" => left % right,
+                    "^" => left.powf(right),
+                    "<" => from_bool(left < right),
+                    "<=" => from_bool(left <= right),
+                    ">" => from_bool(left > right),
+                    ">=" => from_bool(left >= right),
+                    "==" => from_bool(left == right),
+                    "!=" => from_bool(left != right),
+                    "&&" | "||" => from_bool(truth(right)),
+                    _ => return None,
+                }
+            }
+            Expr::Conditional(condition, then, otherwise) => {
+                if truth(condition.evaluate(context)?) {
+                    then.evaluate(context)?
+                } else {
+                    otherwise.evaluate(context)?
+                }
+            }
+            Expr::Call(name, args) => {
+                let values: Option<Vec<f64>> = args.iter().map(|arg| arg.evaluate(context)).collect();
+                let values = values?;
+                match name.as_str() {
+                    "abs" => values[0].abs(),
+                    "sqrt" => values[0].sqrt(),
+                    "exp" => values[0].exp(),
+                    "ln" => values[0].ln(),
+                    "log10" => values[0].log10(),
+                    "sin" => values[0].sin(),
+                    "cos" => values[0].cos(),
+                    "tan" => values[0].tan(),
+                    "floor" => values[0].floor(),
+                    "ceil" => values[0].ceil(),
+                    "round" => values[0].round(),
+                    "min" => values[0].min(values[1]),
+                    "max" => values[0].max(values[1]),
+                    "pow" => values[0].powf(values[1]),
+                    _ => return None,
+                }
+            }
+        })
+    }
+
+    /// Returns the names of the metrics the expression reads, including previous values.
+    pub fn referenced_metrics(&self) -> Vec<String> {
+        let mut metrics = Vec::new();
+        self.collect_metrics(&mut metrics);
+        metrics.sort();
+        metrics.dedup();
+        metrics
+    }
+
+    fn collect_metrics(&self, metrics: &mut Vec<String>) {
+        match self {
+            Expr::Number(_) => {}
+            Expr::Metric(name) | Expr::Previous(name) => metrics.push(name.clone()),
+            Expr::Unary(_, operand) => operand.collect_metrics(metrics),
+            Expr::Binary(_, left, right) => {
+                left.collect_metrics(metrics);
+                right.collect_metrics(metrics);
+            }
+            Expr::Conditional(condition, then, otherwise) => {
+                condition.collect_metrics(metrics);
+                then.collect_metrics(metrics);
+                otherwise.collect_metrics(metrics);
+            }
+            Expr::Call(_, args) => args.iter().for_each(|arg| arg.collect_metrics(metrics)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(source: &str, current: &[(&str, f64)], previous: Option<&[(&str, f64)]>) -> Option<f64> {
+        let current: HashMap<String, f64> = current.iter().map(|(k, v)| (k.to_string(), *v)).collect();
+        let previous: Option<HashMap<String, f64>> =
+            previous.map(|values| values.iter().map(|(k, v)| (k.to_string(), *v)).collect());
+        let context = EvalContext {
+            current: &current,
+            previous: previous.as_ref(),
+        };
+        Expr::parse(source).unwrap().evaluate(&context)
+    }
+
+    #[test]
+    fn test_arithmetic_precedence() {
+        assert_eq!(eval("1 + 2 * 3", &[], None), Some(7.0));
+        assert_eq!(eval("(1 + 2) * 3", &[], None), Some(9.0));
+        assert_eq!(eval("2 ^ 3 ^ 2", &[], None), Some(512.0));
+        assert_eq!(eval("-2 ^ 2", &[], None), Some(-4.0));
+        assert_eq!(eval("10 % 4 - 1.5e1", &[], None), Some(-13.0));
+    }
+
+    #[test]
+    fn test_power_from_metrics() {
+        assert_eq!(eval("voltage * current", &[("voltage", 12.0), ("current", 2.5)], None), Some(30.0));
+        assert_eq!(eval("voltage * current", &[("voltage", 12.0)], None), None);
+    }
+
+    #[test]
+    fn test_dew_point() {
+        let dew_point = "243.04 * (ln(humidity / 100) + 17.625 * temperature / (243.04 + temperature)) \
+                         / (17.625 - ln(humidity / 100) - 17.625 * temperature / (243.04 + temperature))";
+        let value = eval(dew_point, &[("temperature", 25.0), ("humidity", 60.0)], None).unwrap();
+        assert!((value - 16.70).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_conditionals_and_functions() {
+        assert_eq!(eval("temperature > 30 ? 1 : 0", &[("temperature", 35.0)], None), Some(1.0));
+        assert_eq!(eval("if(temperature > 30 && humidity < 50, 2, 3)", &[("temperature", 35.0), ("humidity", 70.0)], None), Some(3.0));
+        assert_eq!(eval("max(abs(-4), sqrt(9))", &[], None), Some(4.0));
+        assert_eq!(eval("round(pi * 100) / 100", &[], None), Some(3.14));
+    }
+
+    #[test]
+    fn test_previous_values() {
+        let delta = "energy - prev(energy)";
+        assert_eq!(eval(delta, &[("energy", 15.0)], Some(&[("energy", 10.0)])), Some(5.0));
+        assert_eq!(eval(delta, &[("energy", 15.0)], None), None);
+        assert_eq!(Expr::parse(delta).unwrap().referenced_metrics(), vec!["energy".to_string()]);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(Expr::parse("1 +").is_err());
+        assert!(Expr::parse("foo(1)").is_err());
+        assert!(Expr::parse("min(1)").is_err());
+        assert!(Expr::parse("prev(1 + 2)").is_err());
+        assert!(Expr::parse("(1 + 2").is_err());
+        assert!(Expr::parse("1 $ 2").is_err());
+    }
+}
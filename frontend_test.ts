@@ -34,8 +34,8 @@ describe('IoT Analytics Dashboard', () => {
     };
 
     const deviceHealth = {
-      'device1': { is_online: true, last_update: '2021-04-01T12:00:00Z' },
-      'device2': { is_online: false, last_update: '2021-04-01T11:00:00Z' },
+      'device1': { status: 'online', last_update: '2021-04-01T12:00:00Z' },
+      'device2': { status: 'offline', last_update: '2021-04-01T11:00:00Z' },
     };
 
     axios.get.mockResolvedValueOnce({ data: analyticsData });
@@ -0,0 +1,798 @@
+// graphql.rs
+
+use crate::analytics::Analytics;
+use crate::auth::{Principal, Scope};
+use crate::device::{Device, DeviceManager, DeviceQuery};
+use crate::events::{AlertQuery, AlertRecord, Event, EventBus, EventKind, SubscriptionFilter};
+use crate::listing::{DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE};
+use crate::monitoring::{Monitoring, StatusTransition};
+use crate::notification::Notification;
+use crate::tenant::{scoped_device_id, split_device_id};
+use async_graphql::extensions::{Extension, ExtensionContext, ExtensionFactory, NextParseQuery};
+use async_graphql::parser::types::{ExecutableDocument, Selection, SelectionSet};
+use async_graphql::{
+    Context, EmptyMutation, Enum, InputObject, Json, Object, Schema, ServerError, ServerResult, SimpleObject,
+    Subscription, Variables,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::Stream;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Deepest nesting of fields a query may select.
+const MAX_QUERY_DEPTH: usize = 10;
+
+/// Highest complexity a query may have. Every field counts one, and the fields selected in a
+/// list count once per item the list may hold.
+const MAX_QUERY_COMPLEXITY: usize = 50_000;
+
+/// Most aliased fields a query may select, so that one query cannot ask for the same field
+/// many times over.
+const MAX_QUERY_ALIASES: usize = 30;
+
+/// Number of items assumed for lists that are not paged, such as a device's metrics.
+const LIST_COMPLEXITY: usize = 10;
+
+/// The GraphQL schema served by the API. It only reads, apart from subscriptions.
+pub type ApiSchema = Schema<QueryRoot, EmptyMutation, SubscriptionRoot>;
+
+/// The services the schema resolves fields from, shared with the REST routes.
+#[derive(Clone)]
+pub struct Services {
+    pub device_manager: Arc<DeviceManager>,
+    pub analytics: Arc<Analytics>,
+    pub monitoring: Arc<Monitoring>,
+    pub events: Arc<EventBus>,
+}
+
+impl Services {
+    /// Returns one of the caller's devices if the caller may read its telemetry.
+    fn readable_device(&self, principal: &Principal, device_id: &str) -> Option<Device> {
+        let device = self.device_manager.get_device(&scoped_device_id(&principal.tenant_id, device_id))?;
+        principal.can_access_device(&device.tags, Scope::ReadTelemetry).then_some(device)
+    }
+}
+
+/// Builds the schema over the services. Every request carries the caller's `Principal` as
+/// data; callers only see their own tenant's devices, addressed by the tenant's own ids, and
+/// callers granted access to device groups only see the devices of those groups.
+pub fn schema(services: Services) -> ApiSchema {
+    Schema::build(QueryRoot, EmptyMutation, SubscriptionRoot)
+        .data(services)
+        .limit_depth(MAX_QUERY_DEPTH)
+        .limit_complexity(MAX_QUERY_COMPLEXITY)
+        .extension(AliasLimit)
+        .finish()
+}
+
+/// Returns the complexity of a page of `first` items, each selecting `child_complexity`.
+fn page_complexity(first: Option<usize>, child_complexity: usize) -> usize {
+    first.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE).saturating_mul(child_complexity)
+}
+
+/// Rejects queries selecting more than `MAX_QUERY_ALIASES` aliased fields.
+struct AliasLimit;
+
+impl ExtensionFactory for AliasLimit {
+    fn create(&self) -> Arc<dyn Extension> {
+        Arc::new(AliasLimit)
+    }
+}
+
+#[async_trait]
+impl Extension for AliasLimit {
+    async fn parse_query(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        query: &str,
+        variables: &Variables,
+        next: NextParseQuery<'_>,
+    ) -> ServerResult<ExecutableDocument> {
+        let document = next.run(ctx, query, variables).await?;
+        let operations = document.operations.iter().map(|(_, operation)| &operation.node.selection_set.node);
+        let fragments = document.fragments.values().map(|fragment| &fragment.node.selection_set.node);
+        let aliases: usize = operations.chain(fragments).map(count_aliases).sum();
+        if aliases > MAX_QUERY_ALIASES {
+            return Err(ServerError::new(
+                format!("query uses {} aliases, more than the limit of {}", aliases, MAX_QUERY_ALIASES),
+                None,
+            ));
+        }
+        Ok(document)
+    }
+}
+
+/// Counts the aliased fields of a selection set and of the selections nested in it.
+fn count_aliases(selection_set: &SelectionSet) -> usize {
+    selection_set
+        .items
+        .iter()
+        .map(|selection| match &selection.node {
+            Selection::Field(field) => {
+                usize::from(field.node.alias.is_some()) + count_aliases(&field.node.selection_set.node)
+            }
+            Selection::InlineFragment(fragment) => count_aliases(&fragment.node.selection_set.node),
+            Selection::FragmentSpread(_) => 0,
+        })
+        .sum()
+}
+
+/// Returns the services and the caller of a request.
+fn caller<'a>(ctx: &Context<'a>) -> async_graphql::Result<(&'a Services, &'a Principal)> {
+    Ok((ctx.data::<Services>()?, ctx.data::<Principal>()?))
+}
+
+/// Returns the device groups the caller is limited to, or None if the caller may read every
+/// device of the tenant.
+fn readable_groups(principal: &Principal) -> Option<Vec<String>> {
+    (!principal.has_scope(Scope::ReadTelemetry)).then(|| principal.groups_with(Scope::ReadTelemetry))
+}
+
+fn local_id(device_id: &str) -> String {
+    split_device_id(device_id).1.to_string()
+}
+
+/// Connectivity state of a device.
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+#[graphql(name = "DeviceStatus", remote = "crate::monitoring::DeviceStatus")]
+enum Status {
+    Unknown,
+    Online,
+    Offline,
+}
+
+/// Filters for listing devices, as for `GET /api/devices`.
+#[derive(InputObject, Default)]
+struct DeviceFilter {
+    device_type: Option<String>,
+    owner: Option<String>,
+    tag: Option<String>,
+    firmware_version: Option<String>,
+    /// Case-insensitive substring of the device id or name.
+    search: Option<String>,
+    status: Option<Status>,
+    last_seen_after: Option<DateTime<Utc>>,
+    last_seen_before: Option<DateTime<Utc>>,
+}
+
+/// Filters for listing alerts, as for `GET /api/alerts`.
+#[derive(InputObject, Default)]
+struct AlertFilter {
+    device_id: Option<String>,
+    /// Alerts about devices carrying this tag.
+    tag: Option<String>,
+    severity: Option<String>,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+}
+
+/// A page of devices.
+#[derive(SimpleObject)]
+struct DeviceConnection {
+    nodes: Vec<DeviceNode>,
+    /// Number of devices across all pages.
+    total_count: usize,
+    /// Passed as `after` to get the next page; missing on the last page.
+    next_cursor: Option<String>,
+}
+
+/// A page of past alerts.
+#[derive(SimpleObject)]
+struct AlertConnection {
+    nodes: Vec<Alert>,
+    /// Number of alerts across all pages.
+    total_count: usize,
+    /// Passed as `after` to get the next page; missing on the last page.
+    next_cursor: Option<String>,
+}
+
+/// A past alert.
+#[derive(SimpleObject)]
+struct Alert {
+    /// Increases with every alert.
+    id: u64,
+    title: String,
+    message: String,
+    severity: String,
+    device_id: Option<String>,
+    timestamp: DateTime<Utc>,
+}
+
+impl From<AlertRecord> for Alert {
+    fn from(record: AlertRecord) -> Self {
+        let notification = record.notification;
+        Alert {
+            id: record.id,
+            title: notification.title,
+            message: notification.message,
+            severity: notification.severity,
+            device_id: notification.device_id.as_deref().map(local_id),
+            timestamp: notification.timestamp,
+        }
+    }
+}
+
+/// An alert as it is raised.
+#[derive(SimpleObject)]
+#[graphql(name = "Notification")]
+struct NotificationEvent {
+    title: String,
+    message: String,
+    severity: String,
+    device_id: Option<String>,
+    timestamp: DateTime<Utc>,
+}
+
+impl From<Notification> for NotificationEvent {
+    fn from(notification: Notification) -> Self {
+        NotificationEvent {
+            title: notification.title,
+            message: notification.message,
+            severity: notification.severity,
+            device_id: notification.device_id,
+            timestamp: notification.timestamp,
+        }
+    }
+}
+
+/// Where a device is installed.
+#[derive(SimpleObject)]
+struct Location {
+    latitude: f64,
+    longitude: f64,
+    description: Option<String>,
+}
+
+/// The value of one metric.
+#[derive(SimpleObject)]
+struct MetricValue {
+    name: String,
+    value: f64,
+}
+
+/// Returns the values of a reading ordered by metric name.
+fn metric_values(data: &HashMap<String, f64>) -> Vec<MetricValue> {
+    let mut values: Vec<MetricValue> =
+        data.iter().map(|(name, value)| MetricValue { name: name.clone(), value: *value }).collect();
+    values.sort_by(|a, b| a.name.cmp(&b.name));
+    values
+}
+
+/// Aggregate of one metric over all of a device's analytics windows.
+#[derive(SimpleObject)]
+struct Metric {
+    name: String,
+    count: u64,
+    mean: f64,
+    min: f64,
+    max: f64,
+}
+
+/// Aggregate of one metric within one event-time window.
+#[derive(SimpleObject)]
+struct SeriesPoint {
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    count: u64,
+    mean: f64,
+    min: f64,
+    max: f64,
+    /// Set once late data can no longer change the window.
+    finalized: bool,
+}
+
+/// A change in a device's connectivity state.
+#[derive(SimpleObject)]
+struct Transition {
+    from: Status,
+    to: Status,
+    at: DateTime<Utc>,
+    /// Set when the transition happened while the device was flapping and was not announced.
+    suppressed: bool,
+}
+
+impl From<StatusTransition> for Transition {
+    fn from(transition: StatusTransition) -> Self {
+        Transition {
+            from: transition.from.into(),
+            to: transition.to.into(),
+            at: transition.at,
+            suppressed: transition.suppressed,
+        }
+    }
+}
+
+/// A device's connectivity and health score.
+#[derive(SimpleObject)]
+struct Health {
+    status: Status,
+    /// From 0 to 100.
+    score: u8,
+    /// What lowered the score.
+    reasons: Vec<String>,
+    last_seen: Option<DateTime<Utc>>,
+    /// Last reported battery level in percent.
+    battery_level: Option<f64>,
+    /// Last reported received signal strength in dBm.
+    rssi: Option<f64>,
+    message_count: u64,
+    parse_errors: u64,
+    reporting_rate_per_min: Option<f64>,
+    flapping: bool,
+    /// Recent connectivity transitions, oldest first.
+    history: Vec<Transition>,
+}
+
+/// A device of the caller's tenant, with its id as seen by the tenant.
+struct DeviceNode(Device);
+
+#[Object(name = "Device")]
+impl DeviceNode {
+    async fn id(&self) -> String {
+        local_id(&self.0.id)
+    }
+
+    async fn name(&self) -> &str {
+        &self.0.name
+    }
+
+    async fn device_type(&self) -> Option<&str> {
+        self.0.device_type.as_deref()
+    }
+
+    async fn firmware_version(&self) -> Option<&str> {
+        self.0.firmware_version.as_deref()
+    }
+
+    async fn tags(&self) -> &[String] {
+        &self.0.tags
+    }
+
+    async fn owner(&self) -> Option<&str> {
+        self.0.owner.as_deref()
+    }
+
+    async fn location(&self) -> Option<Location> {
+        self.0.location.as_ref().map(|location| Location {
+            latitude: location.latitude,
+            longitude: location.longitude,
+            description: location.description.clone(),
+        })
+    }
+
+    /// Free-form attributes supplied when the device was provisioned.
+    async fn attributes(&self) -> Json<HashMap<String, serde_json::Value>> {
+        Json(self.0.attributes.clone())
+    }
+
+    /// The most recent value of each metric.
+    #[graphql(complexity = "LIST_COMPLEXITY * child_complexity")]
+    async fn latest(&self) -> Vec<MetricValue> {
+        metric_values(&self.0.data)
+    }
+
+    /// Number of readings analytics counted for the device.
+    async fn reading_count(&self, ctx: &Context<'_>) -> async_graphql::Result<u64> {
+        let (services, _) = caller(ctx)?;
+        Ok(services.analytics.get_device_analytics(&self.0.id).unwrap_or(0))
+    }
+
+    /// Health as tracked by monitoring; missing until the device was first checked.
+    async fn health(&self, ctx: &Context<'_>) -> async_graphql::Result<Option<Health>> {
+        let (services, _) = caller(ctx)?;
+        let health = services.monitoring.get_device_health(&self.0.id);
+        let report = services.monitoring.get_health_report(&self.0.id);
+        let (health, report) = match (health, report) {
+            (Some(health), Some(report)) => (health, report),
+            _ => return Ok(None),
+        };
+        Ok(Some(Health {
+            status: health.status.into(),
+            score: report.score,
+            reasons: report.reasons,
+            last_seen: health.last_seen(),
+            battery_level: health.battery_level,
+            rssi: health.rssi,
+            message_count: health.message_count,
+            parse_errors: health.parse_errors,
+            reporting_rate_per_min: report.reporting_rate_per_min,
+            flapping: health.flapping,
+            history: health.history.into_iter().map(Into::into).collect(),
+        }))
+    }
+
+    /// Aggregates of every metric over the analytics windows kept for the device.
+    #[graphql(complexity = "LIST_COMPLEXITY * child_complexity")]
+    async fn metrics(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<Metric>> {
+        let (services, _) = caller(ctx)?;
+        let mut metrics: Vec<Metric> = services
+            .analytics
+            .get_device_metrics(&self.0.id)
+            .into_iter()
+            .map(|(name, aggregate)| Metric {
+                name,
+                count: aggregate.count,
+                mean: aggregate.mean(),
+                min: aggregate.min,
+                max: aggregate.max,
+            })
+            .collect();
+        metrics.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(metrics)
+    }
+
+    /// A metric window by window, oldest first, for the windows overlapping `since` to `until`.
+    #[graphql(complexity = "LIST_COMPLEXITY * child_complexity")]
+    async fn time_series(
+        &self,
+        ctx: &Context<'_>,
+        metric: String,
+        since: Option<DateTime<Utc>>,
+        until: Option<DateTime<Utc>>,
+    ) -> async_graphql::Result<Vec<SeriesPoint>> {
+        let (services, _) = caller(ctx)?;
+        let points = services
+            .analytics
+            .get_device_windows(&self.0.id)
+            .into_iter()
+            .filter(|window| !since.is_some_and(|since| window.end <= since))
+            .filter(|window| !until.is_some_and(|until| window.start >= until))
+            .filter_map(|window| {
+                let aggregate = window.metrics.get(&metric)?;
+                Some(SeriesPoint {
+                    start: window.start,
+                    end: window.end,
+                    count: aggregate.count,
+                    mean: aggregate.mean(),
+                    min: aggregate.min,
+                    max: aggregate.max,
+                    finalized: window.finalized,
+                })
+            })
+            .collect();
+        Ok(points)
+    }
+
+    /// Past alerts about the device, newest first.
+    #[graphql(complexity = "page_complexity(first, child_complexity)")]
+    async fn alerts(
+        &self,
+        ctx: &Context<'_>,
+        first: Option<usize>,
+        after: Option<String>,
+    ) -> async_graphql::Result<AlertConnection> {
+        let query = AlertQuery {
+            device_id: Some(local_id(&self.0.id)),
+            cursor: after,
+            limit: first,
+            ..AlertQuery::default()
+        };
+        list_alerts(ctx, query)
+    }
+}
+
+/// Lists the alerts matching the query among those the caller may read.
+fn list_alerts(ctx: &Context<'_>, mut query: AlertQuery) -> async_graphql::Result<AlertConnection> {
+    let (services, principal) = caller(ctx)?;
+    query.tenant_id = Some(principal.tenant_id.clone());
+    query.groups = readable_groups(principal);
+    let page = services.events.list_alerts(&query, &services.device_manager)?;
+    Ok(AlertConnection {
+        nodes: page.items.into_iter().map(Alert::from).collect(),
+        total_count: page.total,
+        next_cursor: page.next_cursor,
+    })
+}
+
+/// Entry points of queries.
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    /// Devices matching the filter, ordered by `sort`, e.g. `name` or `-last_seen`. Pages hold
+    /// `first` devices and start after the `nextCursor` given as `after`.
+    #[graphql(complexity = "page_complexity(first, child_complexity)")]
+    async fn devices(
+        &self,
+        ctx: &Context<'_>,
+        filter: Option<DeviceFilter>,
+        sort: Option<String>,
+        first: Option<usize>,
+        after: Option<String>,
+    ) -> async_graphql::Result<DeviceConnection> {
+        let (services, principal) = caller(ctx)?;
+        let filter = filter.unwrap_or_default();
+        let query = DeviceQuery {
+            device_type: filter.device_type,
+            owner: filter.owner,
+            tag: filter.tag,
+            firmware_version: filter.firmware_version,
+            search: filter.search,
+            status: filter.status.map(Into::into),
+            last_seen_after: filter.last_seen_after,
+            last_seen_before: filter.last_seen_before,
+            sort,
+            cursor: after,
+            limit: first,
+            tenant_id: Some(principal.tenant_id.clone()),
+            groups: readable_groups(principal),
+            ..DeviceQuery::default()
+        };
+        let page = services.device_manager.query_devices(&query, &services.monitoring.get_activity())?;
+        Ok(DeviceConnection {
+            nodes: page.devices.into_iter().map(DeviceNode).collect(),
+            total_count: page.total,
+            next_cursor: page.next_cursor,
+        })
+    }
+
+    /// One of the caller's devices, by the tenant's own id.
+    async fn device(&self, ctx: &Context<'_>, id: String) -> async_graphql::Result<Option<DeviceNode>> {
+        let (services, principal) = caller(ctx)?;
+        Ok(services.readable_device(principal, &id).map(DeviceNode))
+    }
+
+    /// Past alerts matching the filter, newest first unless sorted by `timestamp`, `severity`
+    /// or `device_id`.
+    #[graphql(complexity = "page_complexity(first, child_complexity)")]
+    async fn alerts(
+        &self,
+        ctx: &Context<'_>,
+        filter: Option<AlertFilter>,
+        sort: Option<String>,
+        first: Option<usize>,
+        after: Option<String>,
+    ) -> async_graphql::Result<AlertConnection> {
+        let filter = filter.unwrap_or_default();
+        let query = AlertQuery {
+            device_id: filter.device_id,
+            tag: filter.tag,
+            severity: filter.severity,
+            since: filter.since,
+            until: filter.until,
+            sort,
+            cursor: after,
+            limit: first,
+            ..AlertQuery::default()
+        };
+        list_alerts(ctx, query)
+    }
+}
+
+/// A reading that went through the processing pipeline.
+#[derive(SimpleObject)]
+struct ReadingEvent {
+    device_id: String,
+    values: Vec<MetricValue>,
+    event_time: DateTime<Utc>,
+    received_at: DateTime<Utc>,
+}
+
+/// A device going online, offline or back to unknown.
+#[derive(SimpleObject)]
+struct HealthChange {
+    device_id: String,
+    transition: Transition,
+}
+
+/// Builds the filter of a subscription, limited to what the caller may read. Events of the
+/// given devices, or of devices carrying any of the tags, pass; all of the caller's events
+/// pass when both are missing.
+fn subscription_filter(
+    principal: &Principal,
+    kind: EventKind,
+    devices: Option<Vec<String>>,
+    tags: Option<Vec<String>>,
+) -> SubscriptionFilter {
+    SubscriptionFilter {
+        devices: devices.unwrap_or_default(),
+        tags: tags.unwrap_or_default(),
+        events: vec![kind],
+        tenant_id: Some(principal.tenant_id.clone()),
+        groups: readable_groups(principal),
+    }
+}
+
+/// Streams the events passing the filter as converted by `pick`, with device ids as seen by
+/// their tenant. Events `pick` leaves out, such as notices of falling behind, are skipped.
+fn event_stream<T: Send + 'static>(
+    services: Services,
+    filter: SubscriptionFilter,
+    pick: fn(Event) -> Option<T>,
+) -> impl Stream<Item = T> {
+    let subscription = services.events.subscribe(filter);
+    futures::stream::unfold((services, subscription), move |(services, mut subscription)| async move {
+        loop {
+            let event = subscription.next(&services.device_manager).await?.into_local();
+            if let Some(item) = pick(event) {
+                return Some((item, (services, subscription)));
+            }
+        }
+    })
+}
+
+/// Live updates, as on `/api/stream`.
+pub struct SubscriptionRoot;
+
+#[Subscription]
+impl SubscriptionRoot {
+    /// Readings of the given devices or of devices carrying any of the tags.
+    async fn readings(
+        &self,
+        ctx: &Context<'_>,
+        devices: Option<Vec<String>>,
+        tags: Option<Vec<String>>,
+    ) -> async_graphql::Result<impl Stream<Item = ReadingEvent>> {
+        let (services, principal) = caller(ctx)?;
+        let filter = subscription_filter(principal, EventKind::Reading, devices, tags);
+        Ok(event_stream(services.clone(), filter, |event| match event {
+            Event::Reading { reading } => Some(ReadingEvent {
+                values: metric_values(&reading.data),
+                device_id: reading.device_id,
+                event_time: reading.event_time,
+                received_at: reading.received_at,
+            }),
+            _ => None,
+        }))
+    }
+
+    /// Connectivity changes of the given devices or of devices carrying any of the tags.
+    async fn health_changes(
+        &self,
+        ctx: &Context<'_>,
+        devices: Option<Vec<String>>,
+        tags: Option<Vec<String>>,
+    ) -> async_graphql::Result<impl Stream<Item = HealthChange>> {
+        let (services, principal) = caller(ctx)?;
+        let filter = subscription_filter(principal, EventKind::Health, devices, tags);
+        Ok(event_stream(services.clone(), filter, |event| match event {
+            Event::HealthTransition { device_id, transition } => Some(HealthChange {
+                device_id,
+                transition: transition.into(),
+            }),
+            _ => None,
+        }))
+    }
+
+    /// Alerts as they are raised about the given devices or devices carrying any of the tags.
+    async fn alerts(
+        &self,
+        ctx: &Context<'_>,
+        devices: Option<Vec<String>>,
+        tags: Option<Vec<String>>,
+    ) -> async_graphql::Result<impl Stream<Item = NotificationEvent>> {
+        let (services, principal) = caller(ctx)?;
+        let filter = subscription_filter(principal, EventKind::Alert, devices, tags);
+        Ok(event_stream(services.clone(), filter, |event| match event {
+            Event::Alert { notification } => Some(notification.into()),
+            _ => None,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::CredentialKind;
+    use crate::config::StreamConfig;
+    use chrono::TimeZone;
+    use futures::{FutureExt, StreamExt};
+
+    fn principal(tenant_id: &str, group_scopes: HashMap<String, Vec<Scope>>) -> Principal {
+        let scopes = if group_scopes.is_empty() { vec![Scope::ReadTelemetry] } else { Vec::new() };
+        Principal {
+            subject: "dashboard".to_string(),
+            scopes,
+            credential: CredentialKind::ApiKey,
+            tenant_id: tenant_id.to_string(),
+            group_scopes,
+            source_ip: None,
+        }
+    }
+
+    fn services() -> Services {
+        let device_manager = Arc::new(DeviceManager::new());
+        let mut roof = Device::new("acme/d1".to_string(), "Roof Sensor".to_string());
+        roof.tags = vec!["roof".to_string()];
+        device_manager.add_device(roof);
+        device_manager.add_device(Device::new("acme/d2".to_string(), "Cellar Sensor".to_string()));
+        device_manager.add_device(Device::new("d1".to_string(), "Operator Sensor".to_string()));
+        Services {
+            analytics: Arc::new(Analytics::new(device_manager.clone())),
+            monitoring: Arc::new(Monitoring::new(device_manager.clone())),
+            events: Arc::new(EventBus::new(StreamConfig::default())),
+            device_manager,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_queries_stitch_devices_metrics_and_alerts() {
+        let services = services();
+        let start = Utc.timestamp_opt(1_700_000_040, 0).unwrap();
+        for (offset, value) in [(0, 10.0), (30, 20.0), (120, 40.0)] {
+            let reading = HashMap::from([("temperature".to_string(), value)]);
+            services.analytics.process_event("acme/d1", &reading, start + chrono::Duration::seconds(offset));
+        }
+        let (title, severity) = ("Hot".to_string(), "critical".to_string());
+        let notification = Notification::new(title, "".to_string(), severity, Some("acme/d1".to_string()));
+        services.events.publish(Event::Alert { notification });
+        let schema = schema(services);
+
+        let query = r#"{
+            devices(sort: "-name") {
+                totalCount
+                nodes {
+                    id
+                    readingCount
+                    metrics { name count max }
+                    timeSeries(metric: "temperature") { count mean }
+                    alerts { nodes { severity deviceId } }
+                }
+            }
+        }"#;
+        let request = async_graphql::Request::new(query).data(principal("acme", HashMap::new()));
+        let response = schema.execute(request).await;
+        assert!(response.errors.is_empty(), "{:?}", response.errors);
+        let data = response.data.into_json().unwrap();
+        let devices = &data["devices"];
+        assert_eq!(devices["totalCount"], 2);
+        let roof = &devices["nodes"][0];
+        assert_eq!(roof["id"], "d1");
+        assert_eq!(roof["readingCount"], 3);
+        assert_eq!(roof["metrics"][0]["max"], 40.0);
+        assert_eq!(roof["timeSeries"][0]["mean"], 15.0);
+        assert_eq!(roof["alerts"]["nodes"][0]["deviceId"], "d1");
+
+        // Callers granted access to a device group only see its devices
+        let groups = HashMap::from([("roof".to_string(), vec![Scope::ReadTelemetry])]);
+        let request = async_graphql::Request::new("{ devices { totalCount } device(id: \"d2\") { name } }")
+            .data(principal("acme", groups));
+        let data = schema.execute(request).await.data.into_json().unwrap();
+        assert_eq!(data["devices"]["totalCount"], 1);
+        assert!(data["device"].is_null());
+    }
+
+    #[tokio::test]
+    async fn test_costly_queries_are_rejected() {
+        let schema = schema(services());
+        let execute = |query: String| {
+            let request = async_graphql::Request::new(query).data(principal("acme", HashMap::new()));
+            schema.execute(request)
+        };
+
+        let nested = "{ devices(first: 1000) { nodes { alerts(first: 1000) { nodes { id } } } } }".to_string();
+        let response = execute(nested).await;
+        assert!(response.errors[0].message.contains("complex"), "{:?}", response.errors);
+
+        let aliased: Vec<String> =
+            (0..=MAX_QUERY_ALIASES).map(|i| format!("d{}: device(id: \"d1\") {{ id }}", i)).collect();
+        let response = execute(format!("{{ {} }}", aliased.join(" "))).await;
+        assert!(response.errors[0].message.contains("aliases"), "{:?}", response.errors);
+
+        let response = execute("{ a: device(id: \"d1\") { id } b: device(id: \"d2\") { id } }".to_string()).await;
+        assert!(response.errors.is_empty(), "{:?}", response.errors);
+    }
+
+    #[tokio::test]
+    async fn test_subscriptions_stay_within_the_tenant() {
+        let services = services();
+        let events = services.events.clone();
+        let schema = schema(services);
+        let request = async_graphql::Request::new("subscription { alerts { title deviceId } }")
+            .data(principal("acme", HashMap::new()));
+        let mut stream = schema.execute_stream(request);
+        // Polling once subscribes to the event bus
+        assert!(stream.next().now_or_never().is_none());
+
+        for (title, device_id) in [("Operator", "d1"), ("Acme", "acme/d1")] {
+            let (title, severity) = (title.to_string(), "warning".to_string());
+            let notification = Notification::new(title, "".to_string(), severity, Some(device_id.to_string()));
+            events.publish(Event::Alert { notification });
+        }
+        let response = stream.next().await.unwrap();
+        let data = response.data.into_json().unwrap();
+        assert_eq!(data["alerts"]["title"], "Acme");
+        assert_eq!(data["alerts"]["deviceId"], "d1");
+    }
+}
@@ -1,36 +1,56 @@
 // ingestion_main.rs
 
 use std::env;
-use std::sync::{Arc, Mutex};
-use std::thread;
-use std::time::Duration;
+use tokio::sync::watch;
 use my_iot_platform::{
-    Config, DeviceManager, IngestionService, initialize_services, Result
+    Config, initialize_services, Result
 };
 
-fn main() -> Result<()> {
+#[tokio::main]
+async fn main() -> Result<()> {
     // Load configuration from a file or environment variables
     let config_path = env::var("CONFIG_PATH").unwrap_or_else(|_| "config.toml".into());
     let config = Config::from_file(config_path.into())?;
 
-    // Initialize services
-    let (ingestion_service, _, _, _) = initialize_services(config.clone())?;
+    // Initialize services; the API runs in api_main and sees the pipeline's state through storage
+    let (ingestion_service, storage_service, processing_service, _) = initialize_services(config)?;
 
-    // Start the ingestion service in a separate thread
-    let ingestion_service_arc = Arc::new(Mutex::new(ingestion_service));
-    let ingestion_thread = {
-        let ingestion_service = Arc::clone(&ingestion_service_arc);
-        thread::spawn(move || {
-            let service = ingestion_service.lock().unwrap();
-            service.run();
-        })
-    };
+    // Stop ingesting on Ctrl-C; processing and storage then drain what is already queued
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            println!("Shutdown requested");
+            let _ = shutdown_tx.send(true);
+        }
+    });
 
-    // Optionally, handle signals for graceful shutdown
-    // ...
+    // Run ingestion -> processing -> storage connected by bounded queues
+    // Plugins are hot reloaded from the plugin directory while the pipeline runs, and the
+    // scheduled jobs, such as the health snapshot, work on the state the pipeline updates
+    let storage_queue = processing_service.output_queue();
+    let plugins = processing_service.plugins();
+    let scheduler = processing_service.scheduler();
+    let (ingestion_result, _, _, _, _) = tokio::join!(
+        ingestion_service.run(shutdown_rx.clone()),
+        processing_service.run(),
+        storage_service.run_queue(storage_queue),
+        plugins.watch(shutdown_rx.clone()),
+        scheduler.run(shutdown_rx),
+    );
+    ingestion_result?;
 
-    // Wait for the ingestion thread to finish (it might run indefinitely)
-    ingestion_thread.join().expect("Ingestion thread has panicked");
+    // Keep the health of every device for the next start
+    storage_service.store_device_health(&processing_service.monitoring().get_monitoring_data())?;
+
+    let dedup = ingestion_service.get_dedup_metrics();
+    println!("Dropped {} duplicate messages out of {} checked", dedup.duplicates, dedup.checked);
+
+    for metrics in processing_service.get_queue_metrics() {
+        println!(
+            "Queue {}: enqueued {}, dequeued {}, dropped {}, spilled {}",
+            metrics.name, metrics.enqueued, metrics.dequeued, metrics.dropped, metrics.spilled
+        );
+    }
 
     Ok(())
 }
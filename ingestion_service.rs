@@ -1,76 +1,414 @@
 // ingestion_service.rs
 
-use crate::analytics::Analytics;
-use crate::config::IngestionConfig;
-use crate::device::{Device, DeviceManager};
+use crate::config::{DtlsConfig, IngestionConfig, RateLimitConfig};
+use crate::dedup::{DedupMetrics, Deduplicator, MessageKey};
+use crate::device::{DeviceManager, Reading};
+use crate::device_auth::{AuthFailure, DeviceAuth, DeviceEnvelope};
+use crate::monitoring::Monitoring;
+use crate::processing_service::ProcessingService;
+use crate::rate_limit::RateLimits;
+use crate::tenant::{scoped_device_id, split_device_id, TenantManager, DEFAULT_TENANT};
+use crate::tls::{device_id_from_certificate, CertificateStore};
+use futures::stream::{FuturesUnordered, StreamExt};
 use std::collections::HashMap;
-use std::net::UdpSocket;
-use std::sync::{Arc, Mutex};
-use std::thread;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tokio::sync::watch;
+use webrtc_dtls::config::Config as DtlsServerConfig;
+use webrtc_dtls::conn::DTLSConn;
+use webrtc_util::conn::conn_udp_listener::listen;
+use webrtc_util::conn::{Conn, Listener};
+
+/// Largest datagram accepted from a device, in bytes.
+const MAX_DATAGRAM_BYTES: usize = 1024;
+
+/// How often the nonces of expired signed datagrams are forgotten.
+const NONCE_SWEEP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
 
 /// Service responsible for ingesting data from IoT devices.
 pub struct IngestionService {
     device_manager: Arc<DeviceManager>,
-    analytics: Arc<Analytics>,
+    processing: Arc<ProcessingService>,
+    monitoring: Arc<Monitoring>,
+    dedup: Deduplicator,
+    tenants: Arc<TenantManager>,
+    device_auth: Arc<DeviceAuth>,
+    rate_limits: Arc<RateLimits>,
     config: IngestionConfig,
 }
 
+/// A datagram received from a device.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeviceMessage {
+    /// Key-value telemetry readings, optionally tagged with a device-supplied
+    /// `message_id` used to recognise retransmissions.
+    Telemetry {
+        message_id: Option<String>,
+        readings: HashMap<String, f64>,
+    },
+    /// A liveness signal without readings, sent as `{"type": "heartbeat"}`.
+    Heartbeat,
+}
+
+impl DeviceMessage {
+    /// Parses a raw datagram into a device message.
+    pub fn parse(data: &str) -> serde_json::Result<Self> {
+        let mut value: serde_json::Value = serde_json::from_str(data)?;
+        if value.get("type").and_then(|t| t.as_str()) == Some("heartbeat") {
+            return Ok(DeviceMessage::Heartbeat);
+        }
+        // The tenant token is a credential, not a reading
+        if let Some(object) = value.as_object_mut() {
+            object.remove("tenant_token");
+        }
+        // The id may be a string or a number; either way it is not a reading
+        let message_id = value
+            .as_object_mut()
+            .and_then(|object| object.remove("message_id"))
+            .map(|id| match id {
+                serde_json::Value::String(id) => id,
+                other => other.to_string(),
+            });
+        Ok(DeviceMessage::Telemetry {
+            message_id,
+            readings: serde_json::from_value(value)?,
+        })
+    }
+}
+
 impl IngestionService {
-    /// Creates a new IngestionService with references to the DeviceManager and Analytics services.
-    pub fn new(device_manager: Arc<DeviceManager>, analytics: Arc<Analytics>, config: IngestionConfig) -> Self {
-        IngestionService {
+    /// Creates a new IngestionService with references to the DeviceManager, Processing and Monitoring services.
+    pub fn new(
+        device_manager: Arc<DeviceManager>,
+        processing: Arc<ProcessingService>,
+        monitoring: Arc<Monitoring>,
+        config: IngestionConfig,
+    ) -> crate::Result<Self> {
+        Ok(IngestionService {
             device_manager,
-            analytics,
+            processing,
+            monitoring,
+            dedup: Deduplicator::new(config.dedup.clone()),
+            tenants: Arc::new(TenantManager::new(Vec::new())),
+            device_auth: Arc::new(DeviceAuth::new(config.auth.clone())?),
+            rate_limits: Arc::new(RateLimits::new(RateLimitConfig::default())),
             config,
+        })
+    }
+
+    /// Assigns devices to the tenant whose ingestion token they send as `tenant_token`.
+    /// Devices that send no token belong to the default tenant.
+    pub fn with_tenants(mut self, tenants: Arc<TenantManager>) -> Self {
+        self.tenants = tenants;
+        self
+    }
+
+    /// Checks datagrams against the credentials of the given service, shared with the API that
+    /// issues them.
+    pub fn with_device_auth(mut self, device_auth: Arc<DeviceAuth>) -> Self {
+        self.device_auth = device_auth;
+        self
+    }
+
+    /// Applies the rate limits and quotas of the given service, shared with the API.
+    pub fn with_rate_limits(mut self, rate_limits: Arc<RateLimits>) -> Self {
+        self.rate_limits = rate_limits;
+        self
+    }
+
+    /// Returns the tenant a datagram reports to, or None if it carries an unknown token.
+    fn tenant_for(&self, data: &str) -> Option<String> {
+        let token = serde_json::from_str::<serde_json::Value>(data)
+            .ok()
+            .and_then(|value| value.get("tenant_token").and_then(|token| token.as_str()).map(str::to_string));
+        match token {
+            Some(token) => self.tenants.tenant_for_ingestion_token(&token),
+            None => Some(DEFAULT_TENANT.to_string()),
         }
     }
 
-    /// Starts the ingestion service to listen for incoming data from IoT devices.
-    pub fn start(&self) {
-        let socket = UdpSocket::bind(&self.config.endpoint).expect("Failed to bind to UDP endpoint");
+    /// Runs the ingestion service, listening for incoming data from IoT devices until the
+    /// shutdown signal fires. The processing queue is closed on exit so in-flight readings
+    /// are drained by the services downstream.
+    pub async fn run(&self, shutdown: watch::Receiver<bool>) -> crate::Result<()> {
+        let dtls = async {
+            match &self.config.dtls {
+                Some(dtls) => self.run_dtls(dtls, shutdown.clone()).await,
+                None => Ok(()),
+            }
+        };
+        let result = tokio::try_join!(self.run_udp(shutdown.clone()), dtls, self.sweep_nonces(shutdown.clone()));
+
+        println!("Ingestion service stopped, draining queued readings");
+        self.processing.input_queue().close();
+        result.map(|_| ())
+    }
+
+    /// Periodically forgets the nonces of signed datagrams that could no longer be replayed.
+    async fn sweep_nonces(&self, mut shutdown: watch::Receiver<bool>) -> crate::Result<()> {
+        let mut interval = tokio::time::interval(NONCE_SWEEP_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => self.device_auth.sweep_nonces(chrono::Utc::now()),
+                _ = shutdown.changed() => break,
+            }
+        }
+        Ok(())
+    }
+
+    /// Receives plain datagrams on the configured endpoint.
+    async fn run_udp(&self, mut shutdown: watch::Receiver<bool>) -> crate::Result<()> {
+        let socket = UdpSocket::bind(&self.config.endpoint).await?;
         println!("Ingestion service listening on {}", self.config.endpoint);
 
-        // Clone Arcs to move into the thread
-        let device_manager = Arc::clone(&self.device_manager);
-        let analytics = Arc::clone(&self.analytics);
+        let mut buf = [0; MAX_DATAGRAM_BYTES];
+        loop {
+            tokio::select! {
+                received = socket.recv_from(&mut buf) => match received {
+                    Ok((number_of_bytes, src_addr)) => {
+                        self.handle_datagram(&buf[..number_of_bytes], src_addr, false).await;
+                    }
+                    Err(e) => {
+                        eprintln!("Couldn't receive a datagram: {}", e);
+                    }
+                },
+                _ = shutdown.changed() => break,
+            }
+        }
+        Ok(())
+    }
 
-        thread::spawn(move || {
-            let mut buf = [0; 1024];
+    /// Receives datagrams over DTLS, reloading the certificate when its files change. Devices
+    /// presenting a client certificate are identified by it and need no further credentials.
+    async fn run_dtls(&self, config: &DtlsConfig, mut shutdown: watch::Receiver<bool>) -> crate::Result<()> {
+        let certificates = CertificateStore::new(config.tls.clone())?;
+        let listener = listen(config.endpoint).await?;
+        println!("Ingestion service listening on {} (DTLS)", config.endpoint);
+
+        let handshake_timeout = certificates.handshake_timeout();
+        let idle_timeout = std::time::Duration::from_secs(config.idle_timeout_secs);
+        let reload = certificates.watch(shutdown.clone());
+        let accept = async {
+            let mut connections = FuturesUnordered::new();
             loop {
-                match socket.recv_from(&mut buf) {
-                    Ok((number_of_bytes, src_addr)) => {
-                        let received_data = &mut buf[..number_of_bytes];
-                        let data_str = String::from_utf8_lossy(received_data);
-
-                        // Parse the data into a HashMap
-                        let device_data: HashMap<String, f64> = match serde_json::from_str(&data_str) {
-                            Ok(data) => data,
-                            Err(e) => {
-                                eprintln!("Failed to parse data from {}: {}", src_addr, e);
-                                continue;
+                tokio::select! {
+                    accepted = listener.accept() => match accepted {
+                        Ok((conn, src_addr)) => match certificates.dtls_config() {
+                            Ok(dtls_config) => {
+                                connections.push(self.handle_dtls_connection(
+                                    conn,
+                                    dtls_config,
+                                    src_addr,
+                                    handshake_timeout,
+                                    idle_timeout,
+                                ));
                             }
-                        };
+                            Err(e) => eprintln!("Couldn't set up DTLS for {}: {}", src_addr, e),
+                        },
+                        Err(e) => eprintln!("Couldn't accept a DTLS connection: {}", e),
+                    },
+                    Some(()) = connections.next(), if !connections.is_empty() => {}
+                    _ = shutdown.changed() => break,
+                }
+            }
+        };
+        tokio::join!(accept, reload);
+        if let Err(e) = listener.close().await {
+            eprintln!("Couldn't close the DTLS listener: {}", e);
+        }
+        Ok(())
+    }
 
-                        // Extract device ID from the data or source address
-                        let device_id = src_addr.to_string(); // Placeholder for actual device ID extraction logic
+    /// Completes the DTLS handshake with a device and handles its datagrams until it goes away
+    /// or stays silent for the idle timeout.
+    async fn handle_dtls_connection(
+        &self,
+        conn: Arc<dyn Conn + Send + Sync>,
+        config: DtlsServerConfig,
+        src_addr: SocketAddr,
+        handshake_timeout: std::time::Duration,
+        idle_timeout: std::time::Duration,
+    ) {
+        let handshake = tokio::time::timeout(handshake_timeout, DTLSConn::new(conn.clone(), config, false, None));
+        let conn = match handshake.await {
+            Ok(Ok(conn)) => conn,
+            Ok(Err(e)) => {
+                eprintln!("DTLS handshake with {} failed: {}", src_addr, e);
+                self.device_auth.record_failure(src_addr.ip(), AuthFailure::HandshakeFailed);
+                return;
+            }
+            Err(_) => {
+                eprintln!("DTLS handshake with {} timed out", src_addr);
+                self.device_auth.record_failure(src_addr.ip(), AuthFailure::HandshakeFailed);
+                if let Err(e) = conn.close().await {
+                    eprintln!("Couldn't close the DTLS connection with {}: {}", src_addr, e);
+                }
+                return;
+            }
+        };
+        let certified_device = conn
+            .connection_state()
+            .await
+            .peer_certificates
+            .first()
+            .and_then(|der| device_id_from_certificate(der));
+
+        let mut buf = [0; MAX_DATAGRAM_BYTES];
+        while let Ok(Ok(number_of_bytes)) = tokio::time::timeout(idle_timeout, conn.recv(&mut buf)).await {
+            let data = &buf[..number_of_bytes];
+            match &certified_device {
+                Some(device_id) if self.admit_source(src_addr) => {
+                    let tenant_id = split_device_id(device_id).0.to_string();
+                    self.handle_message(&tenant_id, device_id.clone(), data, src_addr).await;
+                }
+                Some(_) => {}
+                None => self.handle_datagram(data, src_addr, true).await,
+            }
+        }
+        if let Err(e) = conn.close().await {
+            eprintln!("Couldn't close the DTLS connection with {}: {}", src_addr, e);
+        }
+    }
+
+    /// Authenticates a single datagram and queues its readings for processing. Device tokens
+    /// are only accepted when the datagram arrived encrypted.
+    async fn handle_datagram(&self, received_data: &[u8], src_addr: SocketAddr, encrypted: bool) {
+        // Floods are turned away before any signature is checked
+        if !self.admit_source(src_addr) {
+            return;
+        }
+        if let Some((tenant_id, device_id, payload)) = self.authenticate(received_data, src_addr, encrypted) {
+            self.handle_message(&tenant_id, device_id, &payload, src_addr).await;
+        }
+    }
+
+    /// Returns the tenant, the platform-wide device id and the payload of a datagram, or None
+    /// if it was rejected.
+    fn authenticate(
+        &self,
+        received_data: &[u8],
+        src_addr: SocketAddr,
+        encrypted: bool,
+    ) -> Option<(String, String, Vec<u8>)> {
+        let data_str = String::from_utf8_lossy(received_data);
+
+        // Devices only ever report to their own tenant, so a wrong token is not attributed anywhere
+        let tenant_id = match self.tenant_for(&data_str) {
+            Some(tenant_id) => tenant_id,
+            None => {
+                eprintln!("Rejected datagram from {} with an unknown tenant token", src_addr);
+                return None;
+            }
+        };
 
-                        // Update device data and analytics
-                        Self::update_device(&device_manager, &device_id, device_data.clone());
-                        analytics.process_device_data(&device_id, &device_data);
+        // Authenticated datagrams name their device; unsigned ones, where still accepted, are
+        // attributed to their source address
+        match DeviceEnvelope::parse(received_data) {
+            Some(envelope) => {
+                let device_id = scoped_device_id(&tenant_id, &envelope.device_id);
+                if let Err(failure) = self.device_auth.verify(&device_id, &envelope, encrypted) {
+                    self.reject(src_addr, failure);
+                    return None;
+                }
+                Some((tenant_id, device_id, envelope.payload.into_bytes()))
+            }
+            None if self.device_auth.is_required() => {
+                self.reject(src_addr, AuthFailure::Unauthenticated);
+                None
+            }
+            None => {
+                let device_id = scoped_device_id(&tenant_id, &src_addr.to_string());
+                Some((tenant_id, device_id, received_data.to_vec()))
+            }
+        }
+    }
+
+    /// Parses the payload of an authenticated datagram and queues its readings for processing.
+    async fn handle_message(&self, tenant_id: &str, device_id: String, received_data: &[u8], src_addr: SocketAddr) {
+        if let Err(rejected) = self.rate_limits.check_message(tenant_id, &device_id, received_data.len()) {
+            eprintln!("Dropped datagram from {} for {}: {}", src_addr, device_id, rejected);
+            return;
+        }
+        let data_str = String::from_utf8_lossy(received_data);
+
+        // Parse the data into a telemetry or heartbeat message, decoding it with the plugin
+        // configured for the device's type first if there is one
+        let decoder = self
+            .device_manager
+            .get_device(&device_id)
+            .and_then(|device| device.device_type)
+            .and_then(|device_type| self.processing.plugins().decoder_for(&device_type));
+        let parsed = match decoder {
+            Some(decoder) => self
+                .processing
+                .plugins()
+                .decode(&decoder, received_data)
+                .and_then(|decoded| DeviceMessage::parse(&decoded).map_err(|e| e.into())),
+            None => DeviceMessage::parse(&data_str).map_err(|e| e.into()),
+        };
+        let message = match parsed {
+            Ok(message) => message,
+            Err(e) => {
+                eprintln!("Failed to parse data from {}: {}", src_addr, e);
+                self.monitoring.record_parse_error(&device_id);
+                return;
+            }
+        };
+
+        match message {
+            DeviceMessage::Heartbeat => self.monitoring.record_heartbeat(&device_id),
+            DeviceMessage::Telemetry { message_id, readings: device_data } => {
+                // Drop retransmissions before they are processed and counted twice. A message that
+                // is not queued after all is forgotten again so that its retransmission gets through.
+                if self.dedup.is_duplicate(&device_id, message_id.as_deref(), &device_data) {
+                    return;
+                }
+                let key = MessageKey::of(message_id.as_deref(), &device_data);
+
+                // Register the device within the tenant's device quota and hand the reading to the
+                // processing pipeline, waiting or shedding load according to the queue's backpressure policy
+                if self.device_manager.get_device(&device_id).is_none()
+                    && !self.tenants.can_add_device(tenant_id, &self.device_manager)
+                {
+                    eprintln!("Dropped reading from {}: tenant {} reached its device quota", src_addr, tenant_id);
+                    if let Some(key) = &key {
+                        self.dedup.forget(&device_id, key);
                     }
-                    Err(e) => {
-                        eprintln!("Couldn't receive a datagram: {}", e);
+                    return;
+                }
+                self.device_manager.ensure_device(&device_id);
+                let reading = Reading::from_device(device_id.clone(), device_data);
+                if let Err(e) = self.processing.input_queue().push(reading).await {
+                    eprintln!("Failed to queue reading from {}: {}", src_addr, e);
+                    if let Some(key) = &key {
+                        self.dedup.forget(&device_id, key);
                     }
                 }
             }
-        });
+        }
+    }
+
+    /// Returns false if the source address exceeded its rate limit.
+    fn admit_source(&self, src_addr: SocketAddr) -> bool {
+        match self.rate_limits.check_source(src_addr.ip()) {
+            Ok(()) => true,
+            Err(rejected) => {
+                eprintln!("Dropped datagram from {}: {}", src_addr, rejected);
+                false
+            }
+        }
+    }
+
+    /// Counts a datagram that failed to authenticate against its source address.
+    fn reject(&self, src_addr: SocketAddr, failure: AuthFailure) {
+        eprintln!("Rejected datagram from {}: {}", src_addr, failure);
+        self.device_auth.record_failure(src_addr.ip(), failure);
     }
 
-    /// Updates the device's data in the DeviceManager.
-    fn update_device(device_manager: &Arc<DeviceManager>, device_id: &str, data: HashMap<String, f64>) {
-        let mut devices = device_manager.devices.lock().unwrap();
-        let device = devices.entry(device_id.to_string()).or_insert_with(|| Device::new(device_id.to_string(), "Unnamed Device".to_string()));
-        device.update_data(data);
+    /// Returns how many retransmitted messages were dropped.
+    pub fn get_dedup_metrics(&self) -> DedupMetrics {
+        self.dedup.get_metrics()
     }
 }
 
@@ -2,22 +2,50 @@
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::config::IngestionConfig;
+    use crate::config::{DedupConfig, DeviceAuthConfig, IngestionConfig};
+    use crate::device_auth::{sign_datagram, DeviceAuth};
     use crate::device::{Device, DeviceManager};
     use crate::analytics::Analytics;
+    use crate::config::{PluginConfig, ProcessingConfig, QueueConfig, SchedulerConfig};
+    use crate::plugin::PluginHost;
+    use crate::monitoring::Monitoring;
+    use crate::processing_service::ProcessingService;
     use std::net::{IpAddr, Ipv4Addr, SocketAddr};
     use std::sync::{Arc, Mutex};
     use std::collections::HashMap;
 
+    // Most tests send plain datagrams, which are only accepted while device auth is optional
     fn setup_ingestion_service() -> IngestionService {
+        let auth = DeviceAuthConfig {
+            required: false,
+            ..DeviceAuthConfig::default()
+        };
+        setup_ingestion_service_with_auth(auth)
+    }
+
+    fn setup_ingestion_service_with_auth(auth: DeviceAuthConfig) -> IngestionService {
         let device_manager = Arc::new(DeviceManager::new());
-        let analytics = Arc::new(Analytics::new());
+        let analytics = Arc::new(Analytics::new(device_manager.clone()));
+        let monitoring = Arc::new(Monitoring::new(device_manager.clone()));
+        let processing_config = ProcessingConfig {
+            processing_interval: 1000,
+            pipelines: HashMap::new(),
+            queue: QueueConfig::default(),
+            scheduler: SchedulerConfig::default(),
+        };
+        let plugins = Arc::new(PluginHost::new(PluginConfig::default()).unwrap());
+        let processing = Arc::new(
+            ProcessingService::new(device_manager.clone(), analytics, monitoring.clone(), plugins, processing_config).unwrap(),
+        );
         let config = IngestionConfig {
             endpoint: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 12345),
+            dedup: DedupConfig::default(),
+            auth,
+            dtls: None,
             // ... other config options
         };
 
-        IngestionService::new(device_manager, analytics, config)
+        IngestionService::new(device_manager, processing, monitoring, config).unwrap()
     }
 
     #[test]
@@ -65,6 +93,84 @@ mod tests {
         assert_eq!(device.data.get("humidity"), Some(&45.0));
     }
 
+    #[test]
+    fn test_parse_heartbeat_and_telemetry() {
+        assert_eq!(DeviceMessage::parse(r#"{"type": "heartbeat"}"#).unwrap(), DeviceMessage::Heartbeat);
+        assert_eq!(
+            DeviceMessage::parse(r#"{"temperature": 22.5}"#).unwrap(),
+            DeviceMessage::Telemetry {
+                message_id: None,
+                readings: HashMap::from([("temperature".to_string(), 22.5)]),
+            }
+        );
+        assert_eq!(
+            DeviceMessage::parse(r#"{"message_id": 7, "temperature": 22.5}"#).unwrap(),
+            DeviceMessage::Telemetry {
+                message_id: Some("7".to_string()),
+                readings: HashMap::from([("temperature".to_string(), 22.5)]),
+            }
+        );
+        assert!(DeviceMessage::parse(r#"{"type": "unknown"}"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_datagrams_are_assigned_to_the_token_tenant() {
+        let mut acme = crate::tenant::Tenant::new("acme".to_string(), "Acme".to_string());
+        acme.settings.ingestion_token_sha256 = vec![crate::auth::hash_secret("acme-devices")];
+        acme.settings.max_devices = Some(1);
+        let tenants = Arc::new(crate::tenant::TenantManager::new(vec![acme]));
+        let ingestion_service = setup_ingestion_service().with_tenants(tenants);
+        let device_manager = ingestion_service.device_manager.clone();
+        let first = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 5000);
+        let second = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 5001);
+
+        let datagram = br#"{"tenant_token": "acme-devices", "temperature": 22.5}"#;
+        ingestion_service.handle_datagram(datagram, first, false).await;
+        assert!(device_manager.get_device("acme/127.0.0.1:5000").is_some());
+        assert!(device_manager.get_device("127.0.0.1:5000").is_none());
+
+        // Over the device quota and with a wrong token nothing is registered
+        ingestion_service.handle_datagram(datagram, second, false).await;
+        ingestion_service.handle_datagram(br#"{"tenant_token": "guess", "temperature": 1.0}"#, second, false).await;
+        assert_eq!(device_manager.list_devices().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_only_authenticated_datagrams_are_accepted() {
+        let device_auth = Arc::new(DeviceAuth::new(DeviceAuthConfig::default()).unwrap());
+        let ingestion_service = setup_ingestion_service_with_auth(DeviceAuthConfig::default())
+            .with_device_auth(device_auth.clone());
+        let device_manager = ingestion_service.device_manager.clone();
+        device_manager.add_device(Device::new("sensor1".to_string(), "Temperature Sensor".to_string()));
+        let issued = device_auth.issue_credentials("sensor1").unwrap();
+        let source = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 5000);
+
+        // Plain datagrams no longer create devices
+        ingestion_service.handle_datagram(br#"{"temperature": 22.5}"#, source, false).await;
+        assert_eq!(device_manager.list_devices().len(), 1);
+
+        let payload = r#"{"type": "heartbeat"}"#;
+        let timestamp = chrono::Utc::now().timestamp();
+        let datagram = serde_json::json!({
+            "device_id": "sensor1",
+            "payload": payload,
+            "timestamp": timestamp,
+            "nonce": "n1",
+            "signature": sign_datagram(&issued.secret, "sensor1", timestamp, "n1", payload),
+        })
+        .to_string();
+        ingestion_service.handle_datagram(datagram.as_bytes(), source, false).await;
+        assert!(ingestion_service.monitoring.get_device_health("sensor1").is_some());
+
+        // Replaying the same datagram and sending a token in the clear are both counted against the source
+        ingestion_service.handle_datagram(datagram.as_bytes(), source, false).await;
+        let forged = serde_json::json!({"device_id": "sensor1", "payload": payload, "token": "iotd_guess"}).to_string();
+        ingestion_service.handle_datagram(forged.as_bytes(), source, false).await;
+        let failures = device_auth.get_auth_failures();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].count, 3);
+    }
+
     // Additional tests for other ingestion service functionality can be added here
 }
 ```
@@ -8,7 +8,27 @@ pub mod monitoring;
 pub mod ingestion_service;
 pub mod storage_service;
 pub mod processing_service;
+pub mod pipeline;
+pub mod expression;
+pub mod queue;
+pub mod dedup;
+pub mod scheduler;
+pub mod plugin;
+pub mod events;
+pub mod auth;
+pub mod device_auth;
+pub mod tenant;
+pub mod users;
+pub mod audit;
+pub mod tls;
+pub mod rate_limit;
+pub mod graphql;
+pub mod listing;
+pub mod openapi;
 pub mod api_service;
+pub mod notification;
+
+use std::sync::Arc;
 
 // Re-export the main components of the library for easier access
 pub use config::Config;
@@ -19,6 +39,14 @@ pub use ingestion_service::IngestionService;
 pub use storage_service::StorageService;
 pub use processing_service::ProcessingService;
 pub use api_service::APIService;
+pub use notification::{Notification, NotificationDispatcher};
+pub use plugin::PluginHost;
+pub use events::{Event, EventBus};
+pub use audit::AuditLog;
+pub use auth::{AuthService, Scope};
+pub use device_auth::DeviceAuth;
+pub use rate_limit::RateLimits;
+pub use tenant::{Tenant, TenantManager};
 
 // You might also want to define some shared types or utilities that are used across the modules
 // For example, a Result type that is used throughout the library could be defined here
@@ -31,15 +59,80 @@ pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + S
 // defined here. For example, a function to create and initialize all services might look like this:
 
 /// Initializes all services and returns a tuple of their instances.
-pub fn initialize_services(config: Config) -> Result<(IngestionService, StorageService, ProcessingService, APIService)> {
-    let device_manager = DeviceManager::new();
-    let analytics = Analytics::new();
-    let monitoring = Monitoring::new();
-
-    let ingestion_service = IngestionService::new(device_manager.clone(), analytics.clone());
-    let storage_service = StorageService::new(config.storage_config, device_manager.clone());
-    let processing_service = ProcessingService::new(device_manager, analytics, monitoring);
-    let api_service = APIService::new();
+pub fn initialize_services(
+    config: Config,
+) -> Result<(IngestionService, Arc<StorageService>, Arc<ProcessingService>, APIService)> {
+    let device_manager = Arc::new(DeviceManager::new());
+    // Every service sees devices by their tenant-scoped ids and applies the tenant's settings
+    let tenants = Arc::new(TenantManager::new(config.tenants));
+    // Readings are stored in one partition per tenant, kept for the tenant's retention
+    let storage_service =
+        Arc::new(StorageService::new(config.storage_config, device_manager.clone()).with_tenants(tenants.clone()));
+    // Readings, health transitions and alerts are pushed to streaming API clients; alerts are
+    // stored for listing
+    let events = Arc::new(EventBus::new(config.api_config.stream.clone()).with_storage(storage_service.clone())?);
+    // Alerts raised by monitoring and analytics go to the stream and to the configured sinks
+    let notifications =
+        Arc::new(NotificationDispatcher::from_config(&config.notification_config).with_event_bus(events.clone()));
+    let analytics = Arc::new(
+        Analytics::with_config(device_manager.clone(), config.analytics_config)
+            .with_tenants(tenants.clone())
+            .with_notifications(notifications.clone()),
+    );
+    let monitoring = Arc::new(
+        Monitoring::with_config(device_manager.clone(), config.monitoring_config)
+            .with_event_bus(events.clone())
+            .with_notifications(notifications),
+    );
+
+    let plugins = Arc::new(PluginHost::new(config.plugin_config)?);
+
+    let processing_service = Arc::new(ProcessingService::new(
+        device_manager.clone(),
+        analytics.clone(),
+        monitoring.clone(),
+        plugins,
+        config.processing_config,
+    )?
+    .with_event_bus(events.clone())
+    .with_tenants(tenants.clone())
+    .with_storage(storage_service.clone())?);
+    // Devices and API clients share the limits and quotas of their tenant
+    let rate_limits = Arc::new(
+        RateLimits::new(config.rate_limits)
+            .with_tenants(tenants.clone())
+            .with_storage(storage_service.clone()),
+    );
+    // Devices authenticate their datagrams with the credentials issued over the API
+    let device_auth = Arc::new(DeviceAuth::new(config.ingestion_config.auth.clone())?);
+    let ingestion_service = IngestionService::new(
+        device_manager.clone(),
+        processing_service.clone(),
+        monitoring.clone(),
+        config.ingestion_config,
+    )?
+    .with_tenants(tenants.clone())
+    .with_device_auth(device_auth.clone())
+    .with_rate_limits(rate_limits.clone());
+    // Pick up the health state persisted before the last shutdown
+    monitoring.restore_monitoring_data(storage_service.load_device_health()?);
+    let auth = Arc::new(AuthService::new(config.api_config.auth.clone())?);
+    let audit_config = &config.api_config.audit;
+    let audit = Arc::new(AuditLog::new(Some(audit_config.path.clone()), audit_config.anchor_path.clone())?);
+    let api_service = APIService::new(
+        config.api_config,
+        auth,
+        device_manager.clone(),
+        analytics.clone(),
+        monitoring.clone(),
+        processing_service.scheduler(),
+        events,
+    )
+    .with_tenants(tenants)
+    .with_device_auth(device_auth)
+    .with_rate_limits(rate_limits)
+    .with_audit_log(audit)
+    .with_storage(storage_service.clone());
 
     Ok((ingestion_service, storage_service, processing_service, api_service))
 }
@@ -48,13 +48,10 @@ mod tests {
         let device_manager = Arc::new(DeviceManager::new());
         let monitoring = Monitoring::new(device_manager.clone());
         let device_id = "device5".to_string();
-        let device_health = DeviceHealth {
-            last_update: std::time::Instant::now(),
-            is_online: true,
-        };
-        monitoring.update_device_health(&device_id, device_health.clone()).unwrap();
+        monitoring.update_device_health(&device_id);
         let retrieved_health = monitoring.get_device_health(&device_id).unwrap();
-        assert_eq!(retrieved_health.is_online, device_health.is_online);
+        assert!(retrieved_health.is_online());
+        assert!(retrieved_health.last_update.is_some());
     }
 
     #[test]
@@ -62,17 +59,35 @@ mod tests {
         let config = Config {
             ingestion_config: IngestionConfig {
                 endpoint: "127.0.0.1:8080".parse().unwrap(),
+                dedup: DedupConfig::default(),
+                auth: DeviceAuthConfig::default(),
+                dtls: None,
             },
             storage_config: StorageConfig {
                 database_url: "sqlite::memory:".to_string(),
                 max_connections: 5,
+                health_snapshot_path: None,
+                data_dir: None,
             },
             processing_config: ProcessingConfig {
                 processing_interval: 1000,
+                pipelines: HashMap::new(),
+                queue: QueueConfig::default(),
+                scheduler: SchedulerConfig::default(),
             },
             api_config: APIConfig {
                 api_endpoint: "127.0.0.1:8081".parse().unwrap(),
+                stream: StreamConfig::default(),
+                auth: AuthConfig::default(),
+                tls: None,
+                audit: AuditConfig::default(),
             },
+            notification_config: NotificationConfig::default(),
+            monitoring_config: MonitoringConfig::default(),
+            analytics_config: AnalyticsConfig::default(),
+            plugin_config: PluginConfig::default(),
+            tenants: Vec::new(),
+            rate_limits: RateLimitConfig::default(),
         };
 
         let (ingestion_service, storage_service, processing_service, api_service) =
@@ -0,0 +1,191 @@
+// listing.rs
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+
+/// Number of items returned per page unless the query asks for another limit.
+pub const DEFAULT_PAGE_SIZE: usize = 50;
+/// Largest page that can be requested.
+pub const MAX_PAGE_SIZE: usize = 1000;
+
+/// The order of a listing, written as the field name, prefixed with `-` for descending.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sort {
+    pub field: String,
+    pub descending: bool,
+}
+
+impl Sort {
+    /// Parses the sort parameter of a query, accepting only the given fields.
+    pub fn parse(sort: Option<&str>, fields: &[&str], default: &str) -> Result<Sort, String> {
+        let sort = sort.map(str::trim).filter(|sort| !sort.is_empty()).unwrap_or(default);
+        let (field, descending) = match sort.strip_prefix('-') {
+            Some(field) => (field, true),
+            None => (sort, false),
+        };
+        if !fields.contains(&field) {
+            return Err(format!("cannot sort by {}, only by {}", field, fields.join(", ")));
+        }
+        Ok(Sort {
+            field: field.to_string(),
+            descending,
+        })
+    }
+
+    fn name(&self) -> String {
+        format!("{}{}", if self.descending { "-" } else { "" }, self.field)
+    }
+}
+
+/// The sort key of an item: the value of the sorted field, then the item's id so that
+/// items with equal values keep a stable order.
+pub type SortKey = (Option<String>, String);
+
+/// Formats a time as a sort key that orders like the time itself.
+pub fn time_key(time: Option<DateTime<Utc>>) -> Option<String> {
+    time.map(|time| time.format("%Y-%m-%dT%H:%M:%S%.9fZ").to_string())
+}
+
+/// Where the next page starts: just after the item with this key, in this order.
+#[derive(Debug, Serialize, Deserialize)]
+struct Cursor {
+    sort: String,
+    key: Option<String>,
+    id: String,
+}
+
+impl Cursor {
+    fn encode(&self) -> String {
+        hex::encode(serde_json::to_vec(self).expect("cursors serialize to JSON"))
+    }
+
+    fn decode(cursor: &str) -> Result<Cursor, String> {
+        hex::decode(cursor)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .ok_or_else(|| "invalid cursor".to_string())
+    }
+}
+
+/// One page of a listing.
+#[derive(Debug, Clone)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// Number of items across all pages.
+    pub total: usize,
+    /// Passed as `cursor` to get the next page; missing on the last page.
+    pub next_cursor: Option<String>,
+}
+
+/// Sorts the items and returns the page starting after the cursor, or after skipping
+/// `offset` items when there is no cursor. Cursors keep working while items are added or
+/// removed, as they point at a position in the order rather than an index.
+pub fn paginate<T>(
+    mut items: Vec<T>,
+    sort: &Sort,
+    key: impl Fn(&T) -> SortKey,
+    cursor: Option<&str>,
+    offset: usize,
+    limit: Option<usize>,
+) -> Result<Page<T>, String> {
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let order = |a: &SortKey, b: &SortKey| if sort.descending { b.cmp(a) } else { a.cmp(b) };
+    items.sort_by(|a, b| order(&key(a), &key(b)));
+    let total = items.len();
+
+    let start = match cursor {
+        Some(cursor) => {
+            let cursor = Cursor::decode(cursor)?;
+            if cursor.sort != sort.name() {
+                return Err(format!("the cursor was issued for sorting by {}", cursor.sort));
+            }
+            let after = (cursor.key, cursor.id);
+            items.partition_point(|item| order(&key(item), &after) != Ordering::Greater)
+        }
+        None => offset.min(total),
+    };
+    let mut page: Vec<T> = items.into_iter().skip(start).take(limit + 1).collect();
+    let next_cursor = if page.len() > limit {
+        page.truncate(limit);
+        page.last().map(|last| {
+            let (key, id) = key(last);
+            Cursor { sort: sort.name(), key, id }.encode()
+        })
+    } else {
+        None
+    };
+    Ok(Page {
+        items: page,
+        total,
+        next_cursor,
+    })
+}
+
+/// Parses a comma separated list of fields, as in `?fields=id,name,tags`.
+pub fn parse_fields(fields: Option<&str>) -> Option<Vec<String>> {
+    let fields: Vec<String> = fields?
+        .split(',')
+        .map(|field| field.trim().to_string())
+        .filter(|field| !field.is_empty())
+        .collect();
+    (!fields.is_empty()).then_some(fields)
+}
+
+/// Keeps only the requested top-level fields of a JSON object, and always its `id`.
+/// Unknown fields are ignored.
+pub fn select_fields(value: serde_json::Value, fields: &[String]) -> serde_json::Value {
+    match value {
+        serde_json::Value::Object(object) => object
+            .into_iter()
+            .filter(|(name, _)| name == "id" || fields.contains(name))
+            .collect::<serde_json::Map<_, _>>()
+            .into(),
+        value => value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sort(sort: &str) -> Sort {
+        Sort::parse(Some(sort), &["id", "name"], "id").unwrap()
+    }
+
+    #[test]
+    fn test_cursor_pages_through_items_in_a_stable_order() {
+        // Equal names are ordered by id
+        let items: Vec<(String, String)> = [("d3", "b"), ("d1", "a"), ("d2", "b"), ("d4", "c")]
+            .iter()
+            .map(|(id, name)| (id.to_string(), name.to_string()))
+            .collect();
+        let key = |item: &(String, String)| (Some(item.1.clone()), item.0.clone());
+
+        let first = paginate(items.clone(), &sort("-name"), key, None, 0, Some(2)).unwrap();
+        assert_eq!(first.total, 4);
+        let ids: Vec<&str> = first.items.iter().map(|item| item.0.as_str()).collect();
+        assert_eq!(ids, ["d4", "d3"]);
+
+        // Items added before the cursor do not shift the next page
+        let mut more = items.clone();
+        more.push(("d0".to_string(), "z".to_string()));
+        let cursor = first.next_cursor.unwrap();
+        let second = paginate(more, &sort("-name"), key, Some(&cursor), 0, Some(2)).unwrap();
+        let ids: Vec<&str> = second.items.iter().map(|item| item.0.as_str()).collect();
+        assert_eq!(ids, ["d2", "d1"]);
+        assert!(second.next_cursor.is_none());
+
+        assert!(paginate(items.clone(), &sort("name"), key, Some(&cursor), 0, None).is_err());
+        assert!(paginate(items, &sort("name"), key, Some("not a cursor"), 0, None).is_err());
+        assert!(Sort::parse(Some("owner"), &["id", "name"], "id").is_err());
+    }
+
+    #[test]
+    fn test_sparse_fieldsets_keep_the_id() {
+        let device = serde_json::json!({"id": "d1", "name": "Sensor", "tags": ["roof"], "owner": null});
+        let fields = parse_fields(Some("name, tags,")).unwrap();
+        assert_eq!(select_fields(device, &fields), serde_json::json!({"id": "d1", "name": "Sensor", "tags": ["roof"]}));
+        assert!(parse_fields(Some(" ")).is_none());
+    }
+}
@@ -1,67 +1,438 @@
 // monitoring.rs
 
+use crate::config::MonitoringConfig;
 use crate::device::{Device, DeviceManager};
-use std::collections::HashMap;
+use crate::events::{Event, EventBus};
+use crate::notification::{Notification, NotificationDispatcher};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::sync::{Arc, Mutex};
-use std::time::{Duration, Instant};
+use chrono::{DateTime, Utc};
+use std::time::Duration;
 
 /// Monitoring service that keeps track of the status and health of IoT devices.
 pub struct Monitoring {
     device_manager: Arc<DeviceManager>,
     device_health: Arc<Mutex<HashMap<String, DeviceHealth>>>,
+    config: MonitoringConfig,
+    events: Option<Arc<EventBus>>,
+    notifications: Option<Arc<NotificationDispatcher>>,
+    /// Devices and indices of the health alert rules they currently break, so each rule
+    /// fires once until the device recovers.
+    broken_health_rules: Arc<Mutex<HashSet<(String, usize)>>>,
+}
+
+/// Reading keys that devices use to report their battery level in percent.
+const BATTERY_KEYS: [&str; 2] = ["battery", "battery_level"];
+/// Reading keys that devices use to report their received signal strength in dBm.
+const RSSI_KEYS: [&str; 2] = ["rssi", "signal_strength"];
+
+/// Connectivity state of a device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceStatus {
+    /// The device is registered but has never reported.
+    Unknown,
+    Online,
+    Offline,
+}
+
+/// When a device was last heard from and whether it is online, for filtering and sorting
+/// device listings.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DeviceActivity {
+    pub status: DeviceStatus,
+    pub last_seen: Option<DateTime<Utc>>,
 }
 
 /// Represents the health status of a single IoT device.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct DeviceHealth {
-    pub last_update: Instant,
-    pub is_online: bool,
+    /// When the device last sent telemetry.
+    pub last_update: Option<DateTime<Utc>>,
+    /// When the device last sent an explicit heartbeat.
+    pub last_heartbeat: Option<DateTime<Utc>>,
+    pub status: DeviceStatus,
+    /// When the device first sent telemetry.
+    pub first_update: Option<DateTime<Utc>>,
+    /// Number of telemetry messages received.
+    pub message_count: u64,
+    /// Number of messages from the device that could not be parsed.
+    pub parse_errors: u64,
+    /// Time between the two most recent telemetry messages.
+    pub last_gap: Option<Duration>,
+    /// Longest time observed between two telemetry messages.
+    pub longest_gap: Option<Duration>,
+    /// Last reported battery level in percent.
+    pub battery_level: Option<f64>,
+    /// Last reported received signal strength in dBm.
+    pub rssi: Option<f64>,
+    /// Recent connectivity transitions, oldest first.
+    pub history: VecDeque<StatusTransition>,
+    /// Whether the device is bouncing between states often enough that transitions are suppressed.
+    pub flapping: bool,
+}
+
+/// A change in a device's connectivity state.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct StatusTransition {
+    pub from: DeviceStatus,
+    pub to: DeviceStatus,
+    pub at: DateTime<Utc>,
+    /// Set when the transition happened while the device was flapping and was not announced.
+    pub suppressed: bool,
+}
+
+/// Returns the time from `earlier` to `later`, or zero if the clock went backwards.
+fn elapsed_between(earlier: DateTime<Utc>, later: DateTime<Utc>) -> Duration {
+    (later - earlier).to_std().unwrap_or(Duration::ZERO)
+}
+
+impl DeviceHealth {
+    /// Creates the health record of a device that has never been seen.
+    pub fn unknown() -> Self {
+        DeviceHealth {
+            last_update: None,
+            last_heartbeat: None,
+            status: DeviceStatus::Unknown,
+            first_update: None,
+            message_count: 0,
+            parse_errors: 0,
+            last_gap: None,
+            longest_gap: None,
+            battery_level: None,
+            rssi: None,
+            history: VecDeque::new(),
+            flapping: false,
+        }
+    }
+
+    /// Moves the device to a new connectivity state, recording the transition and
+    /// updating flap detection.
+    pub fn set_status(&mut self, status: DeviceStatus, now: DateTime<Utc>, config: &MonitoringConfig) {
+        if self.status == status {
+            return;
+        }
+        let from = self.status;
+        self.status = status;
+        self.history.push_back(StatusTransition {
+            from,
+            to: status,
+            at: now,
+            suppressed: false,
+        });
+        while self.history.len() > config.history_limit {
+            self.history.pop_front();
+        }
+
+        self.refresh_flapping(now, config);
+        if self.flapping {
+            if let Some(transition) = self.history.back_mut() {
+                transition.suppressed = true;
+            }
+        }
+    }
+
+    /// Re-evaluates flap detection. A device starts flapping once the transitions in the
+    /// window reach the flap threshold and only recovers when they drop to the lower
+    /// recovery threshold, so it does not bounce in and out of the flapping state.
+    pub fn refresh_flapping(&mut self, now: DateTime<Utc>, config: &MonitoringConfig) {
+        let window = Duration::from_secs(config.flap_window_secs);
+        let recent = self
+            .history
+            .iter()
+            .filter(|transition| elapsed_between(transition.at, now) <= window)
+            .count();
+
+        if self.flapping {
+            if recent <= config.flap_recovery_threshold {
+                self.flapping = false;
+            }
+        } else if recent >= config.flap_threshold {
+            self.flapping = true;
+        }
+    }
+
+    /// Returns the percentage of time between `start` and `end` that the device was online,
+    /// or None if the window is empty. Time in the unknown state counts as unavailable.
+    pub fn availability(&self, start: DateTime<Utc>, end: DateTime<Utc>) -> Option<f64> {
+        if end <= start {
+            return None;
+        }
+
+        // The state at the start of the window is the target of the last transition before it,
+        // or the source of the first transition after it.
+        let mut status = match self.history.iter().rev().find(|transition| transition.at <= start) {
+            Some(transition) => transition.to,
+            None => self.history.front().map_or(self.status, |transition| transition.from),
+        };
+
+        let mut online = Duration::ZERO;
+        let mut cursor = start;
+        for transition in self.history.iter().filter(|t| t.at > start && t.at < end) {
+            if status == DeviceStatus::Online {
+                online += elapsed_between(cursor, transition.at);
+            }
+            cursor = transition.at;
+            status = transition.to;
+        }
+        if status == DeviceStatus::Online {
+            online += elapsed_between(cursor, end);
+        }
+
+        Some(online.as_secs_f64() / elapsed_between(start, end).as_secs_f64() * 100.0)
+    }
+
+    /// Returns the average number of telemetry messages per minute since the first one.
+    pub fn reporting_rate(&self) -> Option<f64> {
+        let elapsed = elapsed_between(self.first_update?, Utc::now()).as_secs_f64();
+        if self.message_count < 2 || elapsed <= 0.0 {
+            return None;
+        }
+        Some(self.message_count as f64 / elapsed * 60.0)
+    }
+
+    /// Returns the most recent time the device showed any sign of life.
+    pub fn last_seen(&self) -> Option<DateTime<Utc>> {
+        match (self.last_update, self.last_heartbeat) {
+            (Some(update), Some(heartbeat)) => Some(update.max(heartbeat)),
+            (update, heartbeat) => update.or(heartbeat),
+        }
+    }
+
+    /// Returns true if the device is currently considered online.
+    pub fn is_online(&self) -> bool {
+        self.status == DeviceStatus::Online
+    }
 }
 
 impl Monitoring {
-    /// Creates a new Monitoring service.
+    /// Creates a new Monitoring service with the default reporting intervals.
     pub fn new(device_manager: Arc<DeviceManager>) -> Self {
+        Self::with_config(device_manager, MonitoringConfig::default())
+    }
+
+    /// Creates a new Monitoring service using the given reporting intervals.
+    pub fn with_config(device_manager: Arc<DeviceManager>, config: MonitoringConfig) -> Self {
         Monitoring {
             device_manager,
             device_health: Arc::new(Mutex::new(HashMap::new())),
+            config,
+            events: None,
+            notifications: None,
+            broken_health_rules: Arc::new(Mutex::new(HashSet::new())),
         }
     }
 
-    /// Monitors the health of all devices by checking the time since their last update.
-    pub fn monitor_devices(&self) {
-        let devices = self.device_manager.devices.lock().unwrap();
-        let mut device_health = self.device_health.lock().unwrap();
+    /// Publishes status transitions to the given event bus.
+    pub fn with_event_bus(mut self, events: Arc<EventBus>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Raises an alert through the dispatcher when a device goes offline or comes back.
+    pub fn with_notifications(mut self, notifications: Arc<NotificationDispatcher>) -> Self {
+        self.notifications = Some(notifications);
+        self
+    }
 
-        for (id, device) in devices.iter() {
-            let health = device_health.entry(id.clone()).or_insert_with(|| DeviceHealth {
-                last_update: Instant::now(),
-                is_online: true,
+    /// Moves a device to a new status and announces the transition unless it was
+    /// suppressed because the device is flapping.
+    fn transition(&self, device_id: &str, health: &mut DeviceHealth, status: DeviceStatus, now: DateTime<Utc>) {
+        let previous = health.status;
+        health.set_status(status, now, &self.config);
+        let transition = match health.history.back() {
+            Some(transition) if previous != status && !transition.suppressed => transition,
+            _ => return,
+        };
+        if let Some(events) = &self.events {
+            events.publish(Event::HealthTransition {
+                device_id: device_id.to_string(),
+                transition: transition.clone(),
             });
+        }
+        if let Some(notifications) = &self.notifications {
+            let alert = match (previous, status) {
+                (_, DeviceStatus::Offline) => Some(("Device offline", "stopped reporting", "critical")),
+                (DeviceStatus::Offline, DeviceStatus::Online) => Some(("Device back online", "reported again", "info")),
+                _ => None,
+            };
+            if let Some((title, message, severity)) = alert {
+                notifications.raise(Notification::new(
+                    title.to_string(),
+                    format!("{} {}", device_id, message),
+                    severity.to_string(),
+                    Some(device_id.to_string()),
+                ));
+            }
+        }
+    }
 
-            // Check if the device has sent an update within a predefined interval
-            if health.last_update.elapsed() > Duration::from_secs(30) {
-                // If not, mark the device as offline
-                health.is_online = false;
-            } else {
-                // Otherwise, ensure the device is marked as online
-                health.is_online = true;
+    /// Evaluates the configured health alert rules against the device's health metrics and
+    /// raises an alert for every rule it newly breaks.
+    fn check_health_alerts(&self, device_id: &str) {
+        let notifications = match &self.notifications {
+            Some(notifications) if !self.config.health_alerts.is_empty() => notifications,
+            _ => return,
+        };
+        let report = match self.get_health_report(device_id) {
+            Some(report) => report,
+            None => return,
+        };
+        let metrics = report.metrics();
+
+        let mut broken_health_rules = self.broken_health_rules.lock().unwrap();
+        for (index, rule) in self.config.health_alerts.iter().enumerate() {
+            let key = (device_id.to_string(), index);
+            let value = metrics.get(&rule.metric).copied();
+            let broken = value.is_some_and(|value| {
+                rule.below.is_some_and(|below| value < below) || rule.above.is_some_and(|above| value > above)
+            });
+            if !broken {
+                broken_health_rules.remove(&key);
+                continue;
+            }
+            if broken_health_rules.insert(key) {
+                let mut message = format!("{} {} is {:.1}", device_id, rule.metric, value.unwrap_or_default());
+                if !report.reasons.is_empty() {
+                    message.push_str(&format!(" ({})", report.reasons.join("; ")));
+                }
+                notifications.raise(Notification::new(
+                    format!("Device {} alert", rule.metric),
+                    message,
+                    rule.severity.clone(),
+                    Some(device_id.to_string()),
+                ));
             }
         }
     }
 
+    /// Returns how often the device is expected to report, preferring a per-device setting
+    /// over its device type and falling back to the default interval.
+    pub fn expected_interval(&self, device: &Device) -> Duration {
+        let secs = self
+            .config
+            .device_intervals
+            .get(&device.id)
+            .or_else(|| {
+                device
+                    .device_type
+                    .as_ref()
+                    .and_then(|device_type| self.config.device_type_intervals.get(device_type))
+            })
+            .copied()
+            .unwrap_or(self.config.default_interval_secs);
+        Duration::from_secs(secs)
+    }
+
+    /// Returns how long the device may stay silent before it is considered offline.
+    pub fn offline_threshold(&self, device: &Device) -> Duration {
+        // The multiplier is validated with the configuration; huge intervals saturate instead of overflowing
+        let secs = self.expected_interval(device).as_secs_f64() * self.config.grace_multiplier;
+        Duration::try_from_secs_f64(secs).unwrap_or(Duration::MAX)
+    }
+
+    /// Monitors the health of all devices by checking the time since they were last seen.
+    pub fn monitor_devices(&self) {
+        let devices = self.device_manager.list_devices();
+        let mut device_health = self.device_health.lock().unwrap();
+
+        let now = Utc::now();
+
+        for device in devices.iter() {
+            let threshold = self.offline_threshold(device);
+            let health = device_health.entry(device.id.clone()).or_insert_with(DeviceHealth::unknown);
+
+            let status = match health.last_seen() {
+                // Devices that never reported stay unknown instead of being assumed online
+                None => DeviceStatus::Unknown,
+                Some(last_seen) if elapsed_between(last_seen, now) > threshold => DeviceStatus::Offline,
+                Some(_) => DeviceStatus::Online,
+            };
+            self.transition(&device.id, health, status, now);
+            health.refresh_flapping(now, &self.config);
+        }
+        drop(device_health);
+
+        for device in devices.iter() {
+            self.check_health_alerts(&device.id);
+        }
+    }
+
     /// Updates the health status of a specific device when new data is received.
     pub fn update_device_health(&self, device_id: &str) {
+        let mut device_health = self.device_health.lock().unwrap();
+        let health = device_health.entry(device_id.to_string()).or_insert_with(DeviceHealth::unknown);
+        let now = Utc::now();
+
+        if let Some(previous) = health.last_update {
+            let gap = elapsed_between(previous, now);
+            health.last_gap = Some(gap);
+            health.longest_gap = Some(health.longest_gap.map_or(gap, |longest| longest.max(gap)));
+        }
+        health.first_update.get_or_insert(now);
+        health.last_update = Some(now);
+        health.message_count += 1;
+        self.transition(device_id, health, DeviceStatus::Online, now);
+    }
+
+    /// Updates the health of a device from a telemetry message, picking up the battery
+    /// level and signal strength when the device reports them.
+    pub fn record_telemetry(&self, device_id: &str, data: &HashMap<String, f64>) {
+        self.update_device_health(device_id);
+
         let mut device_health = self.device_health.lock().unwrap();
         if let Some(health) = device_health.get_mut(device_id) {
-            health.last_update = Instant::now();
-            health.is_online = true;
-        } else {
-            // If the device is not yet in the health map, add it with the current timestamp
-            device_health.insert(device_id.to_string(), DeviceHealth {
-                last_update: Instant::now(),
-                is_online: true,
-            });
+            if let Some(battery) = BATTERY_KEYS.iter().find_map(|key| data.get(*key)) {
+                health.battery_level = Some(*battery);
+            }
+            if let Some(rssi) = RSSI_KEYS.iter().find_map(|key| data.get(*key)) {
+                health.rssi = Some(*rssi);
+            }
         }
+        drop(device_health);
+        self.check_health_alerts(device_id);
+    }
+
+    /// Counts a message from the device that ingestion failed to parse.
+    pub fn record_parse_error(&self, device_id: &str) {
+        let mut device_health = self.device_health.lock().unwrap();
+        let health = device_health.entry(device_id.to_string()).or_insert_with(DeviceHealth::unknown);
+        health.parse_errors += 1;
+        drop(device_health);
+        self.check_health_alerts(device_id);
+    }
+
+    /// Records an explicit heartbeat, which keeps a device online without carrying telemetry.
+    pub fn record_heartbeat(&self, device_id: &str) {
+        let mut device_health = self.device_health.lock().unwrap();
+        let health = device_health.entry(device_id.to_string()).or_insert_with(DeviceHealth::unknown);
+        let now = Utc::now();
+        health.last_heartbeat = Some(now);
+        self.transition(device_id, health, DeviceStatus::Online, now);
+    }
+
+    /// Retrieves the connectivity transitions recorded for a specific device.
+    pub fn get_status_history(&self, device_id: &str) -> Vec<StatusTransition> {
+        let device_health = self.device_health.lock().unwrap();
+        device_health
+            .get(device_id)
+            .map(|health| health.history.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Returns the percentage of the last `window` that the device was online.
+    pub fn get_availability(&self, device_id: &str, window: Duration) -> Option<f64> {
+        let now = Utc::now();
+        let start = now - chrono::Duration::from_std(window).ok()?;
+        self.get_availability_between(device_id, start, now)
+    }
+
+    /// Returns the percentage of time between `start` and `end` that the device was online.
+    pub fn get_availability_between(&self, device_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Option<f64> {
+        let device_health = self.device_health.lock().unwrap();
+        device_health.get(device_id)?.availability(start, end)
     }
 
     /// Retrieves the health status of a specific device.
@@ -69,6 +440,172 @@ impl Monitoring {
         let device_health = self.device_health.lock().unwrap();
         device_health.get(device_id).cloned()
     }
+
+    /// Returns the status and last sign of life of every device with a health record.
+    pub fn get_activity(&self) -> HashMap<String, DeviceActivity> {
+        let device_health = self.device_health.lock().unwrap();
+        device_health
+            .iter()
+            .map(|(device_id, health)| {
+                let activity = DeviceActivity {
+                    status: health.status,
+                    last_seen: health.last_seen(),
+                };
+                (device_id.clone(), activity)
+            })
+            .collect()
+    }
+
+    /// Retrieves the health status of all devices, e.g. to persist or serve it.
+    pub fn get_monitoring_data(&self) -> HashMap<String, DeviceHealth> {
+        let device_health = self.device_health.lock().unwrap();
+        device_health.clone()
+    }
+
+    /// Restores previously persisted health records, replacing any existing ones for the same devices.
+    pub fn restore_monitoring_data(&self, data: HashMap<String, DeviceHealth>) {
+        let mut device_health = self.device_health.lock().unwrap();
+        device_health.extend(data);
+    }
+
+    /// Builds a health report with a composite score for a specific device.
+    pub fn get_health_report(&self, device_id: &str) -> Option<HealthReport> {
+        let health = self.get_device_health(device_id)?;
+        let expected_interval = match self.device_manager.get_device(device_id) {
+            Some(device) => self.expected_interval(&device),
+            None => Duration::from_secs(self.config.default_interval_secs),
+        };
+        Some(HealthReport::new(device_id, &health, expected_interval))
+    }
+
+    /// Builds health reports for every device with a health record.
+    pub fn get_all_health_reports(&self) -> HashMap<String, HealthReport> {
+        let device_ids: Vec<String> = {
+            let device_health = self.device_health.lock().unwrap();
+            device_health.keys().cloned().collect()
+        };
+        device_ids
+            .into_iter()
+            .filter_map(|id| self.get_health_report(&id).map(|report| (id, report)))
+            .collect()
+    }
+}
+
+/// A serializable summary of a device's health with a composite score from 0 to 100
+/// and the reasons that lowered it.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct HealthReport {
+    pub device_id: String,
+    pub status: DeviceStatus,
+    pub score: u8,
+    pub reasons: Vec<String>,
+    pub battery_level: Option<f64>,
+    pub rssi: Option<f64>,
+    pub message_count: u64,
+    pub parse_errors: u64,
+    pub reporting_rate_per_min: Option<f64>,
+    pub last_gap_secs: Option<f64>,
+    pub longest_gap_secs: Option<f64>,
+}
+
+impl HealthReport {
+    /// Scores the device's health against its expected reporting interval.
+    pub fn new(device_id: &str, health: &DeviceHealth, expected_interval: Duration) -> Self {
+        let mut penalty = 0u32;
+        let mut reasons = Vec::new();
+
+        match health.status {
+            DeviceStatus::Unknown => {
+                penalty += 100;
+                reasons.push("device has never reported".to_string());
+            }
+            DeviceStatus::Offline => {
+                penalty += 50;
+                reasons.push("device is offline".to_string());
+            }
+            DeviceStatus::Online => {}
+        }
+
+        if let Some(battery) = health.battery_level {
+            if battery < 10.0 {
+                penalty += 30;
+                reasons.push(format!("battery critically low ({:.0}%)", battery));
+            } else if battery < 20.0 {
+                penalty += 15;
+                reasons.push(format!("battery low ({:.0}%)", battery));
+            }
+        }
+
+        if let Some(rssi) = health.rssi {
+            if rssi < -100.0 {
+                penalty += 25;
+                reasons.push(format!("very weak signal ({:.0} dBm)", rssi));
+            } else if rssi < -90.0 {
+                penalty += 10;
+                reasons.push(format!("weak signal ({:.0} dBm)", rssi));
+            }
+        }
+
+        let total_messages = health.message_count + health.parse_errors;
+        if health.parse_errors > 0 && health.parse_errors * 20 > total_messages {
+            penalty += 15;
+            reasons.push(format!("{} of {} messages failed to parse", health.parse_errors, total_messages));
+        }
+
+        if let Some(longest_gap) = health.longest_gap {
+            if longest_gap > expected_interval * 2 {
+                penalty += 10;
+                reasons.push(format!("reporting gap of {}s", longest_gap.as_secs()));
+            }
+        }
+
+        let reporting_rate = health.reporting_rate();
+        if let Some(rate) = reporting_rate {
+            let expected_rate = 60.0 / expected_interval.as_secs_f64().max(1.0);
+            if rate < expected_rate / 2.0 {
+                penalty += 10;
+                reasons.push(format!("reporting {:.2}/min, expected {:.2}/min", rate, expected_rate));
+            }
+        }
+
+        HealthReport {
+            device_id: device_id.to_string(),
+            status: health.status,
+            score: 100u32.saturating_sub(penalty) as u8,
+            reasons,
+            battery_level: health.battery_level,
+            rssi: health.rssi,
+            message_count: health.message_count,
+            parse_errors: health.parse_errors,
+            reporting_rate_per_min: reporting_rate,
+            last_gap_secs: health.last_gap.map(|gap| gap.as_secs_f64()),
+            longest_gap_secs: health.longest_gap.map(|gap| gap.as_secs_f64()),
+        }
+    }
+
+    /// Returns the numeric health values by name, which the configured health alert rules
+    /// are evaluated against.
+    pub fn metrics(&self) -> HashMap<String, f64> {
+        let mut metrics = HashMap::from([
+            ("health_score".to_string(), self.score as f64),
+            ("message_count".to_string(), self.message_count as f64),
+            ("parse_errors".to_string(), self.parse_errors as f64),
+            ("is_online".to_string(), if self.status == DeviceStatus::Online { 1.0 } else { 0.0 }),
+        ]);
+        let optional = [
+            ("battery_level", self.battery_level),
+            ("rssi", self.rssi),
+            ("reporting_rate_per_min", self.reporting_rate_per_min),
+            ("last_gap_secs", self.last_gap_secs),
+            ("longest_gap_secs", self.longest_gap_secs),
+        ];
+        for (name, value) in optional {
+            if let Some(value) = value {
+                metrics.insert(name.to_string(), value);
+            }
+        }
+        metrics
+    }
 }
 
 #[cfg(test)]
@@ -77,6 +614,14 @@ mod tests {
     use std::thread;
     use std::time::Duration;
 
+    fn short_interval_config() -> MonitoringConfig {
+        MonitoringConfig {
+            default_interval_secs: 1,
+            grace_multiplier: 1.0,
+            ..MonitoringConfig::default()
+        }
+    }
+
     #[test]
     fn test_device_health_update() {
         let device_manager = Arc::new(DeviceManager::new());
@@ -93,13 +638,13 @@ mod tests {
 
         // Check if the device is still considered online
         let health = monitoring.get_device_health("device1").unwrap();
-        assert_eq!(health.is_online, true);
+        assert_eq!(health.is_online(), true);
     }
 
     #[test]
     fn test_device_health_monitoring() {
         let device_manager = Arc::new(DeviceManager::new());
-        let monitoring = Monitoring::new(device_manager.clone());
+        let monitoring = Monitoring::with_config(device_manager.clone(), short_interval_config());
 
         let device = Device::new("device1".to_string(), "Test Device".to_string());
         device_manager.add_device(device);
@@ -108,13 +653,240 @@ mod tests {
         monitoring.update_device_health("device1");
 
         // Allow some time to pass
-        thread::sleep(Duration::from_secs(35));
+        thread::sleep(Duration::from_secs(2));
 
         // Run the monitoring check
         monitoring.monitor_devices();
 
         // Check if the device is considered offline after the interval
         let health = monitoring.get_device_health("device1").unwrap();
-        assert_eq!(health.is_online, false);
+        assert_eq!(health.is_online(), false);
+        assert_eq!(health.status, DeviceStatus::Offline);
+    }
+
+    #[tokio::test]
+    async fn test_offline_device_raises_alert() {
+        let device_manager = Arc::new(DeviceManager::new());
+        let events = Arc::new(EventBus::new(crate::config::StreamConfig::default()));
+        let notifications = Arc::new(NotificationDispatcher::new(1, Duration::ZERO).with_event_bus(events.clone()));
+        let monitoring =
+            Monitoring::with_config(device_manager.clone(), short_interval_config()).with_notifications(notifications);
+        device_manager.add_device(Device::new("device1".to_string(), "Test Device".to_string()));
+
+        let mut health = DeviceHealth::unknown();
+        health.status = DeviceStatus::Online;
+        health.last_update = Some(Utc::now() - chrono::Duration::seconds(10));
+        monitoring.restore_monitoring_data(HashMap::from([("device1".to_string(), health)]));
+
+        let mut subscription = events.subscribe(crate::events::SubscriptionFilter::default());
+        monitoring.monitor_devices();
+        match subscription.next(&device_manager).await {
+            Some(Event::Alert { notification }) => {
+                assert_eq!(notification.title, "Device offline");
+                assert_eq!(notification.device_id.as_deref(), Some("device1"));
+            }
+            other => panic!("expected an alert, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_never_seen_device_is_unknown() {
+        let device_manager = Arc::new(DeviceManager::new());
+        let monitoring = Monitoring::new(device_manager.clone());
+
+        device_manager.add_device(Device::new("device1".to_string(), "Test Device".to_string()));
+        monitoring.monitor_devices();
+
+        let health = monitoring.get_device_health("device1").unwrap();
+        assert_eq!(health.status, DeviceStatus::Unknown);
+        assert!(health.last_seen().is_none());
+    }
+
+    #[test]
+    fn test_heartbeat_keeps_device_online() {
+        let device_manager = Arc::new(DeviceManager::new());
+        let monitoring = Monitoring::with_config(device_manager.clone(), short_interval_config());
+
+        device_manager.add_device(Device::new("device1".to_string(), "Test Device".to_string()));
+        monitoring.update_device_health("device1");
+        thread::sleep(Duration::from_millis(1500));
+        monitoring.record_heartbeat("device1");
+        monitoring.monitor_devices();
+
+        let health = monitoring.get_device_health("device1").unwrap();
+        assert_eq!(health.status, DeviceStatus::Online);
+        assert!(health.last_heartbeat > health.last_update);
+    }
+
+    #[test]
+    fn test_health_report_scores_vitals() {
+        let device_manager = Arc::new(DeviceManager::new());
+        let monitoring = Monitoring::new(device_manager.clone());
+
+        device_manager.add_device(Device::new("device1".to_string(), "Test Device".to_string()));
+        let data = HashMap::from([
+            ("temperature".to_string(), 21.0),
+            ("battery".to_string(), 8.0),
+            ("rssi".to_string(), -95.0),
+        ]);
+        monitoring.record_telemetry("device1", &data);
+        monitoring.record_parse_error("device1");
+
+        let report = monitoring.get_health_report("device1").unwrap();
+        assert_eq!(report.battery_level, Some(8.0));
+        assert_eq!(report.rssi, Some(-95.0));
+        assert_eq!(report.parse_errors, 1);
+        // Battery (-30), weak signal (-10) and one parse error out of two messages (-15)
+        assert_eq!(report.score, 45);
+        assert_eq!(report.reasons.len(), 3);
+        assert_eq!(report.metrics()["health_score"], 45.0);
+    }
+
+    #[tokio::test]
+    async fn test_low_battery_raises_health_alert_once() {
+        let device_manager = Arc::new(DeviceManager::new());
+        let events = Arc::new(EventBus::new(crate::config::StreamConfig::default()));
+        let notifications = Arc::new(NotificationDispatcher::new(1, Duration::ZERO).with_event_bus(events.clone()));
+        let config = MonitoringConfig {
+            health_alerts: vec![crate::config::HealthAlertRule {
+                metric: "battery_level".to_string(),
+                below: Some(20.0),
+                above: None,
+                severity: "warning".to_string(),
+            }],
+            ..MonitoringConfig::default()
+        };
+        let monitoring = Monitoring::with_config(device_manager.clone(), config).with_notifications(notifications);
+        device_manager.add_device(Device::new("device1".to_string(), "Test Device".to_string()));
+
+        let mut subscription = events.subscribe(crate::events::SubscriptionFilter::default());
+        for battery in [50.0, 15.0, 12.0, 80.0, 10.0] {
+            monitoring.record_telemetry("device1", &HashMap::from([("battery".to_string(), battery)]));
+        }
+
+        // The rule fires when the battery first drops below 20% and again only after it recovered
+        for expected in ["device1 battery_level is 15.0", "device1 battery_level is 10.0"] {
+            match subscription.next(&device_manager).await {
+                Some(Event::Alert { notification }) => {
+                    assert_eq!(notification.title, "Device battery_level alert");
+                    assert!(notification.message.starts_with(expected));
+                }
+                other => panic!("expected an alert, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_unknown_device_scores_zero() {
+        let device_manager = Arc::new(DeviceManager::new());
+        let monitoring = Monitoring::new(device_manager.clone());
+
+        device_manager.add_device(Device::new("device1".to_string(), "Test Device".to_string()));
+        monitoring.monitor_devices();
+
+        let report = monitoring.get_health_report("device1").unwrap();
+        assert_eq!(report.score, 0);
+        assert_eq!(report.reasons, vec!["device has never reported".to_string()]);
+    }
+
+    #[test]
+    fn test_flap_detection_with_hysteresis() {
+        let config = MonitoringConfig {
+            flap_window_secs: 60,
+            flap_threshold: 4,
+            flap_recovery_threshold: 1,
+            ..MonitoringConfig::default()
+        };
+        let start = Utc::now();
+        let mut health = DeviceHealth::unknown();
+
+        let statuses = [DeviceStatus::Online, DeviceStatus::Offline, DeviceStatus::Online, DeviceStatus::Offline];
+        for (i, status) in statuses.iter().enumerate() {
+            health.set_status(*status, start + chrono::Duration::seconds(i as i64), &config);
+        }
+        assert!(health.flapping);
+        assert!(health.history.back().unwrap().suppressed);
+        assert!(!health.history.front().unwrap().suppressed);
+
+        // Two transitions remain in the window, which is above the recovery threshold
+        health.refresh_flapping(start + chrono::Duration::seconds(62), &config);
+        assert!(health.flapping);
+
+        // Only one transition remains in the window, so the device recovers
+        health.refresh_flapping(start + chrono::Duration::seconds(63), &config);
+        assert!(!health.flapping);
+    }
+
+    #[test]
+    fn test_availability_over_window() {
+        let config = MonitoringConfig::default();
+        let start = Utc::now();
+        let mut health = DeviceHealth::unknown();
+
+        health.set_status(DeviceStatus::Online, start, &config);
+        health.set_status(DeviceStatus::Offline, start + chrono::Duration::seconds(30), &config);
+        health.set_status(DeviceStatus::Online, start + chrono::Duration::seconds(40), &config);
+
+        let availability = health.availability(start, start + chrono::Duration::seconds(100)).unwrap();
+        assert!((availability - 90.0).abs() < 1e-9);
+
+        let availability = health
+            .availability(start + chrono::Duration::seconds(20), start + chrono::Duration::seconds(40))
+            .unwrap();
+        assert!((availability - 50.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_status_history_is_recorded() {
+        let device_manager = Arc::new(DeviceManager::new());
+        let monitoring = Monitoring::with_config(device_manager.clone(), short_interval_config());
+
+        device_manager.add_device(Device::new("device1".to_string(), "Test Device".to_string()));
+        monitoring.update_device_health("device1");
+        thread::sleep(Duration::from_secs(2));
+        monitoring.monitor_devices();
+
+        let history = monitoring.get_status_history("device1");
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].to, DeviceStatus::Online);
+        assert_eq!(history[1].to, DeviceStatus::Offline);
+    }
+
+    #[test]
+    fn test_monitoring_data_round_trips_through_json() {
+        let device_manager = Arc::new(DeviceManager::new());
+        let monitoring = Monitoring::new(device_manager.clone());
+        monitoring.record_telemetry("device1", &HashMap::from([("battery".to_string(), 80.0)]));
+
+        let json = serde_json::to_string(&monitoring.get_monitoring_data()).unwrap();
+        let restored: HashMap<String, DeviceHealth> = serde_json::from_str(&json).unwrap();
+
+        let other = Monitoring::new(device_manager);
+        other.restore_monitoring_data(restored);
+        let health = other.get_device_health("device1").unwrap();
+        assert_eq!(health.status, DeviceStatus::Online);
+        assert_eq!(health.battery_level, Some(80.0));
+        assert_eq!(health.last_update, monitoring.get_device_health("device1").unwrap().last_update);
+    }
+
+    #[test]
+    fn test_expected_interval_precedence() {
+        let device_manager = Arc::new(DeviceManager::new());
+        let mut config = MonitoringConfig {
+            grace_multiplier: 2.5,
+            ..MonitoringConfig::default()
+        };
+        config.device_type_intervals.insert("weather".to_string(), 3600);
+        config.device_intervals.insert("station7".to_string(), 60);
+        let monitoring = Monitoring::with_config(device_manager, config);
+
+        let plain = Device::new("plain".to_string(), "Plain".to_string());
+        let weather = Device::new("station1".to_string(), "Station".to_string()).with_type("weather".to_string());
+        let overridden = Device::new("station7".to_string(), "Station".to_string()).with_type("weather".to_string());
+
+        assert_eq!(monitoring.expected_interval(&plain), Duration::from_secs(30));
+        assert_eq!(monitoring.expected_interval(&weather), Duration::from_secs(3600));
+        assert_eq!(monitoring.expected_interval(&overridden), Duration::from_secs(60));
+        assert_eq!(monitoring.offline_threshold(&overridden), Duration::from_secs(150));
     }
 }
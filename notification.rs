@@ -0,0 +1,625 @@
+// notification.rs
+
+use crate::config::{NotificationConfig, SinkConfig};
+use crate::events::{Event, EventBus};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use hyper::client::HttpConnector;
+use hyper_rustls::HttpsConnector;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::process::Command;
+
+/// Header carrying the hex encoded HMAC-SHA256 signature of a webhook body.
+pub const SIGNATURE_HEADER: &str = "X-IoT-Signature";
+
+/// How long a single delivery attempt may take unless configured otherwise.
+const DEFAULT_SEND_TIMEOUT: Duration = Duration::from_secs(10);
+/// How many deliveries the log keeps unless configured otherwise.
+const DEFAULT_DELIVERY_HISTORY: usize = 1000;
+
+/// A message that should reach people or external systems, usually raised by an alert.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Notification {
+    pub title: String,
+    pub message: String,
+    pub severity: String,
+    pub device_id: Option<String>,
+    pub timestamp: DateTime<Utc>,
+}
+
+impl Notification {
+    /// Creates a new notification stamped with the current time.
+    pub fn new(title: String, message: String, severity: String, device_id: Option<String>) -> Self {
+        Notification {
+            title,
+            message,
+            severity,
+            device_id,
+            timestamp: Utc::now(),
+        }
+    }
+
+    /// Returns the values that can be substituted into templates as `{{name}}`.
+    fn template_values(&self) -> HashMap<&'static str, String> {
+        HashMap::from([
+            ("title", self.title.clone()),
+            ("message", self.message.clone()),
+            ("severity", self.severity.clone()),
+            ("device_id", self.device_id.clone().unwrap_or_default()),
+            ("timestamp", self.timestamp.to_rfc3339()),
+        ])
+    }
+
+    /// Replaces every `{{name}}` placeholder in the template with the notification's values.
+    /// When `json_escape` is set the values are escaped so they can sit inside JSON strings.
+    /// The template is scanned once, so placeholders inside substituted values stay as they are,
+    /// and unknown placeholders are kept verbatim.
+    pub fn render(&self, template: &str, json_escape: bool) -> String {
+        let values = self.template_values();
+        let mut rendered = String::with_capacity(template.len());
+        let mut rest = template;
+        while let Some(start) = rest.find("{{") {
+            rendered.push_str(&rest[..start]);
+            let placeholder = &rest[start..];
+            let value = placeholder
+                .find("}}")
+                .and_then(|end| Some((end, values.get(&placeholder[2..end])?)));
+            match value {
+                Some((end, value)) => {
+                    if json_escape {
+                        let quoted = serde_json::to_string(value).unwrap_or_default();
+                        rendered.push_str(&quoted[1..quoted.len() - 1]);
+                    } else {
+                        rendered.push_str(value);
+                    }
+                    rest = &placeholder[end + 2..];
+                }
+                None => {
+                    rendered.push_str("{{");
+                    rest = &placeholder[2..];
+                }
+            }
+        }
+        rendered.push_str(rest);
+        rendered
+    }
+}
+
+/// A destination that notifications can be delivered to.
+#[async_trait]
+pub trait NotificationSink: Send + Sync {
+    /// Name of the sink used in the delivery log.
+    fn name(&self) -> &str;
+
+    /// Attempts a single delivery of the notification.
+    async fn send(&self, notification: &Notification) -> crate::Result<()>;
+}
+
+/// Posts a templated JSON body to an HTTP endpoint, optionally signed with HMAC-SHA256.
+pub struct WebhookSink {
+    name: String,
+    url: String,
+    body_template: String,
+    secret: Option<String>,
+    client: hyper::Client<HttpsConnector<HttpConnector>>,
+}
+
+impl WebhookSink {
+    /// Creates a new webhook sink. The body template may use `{{title}}`, `{{message}}`,
+    /// `{{severity}}`, `{{device_id}}` and `{{timestamp}}` placeholders.
+    pub fn new(name: String, url: String, body_template: String, secret: Option<String>) -> Self {
+        // https endpoints are verified against the Mozilla root certificates
+        let connector = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_webpki_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
+        WebhookSink {
+            name,
+            url,
+            body_template,
+            secret,
+            client: hyper::Client::builder().build(connector),
+        }
+    }
+}
+
+/// Computes the hex encoded HMAC-SHA256 of the body with the given secret.
+pub fn sign_payload(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+#[async_trait]
+impl NotificationSink for WebhookSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn send(&self, notification: &Notification) -> crate::Result<()> {
+        let body = notification.render(&self.body_template, true);
+        // Make sure the rendered template is valid JSON before sending it anywhere
+        serde_json::from_str::<serde_json::Value>(&body)?;
+
+        let mut request = hyper::Request::post(self.url.as_str()).header("Content-Type", "application/json");
+        if let Some(secret) = &self.secret {
+            request = request.header(SIGNATURE_HEADER, sign_payload(secret, body.as_bytes()));
+        }
+        let request = request.body(hyper::Body::from(body))?;
+
+        let response = self.client.request(request).await?;
+        if !response.status().is_success() {
+            return Err(format!("webhook {} responded with {}", self.url, response.status()).into());
+        }
+        Ok(())
+    }
+}
+
+/// Sends plain-text email through an SMTP relay.
+pub struct SmtpSink {
+    name: String,
+    server: String,
+    from: String,
+    to: Vec<String>,
+}
+
+impl SmtpSink {
+    /// Creates a new SMTP sink relaying through `server` (`host:port`).
+    pub fn new(name: String, server: String, from: String, to: Vec<String>) -> Self {
+        SmtpSink { name, server, from, to }
+    }
+}
+
+/// Makes a value safe to put in a header line, so it cannot start headers of its own.
+fn header_value(value: &str) -> String {
+    value.replace(['\r', '\n'], " ")
+}
+
+/// Ends every line with CRLF and doubles a leading dot, so no line of the text can end the
+/// message data early.
+fn dot_stuff(text: &str) -> String {
+    text.split('\n')
+        .map(|line| {
+            let line = line.strip_suffix('\r').unwrap_or(line);
+            if line.starts_with('.') {
+                format!(".{}", line)
+            } else {
+                line.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\r\n")
+}
+
+/// Reads one (possibly multi-line) SMTP reply and checks its status code.
+async fn expect_reply(reader: &mut BufReader<TcpStream>, expected: u16) -> crate::Result<()> {
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 {
+            return Err("SMTP server closed the connection".into());
+        }
+        let code: u16 = line.get(..3).and_then(|c| c.parse().ok()).ok_or("malformed SMTP reply")?;
+        if code != expected {
+            return Err(format!("unexpected SMTP reply: {}", line.trim_end()).into());
+        }
+        // A dash after the code marks a continuation line
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok(());
+        }
+    }
+}
+
+async fn smtp_command(reader: &mut BufReader<TcpStream>, command: &str, expected: u16) -> crate::Result<()> {
+    reader.get_mut().write_all(format!("{}\r\n", command).as_bytes()).await?;
+    expect_reply(reader, expected).await
+}
+
+#[async_trait]
+impl NotificationSink for SmtpSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn send(&self, notification: &Notification) -> crate::Result<()> {
+        let stream = TcpStream::connect(&self.server).await?;
+        let mut reader = BufReader::new(stream);
+
+        expect_reply(&mut reader, 220).await?;
+        smtp_command(&mut reader, "HELO iot-platform", 250).await?;
+        smtp_command(&mut reader, &format!("MAIL FROM:<{}>", self.from), 250).await?;
+        for recipient in &self.to {
+            smtp_command(&mut reader, &format!("RCPT TO:<{}>", recipient), 250).await?;
+        }
+        smtp_command(&mut reader, "DATA", 354).await?;
+
+        let body = dot_stuff(&notification.render(
+            "{{message}}\r\n\r\nSeverity: {{severity}}\r\nDevice: {{device_id}}\r\nTime: {{timestamp}}",
+            false,
+        ));
+        let message = format!(
+            "From: {}\r\nTo: {}\r\nSubject: [{}] {}\r\n\r\n{}\r\n.",
+            self.from,
+            self.to.join(", "),
+            header_value(&notification.severity),
+            header_value(&notification.title),
+            body
+        );
+        smtp_command(&mut reader, &message, 250).await?;
+        smtp_command(&mut reader, "QUIT", 221).await?;
+        Ok(())
+    }
+}
+
+/// Runs a local command, passing the notification through environment variables.
+pub struct CommandSink {
+    name: String,
+    program: String,
+    args: Vec<String>,
+}
+
+impl CommandSink {
+    /// Creates a new command sink. Arguments may use the same placeholders as webhook templates.
+    pub fn new(name: String, program: String, args: Vec<String>) -> Self {
+        CommandSink { name, program, args }
+    }
+}
+
+#[async_trait]
+impl NotificationSink for CommandSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn send(&self, notification: &Notification) -> crate::Result<()> {
+        let mut command = Command::new(&self.program);
+        // The command is killed when the delivery attempt times out
+        command.kill_on_drop(true);
+        command.args(self.args.iter().map(|arg| notification.render(arg, false)));
+        for (key, value) in notification.template_values() {
+            command.env(format!("IOT_NOTIFICATION_{}", key.to_uppercase()), value);
+        }
+        command.env("IOT_NOTIFICATION_JSON", serde_json::to_string(notification)?);
+
+        let status = command.status().await?;
+        if !status.success() {
+            return Err(format!("command {} exited with {}", self.program, status).into());
+        }
+        Ok(())
+    }
+}
+
+/// A single entry in the delivery log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeliveryRecord {
+    pub sink: String,
+    pub title: String,
+    pub attempts: u32,
+    pub delivered: bool,
+    pub error: Option<String>,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// Fans notifications out to every configured sink, retrying failed deliveries with
+/// exponential backoff and recording the outcome of each one.
+#[derive(Clone)]
+pub struct NotificationDispatcher {
+    sinks: Vec<Arc<dyn NotificationSink>>,
+    max_attempts: u32,
+    initial_backoff: Duration,
+    send_timeout: Duration,
+    delivery_log: Arc<Mutex<VecDeque<DeliveryRecord>>>,
+    delivery_history: usize,
+    events: Option<Arc<EventBus>>,
+}
+
+impl NotificationDispatcher {
+    /// Creates a dispatcher without any sinks.
+    pub fn new(max_attempts: u32, initial_backoff: Duration) -> Self {
+        NotificationDispatcher {
+            sinks: Vec::new(),
+            max_attempts: max_attempts.max(1),
+            initial_backoff,
+            send_timeout: DEFAULT_SEND_TIMEOUT,
+            delivery_log: Arc::new(Mutex::new(VecDeque::new())),
+            delivery_history: DEFAULT_DELIVERY_HISTORY,
+            events: None,
+        }
+    }
+
+    /// Gives up on a delivery attempt that takes longer than the timeout.
+    pub fn with_send_timeout(mut self, send_timeout: Duration) -> Self {
+        self.send_timeout = send_timeout;
+        self
+    }
+
+    /// Keeps only the given number of most recent deliveries in the delivery log.
+    pub fn with_delivery_history(mut self, delivery_history: usize) -> Self {
+        self.delivery_history = delivery_history;
+        self
+    }
+
+    /// Publishes every dispatched notification to the given event bus as an alert.
+    pub fn with_event_bus(mut self, events: Arc<EventBus>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Creates a dispatcher with the sinks described in the configuration.
+    pub fn from_config(config: &NotificationConfig) -> Self {
+        let mut dispatcher = Self::new(config.max_attempts, Duration::from_millis(config.initial_backoff_ms))
+            .with_send_timeout(Duration::from_secs(config.timeout_secs))
+            .with_delivery_history(config.delivery_history);
+        for sink in &config.sinks {
+            let sink: Arc<dyn NotificationSink> = match sink.clone() {
+                SinkConfig::Webhook { name, url, body_template, secret } => {
+                    Arc::new(WebhookSink::new(name, url, body_template, secret))
+                }
+                SinkConfig::Smtp { name, server, from, to } => Arc::new(SmtpSink::new(name, server, from, to)),
+                SinkConfig::Command { name, program, args } => Arc::new(CommandSink::new(name, program, args)),
+            };
+            dispatcher.add_sink(sink);
+        }
+        dispatcher
+    }
+
+    /// Registers an additional sink.
+    pub fn add_sink(&mut self, sink: Arc<dyn NotificationSink>) {
+        self.sinks.push(sink);
+    }
+
+    /// Publishes the notification as an alert right away and delivers it to the sinks in the
+    /// background, so services raising alerts while holding their locks never wait on a sink.
+    pub fn raise(&self, notification: Notification) {
+        self.publish(&notification);
+        if self.sinks.is_empty() {
+            return;
+        }
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => {
+                let dispatcher = self.clone();
+                runtime.spawn(async move {
+                    dispatcher.deliver_all(&notification).await;
+                });
+            }
+            Err(_) => eprintln!("No runtime to deliver notification \"{}\"", notification.title),
+        }
+    }
+
+    /// Delivers the notification to all sinks and returns the resulting delivery records.
+    pub async fn dispatch(&self, notification: &Notification) -> Vec<DeliveryRecord> {
+        self.publish(notification);
+        self.deliver_all(notification).await
+    }
+
+    fn publish(&self, notification: &Notification) {
+        if let Some(events) = &self.events {
+            events.publish(Event::Alert {
+                notification: notification.clone(),
+            });
+        }
+    }
+
+    async fn deliver_all(&self, notification: &Notification) -> Vec<DeliveryRecord> {
+        let deliveries = self.sinks.iter().map(|sink| self.deliver(sink.as_ref(), notification));
+        let records = futures::future::join_all(deliveries).await;
+
+        let mut delivery_log = self.delivery_log.lock().unwrap();
+        delivery_log.extend(records.iter().cloned());
+        while delivery_log.len() > self.delivery_history {
+            delivery_log.pop_front();
+        }
+        records
+    }
+
+    /// Delivers to a single sink, retrying with exponential backoff.
+    async fn deliver(&self, sink: &dyn NotificationSink, notification: &Notification) -> DeliveryRecord {
+        let mut backoff = self.initial_backoff;
+        let mut last_error = None;
+        let mut attempts = 0;
+
+        while attempts < self.max_attempts {
+            attempts += 1;
+            let result = match tokio::time::timeout(self.send_timeout, sink.send(notification)).await {
+                Ok(result) => result,
+                Err(_) => Err(format!("timed out after {:?}", self.send_timeout).into()),
+            };
+            match result {
+                Ok(()) => {
+                    last_error = None;
+                    break;
+                }
+                Err(e) => {
+                    eprintln!("Delivery to {} failed (attempt {}): {}", sink.name(), attempts, e);
+                    last_error = Some(e.to_string());
+                    if attempts < self.max_attempts {
+                        tokio::time::sleep(backoff).await;
+                        backoff *= 2;
+                    }
+                }
+            }
+        }
+
+        DeliveryRecord {
+            sink: sink.name().to_string(),
+            title: notification.title.clone(),
+            attempts,
+            delivered: last_error.is_none(),
+            error: last_error,
+            timestamp: Utc::now(),
+        }
+    }
+
+    /// Returns the most recent deliveries, oldest first.
+    pub fn get_delivery_log(&self) -> Vec<DeliveryRecord> {
+        let delivery_log = self.delivery_log.lock().unwrap();
+        delivery_log.iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    fn test_notification() -> Notification {
+        Notification::new(
+            "Device offline".to_string(),
+            "device1 stopped \"reporting\"".to_string(),
+            "critical".to_string(),
+            Some("device1".to_string()),
+        )
+    }
+
+    #[test]
+    fn test_render_escapes_json() {
+        let notification = test_notification();
+        let body = notification.render(r#"{"text": "{{message}}", "device": "{{device_id}}"}"#, true);
+        let value: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(value["text"], "device1 stopped \"reporting\"");
+        assert_eq!(value["device"], "device1");
+    }
+
+    #[test]
+    fn test_render_does_not_expand_substituted_values() {
+        let mut notification = test_notification();
+        notification.title = "{{severity}}".to_string();
+        notification.message = "{{title}} {{unknown}}".to_string();
+        let text = notification.render("{{title}}: {{message}} {{ {{severity}}", false);
+        assert_eq!(text, "{{severity}}: {{title}} {{unknown}} {{ critical");
+    }
+
+    #[tokio::test]
+    async fn test_webhook_sink_signs_body() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0; 4096];
+            let n = socket.read(&mut buf).await.unwrap();
+            socket.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await.unwrap();
+            String::from_utf8_lossy(&buf[..n]).to_string()
+        });
+
+        let sink = WebhookSink::new(
+            "hook".to_string(),
+            url,
+            r#"{"title": "{{title}}"}"#.to_string(),
+            Some("secret".to_string()),
+        );
+        sink.send(&test_notification()).await.unwrap();
+
+        let request = server.await.unwrap();
+        let body = r#"{"title": "Device offline"}"#;
+        assert!(request.ends_with(body));
+        let signature = sign_payload("secret", body.as_bytes());
+        assert!(request.to_lowercase().contains(&format!("x-iot-signature: {}", signature)));
+    }
+
+    #[tokio::test]
+    async fn test_smtp_sink_delivers_message() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = listener.local_addr().unwrap().to_string();
+
+        // A fake SMTP listener that accepts everything and records the message data
+        let fake_smtp = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut reader = BufReader::new(socket);
+            reader.get_mut().write_all(b"220 fake ESMTP\r\n").await.unwrap();
+            let mut data = String::new();
+            let mut in_data = false;
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).await.unwrap() == 0 {
+                    break;
+                }
+                let reply: &[u8] = if in_data {
+                    if line == ".\r\n" {
+                        in_data = false;
+                        b"250 queued\r\n"
+                    } else {
+                        data.push_str(&line);
+                        continue;
+                    }
+                } else if line.starts_with("DATA") {
+                    in_data = true;
+                    b"354 go ahead\r\n"
+                } else if line.starts_with("QUIT") {
+                    reader.get_mut().write_all(b"221 bye\r\n").await.unwrap();
+                    break;
+                } else {
+                    b"250 ok\r\n"
+                };
+                reader.get_mut().write_all(reply).await.unwrap();
+            }
+            data
+        });
+
+        let sink = SmtpSink::new(
+            "mail".to_string(),
+            server,
+            "alerts@example.com".to_string(),
+            vec!["ops@example.com".to_string()],
+        );
+        // Neither the title nor the message can add headers or end the data early
+        let mut notification = test_notification();
+        notification.title = "Device offline\r\nBcc: eve@example.com".to_string();
+        notification.message = ".\nRCPT TO:<eve@example.com>".to_string();
+        sink.send(&notification).await.unwrap();
+
+        let data = fake_smtp.await.unwrap();
+        assert!(data.contains("Subject: [critical] Device offline  Bcc: eve@example.com\r\n"));
+        assert!(data.contains("\r\n..\r\nRCPT TO:<eve@example.com>\r\n"));
+        assert!(data.contains("Device: device1"));
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_retries_and_logs() {
+        let mut dispatcher = NotificationDispatcher::new(3, Duration::from_millis(1));
+        dispatcher.add_sink(Arc::new(CommandSink::new("fails".to_string(), "false".to_string(), vec![])));
+        dispatcher.add_sink(Arc::new(CommandSink::new("succeeds".to_string(), "true".to_string(), vec![])));
+
+        let records = dispatcher.dispatch(&test_notification()).await;
+        assert_eq!(records.len(), 2);
+        assert!(!records[0].delivered);
+        assert_eq!(records[0].attempts, 3);
+        assert!(records[1].delivered);
+        assert_eq!(records[1].attempts, 1);
+        assert_eq!(dispatcher.get_delivery_log().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_delivery_log_keeps_recent_deliveries() {
+        let mut dispatcher = NotificationDispatcher::new(1, Duration::ZERO).with_delivery_history(3);
+        dispatcher.add_sink(Arc::new(CommandSink::new("succeeds".to_string(), "true".to_string(), vec![])));
+
+        for i in 0..5 {
+            let mut notification = test_notification();
+            notification.title = format!("alert {}", i);
+            dispatcher.dispatch(&notification).await;
+        }
+        let titles: Vec<String> = dispatcher.get_delivery_log().into_iter().map(|record| record.title).collect();
+        assert_eq!(titles, vec!["alert 2", "alert 3", "alert 4"]);
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_gives_up_on_slow_sinks() {
+        let mut dispatcher =
+            NotificationDispatcher::new(1, Duration::ZERO).with_send_timeout(Duration::from_millis(50));
+        dispatcher.add_sink(Arc::new(CommandSink::new("hangs".to_string(), "sleep".to_string(), vec!["5".to_string()])));
+
+        let records = dispatcher.dispatch(&test_notification()).await;
+        assert!(!records[0].delivered);
+        assert!(records[0].error.as_deref().unwrap().starts_with("timed out"));
+    }
+}
@@ -0,0 +1,252 @@
+// openapi.rs
+
+use crate::auth::Scope;
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use schemars::schema::Schema;
+use schemars::JsonSchema;
+use serde_json::{json, Map, Value};
+
+/// Version of the OpenAPI specification the document follows.
+const OPENAPI_VERSION: &str = "3.0.3";
+
+type SchemaFn = fn(&mut SchemaGenerator) -> Schema;
+
+fn schema<T: JsonSchema>(gen: &mut SchemaGenerator) -> Schema {
+    gen.subschema_for::<T>()
+}
+
+fn inline_schema<T: JsonSchema>(gen: &mut SchemaGenerator) -> Schema {
+    T::json_schema(gen)
+}
+
+/// Who may call an operation.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Access {
+    Public,
+    /// Any valid credentials.
+    Authenticated,
+    Scope(Scope),
+}
+
+/// A route of the API as described in the OpenAPI document, with the types it takes and
+/// returns.
+#[derive(Clone)]
+pub struct Operation {
+    pub method: &'static str,
+    /// The route's path with its parameters in braces, e.g. `/api/devices/{device_id}`.
+    pub path: &'static str,
+    pub summary: &'static str,
+    access: Access,
+    query: Option<SchemaFn>,
+    body: Option<SchemaFn>,
+    status: u16,
+    response: Option<SchemaFn>,
+    content_type: &'static str,
+    sparse_fields: bool,
+}
+
+impl Operation {
+    /// Creates a public operation answering 200 without a body.
+    pub fn new(method: &'static str, path: &'static str, summary: &'static str) -> Self {
+        Operation {
+            method,
+            path,
+            summary,
+            access: Access::Public,
+            query: None,
+            body: None,
+            status: 200,
+            response: None,
+            content_type: "application/json",
+            sparse_fields: false,
+        }
+    }
+
+    /// Requires credentials granting the scope.
+    pub fn scope(mut self, scope: Scope) -> Self {
+        self.access = Access::Scope(scope);
+        self
+    }
+
+    /// Requires valid credentials, whatever their scopes.
+    pub fn authenticated(mut self) -> Self {
+        self.access = Access::Authenticated;
+        self
+    }
+
+    /// Takes the fields of `T` as query parameters.
+    pub fn query<T: JsonSchema>(mut self) -> Self {
+        self.query = Some(inline_schema::<T>);
+        self
+    }
+
+    /// Takes `T` as the JSON request body.
+    pub fn body<T: JsonSchema>(mut self) -> Self {
+        self.body = Some(schema::<T>);
+        self
+    }
+
+    /// Answers with the status and `T` as the response body.
+    pub fn returns<T: JsonSchema>(mut self, status: u16) -> Self {
+        self.status = status;
+        self.response = Some(schema::<T>);
+        self
+    }
+
+    /// Answers with the status and no body.
+    pub fn returns_nothing(mut self, status: u16) -> Self {
+        self.status = status;
+        self.response = None;
+        self
+    }
+
+    /// Marks a listing whose items only carry the fields named in the `fields` query parameter
+    /// when it is set.
+    pub fn sparse_fields(mut self) -> Self {
+        self.sparse_fields = true;
+        self
+    }
+
+    /// Sends the response body with another content type than JSON.
+    pub fn content_type(mut self, content_type: &'static str) -> Self {
+        self.content_type = content_type;
+        self
+    }
+
+    fn describe(&self, gen: &mut SchemaGenerator, error: &Schema) -> Value {
+        let mut parameters: Vec<Value> = self
+            .path
+            .split('/')
+            .filter_map(|segment| segment.strip_prefix('{').and_then(|segment| segment.strip_suffix('}')))
+            .map(|name| json!({ "name": name, "in": "path", "required": true, "schema": { "type": "string" } }))
+            .collect();
+        if let Some(Schema::Object(query)) = self.query.map(|query| query(gen)) {
+            if let Some(object) = query.object {
+                for (name, schema) in &object.properties {
+                    let required = object.required.contains(name);
+                    parameters.push(json!({ "name": name, "in": "query", "required": required, "schema": schema }));
+                }
+            }
+        }
+
+        let mut response = json!({ "description": reason(self.status) });
+        if let Some(body) = self.response {
+            let mut schema = serde_json::to_value(body(gen)).unwrap_or_default();
+            if self.sparse_fields {
+                let partial = json!({
+                    "type": "array",
+                    "items": { "type": "object", "description": "Only the fields named in `fields`" },
+                });
+                schema = json!({ "oneOf": [schema, partial] });
+            }
+            response["content"] = json!({ self.content_type: { "schema": schema } });
+        }
+        let mut operation = json!({
+            "summary": self.summary,
+            "responses": {
+                self.status.to_string(): response,
+                "default": {
+                    "description": "Error",
+                    "content": { "application/json": { "schema": error } },
+                },
+            },
+        });
+        if !parameters.is_empty() {
+            operation["parameters"] = Value::Array(parameters);
+        }
+        if let Some(body) = self.body {
+            operation["requestBody"] = json!({
+                "required": true,
+                "content": { "application/json": { "schema": body(gen) } },
+            });
+        }
+        match self.access {
+            Access::Public => operation["security"] = json!([]),
+            Access::Authenticated => {}
+            Access::Scope(scope) => operation["x-required-scope"] = json!(scope),
+        }
+        operation
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        101 => "Switching Protocols",
+        201 => "Created",
+        204 => "No Content",
+        _ => "OK",
+    }
+}
+
+/// Builds the OpenAPI document of the operations, with `E` as the body of error responses.
+/// Routes requiring credentials accept an API key or a bearer token.
+pub fn document<E: JsonSchema>(title: &str, operations: &[Operation]) -> Value {
+    let mut gen = SchemaSettings::openapi3().into_generator();
+    let error = gen.subschema_for::<E>();
+    let mut paths = Map::new();
+    for operation in operations {
+        let path = paths.entry(operation.path).or_insert_with(|| json!({}));
+        path[operation.method] = operation.describe(&mut gen, &error);
+    }
+    json!({
+        "openapi": OPENAPI_VERSION,
+        "info": { "title": title, "version": env!("CARGO_PKG_VERSION") },
+        "paths": paths,
+        "security": [{ "api_key": [] }, { "bearer": [] }],
+        "components": {
+            "schemas": gen.take_definitions(),
+            "securitySchemes": {
+                "api_key": { "type": "apiKey", "in": "header", "name": "X-API-Key" },
+                "bearer": { "type": "http", "scheme": "bearer" },
+            },
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::{Device, DeviceQuery};
+
+    #[derive(JsonSchema)]
+    #[allow(dead_code)]
+    struct Error {
+        error: String,
+    }
+
+    #[test]
+    fn test_document_describes_operations_and_their_types() {
+        let operations = vec![
+            Operation::new("get", "/api/devices", "List devices")
+                .scope(Scope::ReadTelemetry)
+                .query::<DeviceQuery>()
+                .returns::<Vec<Device>>(200)
+                .sparse_fields(),
+            Operation::new("patch", "/api/devices/{device_id}", "Update a device")
+                .scope(Scope::ManageDevices)
+                .body::<Device>()
+                .returns::<Device>(200),
+            Operation::new("get", "/health", "Liveness check"),
+        ];
+        let document = document::<Error>("Test", &operations);
+
+        let list = &document["paths"]["/api/devices"]["get"];
+        assert_eq!(list["x-required-scope"], "read_telemetry");
+        let parameters = list["parameters"].as_array().unwrap();
+        assert!(parameters.iter().any(|parameter| parameter["name"] == "tag" && parameter["in"] == "query"));
+        assert!(!parameters.iter().any(|parameter| parameter["name"] == "tenant_id"));
+        // Devices come whole, or with only the requested fields
+        let devices = &list["responses"]["200"]["content"]["application/json"]["schema"]["oneOf"];
+        assert_eq!(devices[0]["items"]["$ref"], "#/components/schemas/Device");
+        assert_eq!(devices[1]["items"]["type"], "object");
+
+        let update = &document["paths"]["/api/devices/{device_id}"]["patch"];
+        assert_eq!(update["parameters"][0]["name"], "device_id");
+        assert_eq!(update["parameters"][0]["in"], "path");
+        assert!(document["components"]["schemas"]["Device"]["properties"]["name"].is_object());
+        assert!(document["components"]["schemas"]["Location"].is_object());
+        let error = &update["responses"]["default"]["content"]["application/json"]["schema"];
+        assert_eq!(error["$ref"], "#/components/schemas/Error");
+        assert_eq!(document["paths"]["/health"]["get"]["security"], json!([]));
+    }
+}
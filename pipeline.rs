@@ -0,0 +1,510 @@
+// pipeline.rs
+
+use crate::config::StageConfig;
+use crate::expression::{EvalContext, Expr};
+use crate::plugin::{PluginHost, PluginStage};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Result of running a single stage on a reading.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StageOutcome {
+    /// Pass the reading on to the next stage.
+    Continue,
+    /// Discard the whole reading.
+    Drop,
+}
+
+/// A single transformation applied to the readings of a device.
+pub trait Stage: Send + Sync {
+    /// Short description of the stage used in metrics.
+    fn name(&self) -> String;
+
+    /// Applies the stage to the readings in place.
+    fn apply(&self, device_id: &str, data: &mut HashMap<String, f64>) -> StageOutcome;
+}
+
+/// Converts a metric between units with a linear `value * gain + offset` formula.
+pub struct UnitConversion {
+    metric: String,
+    from: String,
+    to: String,
+    gain: f64,
+    offset: f64,
+}
+
+impl UnitConversion {
+    /// Creates a conversion between two known units.
+    pub fn new(metric: String, from: String, to: String) -> crate::Result<Self> {
+        let (gain, offset) = match (from.to_lowercase().as_str(), to.to_lowercase().as_str()) {
+            ("celsius", "fahrenheit") => (1.8, 32.0),
+            ("fahrenheit", "celsius") => (1.0 / 1.8, -32.0 / 1.8),
+            ("celsius", "kelvin") => (1.0, 273.15),
+            ("kelvin", "celsius") => (1.0, -273.15),
+            ("pa", "hpa") => (0.01, 0.0),
+            ("hpa", "pa") => (100.0, 0.0),
+            ("mv", "v") => (0.001, 0.0),
+            ("v", "mv") => (1000.0, 0.0),
+            ("ratio", "percent") => (100.0, 0.0),
+            ("percent", "ratio") => (0.01, 0.0),
+            ("m/s", "km/h") => (3.6, 0.0),
+            ("km/h", "m/s") => (1.0 / 3.6, 0.0),
+            _ => return Err(format!("unsupported unit conversion from {} to {}", from, to).into()),
+        };
+        Ok(UnitConversion {
+            metric,
+            from,
+            to,
+            gain,
+            offset,
+        })
+    }
+}
+
+impl Stage for UnitConversion {
+    fn name(&self) -> String {
+        format!("unit_conversion({}: {} -> {})", self.metric, self.from, self.to)
+    }
+
+    fn apply(&self, _device_id: &str, data: &mut HashMap<String, f64>) -> StageOutcome {
+        if let Some(value) = data.get_mut(&self.metric) {
+            *value = *value * self.gain + self.offset;
+        }
+        StageOutcome::Continue
+    }
+}
+
+/// Corrects a sensor's systematic error with a gain and an offset.
+pub struct Calibration {
+    metric: String,
+    gain: f64,
+    offset: f64,
+}
+
+impl Calibration {
+    pub fn new(metric: String, gain: f64, offset: f64) -> Self {
+        Calibration { metric, gain, offset }
+    }
+}
+
+impl Stage for Calibration {
+    fn name(&self) -> String {
+        format!("calibration({})", self.metric)
+    }
+
+    fn apply(&self, _device_id: &str, data: &mut HashMap<String, f64>) -> StageOutcome {
+        if let Some(value) = data.get_mut(&self.metric) {
+            *value = *value * self.gain + self.offset;
+        }
+        StageOutcome::Continue
+    }
+}
+
+/// Limits a metric to a range.
+pub struct Clamp {
+    metric: String,
+    min: Option<f64>,
+    max: Option<f64>,
+}
+
+impl Clamp {
+    pub fn new(metric: String, min: Option<f64>, max: Option<f64>) -> Self {
+        Clamp { metric, min, max }
+    }
+}
+
+impl Stage for Clamp {
+    fn name(&self) -> String {
+        format!("clamp({})", self.metric)
+    }
+
+    fn apply(&self, _device_id: &str, data: &mut HashMap<String, f64>) -> StageOutcome {
+        if let Some(value) = data.get_mut(&self.metric) {
+            if let Some(min) = self.min {
+                *value = value.max(min);
+            }
+            if let Some(max) = self.max {
+                *value = value.min(max);
+            }
+        }
+        StageOutcome::Continue
+    }
+}
+
+/// Smooths a metric with an exponential moving average kept per device.
+pub struct Smoothing {
+    metric: String,
+    alpha: f64,
+    averages: Mutex<HashMap<String, f64>>,
+}
+
+impl Smoothing {
+    /// Creates a smoothing stage. `alpha` is the weight of the newest value, between 0 and 1.
+    pub fn new(metric: String, alpha: f64) -> crate::Result<Self> {
+        if !(alpha > 0.0 && alpha <= 1.0) {
+            return Err(format!("smoothing alpha must be in (0, 1], got {}", alpha).into());
+        }
+        Ok(Smoothing {
+            metric,
+            alpha,
+            averages: Mutex::new(HashMap::new()),
+        })
+    }
+}
+
+impl Stage for Smoothing {
+    fn name(&self) -> String {
+        format!("smoothing({})", self.metric)
+    }
+
+    fn apply(&self, device_id: &str, data: &mut HashMap<String, f64>) -> StageOutcome {
+        if let Some(value) = data.get_mut(&self.metric) {
+            let mut averages = self.averages.lock().unwrap();
+            let average = averages.entry(device_id.to_string()).or_insert(*value);
+            *average = self.alpha * *value + (1.0 - self.alpha) * *average;
+            *value = *average;
+        }
+        StageOutcome::Continue
+    }
+}
+
+/// Operations available to derived metrics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum DerivedOperation {
+    Sum,
+    Difference,
+    Product,
+    Ratio,
+    Mean,
+}
+
+/// Computes a new metric from other metrics of the same reading.
+pub struct Derived {
+    name: String,
+    inputs: Vec<String>,
+    operation: DerivedOperation,
+}
+
+impl Derived {
+    pub fn new(name: String, inputs: Vec<String>, operation: DerivedOperation) -> crate::Result<Self> {
+        if inputs.is_empty() {
+            return Err(format!("derived metric {} needs at least one input", name).into());
+        }
+        Ok(Derived { name, inputs, operation })
+    }
+}
+
+impl Stage for Derived {
+    fn name(&self) -> String {
+        format!("derived({})", self.name)
+    }
+
+    fn apply(&self, _device_id: &str, data: &mut HashMap<String, f64>) -> StageOutcome {
+        // Only derive the metric when every input is present in the reading
+        let values: Option<Vec<f64>> = self.inputs.iter().map(|input| data.get(input).copied()).collect();
+        let values = match values {
+            Some(values) => values,
+            None => return StageOutcome::Continue,
+        };
+
+        let (first, rest) = (values[0], &values[1..]);
+        let result = match self.operation {
+            DerivedOperation::Sum => values.iter().sum(),
+            DerivedOperation::Difference => rest.iter().fold(first, |acc, v| acc - v),
+            DerivedOperation::Product => values.iter().product(),
+            DerivedOperation::Ratio => rest.iter().fold(first, |acc, v| acc / v),
+            DerivedOperation::Mean => values.iter().sum::<f64>() / values.len() as f64,
+        };
+        if result.is_finite() {
+            data.insert(self.name.clone(), result);
+        }
+        StageOutcome::Continue
+    }
+}
+
+/// Computes a virtual metric from an expression over the reading and the device's
+/// previous reading, storing it alongside the raw metrics.
+pub struct ExpressionStage {
+    name: String,
+    expression: Expr,
+    previous: Mutex<HashMap<String, HashMap<String, f64>>>,
+}
+
+impl ExpressionStage {
+    /// Parses the expression and creates the stage.
+    pub fn new(name: String, expression: &str) -> crate::Result<Self> {
+        let expression = Expr::parse(expression).map_err(|e| format!("invalid expression for {}: {}", name, e))?;
+        Ok(ExpressionStage {
+            name,
+            expression,
+            previous: Mutex::new(HashMap::new()),
+        })
+    }
+}
+
+impl Stage for ExpressionStage {
+    fn name(&self) -> String {
+        format!("expression({})", self.name)
+    }
+
+    fn apply(&self, device_id: &str, data: &mut HashMap<String, f64>) -> StageOutcome {
+        let mut previous = self.previous.lock().unwrap();
+        let context = EvalContext {
+            current: data,
+            previous: previous.get(device_id),
+        };
+        let value = self.expression.evaluate(&context);
+
+        if let Some(value) = value.filter(|value| value.is_finite()) {
+            data.insert(self.name.clone(), value);
+        }
+        // Remember the reading, merged over older values, so prev() finds metrics the device
+        // does not send with every message
+        let device_previous = previous.entry(device_id.to_string()).or_default();
+        device_previous.extend(data.iter().map(|(key, value)| (key.clone(), *value)));
+        StageOutcome::Continue
+    }
+}
+
+/// Drops readings, or single metrics, whose value falls outside a range.
+pub struct Filter {
+    metric: String,
+    min: Option<f64>,
+    max: Option<f64>,
+    drop_reading: bool,
+}
+
+impl Filter {
+    /// Creates a filter. When `drop_reading` is false only the offending metric is removed.
+    pub fn new(metric: String, min: Option<f64>, max: Option<f64>, drop_reading: bool) -> Self {
+        Filter {
+            metric,
+            min,
+            max,
+            drop_reading,
+        }
+    }
+}
+
+impl Stage for Filter {
+    fn name(&self) -> String {
+        format!("filter({})", self.metric)
+    }
+
+    fn apply(&self, _device_id: &str, data: &mut HashMap<String, f64>) -> StageOutcome {
+        let value = match data.get(&self.metric) {
+            Some(value) => *value,
+            None => return StageOutcome::Continue,
+        };
+        let out_of_range = value.is_nan()
+            || self.min.map_or(false, |min| value < min)
+            || self.max.map_or(false, |max| value > max);
+
+        if !out_of_range {
+            StageOutcome::Continue
+        } else if self.drop_reading {
+            StageOutcome::Drop
+        } else {
+            data.remove(&self.metric);
+            StageOutcome::Continue
+        }
+    }
+}
+
+/// Counters collected for each stage of a pipeline.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StageMetrics {
+    pub stage: String,
+    pub processed: u64,
+    pub dropped: u64,
+    pub total_micros: u64,
+}
+
+/// An ordered list of stages applied to every reading of a device type.
+pub struct Pipeline {
+    stages: Vec<Box<dyn Stage>>,
+    metrics: Mutex<Vec<StageMetrics>>,
+}
+
+impl Pipeline {
+    /// Creates a pipeline from already constructed stages.
+    pub fn new(stages: Vec<Box<dyn Stage>>) -> Self {
+        let metrics = stages
+            .iter()
+            .map(|stage| StageMetrics {
+                stage: stage.name(),
+                ..StageMetrics::default()
+            })
+            .collect();
+        Pipeline {
+            stages,
+            metrics: Mutex::new(metrics),
+        }
+    }
+
+    /// Builds a pipeline from its configuration, rejecting invalid stage settings.
+    pub fn from_config(config: &[StageConfig]) -> crate::Result<Self> {
+        Self::with_plugins(config, None)
+    }
+
+    /// Builds a pipeline whose plugin stages run on the given plugin host.
+    pub fn with_plugins(config: &[StageConfig], plugins: Option<&Arc<PluginHost>>) -> crate::Result<Self> {
+        let mut stages: Vec<Box<dyn Stage>> = Vec::new();
+        for stage in config.iter().cloned() {
+            let stage: Box<dyn Stage> = match stage {
+                StageConfig::UnitConversion { metric, from, to } => Box::new(UnitConversion::new(metric, from, to)?),
+                StageConfig::Calibration { metric, gain, offset } => Box::new(Calibration::new(metric, gain, offset)),
+                StageConfig::Clamp { metric, min, max } => Box::new(Clamp::new(metric, min, max)),
+                StageConfig::Smoothing { metric, alpha } => Box::new(Smoothing::new(metric, alpha)?),
+                StageConfig::Derived { name, inputs, operation } => Box::new(Derived::new(name, inputs, operation)?),
+                StageConfig::Expression { name, expression } => Box::new(ExpressionStage::new(name, &expression)?),
+                StageConfig::Filter { metric, min, max, drop_reading } => {
+                    Box::new(Filter::new(metric, min, max, drop_reading))
+                }
+                StageConfig::Plugin { name } => {
+                    let host = plugins.ok_or_else(|| format!("plugin stage {} needs a plugin host", name))?;
+                    Box::new(PluginStage::new(name, host.clone()))
+                }
+            };
+            stages.push(stage);
+        }
+        Ok(Self::new(stages))
+    }
+
+    /// Runs the reading through every stage, returning None if a stage dropped it.
+    pub fn run(&self, device_id: &str, mut data: HashMap<String, f64>) -> Option<HashMap<String, f64>> {
+        for (index, stage) in self.stages.iter().enumerate() {
+            let started = Instant::now();
+            let outcome = stage.apply(device_id, &mut data);
+            let elapsed = started.elapsed().as_micros() as u64;
+
+            let mut metrics = self.metrics.lock().unwrap();
+            let stage_metrics = &mut metrics[index];
+            stage_metrics.processed += 1;
+            stage_metrics.total_micros += elapsed;
+            if outcome == StageOutcome::Drop {
+                stage_metrics.dropped += 1;
+                return None;
+            }
+        }
+        Some(data)
+    }
+
+    /// Returns the counters of every stage in order.
+    pub fn get_metrics(&self) -> Vec<StageMetrics> {
+        let metrics = self.metrics.lock().unwrap();
+        metrics.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reading(values: &[(&str, f64)]) -> HashMap<String, f64> {
+        values.iter().map(|(k, v)| (k.to_string(), *v)).collect()
+    }
+
+    #[test]
+    fn test_stages_apply_in_order() {
+        let config = vec![
+            StageConfig::Calibration {
+                metric: "temperature".to_string(),
+                gain: 1.0,
+                offset: -0.5,
+            },
+            StageConfig::UnitConversion {
+                metric: "temperature".to_string(),
+                from: "celsius".to_string(),
+                to: "fahrenheit".to_string(),
+            },
+            StageConfig::Clamp {
+                metric: "humidity".to_string(),
+                min: Some(0.0),
+                max: Some(100.0),
+            },
+            StageConfig::Derived {
+                name: "power".to_string(),
+                inputs: vec!["voltage".to_string(), "current".to_string()],
+                operation: DerivedOperation::Product,
+            },
+        ];
+        let pipeline = Pipeline::from_config(&config).unwrap();
+
+        let data = reading(&[("temperature", 20.5), ("humidity", 104.0), ("voltage", 12.0), ("current", 0.5)]);
+        let processed = pipeline.run("device1", data).unwrap();
+
+        assert!((processed["temperature"] - 68.0).abs() < 1e-9);
+        assert_eq!(processed["humidity"], 100.0);
+        assert_eq!(processed["power"], 6.0);
+    }
+
+    #[test]
+    fn test_smoothing_is_per_device() {
+        let pipeline = Pipeline::new(vec![Box::new(Smoothing::new("temperature".to_string(), 0.5).unwrap())]);
+
+        pipeline.run("device1", reading(&[("temperature", 10.0)]));
+        let smoothed = pipeline.run("device1", reading(&[("temperature", 20.0)])).unwrap();
+        assert_eq!(smoothed["temperature"], 15.0);
+
+        let other = pipeline.run("device2", reading(&[("temperature", 20.0)])).unwrap();
+        assert_eq!(other["temperature"], 20.0);
+    }
+
+    #[test]
+    fn test_filter_drops_and_counts() {
+        let pipeline = Pipeline::new(vec![
+            Box::new(Filter::new("temperature".to_string(), Some(-40.0), Some(85.0), true)),
+            Box::new(Filter::new("humidity".to_string(), Some(0.0), Some(100.0), false)),
+        ]);
+
+        assert!(pipeline.run("device1", reading(&[("temperature", 500.0)])).is_none());
+        let kept = pipeline.run("device1", reading(&[("temperature", 20.0), ("humidity", -3.0)])).unwrap();
+        assert!(!kept.contains_key("humidity"));
+
+        let metrics = pipeline.get_metrics();
+        assert_eq!(metrics[0].processed, 2);
+        assert_eq!(metrics[0].dropped, 1);
+        assert_eq!(metrics[1].processed, 1);
+        assert_eq!(metrics[1].dropped, 0);
+    }
+
+    #[test]
+    fn test_expression_stage_adds_virtual_metrics() {
+        let config = vec![
+            StageConfig::Expression {
+                name: "power".to_string(),
+                expression: "voltage * current".to_string(),
+            },
+            StageConfig::Expression {
+                name: "energy_delta".to_string(),
+                expression: "energy - prev(energy)".to_string(),
+            },
+        ];
+        let pipeline = Pipeline::from_config(&config).unwrap();
+
+        let first = pipeline
+            .run("meter1", reading(&[("voltage", 230.0), ("current", 2.0), ("energy", 100.0)]))
+            .unwrap();
+        assert_eq!(first["power"], 460.0);
+        assert!(!first.contains_key("energy_delta"));
+
+        let second = pipeline.run("meter1", reading(&[("energy", 104.5)])).unwrap();
+        assert_eq!(second["energy_delta"], 4.5);
+        assert!(!second.contains_key("power"));
+    }
+
+    #[test]
+    fn test_invalid_stage_config_is_rejected() {
+        let config = vec![StageConfig::UnitConversion {
+            metric: "temperature".to_string(),
+            from: "celsius".to_string(),
+            to: "parsecs".to_string(),
+        }];
+        assert!(Pipeline::from_config(&config).is_err());
+    }
+}
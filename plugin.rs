@@ -0,0 +1,332 @@
+// plugin.rs
+
+use crate::config::PluginConfig;
+use crate::pipeline::{Stage, StageOutcome};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tokio::sync::watch;
+use wasmtime::{Engine, Linker, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+/// Export called on decoder plugins.
+const DECODE_EXPORT: &str = "decode";
+/// Export called on processing stage plugins.
+const PROCESS_EXPORT: &str = "process";
+
+struct LoadedPlugin {
+    module: Module,
+    path: PathBuf,
+    modified: Option<SystemTime>,
+}
+
+/// Counters describing how a plugin is doing.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PluginMetrics {
+    pub name: String,
+    pub invocations: u64,
+    pub failures: u64,
+    pub last_error: Option<String>,
+}
+
+/// Loads customer plugins compiled to WebAssembly from the plugin directory and runs them in
+/// a sandbox: every call gets a fresh instance with no imports, a fuel budget and a memory limit.
+///
+/// Plugins are named after their file stem and follow this ABI:
+/// - The module exports its `memory` and `alloc(len: i32) -> i32`, returning a buffer the host
+///   copies the input into.
+/// - A decoder exports `decode(ptr: i32, len: i32) -> i64`. The input is the raw datagram and
+///   the output a message in the platform's JSON format, e.g. `{"temperature": 21.5}`.
+/// - A processing stage exports `process(ptr: i32, len: i32) -> i64`. Input and output are the
+///   readings as a JSON object; an empty output drops the reading.
+/// - Results are returned as `(ptr << 32) | len`; a negative value signals an error.
+pub struct PluginHost {
+    config: PluginConfig,
+    engine: Engine,
+    plugins: Arc<Mutex<HashMap<String, LoadedPlugin>>>,
+    metrics: Arc<Mutex<HashMap<String, PluginMetrics>>>,
+}
+
+impl PluginHost {
+    /// Creates a plugin host and loads every plugin in the configured directory.
+    pub fn new(config: PluginConfig) -> crate::Result<Self> {
+        let mut engine_config = wasmtime::Config::new();
+        engine_config.consume_fuel(true);
+        let host = PluginHost {
+            engine: Engine::new(&engine_config)?,
+            config,
+            plugins: Arc::new(Mutex::new(HashMap::new())),
+            metrics: Arc::new(Mutex::new(HashMap::new())),
+        };
+        host.reload()?;
+        Ok(host)
+    }
+
+    /// Loads new and changed plugins and unloads deleted ones. A plugin that fails to compile
+    /// keeps its previous version. Returns the number of plugins (re)loaded.
+    pub fn reload(&self) -> crate::Result<usize> {
+        let dir = match &self.config.plugin_dir {
+            Some(dir) if dir.exists() => dir,
+            _ => return Ok(0),
+        };
+
+        let mut found = HashMap::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            let is_module = matches!(path.extension().and_then(|ext| ext.to_str()), Some("wasm") | Some("wat"));
+            if let (true, Some(name)) = (is_module, path.file_stem().and_then(|stem| stem.to_str())) {
+                let modified = std::fs::metadata(&path).and_then(|metadata| metadata.modified()).ok();
+                found.insert(name.to_string(), (path.clone(), modified));
+            }
+        }
+
+        let mut plugins = self.plugins.lock().unwrap();
+        plugins.retain(|name, _| found.contains_key(name));
+
+        let mut loaded = 0;
+        for (name, (path, modified)) in found {
+            let unchanged = plugins
+                .get(&name)
+                .is_some_and(|plugin| plugin.path == path && plugin.modified == modified);
+            if unchanged {
+                continue;
+            }
+            match Module::from_file(&self.engine, &path) {
+                Ok(module) => {
+                    println!("Loaded plugin {} from {}", name, path.display());
+                    plugins.insert(name, LoadedPlugin { module, path, modified });
+                    loaded += 1;
+                }
+                Err(e) => eprintln!("Failed to load plugin {} from {}: {}", name, path.display(), e),
+            }
+        }
+        Ok(loaded)
+    }
+
+    /// Reloads the plugin directory periodically until the shutdown signal fires.
+    pub async fn watch(&self, mut shutdown: watch::Receiver<bool>) {
+        let interval = std::time::Duration::from_secs(self.config.reload_interval_secs.max(1));
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(interval) => {
+                    if let Err(e) = self.reload() {
+                        eprintln!("Failed to reload plugins: {}", e);
+                    }
+                }
+                _ = shutdown.changed() => break,
+            }
+        }
+    }
+
+    /// Returns the names of the loaded plugins.
+    pub fn list_plugins(&self) -> Vec<String> {
+        let plugins = self.plugins.lock().unwrap();
+        let mut names: Vec<String> = plugins.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Returns the decoder plugin configured for a device type, if any.
+    pub fn decoder_for(&self, device_type: &str) -> Option<String> {
+        self.config.decoders.get(device_type).cloned()
+    }
+
+    /// Runs a decoder plugin on a raw datagram, returning the decoded JSON message.
+    pub fn decode(&self, plugin: &str, payload: &[u8]) -> crate::Result<String> {
+        let output = self.invoke(plugin, DECODE_EXPORT, payload)?;
+        Ok(String::from_utf8(output)?)
+    }
+
+    /// Runs a processing stage plugin on a reading. Returns None if the plugin dropped it.
+    pub fn process(&self, plugin: &str, data: &HashMap<String, f64>) -> crate::Result<Option<HashMap<String, f64>>> {
+        let output = self.invoke(plugin, PROCESS_EXPORT, &serde_json::to_vec(data)?)?;
+        if output.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_slice(&output)?))
+    }
+
+    /// Calls an export of a plugin, recording the outcome in the plugin's metrics.
+    fn invoke(&self, plugin: &str, export: &str, input: &[u8]) -> crate::Result<Vec<u8>> {
+        let result = self.call(plugin, export, input);
+
+        let mut metrics = self.metrics.lock().unwrap();
+        let plugin_metrics = metrics.entry(plugin.to_string()).or_insert_with(|| PluginMetrics {
+            name: plugin.to_string(),
+            ..PluginMetrics::default()
+        });
+        plugin_metrics.invocations += 1;
+        if let Err(e) = &result {
+            plugin_metrics.failures += 1;
+            plugin_metrics.last_error = Some(e.to_string());
+        }
+        result
+    }
+
+    fn call(&self, plugin: &str, export: &str, input: &[u8]) -> crate::Result<Vec<u8>> {
+        let module = {
+            let plugins = self.plugins.lock().unwrap();
+            plugins.get(plugin).ok_or_else(|| format!("plugin {} is not loaded", plugin))?.module.clone()
+        };
+
+        // Every call gets a fresh instance, so plugins cannot keep state or leak memory between calls
+        let limits = StoreLimitsBuilder::new()
+            .memory_size(self.config.max_memory_bytes)
+            .instances(1)
+            .build();
+        let mut store: Store<StoreLimits> = Store::new(&self.engine, limits);
+        store.limiter(|limits| limits);
+        store.set_fuel(self.config.fuel)?;
+
+        // No host functions are linked, so a plugin has no access to the outside world
+        let instance = Linker::new(&self.engine).instantiate(&mut store, &module)?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| format!("plugin {} does not export its memory", plugin))?;
+        let alloc = instance.get_typed_func::<i32, i32>(&mut store, "alloc")?;
+        let entry = instance.get_typed_func::<(i32, i32), i64>(&mut store, export)?;
+
+        let input_len = i32::try_from(input.len())?;
+        let input_ptr = alloc.call(&mut store, input_len)?;
+        memory.write(&mut store, input_ptr as usize, input)?;
+
+        let packed = entry.call(&mut store, (input_ptr, input_len))?;
+        if packed < 0 {
+            return Err(format!("plugin {} failed with code {}", plugin, packed).into());
+        }
+        let (output_ptr, output_len) = ((packed >> 32) as usize, (packed & 0xffff_ffff) as usize);
+        let output = memory
+            .data(&store)
+            .get(output_ptr..output_ptr + output_len)
+            .ok_or_else(|| format!("plugin {} returned output outside its memory", plugin))?;
+        Ok(output.to_vec())
+    }
+
+    /// Returns the counters of every plugin that was called.
+    pub fn get_metrics(&self) -> Vec<PluginMetrics> {
+        let metrics = self.metrics.lock().unwrap();
+        let mut metrics: Vec<PluginMetrics> = metrics.values().cloned().collect();
+        metrics.sort_by(|a, b| a.name.cmp(&b.name));
+        metrics
+    }
+
+    /// Returns the directory plugins are loaded from.
+    pub fn plugin_dir(&self) -> Option<&Path> {
+        self.config.plugin_dir.as_deref()
+    }
+}
+
+/// Processing stage implemented by a plugin. The plugin is looked up on every call, so a
+/// reloaded plugin takes effect immediately. If the plugin fails the reading passes unchanged.
+pub struct PluginStage {
+    plugin: String,
+    host: Arc<PluginHost>,
+}
+
+impl PluginStage {
+    /// Creates a stage calling the named plugin.
+    pub fn new(plugin: String, host: Arc<PluginHost>) -> Self {
+        PluginStage { plugin, host }
+    }
+}
+
+impl Stage for PluginStage {
+    fn name(&self) -> String {
+        format!("plugin({})", self.plugin)
+    }
+
+    fn apply(&self, device_id: &str, data: &mut HashMap<String, f64>) -> StageOutcome {
+        match self.host.process(&self.plugin, data) {
+            Ok(Some(processed)) => {
+                *data = processed;
+                StageOutcome::Continue
+            }
+            Ok(None) => StageOutcome::Drop,
+            Err(e) => {
+                eprintln!("Plugin {} failed on a reading from {}: {}", self.plugin, device_id, e);
+                StageOutcome::Continue
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Returns its input unchanged, dropping readings that are `{}`
+    const ECHO: &str = r#"(module
+        (memory (export "memory") 1)
+        (func (export "alloc") (param i32) (result i32) i32.const 1024)
+        (func (export "process") (param $ptr i32) (param $len i32) (result i64)
+            (if (result i64) (i32.le_u (local.get $len) (i32.const 2))
+                (then (i64.const 0))
+                (else (i64.or
+                    (i64.shl (i64.extend_i32_u (local.get $ptr)) (i64.const 32))
+                    (i64.extend_i32_u (local.get $len)))))))"#;
+
+    // Ignores the payload and always reports the same temperature
+    const DECODER: &str = r#"(module
+        (memory (export "memory") 1)
+        (data (i32.const 16) "{\"temperature\": 21.5}")
+        (func (export "alloc") (param i32) (result i32) i32.const 1024)
+        (func (export "decode") (param i32 i32) (result i64)
+            (i64.or (i64.shl (i64.const 16) (i64.const 32)) (i64.const 21))))"#;
+
+    const SPIN: &str = r#"(module
+        (memory (export "memory") 1)
+        (func (export "alloc") (param i32) (result i32) i32.const 1024)
+        (func (export "process") (param i32 i32) (result i64)
+            (loop $forever (br $forever))
+            i64.const 0))"#;
+
+    fn host(name: &str, plugins: &[(&str, &str)]) -> PluginHost {
+        let dir = std::env::temp_dir().join(format!("iot_plugin_test_{}", name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        for (plugin, source) in plugins {
+            std::fs::write(dir.join(format!("{}.wat", plugin)), source).unwrap();
+        }
+        PluginHost::new(PluginConfig {
+            plugin_dir: Some(dir),
+            ..PluginConfig::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_decoder_and_stage_plugins() {
+        let host = Arc::new(host("run", &[("echo", ECHO), ("decoder", DECODER)]));
+        assert_eq!(host.list_plugins(), vec!["decoder".to_string(), "echo".to_string()]);
+
+        assert_eq!(host.decode("decoder", &[0x01, 0x02]).unwrap(), r#"{"temperature": 21.5}"#);
+
+        let stage = PluginStage::new("echo".to_string(), host.clone());
+        let mut data = HashMap::from([("temperature".to_string(), 21.5)]);
+        assert_eq!(stage.apply("device1", &mut data), StageOutcome::Continue);
+        assert_eq!(data["temperature"], 21.5);
+        assert_eq!(stage.apply("device1", &mut HashMap::new()), StageOutcome::Drop);
+    }
+
+    #[test]
+    fn test_runaway_plugin_runs_out_of_fuel() {
+        let host = host("fuel", &[("spin", SPIN)]);
+        assert!(host.process("spin", &HashMap::new()).is_err());
+
+        let metrics = host.get_metrics();
+        assert_eq!(metrics[0].failures, 1);
+        assert!(metrics[0].last_error.is_some());
+    }
+
+    #[test]
+    fn test_reload_picks_up_new_and_removed_plugins() {
+        let host = host("reload", &[("echo", ECHO)]);
+        let dir = host.plugin_dir().unwrap().to_path_buf();
+
+        std::fs::write(dir.join("decoder.wat"), DECODER).unwrap();
+        std::fs::remove_file(dir.join("echo.wat")).unwrap();
+        assert_eq!(host.reload().unwrap(), 1);
+        assert_eq!(host.list_plugins(), vec!["decoder".to_string()]);
+    }
+}
@@ -4,9 +4,11 @@
 use my_iot_platform::{
     initialize_services, Config, ProcessingService, Result,
 };
+use std::sync::Arc;
 use std::{env, thread, time::Duration};
 
-fn main() -> Result<()> {
+#[tokio::main]
+async fn main() -> Result<()> {
     // Load configuration from a file specified in the environment variable
     let config_path = env::var("CONFIG_PATH").expect("CONFIG_PATH environment variable not set");
     let config = Config::from_file(config_path.into())?;
@@ -14,25 +16,34 @@ fn main() -> Result<()> {
     // Initialize services
     let (_, _, processing_service, _) = initialize_services(config)?;
 
-    // Start the processing loop
-    start_processing_loop(processing_service);
+    // Start the metrics reporting loop
+    start_processing_loop(processing_service.clone());
+
+    // Report until Ctrl-C. The scheduled jobs run next to the pipeline in ingestion_main,
+    // where the state they work on is kept
+    tokio::signal::ctrl_c().await?;
 
     Ok(())
 }
 
 /// Starts the processing loop for the processing service.
-fn start_processing_loop(processing_service: ProcessingService) {
+fn start_processing_loop(processing_service: Arc<ProcessingService>) {
     // Retrieve the processing interval from the service's configuration
     let processing_interval = processing_service.get_processing_interval();
 
-    // Start the processing thread
+    // Readings are pushed through the pipelines as they are ingested, so the loop only
+    // reports how each pipeline stage is doing.
     thread::spawn(move || loop {
-        // Process data
-        if let Err(e) = processing_service.process_data() {
-            eprintln!("Error processing data: {}", e);
+        for (device_type, stages) in processing_service.get_pipeline_metrics() {
+            for stage in stages {
+                println!(
+                    "[{}] {}: processed {}, dropped {}",
+                    device_type, stage.stage, stage.processed, stage.dropped
+                );
+            }
         }
 
-        // Sleep for the specified interval before processing again
+        // Sleep for the specified interval before reporting again
         thread::sleep(Duration::from_secs(processing_interval));
     });
 
@@ -40,27 +51,4 @@ fn start_processing_loop(processing_service: ProcessingService) {
     // the lifecycle of the thread, handle shutdown signals, and ensure that resources are
     // cleaned up properly. For simplicity, this example assumes the thread runs indefinitely.
 }
-
-impl ProcessingService {
-    /// Retrieves the processing interval from the service's configuration.
-    fn get_processing_interval(&self) -> u64 {
-        self.config.processing_interval
-    }
-
-    /// Processes the data from IoT devices.
-    fn process_data(&self) -> Result<()> {
-        // Here you would implement the logic to process data from IoT devices.
-        // This could involve:
-        // - Retrieving data from the storage service
-        // - Running analytics on the data
-        // - Updating the monitoring service with the latest device status
-        // - Any other processing tasks required by the platform
-
-        // For demonstration purposes, we'll just print a message to the console.
-        println!("Processing data from IoT devices...");
-
-        // In a real-world application, you would replace this with actual processing logic.
-        Ok(())
-    }
-}
 ```
@@ -1,64 +1,339 @@
-use crate::config::Config;
-use crate::device::DeviceData;
-use crate::analytics::{Analytics, DataPoint};
-use std::sync::mpsc::{Receiver, Sender};
-use std::thread;
+// processing_service.rs
 
+use crate::analytics::Analytics;
+use crate::config::ProcessingConfig;
+use crate::device::{DeviceManager, Reading};
+use crate::events::{Event, EventBus};
+use crate::monitoring::Monitoring;
+use crate::pipeline::{Pipeline, StageMetrics};
+use crate::plugin::PluginHost;
+use crate::queue::{QueueMetrics, ReadingQueue};
+use crate::scheduler::Scheduler;
+use crate::storage_service::StorageService;
+use crate::tenant::{split_device_id, TenantManager};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Name of the pipeline applied to devices without a pipeline for their type.
+pub const DEFAULT_PIPELINE: &str = "default";
+
+/// Service that runs incoming readings through the configured processing pipelines
+/// before they are stored and analysed. Readings arrive on the input queue, fed by
+/// ingestion, and leave on the output queue, drained by storage.
 pub struct ProcessingService {
-    receiver: Receiver<DeviceData>,
-    sender: Sender<Analytics>,
-    config: Config,
+    device_manager: Arc<DeviceManager>,
+    analytics: Arc<Analytics>,
+    monitoring: Arc<Monitoring>,
+    config: ProcessingConfig,
+    pipelines: HashMap<String, Pipeline>,
+    input: Arc<ReadingQueue>,
+    output: Arc<ReadingQueue>,
+    scheduler: Arc<Scheduler>,
+    plugins: Arc<PluginHost>,
+    events: Option<Arc<EventBus>>,
+    tenants: Option<Arc<TenantManager>>,
+    // Pipelines configured by tenants, built on first use and rebuilt when the settings change
+    tenant_pipelines: Mutex<HashMap<String, TenantPipelines>>,
+}
+
+/// The pipelines of one tenant, built from a revision of its settings.
+struct TenantPipelines {
+    revision: u64,
+    pipelines: HashMap<String, Pipeline>,
 }
 
 impl ProcessingService {
-    pub fn new(receiver: Receiver<DeviceData>, sender: Sender<Analytics>, config: Config) -> Self {
-        ProcessingService {
-            receiver,
-            sender,
-            config,
+    /// Creates a new ProcessingService, building a pipeline for every configured device type,
+    /// the queues connecting it to ingestion and storage, and the scheduled jobs.
+    pub fn new(
+        device_manager: Arc<DeviceManager>,
+        analytics: Arc<Analytics>,
+        monitoring: Arc<Monitoring>,
+        plugins: Arc<PluginHost>,
+        config: ProcessingConfig,
+    ) -> crate::Result<Self> {
+        let mut pipelines = HashMap::new();
+        for (device_type, stages) in config.pipelines.iter() {
+            pipelines.insert(device_type.clone(), Pipeline::with_plugins(stages, Some(&plugins))?);
         }
+        let input = Arc::new(ReadingQueue::new("processing", &config.queue)?);
+        let output = Arc::new(ReadingQueue::new("storage", &config.queue)?);
+        let scheduler = Arc::new(Scheduler::from_config(&config.scheduler, analytics.clone(), monitoring.clone(), None)?);
+
+        Ok(ProcessingService {
+            device_manager,
+            analytics,
+            monitoring,
+            config,
+            pipelines,
+            input,
+            output,
+            scheduler,
+            plugins,
+            events: None,
+            tenants: None,
+            tenant_pipelines: Mutex::new(HashMap::new()),
+        })
     }
 
-    pub fn run(&self) {
-        thread::spawn(move || {
-            for data in self.receiver.iter() {
-                let processed_data = self.process_data(data);
-                self.sender.send(processed_data).unwrap();
-            }
-        });
+    /// Publishes every processed reading to the given event bus.
+    pub fn with_event_bus(mut self, events: Arc<EventBus>) -> Self {
+        self.events = Some(events);
+        self
     }
 
-    fn process_data(&self, data: DeviceData) -> Analytics {
-        // Process the raw data here
-        // This is a placeholder for the actual data processing logic
-        // which would depend on the specific requirements and data format
-        let processed_data_points = data.data_points.iter().map(|point| {
-            DataPoint {
-                timestamp: point.timestamp,
-                value: self.apply_processing_logic(point.value),
+    /// Runs readings of tenants that configured their own pipelines through those.
+    pub fn with_tenants(mut self, tenants: Arc<TenantManager>) -> Self {
+        self.tenants = Some(tenants);
+        self
+    }
+
+    /// Gives the scheduled jobs access to storage, rebuilding them from the configuration.
+    pub fn with_storage(mut self, storage: Arc<StorageService>) -> crate::Result<Self> {
+        self.scheduler = Arc::new(Scheduler::from_config(
+            &self.config.scheduler,
+            self.analytics.clone(),
+            self.monitoring.clone(),
+            Some(storage),
+        )?);
+        Ok(self)
+    }
+
+    /// Retrieves the processing interval from the service's configuration.
+    pub fn get_processing_interval(&self) -> u64 {
+        self.config.processing_interval
+    }
+
+    /// Returns the pipeline that applies to a device type.
+    fn pipeline_for<'a>(pipelines: &'a HashMap<String, Pipeline>, device_type: Option<&str>) -> Option<&'a Pipeline> {
+        device_type
+            .and_then(|device_type| pipelines.get(device_type))
+            .or_else(|| pipelines.get(DEFAULT_PIPELINE))
+    }
+
+    /// Runs a reading through the device's pipeline without storing it, preferring the
+    /// pipelines of the device's tenant over the platform's. Returns None if a stage dropped
+    /// the reading.
+    pub fn apply_pipeline(&self, device_id: &str, data: HashMap<String, f64>) -> Option<HashMap<String, f64>> {
+        let device_type = self.device_manager.get_device(device_id).and_then(|device| device.device_type);
+
+        if let Some(tenants) = &self.tenants {
+            let tenant_id = split_device_id(device_id).0;
+            if let Some(revision) = tenants.revision(tenant_id) {
+                let mut cache = self.tenant_pipelines.lock().unwrap();
+                if cache.get(tenant_id).map(|cached| cached.revision) != Some(revision) {
+                    let settings = tenants.settings_for_device(device_id);
+                    let mut pipelines = HashMap::new();
+                    for (device_type, stages) in settings.pipelines.iter() {
+                        match Pipeline::with_plugins(stages, Some(&self.plugins)) {
+                            Ok(pipeline) => {
+                                pipelines.insert(device_type.clone(), pipeline);
+                            }
+                            Err(e) => eprintln!("Invalid {} pipeline of tenant {}: {}", device_type, tenant_id, e),
+                        }
+                    }
+                    cache.insert(tenant_id.to_string(), TenantPipelines { revision, pipelines });
+                }
+                if let Some(pipeline) = Self::pipeline_for(&cache[tenant_id].pipelines, device_type.as_deref()) {
+                    return pipeline.run(device_id, data);
+                }
             }
-        }).collect();
+        }
+
+        match Self::pipeline_for(&self.pipelines, device_type.as_deref()) {
+            Some(pipeline) => pipeline.run(device_id, data),
+            None => Some(data),
+        }
+    }
+
+    /// Processes a reading received by ingestion: runs it through the pipeline, then updates
+    /// analytics at the reading's event time and the device's health. Returns the processed
+    /// reading, or None if it was dropped.
+    pub fn process(&self, reading: Reading) -> Option<Reading> {
+        let Reading { device_id, data, received_at, event_time } = reading;
+        let processed = self.apply_pipeline(&device_id, data)?;
+
+        self.analytics.process_event(&device_id, &processed, event_time);
+        self.monitoring.record_telemetry(&device_id, &processed);
+        let reading = Reading {
+            device_id,
+            data: processed,
+            received_at,
+            event_time,
+        };
+        if let Some(events) = &self.events {
+            events.publish(Event::Reading { reading: reading.clone() });
+        }
+        Some(reading)
+    }
+
+    /// Processes readings taken now, see `process`.
+    pub fn process_reading(&self, device_id: &str, data: HashMap<String, f64>) -> Option<HashMap<String, f64>> {
+        self.process(Reading::new(device_id.to_string(), data)).map(|reading| reading.data)
+    }
+
+    /// Returns the queue ingestion pushes raw readings to.
+    pub fn input_queue(&self) -> Arc<ReadingQueue> {
+        Arc::clone(&self.input)
+    }
 
-        Analytics {
-            device_id: data.device_id,
-            data_points: processed_data_points,
+    /// Returns the queue storage drains processed readings from.
+    pub fn output_queue(&self) -> Arc<ReadingQueue> {
+        Arc::clone(&self.output)
+    }
+
+    /// Processes readings from the input queue until it is closed and drained, then closes
+    /// the output queue so storage can finish the remaining readings in turn.
+    pub async fn run(&self) {
+        while let Some(reading) = self.input.pop().await {
+            if let Some(processed) = self.process(reading) {
+                if let Err(e) = self.output.push(processed).await {
+                    eprintln!("Failed to forward processed reading: {}", e);
+                }
+            }
         }
+        self.output.close();
+    }
+
+    /// Returns the scheduler running the service's recurring jobs.
+    pub fn scheduler(&self) -> Arc<Scheduler> {
+        Arc::clone(&self.scheduler)
+    }
+
+    /// Returns the monitoring service the processed readings are reported to.
+    pub fn monitoring(&self) -> Arc<Monitoring> {
+        Arc::clone(&self.monitoring)
     }
 
-    fn apply_processing_logic(&self, value: f64) -> f64 {
-        // Apply some kind of processing logic to the value
-        // This is a placeholder for the actual logic
-        value * self.config.processing_multiplier
+    /// Returns the host running the WebAssembly plugins of the pipelines.
+    pub fn plugins(&self) -> Arc<PluginHost> {
+        Arc::clone(&self.plugins)
+    }
+
+    /// Returns the depth and counters of the input and output queues.
+    pub fn get_queue_metrics(&self) -> Vec<QueueMetrics> {
+        vec![self.input.get_metrics(), self.output.get_metrics()]
+    }
+
+    /// Returns the per-stage metrics of every pipeline, keyed by device type.
+    pub fn get_pipeline_metrics(&self) -> HashMap<String, Vec<StageMetrics>> {
+        self.pipelines
+            .iter()
+            .map(|(device_type, pipeline)| (device_type.clone(), pipeline.get_metrics()))
+            .collect()
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::{PluginConfig, QueueConfig, SchedulerConfig, StageConfig};
+    use crate::device::Device;
+
+    fn plugins() -> Arc<PluginHost> {
+        Arc::new(PluginHost::new(PluginConfig::default()).unwrap())
+    }
 
     #[test]
     fn test_processing_service() {
-        // Tests for processing service
-        // This should include tests for data processing logic
+        let device_manager = Arc::new(DeviceManager::new());
+        let analytics = Arc::new(Analytics::new(device_manager.clone()));
+        let monitoring = Arc::new(Monitoring::new(device_manager.clone()));
+
+        let mut pipelines = HashMap::new();
+        pipelines.insert(
+            "thermometer".to_string(),
+            vec![StageConfig::Calibration {
+                metric: "temperature".to_string(),
+                gain: 2.0,
+                offset: 0.0,
+            }],
+        );
+        let config = ProcessingConfig {
+            processing_interval: 1000,
+            pipelines,
+            queue: QueueConfig::default(),
+            scheduler: SchedulerConfig::default(),
+        };
+        let processing_service = ProcessingService::new(device_manager.clone(), analytics, monitoring, plugins(), config).unwrap();
+
+        device_manager.add_device(Device::new("t1".to_string(), "Thermometer".to_string()).with_type("thermometer".to_string()));
+        device_manager.add_device(Device::new("h1".to_string(), "Hygrometer".to_string()));
+
+        let data = HashMap::from([("temperature".to_string(), 10.0)]);
+        assert_eq!(processing_service.process_reading("t1", data.clone()).unwrap()["temperature"], 20.0);
+        // Devices without a matching pipeline pass through unchanged
+        assert_eq!(processing_service.process_reading("h1", data).unwrap()["temperature"], 10.0);
+
+        assert_eq!(processing_service.get_pipeline_metrics()["thermometer"][0].processed, 1);
+    }
+
+    #[test]
+    fn test_tenant_pipelines_replace_platform_pipelines() {
+        let device_manager = Arc::new(DeviceManager::new());
+        let analytics = Arc::new(Analytics::new(device_manager.clone()));
+        let monitoring = Arc::new(Monitoring::new(device_manager.clone()));
+        let calibration = |gain: f64| {
+            vec![StageConfig::Calibration {
+                metric: "temperature".to_string(),
+                gain,
+                offset: 0.0,
+            }]
+        };
+        let config = ProcessingConfig {
+            processing_interval: 1000,
+            pipelines: HashMap::from([("thermometer".to_string(), calibration(2.0))]),
+            queue: QueueConfig::default(),
+            scheduler: SchedulerConfig::default(),
+        };
+        let mut acme = crate::tenant::Tenant::new("acme".to_string(), "Acme".to_string());
+        acme.settings.pipelines.insert("thermometer".to_string(), calibration(3.0));
+        let tenants = Arc::new(TenantManager::new(vec![acme]));
+        let processing_service = ProcessingService::new(device_manager.clone(), analytics, monitoring, plugins(), config)
+            .unwrap()
+            .with_tenants(tenants.clone());
+
+        for device_id in ["t1", "acme/t1"] {
+            device_manager.add_device(Device::new(device_id.to_string(), "Thermometer".to_string()).with_type("thermometer".to_string()));
+        }
+        let data = HashMap::from([("temperature".to_string(), 10.0)]);
+        assert_eq!(processing_service.process_reading("t1", data.clone()).unwrap()["temperature"], 20.0);
+        assert_eq!(processing_service.process_reading("acme/t1", data.clone()).unwrap()["temperature"], 30.0);
+
+        // Changed settings take effect with the next reading
+        let mut settings = tenants.get_tenant("acme").unwrap().settings;
+        settings.pipelines.insert("thermometer".to_string(), calibration(4.0));
+        tenants.update_settings("acme", settings);
+        assert_eq!(processing_service.process_reading("acme/t1", data).unwrap()["temperature"], 40.0);
+    }
+
+    #[tokio::test]
+    async fn test_run_drains_input_before_closing_output() {
+        let device_manager = Arc::new(DeviceManager::new());
+        let analytics = Arc::new(Analytics::new(device_manager.clone()));
+        let monitoring = Arc::new(Monitoring::new(device_manager.clone()));
+        let config = ProcessingConfig {
+            processing_interval: 1000,
+            pipelines: HashMap::new(),
+            queue: QueueConfig::default(),
+            scheduler: SchedulerConfig::default(),
+        };
+        let processing_service = ProcessingService::new(device_manager, analytics, monitoring, plugins(), config).unwrap();
+
+        let input = processing_service.input_queue();
+        for i in 0..3 {
+            let data = HashMap::from([("temperature".to_string(), i as f64)]);
+            input.push(Reading::new("device1".to_string(), data)).await.unwrap();
+        }
+        input.close();
+        processing_service.run().await;
+
+        let output = processing_service.output_queue();
+        let mut forwarded = Vec::new();
+        while let Some(reading) = output.pop().await {
+            forwarded.push(reading.data["temperature"]);
+        }
+        assert_eq!(forwarded, vec![0.0, 1.0, 2.0]);
+        assert_eq!(processing_service.get_queue_metrics()[0].dequeued, 3);
     }
 }
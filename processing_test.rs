@@ -4,67 +4,86 @@
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::config::{Config, ProcessingConfig};
+    use crate::config::{PluginConfig, ProcessingConfig, QueueConfig, SchedulerConfig, StageConfig};
+    use crate::plugin::PluginHost;
     use crate::device::{Device, DeviceManager};
     use crate::analytics::Analytics;
-    use crate::monitoring::{Monitoring, DeviceHealth};
+    use crate::monitoring::Monitoring;
     use std::collections::HashMap;
-    use std::sync::{Arc, Mutex};
-    use std::time::{Duration, Instant};
+    use std::sync::Arc;
 
     // Helper function to create a test device with some dummy data
     fn create_test_device(id: &str, name: &str) -> Device {
-        let mut data = HashMap::new();
-        data.insert("temperature".to_string(), 22.5);
-        data.insert("humidity".to_string(), 45.0);
-        Device::new(id.to_string(), name.to_string())
+        let mut device = Device::new(id.to_string(), name.to_string());
+        device.data.insert("temperature".to_string(), 22.5);
+        device.data.insert("humidity".to_string(), 45.0);
+        device
     }
 
     // Helper function to create a ProcessingService with test data
-    fn setup_processing_service() -> ProcessingService {
+    fn setup_processing_service(pipelines: HashMap<String, Vec<StageConfig>>) -> ProcessingService {
         let device_manager = Arc::new(DeviceManager::new());
         let analytics = Arc::new(Analytics::new(device_manager.clone()));
         let monitoring = Arc::new(Monitoring::new(device_manager.clone()));
 
-        let config = Config {
-            processing_config: ProcessingConfig {
-                processing_interval: 1000, // 1 second for testing
-            },
-            // Other config structs would be initialized here
-            ..Default::default()
+        let config = ProcessingConfig {
+            processing_interval: 1000, // 1 second for testing
+            pipelines,
+            queue: QueueConfig::default(),
+            scheduler: SchedulerConfig::default(),
         };
 
-        ProcessingService::new(device_manager, analytics, monitoring, config.processing_config)
+        let plugins = Arc::new(PluginHost::new(PluginConfig::default()).unwrap());
+        ProcessingService::new(device_manager, analytics, monitoring, plugins, config).unwrap()
     }
 
     #[test]
     fn test_processing_service_initialization() {
-        let processing_service = setup_processing_service();
-        assert!(processing_service.is_some(), "ProcessingService should be successfully initialized");
+        let processing_service = setup_processing_service(HashMap::new());
+        assert_eq!(processing_service.get_processing_interval(), 1000);
     }
 
     #[test]
     fn test_device_data_processing() {
-        let processing_service = setup_processing_service().unwrap();
+        let processing_service = setup_processing_service(HashMap::new());
         let test_device = create_test_device("test_device_id", "Test Device");
 
         // Simulate device data ingestion
         processing_service.device_manager.add_device(test_device.clone());
-        processing_service.device_manager.update_device_data(&test_device.id, test_device.data.clone());
 
         // Process the data
-        processing_service.process_data();
+        processing_service.process_reading(&test_device.id, test_device.data.clone());
 
         // Check if the analytics and monitoring services have been updated
-        let analytics_data = processing_service.analytics.update_counts.lock().unwrap();
-        let monitoring_data = processing_service.monitoring.device_health.lock().unwrap();
+        let analytics_data = processing_service.analytics.get_device_analytics(&test_device.id);
+        let device_health = processing_service.monitoring.get_device_health(&test_device.id);
 
-        assert_eq!(analytics_data.get(&test_device.id), Some(&1), "Analytics should have processed the device data once");
-        assert!(monitoring_data.get(&test_device.id).is_some(), "Monitoring should have a health record for the device");
+        assert_eq!(analytics_data, Some(1), "Analytics should have processed the device data once");
+        assert!(device_health.is_some(), "Monitoring should have a health record for the device");
 
-        let device_health = monitoring_data.get(&test_device.id).unwrap();
-        assert!(device_health.is_online, "Device should be marked as online after processing");
-        assert!(device_health.last_update.elapsed() < Duration::from_secs(2), "Device last update should be recent");
+        let device_health = device_health.unwrap();
+        assert!(device_health.is_online(), "Device should be marked as online after processing");
+        assert!(chrono::Utc::now() - device_health.last_update.unwrap() < chrono::Duration::seconds(2), "Device last update should be recent");
+    }
+
+    #[test]
+    fn test_dropped_reading_is_not_stored() {
+        let pipelines = HashMap::from([(
+            "default".to_string(),
+            vec![StageConfig::Filter {
+                metric: "temperature".to_string(),
+                min: None,
+                max: Some(0.0),
+                drop_reading: true,
+            }],
+        )]);
+        let processing_service = setup_processing_service(pipelines);
+        let test_device = create_test_device("test_device_id", "Test Device");
+        processing_service.device_manager.add_device(Device::new(test_device.id.clone(), test_device.name.clone()));
+
+        assert!(processing_service.process_reading(&test_device.id, test_device.data.clone()).is_none());
+        assert_eq!(processing_service.analytics.get_device_analytics(&test_device.id), None);
+        assert!(processing_service.monitoring.get_device_health(&test_device.id).is_none());
     }
 }
 ```
@@ -0,0 +1,356 @@
+// queue.rs
+
+use crate::config::{BackpressurePolicy, QueueConfig};
+use crate::device::Reading;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use tokio::sync::Notify;
+
+/// Queue carrying readings between the ingestion, processing and storage stages.
+pub type ReadingQueue = BoundedQueue<Reading>;
+
+/// Counters describing the state of a queue.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct QueueMetrics {
+    pub name: String,
+    pub capacity: usize,
+    /// Items currently waiting, including those spilled to disk.
+    pub depth: usize,
+    /// Highest number of items held in memory at once.
+    pub high_watermark: usize,
+    pub enqueued: u64,
+    pub dequeued: u64,
+    pub dropped: u64,
+    pub spilled: u64,
+}
+
+struct QueueState<T> {
+    items: VecDeque<T>,
+    /// Number of items currently waiting in the spill file.
+    spilled: usize,
+    /// Where the first item still waiting in the spill file starts.
+    spill_offset: u64,
+    /// Set while a consumer reads spilled items back, which it does without holding the lock.
+    unspilling: bool,
+    closed: bool,
+    metrics: QueueMetrics,
+}
+
+/// An async queue with a fixed in-memory capacity and a policy for what happens when it is full.
+pub struct BoundedQueue<T> {
+    capacity: usize,
+    policy: BackpressurePolicy,
+    spill_path: Option<PathBuf>,
+    state: Mutex<QueueState<T>>,
+    items_available: Notify,
+    space_available: Notify,
+}
+
+impl<T: Serialize + DeserializeOwned> BoundedQueue<T> {
+    /// Creates a new queue. Spilling to disk requires a spill directory in the configuration.
+    pub fn new(name: &str, config: &QueueConfig) -> crate::Result<Self> {
+        if config.capacity == 0 {
+            return Err(format!("queue {} needs a capacity of at least 1", name).into());
+        }
+        let spill_path = match (&config.policy, &config.spill_dir) {
+            (BackpressurePolicy::SpillToDisk, Some(dir)) => {
+                std::fs::create_dir_all(dir)?;
+                let path = dir.join(format!("{}.spill", name));
+                // Anything left from a previous run is stale once the queue is recreated
+                if path.exists() {
+                    std::fs::remove_file(&path)?;
+                }
+                Some(path)
+            }
+            (BackpressurePolicy::SpillToDisk, None) => {
+                return Err(format!("queue {} spills to disk but no spill_dir is configured", name).into())
+            }
+            _ => None,
+        };
+
+        Ok(BoundedQueue {
+            capacity: config.capacity,
+            policy: config.policy.clone(),
+            spill_path,
+            state: Mutex::new(QueueState {
+                items: VecDeque::with_capacity(config.capacity),
+                spilled: 0,
+                spill_offset: 0,
+                unspilling: false,
+                closed: false,
+                metrics: QueueMetrics {
+                    name: name.to_string(),
+                    capacity: config.capacity,
+                    ..QueueMetrics::default()
+                },
+            }),
+            items_available: Notify::new(),
+            space_available: Notify::new(),
+        })
+    }
+
+    /// Adds an item, applying the backpressure policy when the queue is full.
+    /// With the blocking policy this waits until a consumer makes room.
+    pub async fn push(&self, item: T) -> crate::Result<()> {
+        let mut item = Some(item);
+        loop {
+            let space_available = self.space_available.notified();
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.closed {
+                    return Err(format!("queue {} is closed", state.metrics.name).into());
+                }
+                let full = state.items.len() >= self.capacity;
+
+                // Once items were spilled, newer ones must follow them to the spill file to keep order
+                if !full && state.spilled == 0 {
+                    Self::enqueue(&mut state, item.take().unwrap());
+                    drop(state);
+                    self.items_available.notify_one();
+                    return Ok(());
+                }
+
+                match self.policy {
+                    BackpressurePolicy::Block => {}
+                    BackpressurePolicy::DropNewest => {
+                        state.metrics.dropped += 1;
+                        return Ok(());
+                    }
+                    BackpressurePolicy::DropOldest => {
+                        state.items.pop_front();
+                        state.metrics.dropped += 1;
+                        Self::enqueue(&mut state, item.take().unwrap());
+                        return Ok(());
+                    }
+                    BackpressurePolicy::SpillToDisk => {
+                        self.spill(&mut state, item.as_ref().unwrap())?;
+                        drop(state);
+                        self.items_available.notify_one();
+                        return Ok(());
+                    }
+                }
+            }
+            space_available.await;
+        }
+    }
+
+    /// Removes the oldest item, waiting for one to arrive. Returns None once the queue
+    /// is closed and every remaining item has been drained.
+    pub async fn pop(&self) -> Option<T> {
+        loop {
+            let items_available = self.items_available.notified();
+            let to_unspill = {
+                let mut state = self.state.lock().unwrap();
+                if let Some(item) = state.items.pop_front() {
+                    state.metrics.dequeued += 1;
+                    drop(state);
+                    self.space_available.notify_one();
+                    return Some(item);
+                }
+                if state.spilled > 0 && !state.unspilling {
+                    state.unspilling = true;
+                    Some((state.spill_offset, state.spilled.min(self.capacity)))
+                } else if state.spilled == 0 && state.closed {
+                    return None;
+                } else {
+                    None
+                }
+            };
+            match to_unspill {
+                Some((offset, count)) => self.unspill(offset, count),
+                None => items_available.await,
+            }
+        }
+    }
+
+    /// Stops accepting new items. Consumers keep receiving the remaining items until the queue is empty.
+    pub fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        drop(state);
+        self.items_available.notify_waiters();
+        self.space_available.notify_waiters();
+    }
+
+    /// Returns the number of items waiting, including spilled ones.
+    pub fn depth(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        state.items.len() + state.spilled
+    }
+
+    /// Returns a snapshot of the queue's counters.
+    pub fn get_metrics(&self) -> QueueMetrics {
+        let state = self.state.lock().unwrap();
+        QueueMetrics {
+            depth: state.items.len() + state.spilled,
+            ..state.metrics.clone()
+        }
+    }
+
+    fn enqueue(state: &mut QueueState<T>, item: T) {
+        state.items.push_back(item);
+        state.metrics.enqueued += 1;
+        state.metrics.high_watermark = state.metrics.high_watermark.max(state.items.len());
+    }
+
+    /// Appends an item to the spill file as a JSON line.
+    fn spill(&self, state: &mut QueueState<T>, item: &T) -> crate::Result<()> {
+        let path = self.spill_path.as_ref().ok_or("queue has no spill file")?;
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        writeln!(file, "{}", serde_json::to_string(item)?)?;
+        state.spilled += 1;
+        state.metrics.enqueued += 1;
+        state.metrics.spilled += 1;
+        Ok(())
+    }
+
+    /// Moves the next spilled items, at most a capacity's worth, back into memory. The spill
+    /// file is read without holding the lock and removed once every item was read back.
+    fn unspill(&self, offset: u64, count: usize) {
+        let read = self.read_spilled(offset, count);
+
+        let mut state = self.state.lock().unwrap();
+        state.unspilling = false;
+        match read {
+            Ok((items, next_offset)) => {
+                state.spilled -= items.len();
+                state.spill_offset = next_offset;
+                state.items.extend(items);
+            }
+            Err(e) => {
+                eprintln!("Failed to read spilled items of queue {}: {}", state.metrics.name, e);
+                state.spilled = 0;
+            }
+        }
+        // Pushes only append to the file while items are waiting in it, so it can go now
+        if state.spilled == 0 {
+            state.spill_offset = 0;
+            if let Some(path) = &self.spill_path {
+                if let Err(e) = std::fs::remove_file(path) {
+                    eprintln!("Failed to remove the spill file of queue {}: {}", state.metrics.name, e);
+                }
+            }
+        }
+        drop(state);
+        // Other consumers may be waiting for the items just read back
+        self.items_available.notify_waiters();
+    }
+
+    /// Reads `count` items from the spill file starting at `offset`, returning them with the
+    /// offset of the item after them. Only items counted as spilled are read, and those were
+    /// written completely.
+    fn read_spilled(&self, offset: u64, count: usize) -> crate::Result<(Vec<T>, u64)> {
+        let path = self.spill_path.as_ref().ok_or("queue has no spill file")?;
+        let mut file = std::fs::File::open(path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut reader = BufReader::new(file);
+        let mut items = Vec::with_capacity(count);
+        let mut next_offset = offset;
+        let mut line = String::new();
+        while items.len() < count {
+            line.clear();
+            let bytes = reader.read_line(&mut line)?;
+            if bytes == 0 {
+                return Err("the spill file ended early".into());
+            }
+            items.push(serde_json::from_str(line.trim_end())?);
+            next_offset += bytes as u64;
+        }
+        Ok((items, next_offset))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn queue(name: &str, capacity: usize, policy: BackpressurePolicy) -> BoundedQueue<u32> {
+        let config = QueueConfig {
+            capacity,
+            policy,
+            spill_dir: Some(std::env::temp_dir().join("iot_queue_test")),
+        };
+        BoundedQueue::new(name, &config).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_drop_newest_and_drop_oldest() {
+        let newest = queue("newest", 2, BackpressurePolicy::DropNewest);
+        let oldest = queue("oldest", 2, BackpressurePolicy::DropOldest);
+        for i in 1..=3 {
+            newest.push(i).await.unwrap();
+            oldest.push(i).await.unwrap();
+        }
+
+        assert_eq!(newest.pop().await, Some(1));
+        assert_eq!(newest.pop().await, Some(2));
+        assert_eq!(oldest.pop().await, Some(2));
+        assert_eq!(oldest.pop().await, Some(3));
+        assert_eq!(newest.get_metrics().dropped, 1);
+        assert_eq!(oldest.get_metrics().dropped, 1);
+    }
+
+    #[tokio::test]
+    async fn test_block_waits_for_space() {
+        let queue = Arc::new(queue("block", 1, BackpressurePolicy::Block));
+        queue.push(1).await.unwrap();
+
+        let producer = {
+            let queue = queue.clone();
+            tokio::spawn(async move { queue.push(2).await })
+        };
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!producer.is_finished());
+        assert_eq!(queue.depth(), 1);
+
+        assert_eq!(queue.pop().await, Some(1));
+        producer.await.unwrap().unwrap();
+        assert_eq!(queue.pop().await, Some(2));
+    }
+
+    #[tokio::test]
+    async fn test_spill_to_disk_keeps_order() {
+        let queue = queue("spill", 2, BackpressurePolicy::SpillToDisk);
+        for i in 1..=5 {
+            queue.push(i).await.unwrap();
+        }
+        let metrics = queue.get_metrics();
+        assert_eq!(metrics.depth, 5);
+        assert_eq!(metrics.spilled, 3);
+
+        let mut drained = Vec::new();
+        for _ in 0..3 {
+            drained.push(queue.pop().await.unwrap());
+        }
+        // Spilled items come back at most a capacity's worth at a time, and new items
+        // keep following them through the spill file
+        assert_eq!(queue.state.lock().unwrap().spilled, 1);
+        queue.push(6).await.unwrap();
+        for _ in 0..3 {
+            drained.push(queue.pop().await.unwrap());
+        }
+        assert_eq!(drained, vec![1, 2, 3, 4, 5, 6]);
+        assert_eq!(queue.depth(), 0);
+        assert!(!queue.spill_path.as_ref().unwrap().exists());
+    }
+
+    #[tokio::test]
+    async fn test_close_drains_remaining_items() {
+        let queue = queue("close", 4, BackpressurePolicy::Block);
+        queue.push(1).await.unwrap();
+        queue.push(2).await.unwrap();
+        queue.close();
+
+        assert!(queue.push(3).await.is_err());
+        assert_eq!(queue.pop().await, Some(1));
+        assert_eq!(queue.pop().await, Some(2));
+        assert_eq!(queue.pop().await, None);
+    }
+}
@@ -0,0 +1,431 @@
+// rate_limit.rs
+
+use crate::auth::Principal;
+use crate::config::{RateLimit, RateLimitConfig};
+use crate::storage_service::StorageService;
+use crate::tenant::TenantManager;
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+/// Number of buckets a limiter keeps; the ones not used for longest are forgotten first.
+const MAX_BUCKETS: usize = 100_000;
+
+/// Why a message or request was turned away.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Rejected {
+    /// A rate limit was hit; another attempt succeeds after `retry_after`.
+    RateLimited { limit: &'static str, retry_after: Duration },
+    /// A quota is used up until the next day or until the quota is raised.
+    QuotaExceeded { quota: &'static str },
+}
+
+impl Rejected {
+    /// Returns how many whole seconds to wait before retrying, if waiting helps.
+    pub fn retry_after_secs(&self) -> Option<u64> {
+        match self {
+            Rejected::RateLimited { retry_after, .. } => {
+                Some((retry_after.num_milliseconds().max(0) as u64).div_ceil(1000).max(1))
+            }
+            Rejected::QuotaExceeded { .. } => None,
+        }
+    }
+}
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Rejected::RateLimited { limit, .. } => write!(f, "{} rate limit exceeded", limit),
+            Rejected::QuotaExceeded { quota } => write!(f, "{} quota exceeded", quota),
+        }
+    }
+}
+
+/// A token bucket, refilled continuously at the limit's rate up to its burst size.
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    tokens: f64,
+    updated_at: DateTime<Utc>,
+}
+
+impl TokenBucket {
+    fn refill(&mut self, limit: &RateLimit, now: DateTime<Utc>) {
+        let elapsed = (now - self.updated_at).num_milliseconds().max(0) as f64 / 1000.0;
+        self.tokens = (self.tokens + elapsed * limit.per_second).min(limit.burst as f64);
+        self.updated_at = now;
+    }
+}
+
+/// Buckets in two generations: used ones move to the current generation, and once that is
+/// half the limit the previous one, holding the buckets not used since, is dropped.
+#[derive(Default)]
+struct Buckets {
+    current: HashMap<String, TokenBucket>,
+    previous: HashMap<String, TokenBucket>,
+}
+
+impl Buckets {
+    fn get_or_insert(&mut self, key: &str, new_bucket: impl FnOnce() -> TokenBucket) -> &mut TokenBucket {
+        if !self.current.contains_key(key) {
+            if self.current.len() >= MAX_BUCKETS / 2 {
+                self.previous = std::mem::take(&mut self.current);
+            }
+            let bucket = self.previous.remove(key).unwrap_or_else(new_bucket);
+            self.current.insert(key.to_string(), bucket);
+        }
+        self.current.get_mut(key).unwrap()
+    }
+}
+
+/// Token buckets by device, source address, tenant or API client.
+pub struct RateLimiter {
+    buckets: Arc<Mutex<Buckets>>,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        RateLimiter {
+            buckets: Arc::new(Mutex::new(Buckets::default())),
+        }
+    }
+
+    /// Takes a token from the key's bucket. Returns how long until the next token if the
+    /// bucket is empty.
+    pub fn check(&self, key: &str, limit: &RateLimit) -> Result<(), Duration> {
+        self.check_at(key, limit, Utc::now())
+    }
+
+    fn check_at(&self, key: &str, limit: &RateLimit, now: DateTime<Utc>) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.get_or_insert(key, || TokenBucket {
+            tokens: limit.burst as f64,
+            updated_at: now,
+        });
+        bucket.refill(limit, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        if limit.per_second <= 0.0 {
+            return Err(Duration::days(1));
+        }
+        let wait_secs = (1.0 - bucket.tokens) / limit.per_second;
+        Err(Duration::milliseconds((wait_secs * 1000.0).ceil() as i64))
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What a tenant's devices sent, for quotas and billing.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct QuotaUsage {
+    pub tenant_id: String,
+    /// The UTC day the daily counters are for.
+    pub day: NaiveDate,
+    pub messages_today: u64,
+    pub bytes_today: u64,
+    /// Datagrams turned away today by rate limits or quotas.
+    pub rejected_today: u64,
+    /// Bytes the tenant's stored data takes up, or without storage the payload bytes accepted
+    /// since the service started.
+    pub storage_bytes: u64,
+}
+
+impl QuotaUsage {
+    fn new(tenant_id: &str, day: NaiveDate) -> Self {
+        QuotaUsage {
+            tenant_id: tenant_id.to_string(),
+            day,
+            messages_today: 0,
+            bytes_today: 0,
+            rejected_today: 0,
+            storage_bytes: 0,
+        }
+    }
+
+    /// Starts the daily counters over when the day changed.
+    fn roll_over(&mut self, day: NaiveDate) {
+        if self.day != day {
+            self.day = day;
+            self.messages_today = 0;
+            self.bytes_today = 0;
+            self.rejected_today = 0;
+        }
+    }
+}
+
+/// Applies the rate limits and quotas of devices, tenants and API clients, and keeps track of
+/// each tenant's usage.
+pub struct RateLimits {
+    config: RateLimitConfig,
+    tenants: Arc<TenantManager>,
+    devices: RateLimiter,
+    sources: RateLimiter,
+    sign_ins: RateLimiter,
+    tenant_messages: RateLimiter,
+    api_clients: RateLimiter,
+    api_tenants: RateLimiter,
+    usage: Arc<Mutex<HashMap<String, QuotaUsage>>>,
+    storage: Option<Arc<StorageService>>,
+}
+
+impl RateLimits {
+    pub fn new(config: RateLimitConfig) -> Self {
+        RateLimits {
+            config,
+            tenants: Arc::new(TenantManager::new(Vec::new())),
+            devices: RateLimiter::new(),
+            sources: RateLimiter::new(),
+            sign_ins: RateLimiter::new(),
+            tenant_messages: RateLimiter::new(),
+            api_clients: RateLimiter::new(),
+            api_tenants: RateLimiter::new(),
+            usage: Arc::new(Mutex::new(HashMap::new())),
+            storage: None,
+        }
+    }
+
+    /// Applies the rate limits and quotas in the settings of the given manager's tenants.
+    pub fn with_tenants(mut self, tenants: Arc<TenantManager>) -> Self {
+        self.tenants = tenants;
+        self
+    }
+
+    /// Takes the tenants' storage usage from the given storage instead of counting payload bytes.
+    pub fn with_storage(mut self, storage: Arc<StorageService>) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+
+    /// Checks the limit of a source address, before anything it sent is looked at.
+    pub fn check_source(&self, source: IpAddr) -> Result<(), Rejected> {
+        check(&self.sources, &source.to_string(), self.config.per_source_ip.as_ref(), "source address")
+    }
+
+    /// Checks a sign-in attempt against the limits of its source address.
+    pub fn check_sign_in(&self, source: IpAddr) -> Result<(), Rejected> {
+        self.check_source(source)?;
+        let limit = self.config.sign_in_per_source_ip.as_ref();
+        check(&self.sign_ins, &source.to_string(), limit, "sign-in")
+    }
+
+    /// Checks a device's datagram against the device's and tenant's limits and the tenant's
+    /// quotas, counting it towards the tenant's usage if it is accepted.
+    pub fn check_message(&self, tenant_id: &str, device_id: &str, bytes: usize) -> Result<(), Rejected> {
+        self.check_message_at(tenant_id, device_id, bytes, Utc::now())
+    }
+
+    fn check_message_at(
+        &self,
+        tenant_id: &str,
+        device_id: &str,
+        bytes: usize,
+        now: DateTime<Utc>,
+    ) -> Result<(), Rejected> {
+        let settings = self.tenants.get_tenant(tenant_id).map(|tenant| tenant.settings).unwrap_or_default();
+        let mut all_usage = self.usage.lock().unwrap();
+        let usage = all_usage
+            .entry(tenant_id.to_string())
+            .or_insert_with(|| QuotaUsage::new(tenant_id, now.date_naive()));
+        usage.roll_over(now.date_naive());
+        if let Some(storage) = &self.storage {
+            usage.storage_bytes = storage.tenant_storage_bytes(tenant_id);
+        }
+
+        // Quotas are checked first so a rejected message takes no tokens from the rate limits
+        let tenant_limit = settings.rate_limit.or(self.config.per_tenant);
+        let checked = match settings.max_messages_per_day {
+            Some(max) if usage.messages_today >= max => Err(Rejected::QuotaExceeded { quota: "daily message" }),
+            _ => Ok(()),
+        }
+        .and_then(|_| match settings.max_storage_bytes {
+            Some(max) if usage.storage_bytes + bytes as u64 > max => Err(Rejected::QuotaExceeded { quota: "storage" }),
+            _ => Ok(()),
+        })
+        .and_then(|_| check_at(&self.devices, device_id, self.config.per_device.as_ref(), "device", now))
+        .and_then(|_| check_at(&self.tenant_messages, tenant_id, tenant_limit.as_ref(), "tenant", now));
+        match checked {
+            Ok(()) => {
+                usage.messages_today += 1;
+                usage.bytes_today += bytes as u64;
+                if self.storage.is_none() {
+                    usage.storage_bytes += bytes as u64;
+                }
+            }
+            Err(_) => usage.rejected_today += 1,
+        }
+        checked
+    }
+
+    /// Checks the limits of an API client and its tenant.
+    pub fn check_api(&self, principal: &Principal) -> Result<(), Rejected> {
+        let client = format!("{}/{}", principal.tenant_id, principal.subject);
+        check(&self.api_clients, &client, self.config.per_api_client.as_ref(), "API client")?;
+        check(&self.api_tenants, &principal.tenant_id, self.config.per_api_tenant.as_ref(), "API tenant")
+    }
+
+    /// Returns the usage of a tenant, or of all tenants, ordered by tenant id.
+    pub fn get_usage(&self, tenant_id: Option<&str>) -> Vec<QuotaUsage> {
+        let today = Utc::now().date_naive();
+        let mut usage: Vec<QuotaUsage> = self
+            .usage
+            .lock()
+            .unwrap()
+            .values_mut()
+            .filter(|usage| tenant_id.is_none() || tenant_id == Some(usage.tenant_id.as_str()))
+            .map(|usage| {
+                usage.roll_over(today);
+                if let Some(storage) = &self.storage {
+                    usage.storage_bytes = storage.tenant_storage_bytes(&usage.tenant_id);
+                }
+                usage.clone()
+            })
+            .collect();
+        usage.sort_by(|a, b| a.tenant_id.cmp(&b.tenant_id));
+        usage
+    }
+}
+
+fn check(limiter: &RateLimiter, key: &str, limit: Option<&RateLimit>, name: &'static str) -> Result<(), Rejected> {
+    check_at(limiter, key, limit, name, Utc::now())
+}
+
+fn check_at(
+    limiter: &RateLimiter,
+    key: &str,
+    limit: Option<&RateLimit>,
+    name: &'static str,
+    now: DateTime<Utc>,
+) -> Result<(), Rejected> {
+    match limit {
+        Some(limit) => limiter
+            .check_at(key, limit, now)
+            .map_err(|retry_after| Rejected::RateLimited { limit: name, retry_after }),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tenant::Tenant;
+
+    #[test]
+    fn test_token_bucket_allows_bursts_and_refills() {
+        let limiter = RateLimiter::new();
+        let limit = RateLimit { per_second: 2.0, burst: 3 };
+        let now = Utc::now();
+        for _ in 0..3 {
+            assert!(limiter.check_at("d1", &limit, now).is_ok());
+        }
+        assert_eq!(limiter.check_at("d1", &limit, now), Err(Duration::milliseconds(500)));
+        assert!(limiter.check_at("d2", &limit, now).is_ok());
+        assert!(limiter.check_at("d1", &limit, now + Duration::milliseconds(500)).is_ok());
+        assert!(limiter.check_at("d1", &limit, now + Duration::milliseconds(500)).is_err());
+    }
+
+    #[test]
+    fn test_tenant_limits_and_daily_quota() {
+        let mut acme = Tenant::new("acme".to_string(), "Acme".to_string());
+        acme.settings.max_messages_per_day = Some(2);
+        acme.settings.max_storage_bytes = Some(1_000);
+        let config = RateLimitConfig {
+            per_device: Some(RateLimit { per_second: 0.0, burst: 1 }),
+            ..RateLimitConfig::default()
+        };
+        let limits = RateLimits::new(config).with_tenants(Arc::new(TenantManager::new(vec![acme])));
+        let now = Utc::now();
+
+        assert!(limits.check_message_at("acme", "acme/d1", 10, now).is_ok());
+        let rejected = limits.check_message_at("acme", "acme/d1", 10, now).unwrap_err();
+        assert!(matches!(rejected, Rejected::RateLimited { limit: "device", .. }));
+        assert!(limits.check_message_at("acme", "acme/d2", 2_000, now).is_err());
+        assert!(limits.check_message_at("acme", "acme/d3", 10, now).is_ok());
+        let rejected = limits.check_message_at("acme", "acme/d4", 10, now).unwrap_err();
+        assert_eq!(rejected, Rejected::QuotaExceeded { quota: "daily message" });
+        assert_eq!(rejected.retry_after_secs(), None);
+
+        let usage = limits.get_usage(Some("acme"));
+        assert_eq!(usage[0].messages_today, 2);
+        assert_eq!(usage[0].storage_bytes, 20);
+        assert_eq!(usage[0].rejected_today, 3);
+
+        // The daily quota starts over the next day, while stored bytes keep adding up
+        assert!(limits.check_message_at("acme", "acme/d5", 10, now + Duration::days(1)).is_ok());
+        assert!(limits.check_message_at(crate::tenant::DEFAULT_TENANT, "d1", 10, now).is_ok());
+    }
+
+    #[test]
+    fn test_storage_quota_follows_stored_data() {
+        let data_dir = std::env::temp_dir().join(format!("iot_rate_limit_test_{}", crate::auth::random_hex(8)));
+        let mut config = crate::config::Config::from_env().unwrap();
+        config.storage_config.data_dir = Some(data_dir.clone());
+        let storage = Arc::new(StorageService::new(
+            config.storage_config,
+            Arc::new(crate::device::DeviceManager::new()),
+        ));
+        let mut acme = Tenant::new("acme".to_string(), "Acme".to_string());
+        acme.settings.max_storage_bytes = Some(1_000);
+        let config = RateLimitConfig {
+            per_device: Some(RateLimit { per_second: 0.0, burst: 1 }),
+            ..RateLimitConfig::default()
+        };
+        let limits = RateLimits::new(config)
+            .with_tenants(Arc::new(TenantManager::new(vec![acme])))
+            .with_storage(storage.clone());
+        let now = Utc::now();
+
+        // Quota rejections leave the device's single token in place
+        assert!(limits.check_message_at("acme", "acme/d1", 2_000, now).is_err());
+        assert!(limits.check_message_at("acme", "acme/d1", 10, now).is_ok());
+        assert_eq!(limits.get_usage(Some("acme"))[0].storage_bytes, 0);
+
+        let data = HashMap::from([("temperature".to_string(), 21.0)]);
+        storage.store_reading(&crate::device::Reading::new("acme/d1".to_string(), data)).unwrap();
+        let stored = storage.tenant_storage_bytes("acme");
+        assert!(stored > 0);
+        assert_eq!(limits.get_usage(Some("acme"))[0].storage_bytes, stored);
+        assert!(limits.check_message_at("acme", "acme/d2", 1_000 - stored as usize + 1, now).is_err());
+        std::fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[test]
+    fn test_sign_ins_are_limited_by_default() {
+        let limits = RateLimits::new(RateLimitConfig::default());
+        let (source, other): (IpAddr, IpAddr) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+        let burst = RateLimitConfig::default().sign_in_per_source_ip.unwrap().burst;
+        for _ in 0..burst {
+            assert!(limits.check_sign_in(source).is_ok());
+        }
+        let rejected = limits.check_sign_in(source).unwrap_err();
+        assert!(matches!(rejected, Rejected::RateLimited { limit: "sign-in", .. }));
+        assert!(limits.check_sign_in(other).is_ok());
+        // Datagrams from the address are not held back by its sign-ins
+        assert!(limits.check_source(source).is_ok());
+    }
+
+    #[test]
+    fn test_buckets_not_used_recently_are_forgotten_first() {
+        let limiter = RateLimiter::new();
+        let limit = RateLimit { per_second: 0.0, burst: 1 };
+        let now = Utc::now();
+        assert!(limiter.check_at("busy", &limit, now).is_ok());
+        for i in 0..MAX_BUCKETS {
+            assert!(limiter.check_at(&format!("d{}", i), &limit, now).is_ok());
+            // The busy key keeps being used, so its empty bucket is never dropped
+            if i % 1_000 == 0 {
+                assert!(limiter.check_at("busy", &limit, now).is_err());
+            }
+        }
+        let buckets = limiter.buckets.lock().unwrap();
+        assert!(buckets.current.len() + buckets.previous.len() <= MAX_BUCKETS);
+    }
+}
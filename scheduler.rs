@@ -0,0 +1,623 @@
+// scheduler.rs
+
+use crate::analytics::Analytics;
+use crate::config::{JobKind, MisfirePolicy, SchedulerConfig};
+use crate::monitoring::{DeviceStatus, Monitoring};
+use crate::storage_service::StorageService;
+use async_trait::async_trait;
+use chrono::{DateTime, Datelike, Duration, TimeZone, Timelike, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+
+/// The longest the scheduler sleeps before checking for due jobs again.
+const MAX_SLEEP_SECS: i64 = 60;
+
+/// A unit of recurring work run by the scheduler.
+#[async_trait]
+pub trait Job: Send + Sync {
+    /// Runs the job once, returning a short summary of what it did.
+    async fn run(&self) -> crate::Result<String>;
+}
+
+/// One field of a cron expression, stored as a bit set of the allowed values.
+#[derive(Debug, Clone, Copy)]
+struct CronField {
+    allowed: u64,
+    /// False when the field is `*`, which matters for the day-of-month/day-of-week rule.
+    restricted: bool,
+}
+
+impl CronField {
+    fn parse(expr: &str, min: u32, max: u32) -> crate::Result<Self> {
+        let mut allowed = 0u64;
+        for part in expr.split(',') {
+            let (range, step) = match part.split_once('/') {
+                Some((range, step)) => (range, step.parse::<u32>().map_err(|_| format!("invalid step in {}", part))?),
+                None => (part, 1),
+            };
+            if step == 0 {
+                return Err(format!("invalid step in {}", part).into());
+            }
+            let (start, end) = if range == "*" {
+                (min, max)
+            } else if let Some((start, end)) = range.split_once('-') {
+                (parse_value(start, part)?, parse_value(end, part)?)
+            } else {
+                let start = parse_value(range, part)?;
+                // `5/10` means every 10th value starting at 5
+                (start, if part.contains('/') { max } else { start })
+            };
+            if start < min || end > max || start > end {
+                return Err(format!("{} is out of range {}-{}", part, min, max).into());
+            }
+            for value in (start..=end).step_by(step as usize) {
+                allowed |= 1 << value;
+            }
+        }
+        Ok(CronField {
+            allowed,
+            restricted: expr != "*",
+        })
+    }
+
+    fn matches(&self, value: u32) -> bool {
+        self.allowed & (1 << value) != 0
+    }
+}
+
+fn parse_value(value: &str, part: &str) -> crate::Result<u32> {
+    value.parse().map_err(|_| format!("invalid value in {}", part).into())
+}
+
+/// A parsed cron schedule, evaluated in UTC.
+#[derive(Debug, Clone)]
+pub struct CronSchedule {
+    expression: String,
+    minutes: CronField,
+    hours: CronField,
+    days_of_month: CronField,
+    months: CronField,
+    days_of_week: CronField,
+}
+
+impl CronSchedule {
+    /// Parses a five-field cron expression (minute hour day-of-month month day-of-week)
+    /// or one of the aliases `@hourly`, `@daily`, `@weekly`, `@monthly` and `@yearly`.
+    pub fn parse(expression: &str) -> crate::Result<Self> {
+        let fields = match expression.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            other => other,
+        };
+        let fields: Vec<&str> = fields.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!("cron expression {:?} needs five fields", expression).into());
+        }
+
+        let mut days_of_week = CronField::parse(fields[4], 0, 7)?;
+        // Both 0 and 7 stand for Sunday
+        if days_of_week.matches(7) {
+            days_of_week.allowed |= 1;
+        }
+        Ok(CronSchedule {
+            expression: expression.trim().to_string(),
+            minutes: CronField::parse(fields[0], 0, 59)?,
+            hours: CronField::parse(fields[1], 0, 23)?,
+            days_of_month: CronField::parse(fields[2], 1, 31)?,
+            months: CronField::parse(fields[3], 1, 12)?,
+            days_of_week,
+        })
+    }
+
+    /// Returns the expression the schedule was parsed from.
+    pub fn expression(&self) -> &str {
+        &self.expression
+    }
+
+    fn day_matches(&self, time: DateTime<Utc>) -> bool {
+        let day_of_month = self.days_of_month.matches(time.day());
+        let day_of_week = self.days_of_week.matches(time.weekday().num_days_from_sunday());
+        // As in cron, a day matches either field when both are restricted
+        match (self.days_of_month.restricted, self.days_of_week.restricted) {
+            (true, true) => day_of_month || day_of_week,
+            _ => day_of_month && day_of_week,
+        }
+    }
+
+    /// Returns the first scheduled time strictly after `after`, or None if the schedule
+    /// never fires (e.g. February 30th).
+    pub fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let mut time = after.with_second(0)?.with_nanosecond(0)? + Duration::minutes(1);
+        let limit = after + Duration::days(5 * 366);
+
+        // Skip whole months, days and hours that cannot match instead of stepping minute by minute
+        while time <= limit {
+            if !self.months.matches(time.month()) {
+                let (year, month) = if time.month() == 12 {
+                    (time.year() + 1, 1)
+                } else {
+                    (time.year(), time.month() + 1)
+                };
+                time = Utc.with_ymd_and_hms(year, month, 1, 0, 0, 0).single()?;
+            } else if !self.day_matches(time) {
+                time = Utc.with_ymd_and_hms(time.year(), time.month(), time.day(), 0, 0, 0).single()? + Duration::days(1);
+            } else if !self.hours.matches(time.hour()) {
+                time = time.with_minute(0)? + Duration::hours(1);
+            } else if !self.minutes.matches(time.minute()) {
+                time += Duration::minutes(1);
+            } else {
+                return Some(time);
+            }
+        }
+        None
+    }
+}
+
+/// The outcome of a single job run.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct JobRun {
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    pub success: bool,
+    /// The job's summary, or the error if it failed.
+    pub message: String,
+}
+
+/// The schedule and recent results of a job.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct JobStatus {
+    pub name: String,
+    pub schedule: String,
+    pub misfire: MisfirePolicy,
+    pub running: bool,
+    pub next_run: Option<DateTime<Utc>>,
+    pub last_run: Option<JobRun>,
+    pub run_count: u64,
+    pub failure_count: u64,
+}
+
+struct ScheduledJob {
+    job: Arc<dyn Job>,
+    schedule: CronSchedule,
+    status: JobStatus,
+}
+
+/// Runs jobs on cron schedules and keeps their status. When a state file is configured the
+/// results are persisted, so runs missed while the service was down are handled according
+/// to each job's misfire policy on restart.
+pub struct Scheduler {
+    jobs: Arc<Mutex<HashMap<String, ScheduledJob>>>,
+    state_path: Option<PathBuf>,
+    /// Statuses loaded from the state file, picked up as jobs are added.
+    restored: Mutex<HashMap<String, JobStatus>>,
+}
+
+impl Scheduler {
+    /// Creates a scheduler without jobs, loading the persisted job results if there are any.
+    pub fn new(state_path: Option<PathBuf>) -> crate::Result<Self> {
+        let restored = match &state_path {
+            Some(path) if path.exists() => serde_json::from_slice(&std::fs::read(path)?)?,
+            _ => HashMap::new(),
+        };
+        Ok(Scheduler {
+            jobs: Arc::new(Mutex::new(HashMap::new())),
+            state_path,
+            restored: Mutex::new(restored),
+        })
+    }
+
+    /// Creates a scheduler with the built-in jobs listed in the configuration. Jobs that work on
+    /// stored data fail when they run without storage.
+    pub fn from_config(
+        config: &SchedulerConfig,
+        analytics: Arc<Analytics>,
+        monitoring: Arc<Monitoring>,
+        storage: Option<Arc<StorageService>>,
+    ) -> crate::Result<Self> {
+        let scheduler = Scheduler::new(config.state_path.clone())?;
+        for job_config in config.jobs.iter() {
+            let job: Arc<dyn Job> = match job_config.job {
+                JobKind::StaleDeviceSweep => Arc::new(StaleDeviceSweep { monitoring: monitoring.clone() }),
+                JobKind::HealthReport => Arc::new(HealthReportJob {
+                    monitoring: monitoring.clone(),
+                    storage: storage.clone(),
+                }),
+                JobKind::AnalyticsRollup => Arc::new(AnalyticsRollup {
+                    analytics: analytics.clone(),
+                    storage: storage.clone(),
+                }),
+                JobKind::StorageRetention => Arc::new(StorageRetention { storage: storage.clone() }),
+                JobKind::HealthSnapshot => Arc::new(HealthSnapshot {
+                    monitoring: monitoring.clone(),
+                    storage: storage.clone(),
+                }),
+            };
+            scheduler.add_job(&job_config.name, &job_config.schedule, job_config.misfire.clone(), job)?;
+        }
+        Ok(scheduler)
+    }
+
+    /// Schedules a job. If the job ran before a restart and missed runs since, the misfire
+    /// policy decides whether it runs right away or waits for its next scheduled time.
+    pub fn add_job(&self, name: &str, schedule: &str, misfire: MisfirePolicy, job: Arc<dyn Job>) -> crate::Result<()> {
+        self.add_job_at(name, schedule, misfire, job, Utc::now())
+    }
+
+    fn add_job_at(&self, name: &str, schedule: &str, misfire: MisfirePolicy, job: Arc<dyn Job>, now: DateTime<Utc>) -> crate::Result<()> {
+        let schedule = CronSchedule::parse(schedule)?;
+        let restored = self.restored.lock().unwrap().remove(name);
+
+        let (last_run, run_count, failure_count) = match restored {
+            Some(status) => (status.last_run, status.run_count, status.failure_count),
+            None => (None, 0, 0),
+        };
+        let missed = last_run
+            .as_ref()
+            .and_then(|run| schedule.next_after(run.started_at))
+            .filter(|due| *due <= now);
+        let next_run = match (missed, &misfire) {
+            (Some(_), MisfirePolicy::RunOnce) => {
+                println!("Job {} missed a run while the service was down, running it now", name);
+                Some(now)
+            }
+            _ => schedule.next_after(now),
+        };
+
+        let status = JobStatus {
+            name: name.to_string(),
+            schedule: schedule.expression().to_string(),
+            misfire,
+            running: false,
+            next_run,
+            last_run,
+            run_count,
+            failure_count,
+        };
+        let mut jobs = self.jobs.lock().unwrap();
+        jobs.insert(name.to_string(), ScheduledJob { job, schedule, status });
+        Ok(())
+    }
+
+    /// Runs due jobs until the shutdown signal fires.
+    pub async fn run(&self, mut shutdown: watch::Receiver<bool>) {
+        loop {
+            for name in self.due_jobs(Utc::now()) {
+                if let Err(e) = self.run_job(&name).await {
+                    eprintln!("Failed to run job {}: {}", name, e);
+                }
+            }
+
+            let sleep_secs = self
+                .next_run()
+                .map(|next_run| (next_run - Utc::now()).num_seconds().clamp(0, MAX_SLEEP_SECS))
+                .unwrap_or(MAX_SLEEP_SECS);
+            tokio::select! {
+                _ = tokio::time::sleep(std::time::Duration::from_secs(sleep_secs as u64)) => {}
+                _ = shutdown.changed() => break,
+            }
+        }
+    }
+
+    fn due_jobs(&self, now: DateTime<Utc>) -> Vec<String> {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.values()
+            .filter(|scheduled| !scheduled.status.running && scheduled.status.next_run.is_some_and(|next_run| next_run <= now))
+            .map(|scheduled| scheduled.status.name.clone())
+            .collect()
+    }
+
+    fn next_run(&self) -> Option<DateTime<Utc>> {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.values().filter_map(|scheduled| scheduled.status.next_run).min()
+    }
+
+    /// Runs a job immediately, regardless of its schedule, and records the result.
+    pub async fn run_job(&self, name: &str) -> crate::Result<JobRun> {
+        let job = {
+            let mut jobs = self.jobs.lock().unwrap();
+            let scheduled = jobs.get_mut(name).ok_or_else(|| format!("no job named {}", name))?;
+            if scheduled.status.running {
+                return Err(format!("job {} is already running", name).into());
+            }
+            scheduled.status.running = true;
+            scheduled.job.clone()
+        };
+
+        let started_at = Utc::now();
+        let result = job.run().await;
+        let finished_at = Utc::now();
+        let run = JobRun {
+            started_at,
+            finished_at,
+            success: result.is_ok(),
+            message: match result {
+                Ok(summary) => summary,
+                Err(e) => e.to_string(),
+            },
+        };
+        println!("Job {} finished ({}): {}", name, if run.success { "ok" } else { "failed" }, run.message);
+
+        {
+            let mut jobs = self.jobs.lock().unwrap();
+            if let Some(scheduled) = jobs.get_mut(name) {
+                let status = &mut scheduled.status;
+                status.running = false;
+                status.run_count += 1;
+                if !run.success {
+                    status.failure_count += 1;
+                }
+                status.last_run = Some(run.clone());
+                // Runs missed while the job was busy are skipped
+                status.next_run = scheduled.schedule.next_after(finished_at);
+            }
+        }
+        if let Err(e) = self.save_state() {
+            eprintln!("Failed to persist scheduler state: {}", e);
+        }
+        Ok(run)
+    }
+
+    /// Writes the status of every job to the state file.
+    fn save_state(&self) -> crate::Result<()> {
+        let path = match &self.state_path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let statuses: HashMap<String, JobStatus> = self
+            .get_job_statuses()
+            .into_iter()
+            .map(|status| (status.name.clone(), status))
+            .collect();
+
+        // Write to a temporary file first so a crash never leaves a truncated state file behind
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, serde_json::to_vec(&statuses)?)?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    /// Retrieves the status of a specific job.
+    pub fn get_job_status(&self, name: &str) -> Option<JobStatus> {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.get(name).map(|scheduled| scheduled.status.clone())
+    }
+
+    /// Retrieves the status of all jobs, ordered by name.
+    pub fn get_job_statuses(&self) -> Vec<JobStatus> {
+        let jobs = self.jobs.lock().unwrap();
+        let mut statuses: Vec<JobStatus> = jobs.values().map(|scheduled| scheduled.status.clone()).collect();
+        statuses.sort_by(|a, b| a.name.cmp(&b.name));
+        statuses
+    }
+}
+
+/// Marks devices that stopped reporting as offline.
+struct StaleDeviceSweep {
+    monitoring: Arc<Monitoring>,
+}
+
+#[async_trait]
+impl Job for StaleDeviceSweep {
+    async fn run(&self) -> crate::Result<String> {
+        self.monitoring.monitor_devices();
+        let offline = self
+            .monitoring
+            .get_monitoring_data()
+            .values()
+            .filter(|health| health.status == DeviceStatus::Offline)
+            .count();
+        Ok(format!("{} devices offline", offline))
+    }
+}
+
+/// Summarises the health of all devices, storing the reports when storage is configured.
+struct HealthReportJob {
+    monitoring: Arc<Monitoring>,
+    storage: Option<Arc<StorageService>>,
+}
+
+#[async_trait]
+impl Job for HealthReportJob {
+    async fn run(&self) -> crate::Result<String> {
+        let reports = self.monitoring.get_all_health_reports();
+        if let Some(storage) = &self.storage {
+            storage.store_health_reports(Utc::now(), &reports)?;
+        }
+        let mut unhealthy: Vec<String> = reports
+            .values()
+            .filter(|report| report.score < 50)
+            .map(|report| format!("{} ({})", report.device_id, report.score))
+            .collect();
+        unhealthy.sort();
+        Ok(format!(
+            "{} devices, {} unhealthy{}{}",
+            reports.len(),
+            unhealthy.len(),
+            if unhealthy.is_empty() { "" } else { ": " },
+            unhealthy.join(", ")
+        ))
+    }
+}
+
+/// Rolls the finalized analytics windows up into per-device aggregates, storing them when
+/// storage is configured.
+struct AnalyticsRollup {
+    analytics: Arc<Analytics>,
+    storage: Option<Arc<StorageService>>,
+}
+
+#[async_trait]
+impl Job for AnalyticsRollup {
+    async fn run(&self) -> crate::Result<String> {
+        let rollups = self.analytics.rollup_finalized_windows();
+        if let Some(storage) = &self.storage {
+            storage.store_rollups(Utc::now(), &rollups)?;
+        }
+        let metrics: usize = rollups.values().map(|metrics| metrics.len()).sum();
+        Ok(format!("rolled up {} metrics across {} devices", metrics, rollups.len()))
+    }
+}
+
+/// Deletes stored data older than each tenant's retention.
+struct StorageRetention {
+    storage: Option<Arc<StorageService>>,
+}
+
+#[async_trait]
+impl Job for StorageRetention {
+    async fn run(&self) -> crate::Result<String> {
+        let storage = self.storage.as_ref().ok_or("storage is not configured")?;
+        let removed = storage.apply_retention(Utc::now())?;
+        Ok(format!("removed {} expired partition files", removed))
+    }
+}
+
+/// Saves the health of every device to the snapshot it is restored from after a restart.
+struct HealthSnapshot {
+    monitoring: Arc<Monitoring>,
+    storage: Option<Arc<StorageService>>,
+}
+
+#[async_trait]
+impl Job for HealthSnapshot {
+    async fn run(&self) -> crate::Result<String> {
+        let storage = self.storage.as_ref().ok_or("storage is not configured")?;
+        let health = self.monitoring.get_monitoring_data();
+        storage.store_device_health(&health)?;
+        Ok(format!("saved the health of {} devices", health.len()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FailingJob;
+
+    #[async_trait]
+    impl Job for FailingJob {
+        async fn run(&self) -> crate::Result<String> {
+            Err("model training data unavailable".into())
+        }
+    }
+
+    fn at(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(year, month, day, hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn test_cron_next_after() {
+        let every_quarter = CronSchedule::parse("*/15 * * * *").unwrap();
+        assert_eq!(every_quarter.next_after(at(2024, 3, 10, 12, 7)), Some(at(2024, 3, 10, 12, 15)));
+
+        let daily = CronSchedule::parse("@daily").unwrap();
+        assert_eq!(daily.next_after(at(2024, 12, 31, 0, 0)), Some(at(2025, 1, 1, 0, 0)));
+
+        // Weekdays at 08:30; 2024-03-09 is a Saturday
+        let weekdays = CronSchedule::parse("30 8 * * 1-5").unwrap();
+        assert_eq!(weekdays.next_after(at(2024, 3, 9, 9, 0)), Some(at(2024, 3, 11, 8, 30)));
+
+        assert!(CronSchedule::parse("0 0 30 2 *").unwrap().next_after(at(2024, 1, 1, 0, 0)).is_none());
+        assert!(CronSchedule::parse("61 * * * *").is_err());
+        assert!(CronSchedule::parse("* * *").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_run_job_records_status_and_misfires_on_restart() {
+        let state_path = std::env::temp_dir().join("iot_scheduler_test.json");
+        let _ = std::fs::remove_file(&state_path);
+
+        let scheduler = Scheduler::new(Some(state_path.clone())).unwrap();
+        scheduler.add_job("retrain", "@daily", MisfirePolicy::RunOnce, Arc::new(FailingJob)).unwrap();
+        scheduler.add_job("report", "@daily", MisfirePolicy::Skip, Arc::new(FailingJob)).unwrap();
+        assert!(scheduler.run_job("retrain").await.is_ok());
+        assert!(scheduler.run_job("report").await.is_ok());
+
+        let status = scheduler.get_job_status("retrain").unwrap();
+        assert_eq!(status.run_count, 1);
+        assert_eq!(status.failure_count, 1);
+        assert_eq!(status.last_run.unwrap().message, "model training data unavailable");
+
+        // Two days later the daily runs were missed
+        let restarted = Scheduler::new(Some(state_path.clone())).unwrap();
+        let now = Utc::now() + Duration::days(2);
+        restarted.add_job_at("retrain", "@daily", MisfirePolicy::RunOnce, Arc::new(FailingJob), now).unwrap();
+        restarted.add_job_at("report", "@daily", MisfirePolicy::Skip, Arc::new(FailingJob), now).unwrap();
+
+        assert_eq!(restarted.get_job_status("retrain").unwrap().run_count, 1);
+        assert_eq!(restarted.due_jobs(now), vec!["retrain".to_string()]);
+        assert!(restarted.get_job_status("report").unwrap().next_run.unwrap() > now);
+
+        std::fs::remove_file(&state_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_health_snapshot_job_saves_device_health() {
+        let mut config = crate::config::Config::from_env().unwrap();
+        let snapshot_path =
+            std::env::temp_dir().join(format!("iot_scheduler_health_{}.json", crate::auth::random_hex(8)));
+        config.storage_config.health_snapshot_path = Some(snapshot_path.clone());
+        let device_manager = Arc::new(crate::device::DeviceManager::new());
+        let storage = Arc::new(StorageService::new(config.storage_config, device_manager.clone()));
+        let monitoring = Arc::new(Monitoring::new(device_manager.clone()));
+        monitoring.restore_monitoring_data(HashMap::from([(
+            "device1".to_string(),
+            crate::monitoring::DeviceHealth::unknown(),
+        )]));
+        let scheduler_config = SchedulerConfig {
+            jobs: vec![crate::config::JobConfig {
+                name: "health".to_string(),
+                schedule: "*/5 * * * *".to_string(),
+                job: JobKind::HealthSnapshot,
+                misfire: MisfirePolicy::Skip,
+            }],
+            state_path: None,
+        };
+        let analytics = Arc::new(Analytics::new(device_manager));
+        let scheduler = Scheduler::from_config(&scheduler_config, analytics, monitoring, Some(storage.clone())).unwrap();
+
+        let run = scheduler.run_job("health").await.unwrap();
+        assert!(run.success, "{}", run.message);
+        assert!(storage.load_device_health().unwrap().contains_key("device1"));
+        std::fs::remove_file(snapshot_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_health_reports_are_stored() {
+        let mut config = crate::config::Config::from_env().unwrap();
+        let data_dir = std::env::temp_dir().join(format!("iot_scheduler_reports_{}", crate::auth::random_hex(8)));
+        config.storage_config.data_dir = Some(data_dir.clone());
+        let device_manager = Arc::new(crate::device::DeviceManager::new());
+        let storage = Arc::new(StorageService::new(config.storage_config, device_manager.clone()));
+        let monitoring = Arc::new(Monitoring::new(device_manager.clone()));
+        monitoring.restore_monitoring_data(HashMap::from([(
+            "device1".to_string(),
+            crate::monitoring::DeviceHealth::unknown(),
+        )]));
+        let scheduler_config = SchedulerConfig {
+            jobs: vec![crate::config::JobConfig {
+                name: "reports".to_string(),
+                schedule: "*/5 * * * *".to_string(),
+                job: JobKind::HealthReport,
+                misfire: MisfirePolicy::Skip,
+            }],
+            state_path: None,
+        };
+        let analytics = Arc::new(Analytics::new(device_manager));
+        let scheduler = Scheduler::from_config(&scheduler_config, analytics, monitoring, Some(storage.clone())).unwrap();
+
+        let run = scheduler.run_job("reports").await.unwrap();
+        assert!(run.success, "{}", run.message);
+        let stored = storage.load_health_reports(crate::tenant::DEFAULT_TENANT).unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].report.device_id, "device1");
+        std::fs::remove_dir_all(data_dir).unwrap();
+    }
+}
@@ -1,28 +1,126 @@
 ```rust
 // storage_service.rs
 
+use crate::analytics::MetricAggregate;
 use crate::config::StorageConfig;
-use crate::device::{Device, DeviceManager};
+use crate::device::{DeviceManager, Reading};
+use crate::events::AlertRecord;
+use crate::monitoring::{DeviceHealth, HealthReport};
+use crate::queue::ReadingQueue;
+use crate::tenant::{is_valid_id, split_device_id, TenantManager, DEFAULT_TENANT};
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
-use std::error::Error;
+
+/// Directory of a tenant's partition holding its readings, one file per UTC day.
+pub const READINGS: &str = "readings";
+
+/// Directory of a tenant's partition holding the analytics rollups of its devices.
+pub const ROLLUPS: &str = "rollups";
+
+/// Directory of a tenant's partition holding the health reports of its devices.
+pub const HEALTH_REPORTS: &str = "health_reports";
+
+/// Directory of a tenant's partition holding the alerts raised about its devices.
+pub const ALERTS: &str = "alerts";
+
+/// The aggregates of a device's finalized analytics windows as of a scheduled rollup.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredRollup {
+    /// The platform-wide device id.
+    pub device_id: String,
+    pub rolled_up_at: DateTime<Utc>,
+    pub metrics: HashMap<String, MetricAggregate>,
+}
+
+/// A device's health report as of a scheduled run.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredHealthReport {
+    pub generated_at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub report: HealthReport,
+}
 
 /// Represents the storage service responsible for persisting device data.
 pub struct StorageService {
     config: StorageConfig,
-    device_manager: Arc<Mutex<DeviceManager>>,
+    device_manager: Arc<DeviceManager>,
+    tenants: Option<Arc<TenantManager>>,
+    // Bytes stored per tenant, counted when the service starts and kept up to date as records
+    // are written and removed. Also held while appending so records never interleave.
+    partition_bytes: Mutex<HashMap<String, u64>>,
+}
+
+/// Returns the total size of the files below a directory.
+fn dir_size(path: &Path) -> std::io::Result<u64> {
+    let mut size = 0;
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        size += if metadata.is_dir() { dir_size(&entry.path())? } else { metadata.len() };
+    }
+    Ok(size)
+}
+
+/// Returns the day a daily partition file holds records of, e.g. `2024-03-10.jsonl`.
+fn partition_day(path: &Path) -> Option<NaiveDate> {
+    if path.extension()? != "jsonl" {
+        return None;
+    }
+    NaiveDate::parse_from_str(path.file_stem()?.to_str()?, "%Y-%m-%d").ok()
+}
+
+/// Returns the daily files in a directory, oldest first.
+fn daily_files(dir: &Path) -> crate::Result<Vec<(NaiveDate, PathBuf)>> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if let Some(day) = partition_day(&path) {
+            files.push((day, path));
+        }
+    }
+    files.sort();
+    Ok(files)
 }
 
 impl StorageService {
     /// Creates a new storage service with the given configuration.
-    pub fn new(config: StorageConfig, device_manager: Arc<Mutex<DeviceManager>>) -> Self {
+    pub fn new(config: StorageConfig, device_manager: Arc<DeviceManager>) -> Self {
+        let mut partition_bytes = HashMap::new();
+        if let Some(entries) = config.data_dir.as_ref().and_then(|data_dir| std::fs::read_dir(data_dir).ok()) {
+            for entry in entries.flatten().filter(|entry| entry.path().is_dir()) {
+                match dir_size(&entry.path()) {
+                    Ok(size) => {
+                        partition_bytes.insert(entry.file_name().to_string_lossy().to_string(), size);
+                    }
+                    Err(e) => eprintln!("Failed to measure storage partition {:?}: {}", entry.path(), e),
+                }
+            }
+        }
         StorageService {
             config,
             device_manager,
+            tenants: None,
+            partition_bytes: Mutex::new(partition_bytes),
         }
     }
 
+    /// Applies the retention configured for each tenant to its partition.
+    pub fn with_tenants(mut self, tenants: Arc<TenantManager>) -> Self {
+        self.tenants = Some(tenants);
+        self
+    }
+
     /// Runs the storage service, listening for incoming data and storing it.
-    pub fn run(&mut self) -> Result<(), Box<dyn Error>> {
+    pub fn run(&mut self) -> crate::Result<()> {
         // Here you would connect to the database using the provided database URL
         // and max_connections from the config. Since we're not implementing a real
         // database connection in this example, we'll just print the configuration.
@@ -48,18 +146,250 @@ impl StorageService {
         Ok(())
     }
 
+    /// Stores processed readings from the queue until it is closed and drained.
+    pub async fn run_queue(&self, queue: Arc<ReadingQueue>) {
+        while let Some(reading) = queue.pop().await {
+            if let Err(e) = self.store_reading(&reading) {
+                eprintln!("Failed to store data for device {}: {}", reading.device_id, e);
+            }
+        }
+        println!("Storage queue drained");
+    }
+
     /// Stores the data for a specific device.
-    fn store_device_data(&self, device_id: &str, data: std::collections::HashMap<String, f64>) -> Result<(), Box<dyn Error>> {
-        let mut device_manager = self.device_manager.lock().unwrap();
-        device_manager.update_device_data(device_id, data);
+    fn store_device_data(&self, device_id: &str, data: std::collections::HashMap<String, f64>) -> crate::Result<()> {
+        self.store_reading(&Reading::new(device_id.to_string(), data))?;
+        Ok(())
+    }
 
-        // Here you would typically insert the data into the database.
-        // For this example, we'll just print the data that would be stored.
+    /// Appends a reading to the daily file of its event time in its tenant's partition.
+    pub fn store_reading(&self, reading: &Reading) -> crate::Result<()> {
+        let tenant_id = split_device_id(&reading.device_id).0;
+        self.append(tenant_id, READINGS, reading.event_time, reading)?;
+        self.device_manager.update_device_data(&reading.device_id, reading.data.clone());
+        Ok(())
+    }
+
+    /// Returns the readings stored for a tenant, oldest day first.
+    pub fn load_readings(&self, tenant_id: &str) -> crate::Result<Vec<Reading>> {
+        self.read_partition(tenant_id, READINGS)
+    }
+
+    /// Stores the rollups of a scheduled run in the partitions of the devices' tenants.
+    pub fn store_rollups(
+        &self,
+        at: DateTime<Utc>,
+        rollups: &HashMap<String, HashMap<String, MetricAggregate>>,
+    ) -> crate::Result<()> {
+        for (device_id, metrics) in rollups {
+            let record = StoredRollup {
+                device_id: device_id.clone(),
+                rolled_up_at: at,
+                metrics: metrics.clone(),
+            };
+            self.append(split_device_id(device_id).0, ROLLUPS, at, &record)?;
+        }
+        Ok(())
+    }
 
-        println!("Storing data for device {}: {:?}", device_id, data);
+    /// Returns the rollups stored for a tenant, oldest first.
+    pub fn load_rollups(&self, tenant_id: &str) -> crate::Result<Vec<StoredRollup>> {
+        self.read_partition(tenant_id, ROLLUPS)
+    }
 
+    /// Stores the health reports of a scheduled run in the partitions of the devices' tenants.
+    pub fn store_health_reports(
+        &self,
+        at: DateTime<Utc>,
+        reports: &HashMap<String, HealthReport>,
+    ) -> crate::Result<()> {
+        for (device_id, report) in reports {
+            let record = StoredHealthReport {
+                generated_at: at,
+                report: report.clone(),
+            };
+            self.append(split_device_id(device_id).0, HEALTH_REPORTS, at, &record)?;
+        }
         Ok(())
     }
+
+    /// Returns the health reports stored for a tenant, oldest first.
+    pub fn load_health_reports(&self, tenant_id: &str) -> crate::Result<Vec<StoredHealthReport>> {
+        self.read_partition(tenant_id, HEALTH_REPORTS)
+    }
+
+    /// Stores an alert in its tenant's partition. Alerts that are not about a specific device
+    /// belong to the default tenant.
+    pub fn store_alert(&self, alert: &AlertRecord) -> crate::Result<()> {
+        let tenant_id = alert.notification.device_id.as_deref().map_or(DEFAULT_TENANT, |id| split_device_id(id).0);
+        self.append(tenant_id, ALERTS, alert.notification.timestamp, alert)
+    }
+
+    /// Returns the alerts stored for a tenant, or for every tenant when None, oldest day first.
+    pub fn load_alerts(&self, tenant_id: Option<&str>) -> crate::Result<Vec<AlertRecord>> {
+        let tenant_ids = match tenant_id {
+            Some(tenant_id) => vec![tenant_id.to_string()],
+            None => self.stored_tenants()?,
+        };
+        let mut alerts = Vec::new();
+        for tenant_id in tenant_ids {
+            alerts.extend(self.read_partition::<AlertRecord>(&tenant_id, ALERTS)?);
+        }
+        Ok(alerts)
+    }
+
+    /// Returns true if records are written to tenant partitions, i.e. a data directory is set.
+    pub fn has_partitions(&self) -> bool {
+        self.config.data_dir.is_some()
+    }
+
+    /// Returns the directory of a tenant's partition, or None when nothing is persisted.
+    fn partition_dir(&self, tenant_id: &str) -> crate::Result<Option<PathBuf>> {
+        let data_dir = match &self.config.data_dir {
+            Some(data_dir) => data_dir,
+            None => return Ok(None),
+        };
+        // Tenant ids name directories, so they must not reach outside the data directory
+        if !is_valid_id(tenant_id) || tenant_id.starts_with('.') || tenant_id.contains(['\\', '\0']) {
+            return Err(format!("tenant id {:?} cannot name a storage partition", tenant_id).into());
+        }
+        Ok(Some(data_dir.join(tenant_id)))
+    }
+
+    /// Appends a record as a JSON line to the daily file of `kind` in the tenant's partition.
+    fn append<T: Serialize>(&self, tenant_id: &str, kind: &str, at: DateTime<Utc>, record: &T) -> crate::Result<()> {
+        let dir = match self.partition_dir(tenant_id)? {
+            Some(partition) => partition.join(kind),
+            None => return Ok(()),
+        };
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+
+        let mut partition_bytes = self.partition_bytes.lock().unwrap();
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join(format!("{}.jsonl", at.format("%Y-%m-%d")));
+        OpenOptions::new().create(true).append(true).open(path)?.write_all(&line)?;
+        *partition_bytes.entry(tenant_id.to_string()).or_insert(0) += line.len() as u64;
+        Ok(())
+    }
+
+    /// Reads every record of `kind` in the tenant's partition, oldest day first. Lines that do
+    /// not parse, e.g. one cut short by a crash, are skipped.
+    fn read_partition<T: DeserializeOwned>(&self, tenant_id: &str, kind: &str) -> crate::Result<Vec<T>> {
+        let dir = match self.partition_dir(tenant_id)? {
+            Some(partition) => partition.join(kind),
+            None => return Ok(Vec::new()),
+        };
+        let mut records = Vec::new();
+        for (_, path) in daily_files(&dir)? {
+            for line in BufReader::new(File::open(&path)?).lines() {
+                let line = line?;
+                match serde_json::from_str(&line) {
+                    Ok(record) => records.push(record),
+                    Err(e) if !line.trim().is_empty() => eprintln!("Skipping unreadable record in {:?}: {}", path, e),
+                    Err(_) => {}
+                }
+            }
+        }
+        Ok(records)
+    }
+
+    /// Returns the ids of the tenants with a partition.
+    fn stored_tenants(&self) -> crate::Result<Vec<String>> {
+        let data_dir = match &self.config.data_dir {
+            Some(data_dir) if data_dir.exists() => data_dir,
+            _ => return Ok(Vec::new()),
+        };
+        let mut tenant_ids = Vec::new();
+        for entry in std::fs::read_dir(data_dir)? {
+            let entry = entry?;
+            if entry.path().is_dir() {
+                tenant_ids.push(entry.file_name().to_string_lossy().to_string());
+            }
+        }
+        Ok(tenant_ids)
+    }
+
+    /// Returns how many bytes the tenant's partition takes up.
+    pub fn tenant_storage_bytes(&self, tenant_id: &str) -> u64 {
+        let partition_bytes = self.partition_bytes.lock().unwrap();
+        partition_bytes.get(tenant_id).copied().unwrap_or(0)
+    }
+
+    /// Deletes the daily files older than each tenant's retention, returning how many were
+    /// removed. Tenants without a retention keep their data.
+    pub fn apply_retention(&self, now: DateTime<Utc>) -> crate::Result<usize> {
+        let tenants = match &self.tenants {
+            Some(tenants) => tenants,
+            None => return Ok(0),
+        };
+        let mut removed = 0;
+        for tenant_id in self.stored_tenants()? {
+            let retention_days = match tenants.get_tenant(&tenant_id).and_then(|tenant| tenant.settings.retention_days) {
+                Some(days) => days,
+                None => continue,
+            };
+            let cutoff = (now - Duration::days(retention_days as i64)).date_naive();
+            let partition = match self.partition_dir(&tenant_id)? {
+                Some(partition) => partition,
+                None => continue,
+            };
+            for kind in std::fs::read_dir(&partition)? {
+                let kind = kind?.path();
+                if !kind.is_dir() {
+                    continue;
+                }
+                for (day, path) in daily_files(&kind)? {
+                    if day >= cutoff {
+                        break;
+                    }
+                    let mut partition_bytes = self.partition_bytes.lock().unwrap();
+                    let size = std::fs::metadata(&path)?.len();
+                    std::fs::remove_file(&path)?;
+                    if let Some(bytes) = partition_bytes.get_mut(&tenant_id) {
+                        *bytes = bytes.saturating_sub(size);
+                    }
+                    removed += 1;
+                }
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Deletes everything stored for a tenant.
+    pub fn delete_tenant_data(&self, tenant_id: &str) -> crate::Result<()> {
+        let mut partition_bytes = self.partition_bytes.lock().unwrap();
+        if let Some(partition) = self.partition_dir(tenant_id)?.filter(|partition| partition.exists()) {
+            std::fs::remove_dir_all(partition)?;
+        }
+        partition_bytes.remove(tenant_id);
+        Ok(())
+    }
+
+    /// Persists the health records of all devices to the configured snapshot file.
+    pub fn store_device_health(&self, health: &HashMap<String, DeviceHealth>) -> crate::Result<()> {
+        let path = match &self.config.health_snapshot_path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        // Write to a temporary file first so a crash never leaves a truncated snapshot behind
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, serde_json::to_vec(health)?)?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    /// Loads the persisted health records, returning an empty map if none were stored yet.
+    pub fn load_device_health(&self) -> crate::Result<HashMap<String, DeviceHealth>> {
+        match &self.config.health_snapshot_path {
+            Some(path) if path.exists() => {
+                let snapshot = std::fs::read(path)?;
+                Ok(serde_json::from_slice(&snapshot)?)
+            }
+            _ => Ok(HashMap::new()),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -70,11 +400,64 @@ mod tests {
     #[test]
     fn test_storage_service_run() {
         let config = Config::from_env().unwrap();
-        let device_manager = Arc::new(Mutex::new(DeviceManager::new()));
+        let device_manager = Arc::new(DeviceManager::new());
         let mut storage_service = StorageService::new(config.storage_config, device_manager);
 
         let result = storage_service.run();
         assert!(result.is_ok());
     }
+
+    #[test]
+    fn test_device_health_snapshot_round_trip() {
+        let mut config = Config::from_env().unwrap();
+        let snapshot_path = std::env::temp_dir().join("iot_health_snapshot_test.json");
+        config.storage_config.health_snapshot_path = Some(snapshot_path.clone());
+        let device_manager = Arc::new(DeviceManager::new());
+        let storage_service = StorageService::new(config.storage_config, device_manager);
+
+        let mut health = HashMap::new();
+        health.insert("device1".to_string(), DeviceHealth::unknown());
+        storage_service.store_device_health(&health).unwrap();
+
+        let loaded = storage_service.load_device_health().unwrap();
+        assert!(loaded.contains_key("device1"));
+        std::fs::remove_file(snapshot_path).unwrap();
+    }
+
+    #[test]
+    fn test_readings_are_partitioned_and_retained_per_tenant() {
+        let data_dir = std::env::temp_dir().join(format!("iot_storage_test_{}", crate::auth::random_hex(8)));
+        let mut config = Config::from_env().unwrap();
+        config.storage_config.data_dir = Some(data_dir.clone());
+        let mut acme = crate::tenant::Tenant::new("acme".to_string(), "Acme".to_string());
+        acme.settings.retention_days = Some(7);
+        let tenants = Arc::new(TenantManager::new(vec![acme]));
+        let storage_service =
+            StorageService::new(config.storage_config, Arc::new(DeviceManager::new())).with_tenants(tenants);
+
+        let now = Utc::now();
+        let data = HashMap::from([("temperature".to_string(), 21.0)]);
+        for (device_id, days_ago) in [("d1", 30), ("acme/d1", 30), ("acme/d1", 1)] {
+            let event_time = now - Duration::days(days_ago);
+            let reading = Reading::new(device_id.to_string(), data.clone()).with_event_time(event_time);
+            storage_service.store_reading(&reading).unwrap();
+        }
+        assert_eq!(storage_service.load_readings("acme").unwrap().len(), 2);
+        assert_eq!(storage_service.load_readings("default").unwrap().len(), 1);
+        let acme_bytes = storage_service.tenant_storage_bytes("acme");
+        assert!(acme_bytes > 0);
+        assert!(storage_service.load_readings("../acme").is_err());
+
+        // Only acme has a retention, so only its month-old reading goes
+        assert_eq!(storage_service.apply_retention(now).unwrap(), 1);
+        assert_eq!(storage_service.load_readings("acme").unwrap().len(), 1);
+        assert_eq!(storage_service.load_readings("default").unwrap().len(), 1);
+        assert!(storage_service.tenant_storage_bytes("acme") < acme_bytes);
+
+        storage_service.delete_tenant_data("acme").unwrap();
+        assert!(storage_service.load_readings("acme").unwrap().is_empty());
+        assert_eq!(storage_service.tenant_storage_bytes("acme"), 0);
+        std::fs::remove_dir_all(data_dir).unwrap();
+    }
 }
 ```
@@ -0,0 +1,233 @@
+// tenant.rs
+
+use crate::config::{RateLimit, StageConfig};
+use crate::device::{Device, DeviceManager};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Tenant that owns everything not explicitly assigned to another tenant. Its device ids are
+/// not prefixed, so single-tenant deployments keep their ids, and its admins operate the platform.
+pub const DEFAULT_TENANT: &str = "default";
+
+/// Separates the tenant from the device id in platform-wide device ids.
+const TENANT_SEPARATOR: char = '/';
+
+/// Returns the platform-wide id of a tenant's device, e.g. `acme/sensor1`.
+pub fn scoped_device_id(tenant_id: &str, device_id: &str) -> String {
+    if tenant_id == DEFAULT_TENANT {
+        device_id.to_string()
+    } else {
+        format!("{}{}{}", tenant_id, TENANT_SEPARATOR, device_id)
+    }
+}
+
+/// Splits a platform-wide device id into the tenant and the tenant's own device id.
+pub fn split_device_id(scoped_id: &str) -> (&str, &str) {
+    match scoped_id.split_once(TENANT_SEPARATOR) {
+        Some((tenant_id, device_id)) => (tenant_id, device_id),
+        None => (DEFAULT_TENANT, scoped_id),
+    }
+}
+
+/// Returns true if a tenant or device id can be used in platform-wide device ids.
+pub fn is_valid_id(id: &str) -> bool {
+    !id.trim().is_empty() && !id.contains(TENANT_SEPARATOR)
+}
+
+/// Returns the device as the tenant sees it, with its own id.
+pub fn local_device(mut device: Device) -> Device {
+    device.id = split_device_id(&device.id).1.to_string();
+    device
+}
+
+/// Keeps the entries of a map keyed by platform-wide device id that belong to the tenant,
+/// keyed by the tenant's own device ids.
+pub fn tenant_view<T>(entries: HashMap<String, T>, tenant_id: &str) -> HashMap<String, T> {
+    entries
+        .into_iter()
+        .filter_map(|(scoped_id, value)| {
+            let (owner, device_id) = split_device_id(&scoped_id);
+            (owner == tenant_id).then(|| (device_id.to_string(), value))
+        })
+        .collect()
+}
+
+/// Settings that apply to a single tenant.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct TenantSettings {
+    /// Maximum number of devices the tenant may register.
+    #[serde(default)]
+    pub max_devices: Option<usize>,
+    /// How long the tenant's analytics windows and stored data are kept, in days.
+    #[serde(default)]
+    pub retention_days: Option<u32>,
+    /// Processing pipelines by device type, used instead of the platform's for the same type.
+    #[serde(default)]
+    pub pipelines: HashMap<String, Vec<StageConfig>>,
+    /// Hex encoded SHA-256 of the tokens devices send as `tenant_token` to report to this tenant.
+    #[serde(default)]
+    pub ingestion_token_sha256: Vec<String>,
+    /// Datagrams the tenant's devices may send, instead of the platform's per-tenant limit.
+    #[serde(default)]
+    pub rate_limit: Option<RateLimit>,
+    /// Datagrams accepted per day, counted in UTC days.
+    #[serde(default)]
+    pub max_messages_per_day: Option<u64>,
+    /// Payload bytes accepted in total, which is what the tenant's readings take up in storage.
+    #[serde(default)]
+    pub max_storage_bytes: Option<u64>,
+}
+
+/// A customer of the platform, owning its devices and their data.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Tenant {
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub settings: TenantSettings,
+    /// Incremented whenever the settings change, so cached pipelines can be rebuilt.
+    #[serde(skip)]
+    pub revision: u64,
+}
+
+impl Tenant {
+    /// Creates a tenant with default settings.
+    pub fn new(id: String, name: String) -> Self {
+        Tenant {
+            id,
+            name,
+            settings: TenantSettings::default(),
+            revision: 0,
+        }
+    }
+}
+
+/// Keeps track of the platform's tenants and their settings.
+pub struct TenantManager {
+    tenants: Arc<Mutex<HashMap<String, Tenant>>>,
+}
+
+impl TenantManager {
+    /// Creates a manager with the configured tenants, adding the default tenant if missing.
+    pub fn new(tenants: Vec<Tenant>) -> Self {
+        let mut by_id: HashMap<String, Tenant> = tenants.into_iter().map(|tenant| (tenant.id.clone(), tenant)).collect();
+        by_id
+            .entry(DEFAULT_TENANT.to_string())
+            .or_insert_with(|| Tenant::new(DEFAULT_TENANT.to_string(), "Default".to_string()));
+        TenantManager {
+            tenants: Arc::new(Mutex::new(by_id)),
+        }
+    }
+
+    /// Adds a tenant unless one with the same id exists. Returns false if the id is taken.
+    pub fn create_tenant(&self, tenant: Tenant) -> bool {
+        let mut tenants = self.tenants.lock().unwrap();
+        if tenants.contains_key(&tenant.id) {
+            return false;
+        }
+        tenants.insert(tenant.id.clone(), tenant);
+        true
+    }
+
+    /// Replaces a tenant's settings, returning the updated tenant if it exists.
+    pub fn update_settings(&self, tenant_id: &str, settings: TenantSettings) -> Option<Tenant> {
+        let mut tenants = self.tenants.lock().unwrap();
+        let tenant = tenants.get_mut(tenant_id)?;
+        tenant.settings = settings;
+        tenant.revision += 1;
+        Some(tenant.clone())
+    }
+
+    /// Removes a tenant. The default tenant cannot be removed.
+    pub fn remove_tenant(&self, tenant_id: &str) -> Option<Tenant> {
+        if tenant_id == DEFAULT_TENANT {
+            return None;
+        }
+        self.tenants.lock().unwrap().remove(tenant_id)
+    }
+
+    /// Retrieves a tenant by id.
+    pub fn get_tenant(&self, tenant_id: &str) -> Option<Tenant> {
+        self.tenants.lock().unwrap().get(tenant_id).cloned()
+    }
+
+    /// Returns how often a tenant's settings changed, or None if there is no such tenant.
+    pub fn revision(&self, tenant_id: &str) -> Option<u64> {
+        self.tenants.lock().unwrap().get(tenant_id).map(|tenant| tenant.revision)
+    }
+
+    /// Returns all tenants ordered by id.
+    pub fn list_tenants(&self) -> Vec<Tenant> {
+        let mut tenants: Vec<Tenant> = self.tenants.lock().unwrap().values().cloned().collect();
+        tenants.sort_by(|a, b| a.id.cmp(&b.id));
+        tenants
+    }
+
+    /// Returns the settings of the tenant owning a platform-wide device id.
+    pub fn settings_for_device(&self, scoped_id: &str) -> TenantSettings {
+        let tenant_id = split_device_id(scoped_id).0;
+        self.get_tenant(tenant_id).map(|tenant| tenant.settings).unwrap_or_default()
+    }
+
+    /// Finds the tenant an ingestion token belongs to.
+    pub fn tenant_for_ingestion_token(&self, token: &str) -> Option<String> {
+        let hash = hex::encode(Sha256::digest(token.as_bytes()));
+        let tenants = self.tenants.lock().unwrap();
+        tenants
+            .values()
+            .find(|tenant| tenant.settings.ingestion_token_sha256.iter().any(|expected| expected.eq_ignore_ascii_case(&hash)))
+            .map(|tenant| tenant.id.clone())
+    }
+
+    /// Returns true if the tenant may register another device under its device quota.
+    pub fn can_add_device(&self, tenant_id: &str, device_manager: &DeviceManager) -> bool {
+        let max_devices = match self.get_tenant(tenant_id) {
+            Some(tenant) => tenant.settings.max_devices,
+            None => return false,
+        };
+        match max_devices {
+            Some(max_devices) => device_manager.count_devices(tenant_id) < max_devices,
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scoped_device_ids() {
+        assert_eq!(scoped_device_id(DEFAULT_TENANT, "sensor1"), "sensor1");
+        assert_eq!(scoped_device_id("acme", "sensor1"), "acme/sensor1");
+        assert_eq!(split_device_id("acme/sensor1"), ("acme", "sensor1"));
+        assert_eq!(split_device_id("sensor1"), (DEFAULT_TENANT, "sensor1"));
+        assert!(!is_valid_id("a/b"));
+
+        let entries = HashMap::from([("acme/s1".to_string(), 1), ("s1".to_string(), 2)]);
+        assert_eq!(tenant_view(entries, "acme"), HashMap::from([("s1".to_string(), 1)]));
+    }
+
+    #[test]
+    fn test_device_quota_and_ingestion_tokens() {
+        let mut acme = Tenant::new("acme".to_string(), "Acme".to_string());
+        acme.settings.max_devices = Some(1);
+        acme.settings.ingestion_token_sha256 = vec![hex::encode(Sha256::digest(b"acme-devices"))];
+        let tenants = TenantManager::new(vec![acme]);
+        let device_manager = DeviceManager::new();
+
+        assert_eq!(tenants.tenant_for_ingestion_token("acme-devices").as_deref(), Some("acme"));
+        assert!(tenants.tenant_for_ingestion_token("guess").is_none());
+
+        assert!(tenants.can_add_device("acme", &device_manager));
+        device_manager.ensure_device(&scoped_device_id("acme", "s1"));
+        device_manager.ensure_device("s1");
+        assert!(!tenants.can_add_device("acme", &device_manager));
+        assert!(tenants.can_add_device(DEFAULT_TENANT, &device_manager));
+        assert!(!tenants.can_add_device("unknown", &device_manager));
+        assert!(tenants.remove_tenant(DEFAULT_TENANT).is_none());
+    }
+}
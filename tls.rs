@@ -0,0 +1,214 @@
+// tls.rs
+
+use crate::config::TlsConfig;
+use crate::tenant::{is_valid_id, split_device_id};
+use rustls::server::AllowAnyAnonymousOrAuthenticatedClient;
+use rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+use webrtc_dtls::config::{ClientAuthType, Config as DtlsServerConfig, ExtendedMasterSecretType};
+use webrtc_dtls::crypto::{Certificate as DtlsCertificate, CryptoPrivateKey};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+/// Certificates and keys loaded from a listener's PEM files, with the server configurations
+/// built from them.
+struct LoadedCertificates {
+    /// Contents of the certificate, key and client CA files, to tell when they changed.
+    files: Vec<Vec<u8>>,
+    certs: Vec<Certificate>,
+    key: PrivateKey,
+    client_cas: Option<RootCertStore>,
+    tls: Arc<ServerConfig>,
+}
+
+/// Holds the certificate of a TLS or DTLS listener and reloads it when its files change, so
+/// renewed certificates apply to new connections without a restart.
+pub struct CertificateStore {
+    config: TlsConfig,
+    loaded: Mutex<Arc<LoadedCertificates>>,
+}
+
+impl CertificateStore {
+    /// Loads the listener's certificate files.
+    pub fn new(config: TlsConfig) -> crate::Result<Self> {
+        let loaded = load(&config, read_files(&config)?)?;
+        Ok(CertificateStore {
+            config,
+            loaded: Mutex::new(Arc::new(loaded)),
+        })
+    }
+
+    /// Loads the certificate files again if they changed. Files that fail to load keep the
+    /// previous certificate in use. Returns true if a new certificate was loaded.
+    pub fn reload(&self) -> crate::Result<bool> {
+        let files = read_files(&self.config)?;
+        if self.loaded.lock().unwrap().files == files {
+            return Ok(false);
+        }
+        let loaded = load(&self.config, files)?;
+        *self.loaded.lock().unwrap() = Arc::new(loaded);
+        println!("Reloaded the TLS certificate from {}", self.config.cert_path.display());
+        Ok(true)
+    }
+
+    /// Reloads the certificate files periodically until the shutdown signal fires.
+    pub async fn watch(&self, mut shutdown: watch::Receiver<bool>) {
+        let interval = std::time::Duration::from_secs(self.config.reload_interval_secs.max(1));
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(interval) => {
+                    if let Err(e) = self.reload() {
+                        eprintln!("Failed to reload the TLS certificate: {}", e);
+                    }
+                }
+                _ = shutdown.changed() => break,
+            }
+        }
+    }
+
+    /// Returns how long clients may take to complete the handshake.
+    pub fn handshake_timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.config.handshake_timeout_secs)
+    }
+
+    /// Returns the TLS configuration for new connections.
+    pub fn tls_config(&self) -> Arc<ServerConfig> {
+        self.loaded.lock().unwrap().tls.clone()
+    }
+
+    /// Returns the DTLS configuration for new connections. Clients may present a certificate
+    /// signed by one of the client CAs, but do not have to.
+    pub fn dtls_config(&self) -> crate::Result<DtlsServerConfig> {
+        let loaded = self.loaded.lock().unwrap().clone();
+        let key_pair = rcgen::KeyPair::from_der(&loaded.key.0)?;
+        let certificate = DtlsCertificate {
+            certificate: loaded.certs.clone(),
+            private_key: CryptoPrivateKey::from_key_pair(&key_pair)?,
+        };
+        let (client_auth, client_cas) = match &loaded.client_cas {
+            Some(roots) => (ClientAuthType::VerifyClientCertIfGiven, roots.clone()),
+            None => (ClientAuthType::NoClientCert, RootCertStore::empty()),
+        };
+        Ok(DtlsServerConfig {
+            certificates: vec![certificate],
+            client_auth,
+            client_cas,
+            extended_master_secret: ExtendedMasterSecretType::Require,
+            ..Default::default()
+        })
+    }
+}
+
+/// Returns the device id a client certificate was issued to, taken from its common name.
+/// Tenant devices use their platform-wide id, e.g. `acme/sensor1`.
+pub fn device_id_from_certificate(der: &[u8]) -> Option<String> {
+    let (_, certificate) = X509Certificate::from_der(der).ok()?;
+    let common_name = certificate.subject().iter_common_name().next()?.as_str().ok()?;
+    let (tenant_id, device_id) = split_device_id(common_name);
+    (is_valid_id(tenant_id) && is_valid_id(device_id)).then(|| common_name.to_string())
+}
+
+fn read_files(config: &TlsConfig) -> crate::Result<Vec<Vec<u8>>> {
+    let mut files = vec![std::fs::read(&config.cert_path)?, std::fs::read(&config.key_path)?];
+    if let Some(path) = &config.client_ca_path {
+        files.push(std::fs::read(path)?);
+    }
+    Ok(files)
+}
+
+fn load(config: &TlsConfig, files: Vec<Vec<u8>>) -> crate::Result<LoadedCertificates> {
+    let certs: Vec<Certificate> =
+        rustls_pemfile::certs(&mut files[0].as_slice())?.into_iter().map(Certificate).collect();
+    if certs.is_empty() {
+        return Err(format!("no certificate in {}", config.cert_path.display()).into());
+    }
+    let key = rustls_pemfile::pkcs8_private_keys(&mut files[1].as_slice())?
+        .into_iter()
+        .next()
+        .map(PrivateKey)
+        .ok_or_else(|| format!("no PKCS#8 private key in {}", config.key_path.display()))?;
+    let client_cas = match files.get(2) {
+        Some(pem) => {
+            let mut roots = RootCertStore::empty();
+            for ca in rustls_pemfile::certs(&mut pem.as_slice())? {
+                roots.add(&Certificate(ca))?;
+            }
+            Some(roots)
+        }
+        None => None,
+    };
+
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match &client_cas {
+        Some(roots) => {
+            builder.with_client_cert_verifier(AllowAnyAnonymousOrAuthenticatedClient::new(roots.clone()).boxed())
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut tls = builder.with_single_cert(certs.clone(), key.clone())?;
+    tls.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+    Ok(LoadedCertificates {
+        files,
+        certs,
+        key,
+        client_cas,
+        tls: Arc::new(tls),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{CertificateParams, DistinguishedName, DnType};
+
+    fn certificate(common_name: &str) -> rcgen::Certificate {
+        let mut params = CertificateParams::new(vec!["localhost".to_string()]);
+        params.distinguished_name = DistinguishedName::new();
+        params.distinguished_name.push(DnType::CommonName, common_name);
+        rcgen::Certificate::from_params(params).unwrap()
+    }
+
+    #[test]
+    fn test_certificates_are_reloaded_when_they_change() {
+        let dir = std::env::temp_dir().join(format!("iot_tls_test_{}", crate::auth::random_hex(8)));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = TlsConfig {
+            cert_path: dir.join("cert.pem"),
+            key_path: dir.join("key.pem"),
+            client_ca_path: None,
+            reload_interval_secs: 1,
+            handshake_timeout_secs: 10,
+        };
+        let first = certificate("first");
+        std::fs::write(&config.cert_path, first.serialize_pem().unwrap()).unwrap();
+        std::fs::write(&config.key_path, first.serialize_private_key_pem()).unwrap();
+
+        let store = CertificateStore::new(config.clone()).unwrap();
+        assert!(!store.reload().unwrap());
+        assert!(store.dtls_config().is_ok());
+
+        let second = certificate("second");
+        let second_pem = second.serialize_pem().unwrap();
+        std::fs::write(&config.cert_path, &second_pem).unwrap();
+        std::fs::write(&config.key_path, second.serialize_private_key_pem()).unwrap();
+        assert!(store.reload().unwrap());
+
+        // A broken file keeps the certificate in use
+        std::fs::write(&config.key_path, "not a key").unwrap();
+        assert!(store.reload().is_err());
+        let der = rustls_pemfile::certs(&mut second_pem.as_bytes()).unwrap().remove(0);
+        assert_eq!(store.loaded.lock().unwrap().certs[0].0, der);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_device_id_from_client_certificate() {
+        let der = certificate("acme/sensor1").serialize_der().unwrap();
+        assert_eq!(device_id_from_certificate(&der).as_deref(), Some("acme/sensor1"));
+        let der = certificate("sensor1").serialize_der().unwrap();
+        assert_eq!(device_id_from_certificate(&der).as_deref(), Some("sensor1"));
+        let der = certificate("acme/").serialize_der().unwrap();
+        assert!(device_id_from_certificate(&der).is_none());
+    }
+}
@@ -0,0 +1,435 @@
+// users.rs
+
+use crate::auth::{hash_secret, random_hex, CredentialKind, Principal, Scope};
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use chrono::{DateTime, Duration, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, OnceLock};
+
+/// Prefix of session tokens, telling them apart from JWTs in the `Authorization` header.
+pub const SESSION_PREFIX: &str = "iots";
+
+/// Shortest password accepted for a user.
+pub const MIN_PASSWORD_LENGTH: usize = 10;
+
+/// What a user may do, across the organization or within a device group.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Reads devices and telemetry.
+    Viewer,
+    /// Also manages devices.
+    Operator,
+    /// Everything, including users and API keys.
+    Admin,
+}
+
+impl Role {
+    /// Returns the scopes the role grants.
+    pub fn scopes(&self) -> Vec<Scope> {
+        match self {
+            Role::Viewer => vec![Scope::ReadTelemetry],
+            Role::Operator => vec![Scope::ReadTelemetry, Scope::ManageDevices],
+            Role::Admin => vec![Scope::Admin],
+        }
+    }
+}
+
+/// A role on a device group, i.e. on the devices carrying the group's tag.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct Grant {
+    pub group: String,
+    pub role: Role,
+}
+
+/// A person signing in to the platform. Organizations are tenants: a user belongs to one.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct User {
+    pub id: String,
+    pub username: String,
+    pub tenant_id: String,
+    /// Role on the whole organization, if any.
+    pub role: Option<Role>,
+    /// Roles on device groups, in addition to the organization role.
+    #[serde(default)]
+    pub grants: Vec<Grant>,
+    #[serde(default)]
+    pub disabled: bool,
+    pub created_at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "String::is_empty", default)]
+    #[schemars(skip)]
+    password_hash: String,
+}
+
+impl User {
+    /// Returns a copy without the password hash, for listing users over the API.
+    pub fn redacted(&self) -> User {
+        User {
+            password_hash: String::new(),
+            ..self.clone()
+        }
+    }
+
+    /// Applies an update's role, grants and disabled flag. Passwords are set by
+    /// [`UserManager::update_user`], which hashes them.
+    pub fn apply_update(&mut self, update: &UserUpdate) {
+        if let Some(role) = update.role {
+            self.role = role;
+        }
+        if let Some(grants) = &update.grants {
+            self.grants = grants.clone();
+        }
+        if let Some(disabled) = update.disabled {
+            self.disabled = disabled;
+        }
+    }
+
+    /// Returns the principal acting on behalf of the user.
+    fn principal(&self) -> Principal {
+        let mut group_scopes: HashMap<String, Vec<Scope>> = HashMap::new();
+        for grant in &self.grants {
+            group_scopes.entry(grant.group.clone()).or_default().extend(grant.role.scopes());
+        }
+        Principal {
+            subject: self.username.clone(),
+            scopes: self.role.map(|role| role.scopes()).unwrap_or_default(),
+            credential: CredentialKind::Session,
+            tenant_id: self.tenant_id.clone(),
+            group_scopes,
+            source_ip: None,
+        }
+    }
+}
+
+/// Changes to a user; fields left out are not changed.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct UserUpdate {
+    /// The new organization role; `null` removes it.
+    #[serde(default, with = "double_option")]
+    #[schemars(with = "Option<Role>")]
+    pub role: Option<Option<Role>>,
+    pub grants: Option<Vec<Grant>>,
+    pub password: Option<String>,
+    pub disabled: Option<bool>,
+}
+
+/// Tells a field set to `null` apart from a missing one.
+mod double_option {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<T: Serialize, S: Serializer>(value: &Option<Option<T>>, serializer: S) -> Result<S::Ok, S::Error> {
+        match value {
+            Some(inner) => inner.serialize(serializer),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, T: Deserialize<'de>, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Option<T>>, D::Error> {
+        Option::<T>::deserialize(deserializer).map(Some)
+    }
+}
+
+/// A signed-in user.
+#[derive(Debug, Clone)]
+struct Session {
+    user_id: String,
+    expires_at: DateTime<Utc>,
+}
+
+/// A session token returned when a user signs in.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct SessionToken {
+    /// The value clients send as `Authorization: Bearer <token>`.
+    pub token: String,
+    pub expires_at: DateTime<Utc>,
+    pub user: User,
+}
+
+/// What is persisted to the user store file.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct UserStore {
+    users: HashMap<String, User>,
+}
+
+/// Manages users, their roles and grants, and their sessions.
+pub struct UserManager {
+    store: Arc<Mutex<UserStore>>,
+    // Sessions by token hash; they are kept in memory only, so a restart signs everybody out
+    sessions: Arc<Mutex<HashMap<String, Session>>>,
+    store_path: Option<PathBuf>,
+    session_ttl: Duration,
+}
+
+impl UserManager {
+    /// Creates the manager, loading the users persisted at `store_path`.
+    pub fn new(store_path: Option<PathBuf>, session_ttl: Duration) -> crate::Result<Self> {
+        let store = match &store_path {
+            Some(path) if path.exists() => serde_json::from_slice(&std::fs::read(path)?)?,
+            _ => UserStore::default(),
+        };
+        Ok(UserManager {
+            store: Arc::new(Mutex::new(store)),
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+            store_path,
+            session_ttl,
+        })
+    }
+
+    /// Creates a user in a tenant. Returns None if the username is taken.
+    pub fn create_user(
+        &self,
+        tenant_id: &str,
+        username: &str,
+        password: &str,
+        role: Option<Role>,
+        grants: Vec<Grant>,
+    ) -> crate::Result<Option<User>> {
+        let password_hash = hash_password(password)?;
+        let user = {
+            let mut store = self.store.lock().unwrap();
+            if store.users.values().any(|user| user.username == username) {
+                return Ok(None);
+            }
+            let user = User {
+                id: random_hex(8),
+                username: username.to_string(),
+                tenant_id: tenant_id.to_string(),
+                role,
+                grants,
+                disabled: false,
+                created_at: Utc::now(),
+                password_hash,
+            };
+            store.users.insert(user.id.clone(), user.clone());
+            user
+        };
+        self.save()?;
+        Ok(Some(user.redacted()))
+    }
+
+    /// Applies an update to a user, returning the updated user if it exists.
+    pub fn update_user(&self, user_id: &str, update: UserUpdate) -> crate::Result<Option<User>> {
+        let password_hash = update.password.as_deref().map(hash_password).transpose()?;
+        let updated = {
+            let mut store = self.store.lock().unwrap();
+            let user = match store.users.get_mut(user_id) {
+                Some(user) => user,
+                None => return Ok(None),
+            };
+            user.apply_update(&update);
+            if let Some(password_hash) = password_hash {
+                user.password_hash = password_hash;
+            }
+            user.clone()
+        };
+        self.save()?;
+        Ok(Some(updated.redacted()))
+    }
+
+    /// Removes a user and ends its sessions, returning it if it existed.
+    pub fn remove_user(&self, user_id: &str) -> crate::Result<Option<User>> {
+        let removed = self.store.lock().unwrap().users.remove(user_id);
+        self.sessions.lock().unwrap().retain(|_, session| session.user_id != user_id);
+        self.save()?;
+        Ok(removed.map(|user| user.redacted()))
+    }
+
+    /// Removes every user of a removed tenant and ends their sessions, returning how many
+    /// there were.
+    pub fn remove_tenant_users(&self, tenant_id: &str) -> crate::Result<usize> {
+        let removed: Vec<String> = {
+            let mut store = self.store.lock().unwrap();
+            let ids: Vec<String> =
+                store.users.values().filter(|user| user.tenant_id == tenant_id).map(|user| user.id.clone()).collect();
+            for id in &ids {
+                store.users.remove(id);
+            }
+            ids
+        };
+        self.sessions.lock().unwrap().retain(|_, session| !removed.contains(&session.user_id));
+        self.save()?;
+        Ok(removed.len())
+    }
+
+    /// Retrieves a user without its password hash.
+    pub fn get_user(&self, user_id: &str) -> Option<User> {
+        self.store.lock().unwrap().users.get(user_id).map(User::redacted)
+    }
+
+    /// Retrieves a user by username, without its password hash.
+    pub fn find_user(&self, username: &str) -> Option<User> {
+        self.store.lock().unwrap().users.values().find(|user| user.username == username).map(User::redacted)
+    }
+
+    /// Lists the users of a tenant, or of all tenants, ordered by username.
+    pub fn list_users(&self, tenant_id: Option<&str>) -> Vec<User> {
+        let store = self.store.lock().unwrap();
+        let mut users: Vec<User> = store
+            .users
+            .values()
+            .filter(|user| match tenant_id {
+                Some(tenant_id) => user.tenant_id == tenant_id,
+                None => true,
+            })
+            .map(User::redacted)
+            .collect();
+        users.sort_by(|a, b| a.username.cmp(&b.username));
+        users
+    }
+
+    /// Signs a user in, returning a new session token if the password is right.
+    pub fn login(&self, username: &str, password: &str) -> Option<SessionToken> {
+        let user = {
+            let store = self.store.lock().unwrap();
+            store.users.values().find(|user| user.username == username && !user.disabled).cloned()
+        };
+        // Unknown usernames cost a password check too, so timing does not tell which exist
+        let verified = {
+            let password_hash = match &user {
+                Some(user) => user.password_hash.as_str(),
+                None => dummy_password_hash(),
+            };
+            verify_password(password, password_hash)
+        };
+        if !verified {
+            return None;
+        }
+        let user = user?;
+
+        let token = format!("{}_{}", SESSION_PREFIX, random_hex(32));
+        let expires_at = Utc::now() + self.session_ttl;
+        let mut sessions = self.sessions.lock().unwrap();
+        let now = Utc::now();
+        sessions.retain(|_, session| session.expires_at > now);
+        sessions.insert(
+            hash_secret(&token),
+            Session {
+                user_id: user.id.clone(),
+                expires_at,
+            },
+        );
+        Some(SessionToken {
+            token,
+            expires_at,
+            user: user.redacted(),
+        })
+    }
+
+    /// Ends a session. Returns false if the token was not a live session.
+    pub fn logout(&self, token: &str) -> bool {
+        self.sessions.lock().unwrap().remove(&hash_secret(token)).is_some()
+    }
+
+    /// Returns the principal of a live session. Role and grant changes apply immediately
+    /// because the principal is built from the current user record on every request.
+    pub fn authenticate_session(&self, token: &str) -> Option<Principal> {
+        let user_id = {
+            let sessions = self.sessions.lock().unwrap();
+            let session = sessions.get(&hash_secret(token))?;
+            if session.expires_at <= Utc::now() {
+                return None;
+            }
+            session.user_id.clone()
+        };
+        let store = self.store.lock().unwrap();
+        store.users.get(&user_id).filter(|user| !user.disabled).map(User::principal)
+    }
+
+    /// Writes the users to the user store file.
+    fn save(&self) -> crate::Result<()> {
+        let path = match &self.store_path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let contents = serde_json::to_vec(&*self.store.lock().unwrap())?;
+
+        // Write to a temporary file first so a crash never leaves a truncated user store behind
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, contents)?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+}
+
+/// Checks a new password against the password policy.
+pub fn validate_password(password: &str) -> Result<(), String> {
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(format!("password must have at least {} characters", MIN_PASSWORD_LENGTH));
+    }
+    Ok(())
+}
+
+/// Hashes a password with Argon2id and a random salt.
+fn hash_password(password: &str) -> crate::Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| format!("failed to hash password: {}", e))?;
+    Ok(hash.to_string())
+}
+
+/// A hash no password matches in practice, checked against when the username is unknown.
+fn dummy_password_hash() -> &'static str {
+    static DUMMY: OnceLock<String> = OnceLock::new();
+    DUMMY.get_or_init(|| hash_password(&random_hex(32)).expect("hashing a random password cannot fail"))
+}
+
+fn verify_password(password: &str, password_hash: &str) -> bool {
+    PasswordHash::new(password_hash)
+        .is_ok_and(|hash| Argon2::default().verify_password(password.as_bytes(), &hash).is_ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_login_sessions_and_role_changes() {
+        let users = UserManager::new(None, Duration::hours(1)).unwrap();
+        let grants = vec![Grant {
+            group: "roof".to_string(),
+            role: Role::Operator,
+        }];
+        let user = users.create_user("acme", "alice", "correct horse", None, grants).unwrap().unwrap();
+        assert!(users.create_user("acme", "alice", "another password", None, Vec::new()).unwrap().is_none());
+
+        assert!(users.login("alice", "wrong password").is_none());
+        assert!(users.login("mallory", "correct horse").is_none());
+        let session = users.login("alice", "correct horse").unwrap();
+        let principal = users.authenticate_session(&session.token).unwrap();
+        assert_eq!(principal.tenant_id, "acme");
+        assert!(!principal.has_scope(Scope::ReadTelemetry));
+        assert!(principal.group_scopes["roof"].contains(&Scope::ManageDevices));
+
+        // A role change applies to the live session
+        let update = UserUpdate {
+            role: Some(Some(Role::Viewer)),
+            ..UserUpdate::default()
+        };
+        let updated = users.update_user(&user.id, update).unwrap().unwrap();
+        assert_eq!(updated.role, Some(Role::Viewer));
+        assert_eq!(updated.grants, user.grants);
+        assert!(users.authenticate_session(&session.token).unwrap().has_scope(Scope::ReadTelemetry));
+
+        let disable = UserUpdate {
+            disabled: Some(true),
+            ..UserUpdate::default()
+        };
+        users.update_user(&user.id, disable).unwrap();
+        assert!(users.authenticate_session(&session.token).is_none());
+        assert!(users.logout(&session.token));
+    }
+
+    #[test]
+    fn test_update_distinguishes_null_from_missing_role() {
+        let update: UserUpdate = serde_json::from_str(r#"{"role": null}"#).unwrap();
+        assert_eq!(update.role, Some(None));
+        let update: UserUpdate = serde_json::from_str(r#"{"disabled": false}"#).unwrap();
+        assert_eq!(update.role, None);
+    }
+}